@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ai::profile::AiProfile;
+use crate::game_map::GameMap;
+use crate::tile::Tile;
+use crate::unit::{Team, Unit};
+
+//A tile one of the team's units might end its move on
+struct Spot {
+    tile: Tile,
+    targets: Vec<usize>, //Units it could attack from there
+    threats: Vec<usize>, //Units of the other team that could attack it there next turn
+}
+
+/* The fighting a team's plan leads to, for the GA to weigh up alongside where the units end up. The units that can be
+ * attacked (the other team's and the barbarians) stay put for the whole turn, so what each of the team's units could
+ * attack, and be attacked by, from each tile it might move to is worked out once up front.
+ * Plans are scored on expected damage rather than rolls: each unit attacks the target that's worth the most given what
+ * the units before it are expected to have done to it, so finishing off a wounded unit (focus fire) is worth more than
+ * spreading the damage around. Then each unit is docked for the damage the other team is expected to do to it next turn.
+ */
+pub struct Battlefield {
+    fighters: Vec<Unit>, //The team's units, in the order the GA visits them
+    spots: Vec<HashMap<(u32, u32), Spot>>,
+    targets: Vec<(Unit, Tile)>,
+}
+
+impl Battlefield {
+    //moves holds the tiles each of the team's units might end its move on, in the GA's order
+    pub fn new(game_map: &mut GameMap, team: Team, moves: &[Vec<(u32, u32)>]) -> Battlefield {
+        let GameMap { map_tiles, player_units, enemy_units, barbarian_units, .. } = game_map;
+        let (own_units, other_units) = match team {
+            Team::Player => (&*player_units, &*enemy_units),
+            _ => (&*enemy_units, &*player_units),
+        };
+
+        let mut fighter_positions: Vec<(u32, u32)> = own_units.keys().copied().collect();
+        fighter_positions.sort_by_key(|pos| (pos.1, pos.0));
+        let mut target_positions: Vec<(u32, u32)> = other_units.keys().copied().collect();
+        target_positions.sort_by_key(|pos| (pos.1, pos.0));
+        let num_threats = target_positions.len();
+        let mut barbarian_positions: Vec<(u32, u32)> = barbarian_units.keys().copied().collect();
+        barbarian_positions.sort_by_key(|pos| (pos.1, pos.0));
+        target_positions.append(&mut barbarian_positions);
+
+        let mut targets: Vec<(Unit, Tile)> = Vec::new();
+        let mut target_index: HashMap<(u32, u32), usize> = HashMap::new();
+        for pos in target_positions.iter() {
+            let unit = other_units.get(pos).or_else(|| barbarian_units.get(pos)).unwrap();
+            //Remember map is flipped indexing
+            if let Some(tile) = map_tiles.get(&(pos.1, pos.0)) {
+                target_index.insert(*pos, targets.len());
+                targets.push((unit.clone(), tile.clone()));
+            }
+        }
+
+        //Every tile each of the other team's units could attack after moving on its next turn
+        let mut threatened: Vec<HashSet<(u32, u32)>> = Vec::new();
+        for (unit, _) in targets.iter().take(num_threats) {
+            let mut unit = unit.clone();
+            let mut tiles: HashSet<(u32, u32)> = HashSet::new();
+            for pos in unit.get_tiles_in_movement_range(map_tiles) {
+                unit.update_pos(pos.0, pos.1);
+                tiles.extend(unit.get_tiles_in_attack_range(map_tiles));
+            }
+            threatened.push(tiles);
+        }
+
+        let mut spots: Vec<HashMap<(u32, u32), Spot>> = Vec::new();
+        for (index, pos) in fighter_positions.iter().enumerate() {
+            let fighter = &own_units[pos];
+            let mut unit_spots: HashMap<(u32, u32), Spot> = HashMap::new();
+            let candidates = moves.get(index).map_or(&[][..], |m| &m[..]);
+            for spot in candidates.iter().chain(std::iter::once(pos)) {
+                if unit_spots.contains_key(spot) {
+                    continue;
+                }
+                let tile = match map_tiles.get(&(spot.1, spot.0)) {
+                    Some(tile) => tile.clone(),
+                    None => continue,
+                };
+                let spot_targets = fighter.get_tiles_can_attack_from_pos(*spot, map_tiles).iter().filter_map(|t| target_index.get(t).copied()).collect();
+                let spot_threats = (0..threatened.len()).filter(|&t| threatened[t].contains(spot)).collect();
+                unit_spots.insert(*spot, Spot { tile, targets: spot_targets, threats: spot_threats });
+            }
+            spots.push(unit_spots);
+        }
+
+        Battlefield {
+            fighters: fighter_positions.iter().map(|pos| own_units[pos].clone()).collect(),
+            spots,
+            targets,
+        }
+    }
+
+    //Value of the fighting if the team's units (in the GA's order) end their moves on the given tiles
+    pub fn plan_value(&self, moves: &[(u32, u32)], profile: &AiProfile) -> f64 {
+        let mut value = 0.0;
+        let mut hp_left: Vec<f64> = self.targets.iter().map(|(unit, _)| unit.hp as f64).collect();
+        let mut alive: Vec<f64> = vec![1.0; self.targets.len()];
+
+        for (index, pos) in moves.iter().enumerate() {
+            let (fighter, spot) = match (self.fighters.get(index), self.spots.get(index).and_then(|s| s.get(pos))) {
+                (Some(fighter), Some(spot)) => (fighter, spot),
+                _ => continue,
+            };
+            let best = spot.targets.iter()
+                .filter(|&&t| hp_left[t] > 0.0)
+                .map(|&t| (t, attack_value(fighter, &self.targets[t].0, &self.targets[t].1, hp_left[t], profile)))
+                .max_by(|(_, a), (_, b)| a.0.partial_cmp(&b.0).unwrap());
+            if let Some((t, (attack, damage, kill))) = best {
+                value += attack;
+                hp_left[t] -= damage;
+                alive[t] *= 1.0 - kill;
+            }
+        }
+
+        //What's left of the other team gets its turn next
+        for (index, pos) in moves.iter().enumerate() {
+            let (fighter, spot) = match (self.fighters.get(index), self.spots.get(index).and_then(|s| s.get(pos))) {
+                (Some(fighter), Some(spot)) => (fighter, spot),
+                _ => continue,
+            };
+            let mut fighter_hp = fighter.hp as f64;
+            for &t in spot.threats.iter() {
+                if fighter_hp <= 0.0 {
+                    break;
+                }
+                let (_, damage, kill) = attack_value(&self.targets[t].0, fighter, &spot.tile, fighter_hp, profile);
+                value -= alive[t] * (profile.threat_weight * damage + profile.kill_weight * kill * worth(fighter));
+                fighter_hp -= alive[t] * damage;
+            }
+        }
+
+        value
+    }
+}
+
+//How much the attack is worth to the attacker, followed by the damage it does on average and the chance of it being a kill
+//when the target only has hp_left. The target's class and tile are taken into account (e.g. evasion makes hits less likely)
+pub fn attack_value(attacker: &Unit, target: &Unit, target_tile: &Tile, hp_left: f64, profile: &AiProfile) -> (f64, f64, f64) {
+    let hit = attacker.hit_chance(target, target_tile) as f64 / 100.0;
+    let damages = attacker.hit_damages(target, target_tile);
+    if hit <= 0.0 || damages.is_empty() {
+        return (0.0, 0.0, 0.0);
+    }
+    let rolls = damages.len() as f64;
+
+    let damage = hit * damages.iter().map(|&d| (d as f64).min(hp_left)).sum::<f64>() / rolls;
+    let kill = hit * damages.iter().filter(|&&d| d as f64 >= hp_left).count() as f64 / rolls;
+    (profile.damage_weight * damage + profile.kill_weight * kill * worth(target), damage, kill)
+}
+
+//What a unit is worth keeping around (or taking out): the damage it can be expected to deal each turn, for as long as it lasts
+pub fn worth(unit: &Unit) -> f64 {
+    unit.average_damage() * (1.0 + unit.max_hp as f64 / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::profile::tests::test_profile;
+    use crate::game_map::tests::load_unit_classes;
+    use crate::map_data::MapData;
+    use crate::net::util::{EVENT_UNIT_ARCHER, EVENT_UNIT_MELEE};
+    use crate::unit_class::UnitClass;
+
+    const SCOUT: u8 = 4;
+    const GUARD: u8 = 1;
+
+    //An open 16x4 field with the given units
+    fn field(units: &str) -> GameMap {
+        load_unit_classes();
+        let rows = vec![". ".repeat(16).trim_end().to_string(); 4].join("\n");
+        let map = format!("castle_quest_map 1\nname Field\nplayers 2\nsize 16 4\n\nlegend\n'.' grass_tile walk shoot\nend\n\ntiles\n{}\nend\n\nstructures\ncastle 1 0 3\ncastle 2 15 0\nend\n\nunits\n{}end\n", rows, units);
+        GameMap::new(&MapData::parse(&map).unwrap(), Team::Player, 1).unwrap()
+    }
+
+    //The same class, without its abilities
+    fn plain(class: &UnitClass) -> &'static UnitClass {
+        Box::leak(Box::new(UnitClass {
+            id: class.id,
+            name: class.name.clone(),
+            hp: class.hp,
+            movement_range: class.movement_range,
+            attack_range: class.attack_range,
+            accuracy: class.accuracy,
+            min_damage: class.min_damage,
+            max_damage: class.max_damage,
+            vision_range: class.vision_range,
+            ranged_attacker: class.ranged_attacker,
+            sprites: class.sprites.clone(),
+            barbarian_stats: class.barbarian_stats,
+            abilities: Vec::new(),
+        }))
+    }
+
+    #[test]
+    fn focus_fire_beats_spreading_attacks() {
+        let profile = test_profile("normal");
+        let mut game_map = field("1 melee 5 1\n1 melee 10 1\n2 melee 7 0\n2 melee 8 2\n");
+        game_map.player_units.get_mut(&(5, 1)).unwrap().hp = 6;
+        let battlefield = Battlefield::new(&mut game_map, Team::Enemy, &[vec![(5, 0)], vec![(5, 2), (10, 2)]]);
+
+        //Both attacking the wounded unit might kill it, one attack each can't kill either
+        let focused = battlefield.plan_value(&[(5, 0), (5, 2)], profile);
+        let spread = battlefield.plan_value(&[(5, 0), (10, 2)], profile);
+        assert!(focused > spread, "{} {}", focused, spread);
+    }
+
+    #[test]
+    fn kills_get_likelier_as_hp_drops() {
+        let profile = test_profile("normal");
+        let game_map = field("1 melee 5 1\n2 melee 6 1\n");
+        let (attacker, target, tile) = (&game_map.enemy_units[&(6, 1)], &game_map.player_units[&(5, 1)], &game_map.map_tiles[&(1, 5)]);
+
+        let mut last_kill = -1.0;
+        for &hp in [20.0, 6.0, 4.0, 2.0, 1.0].iter() {
+            let (_, damage, kill) = attack_value(attacker, target, tile, hp, profile);
+            assert!(kill >= last_kill && damage <= hp, "{} hp: {} damage, {} kill", hp, damage, kill);
+            last_kill = kill;
+        }
+        assert_eq!(attack_value(attacker, target, tile, 20.0, profile).2, 0.0);
+        assert!(last_kill > 0.9);
+    }
+
+    #[test]
+    fn tiles_the_player_can_reach_cost_threat() {
+        let profile = test_profile("normal");
+        //The player's melee can move 7 tiles and attack the one after
+        let mut game_map = field("1 melee 0 1\n2 melee 12 1\n");
+        let battlefield = Battlefield::new(&mut game_map, Team::Enemy, &[vec![(8, 1), (10, 1)]]);
+
+        assert_eq!(battlefield.plan_value(&[(10, 1)], profile), 0.0);
+        assert!(battlefield.plan_value(&[(8, 1)], profile) < 0.0);
+    }
+
+    #[test]
+    fn abilities_make_units_less_worth_attacking() {
+        let profile = test_profile("normal");
+        let game_map = field("1 melee 5 1\n");
+        let tile = &game_map.map_tiles[&(1, 5)];
+        let melee = Unit::from_kind(EVENT_UNIT_MELEE, Team::Enemy, 6, 1).unwrap();
+        let archer = Unit::from_kind(EVENT_UNIT_ARCHER, Team::Enemy, 8, 1).unwrap();
+
+        //Scouts are harder to hit
+        let scout = Unit::from_kind(SCOUT, Team::Player, 5, 1).unwrap();
+        let mut plain_scout = scout.clone();
+        plain_scout.class = plain(scout.class);
+        assert!(attack_value(&melee, &scout, tile, 9.0, profile).0 < attack_value(&melee, &plain_scout, tile, 9.0, profile).0);
+
+        //Guards take half damage from ranged attacks, but not from melee ones
+        let guard = Unit::from_kind(GUARD, Team::Player, 5, 1).unwrap();
+        let mut plain_guard = guard.clone();
+        plain_guard.class = plain(guard.class);
+        assert!(attack_value(&archer, &guard, tile, 25.0, profile).0 < attack_value(&archer, &plain_guard, tile, 25.0, profile).0);
+        assert_eq!(attack_value(&melee, &guard, tile, 25.0, profile).0, attack_value(&melee, &plain_guard, tile, 25.0, profile).0);
+    }
+}
@@ -1,6 +1,15 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::game_map::GameMap;
+use crate::tile::Tile;
+
+const CACHE_DIR: &str = "cache";
+
+//Distance stored for tiles that can't reach the goal at all (e.g. mountains or walled off areas)
+pub const UNREACHABLE: u32 = u32::MAX;
 
 pub struct DistanceMap {
     /* All map coordinates are in (x, y) order */
@@ -10,22 +19,63 @@ pub struct DistanceMap {
 }
 
 impl DistanceMap {
-    pub fn new() -> DistanceMap {
-        return DistanceMap::read_from_file("./src/ai/distances.txt".to_string());
+    //Loads the distance fields for this map from the cache, generating (and caching) them if they haven't been computed before
+    pub fn new(game_map: &GameMap) -> DistanceMap {
+        let path = format!("{}/distances_{:016x}.txt", CACHE_DIR, map_hash(game_map));
+
+        match DistanceMap::read_from_file(&path) {
+            Ok(distance_map) if distance_map.covers(game_map) => distance_map,
+            _ => {
+                let distance_map = DistanceMap::generate(game_map);
+                if let Err(e) = distance_map.write_to_file(&path) {
+                    println!("Could not cache distance map to {}: {}", path, e);
+                }
+                distance_map
+            },
+        }
+    }
+
+    //Computes the distance from every tile to each castle & barbarian camp, one breadth first search per goal
+    pub fn generate(game_map: &GameMap) -> DistanceMap {
+        println!("Calculating distances to each goal from each tile");
+
+        let map = &game_map.map_tiles;
+        let objectives = &game_map.objectives;
+
+        let mut to_barbarian_camps: HashMap<(u32, u32), HashMap<(u32, u32), u32>> = HashMap::new();
+        for camp in objectives.barbarian_camps.iter() {
+            //Camps are 2x2, so a unit standing on any of the tiles is at the camp
+            let camp_tiles = vec![*camp, (camp.0 + 1, camp.1), (camp.0, camp.1 + 1), (camp.0 + 1, camp.1 + 1)];
+            to_barbarian_camps.insert(*camp, distance_field(map, game_map.map_size, &camp_tiles));
+        }
+
+        DistanceMap {
+            to_player_castle: distance_field(map, game_map.map_size, &[objectives.p1_castle]),
+            to_enemy_castle: distance_field(map, game_map.map_size, &[objectives.p2_castle]),
+            to_barbarian_camps,
+        }
+    }
+
+    //Makes sure a cached file has a distance for every tile & goal, in case it was cut short while being written
+    fn covers(&self, game_map: &GameMap) -> bool {
+        let num_tiles = game_map.map_size.0 * game_map.map_size.1;
+        self.to_player_castle.len() == num_tiles &&
+        self.to_enemy_castle.len() == num_tiles &&
+        game_map.objectives.barbarian_camps.iter().all(|camp| self.to_barbarian_camps.get(camp).is_some_and(|d| d.len() == num_tiles))
     }
 
-    fn read_from_file(path: String) -> DistanceMap {
+    fn read_from_file(path: &str) -> Result<DistanceMap, String> {
         let mut to_player_castle: HashMap<(u32, u32), u32> = HashMap::new();
         let mut to_enemy_castle: HashMap<(u32, u32), u32> = HashMap::new();
         let mut to_barbarian_camps: HashMap<(u32, u32), HashMap<(u32, u32), u32>> = HashMap::new();
 
-        let file = File::open(path).expect("Could not open file");
+        let file = File::open(path).map_err(|e| e.to_string())?;
         let file_io = BufReader::new(file);
 
-        //Parse distances.txt and populate the hashmaps with the values from the file
+        //Parse the cached file and populate the hashmaps with the values from the file
         let mut cur_map = &mut to_player_castle;
         for line in file_io.lines() {
-            let line = line.unwrap();
+            let line = line.map_err(|e| e.to_string())?;
             //Put contents of current line into a vector
             let values: Vec<&str> = line.split(" ").collect();
 
@@ -39,13 +89,13 @@ impl DistanceMap {
             else if values.len() == 3 {
                 if values[0] == "#" {
                     //Add a new nested hashmap to the hashmap of barbarian camps
-                    let coords = (values[1].parse::<u32>().unwrap(), values[2].parse::<u32>().unwrap());
+                    let coords = (values[1].parse::<u32>().map_err(|e| e.to_string())?, values[2].parse::<u32>().map_err(|e| e.to_string())?);
                     to_barbarian_camps.insert(coords, HashMap::new());
                     cur_map = to_barbarian_camps.get_mut(&coords).unwrap();
                 }
                 else {
                     //Convert the string values into u32s
-                    let num_vals: Vec<u32> = values.iter().map(|s| s.parse::<u32>().unwrap()).collect();
+                    let num_vals: Vec<u32> = values.iter().map(|s| s.parse::<u32>()).collect::<Result<Vec<u32>, _>>().map_err(|e| e.to_string())?;
                     //num_vals[0] = x coordinate
                     //num_vals[1] = y coordinate
                     //num_vals[2] = distance from (x, y) to whichever goal the current map is for
@@ -54,10 +104,178 @@ impl DistanceMap {
             }
         }
 
-        DistanceMap {
+        Ok(DistanceMap {
             to_player_castle,
             to_enemy_castle,
             to_barbarian_camps,
+        })
+    }
+
+    fn write_to_file(&self, path: &str) -> Result<(), String> {
+        fs::create_dir_all(CACHE_DIR).map_err(|e| e.to_string())?;
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut file_io = BufWriter::new(file);
+
+        write_section(&mut file_io, "p1_castle", &self.to_player_castle)?;
+        write_section(&mut file_io, "enemy_castle", &self.to_enemy_castle)?;
+
+        writeln!(file_io, "barb_camps").map_err(|e| e.to_string())?;
+        for (camp, distances) in self.to_barbarian_camps.iter() {
+            writeln!(file_io, "# {} {}", camp.0, camp.1).map_err(|e| e.to_string())?;
+            for (pos, dist) in distances.iter() {
+                writeln!(file_io, "{} {} {}", pos.0, pos.1, dist).map_err(|e| e.to_string())?;
+            }
+        }
+        writeln!(file_io, "end").map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
+
+fn write_section(file_io: &mut BufWriter<File>, name: &str, distances: &HashMap<(u32, u32), u32>) -> Result<(), String> {
+    writeln!(file_io, "{}", name).map_err(|e| e.to_string())?;
+    for (pos, dist) in distances.iter() {
+        writeln!(file_io, "{} {} {}", pos.0, pos.1, dist).map_err(|e| e.to_string())?;
+    }
+    writeln!(file_io, "end").map_err(|e| e.to_string())?;
+    writeln!(file_io).map_err(|e| e.to_string())
+}
+
+//Multi-source Dijkstra outwards from the goal tiles over traversable terrain. The distance of a tile is the
+//total movement cost of the tiles a unit has to step onto to get from it to the nearest goal
+fn distance_field(map: &HashMap<(u32, u32), Tile>, map_size: (usize, usize), goals: &[(u32, u32)]) -> HashMap<(u32, u32), u32> {
+    let mut distances: HashMap<(u32, u32), u32> = HashMap::new();
+    let mut heap: BinaryHeap<Reverse<(u32, (u32, u32))>> = BinaryHeap::new();
+
+    for goal in goals.iter() {
+        //Remember map is flipped indexing
+        if map.get(&(goal.1, goal.0)).is_some_and(|t| t.is_traversable) {
+            distances.insert(*goal, 0);
+            heap.push(Reverse((0, *goal)));
+        }
+    }
+
+    while let Some(Reverse((distance, coords))) = heap.pop() {
+        if distances[&coords] < distance {
+            continue;
         }
+        //Getting here from a neighbour means stepping onto this tile
+        let cost = distance + map[&(coords.1, coords.0)].movement_cost;
+
+        let mut neighbours = vec![(coords.0 + 1, coords.1), (coords.0, coords.1 + 1)];
+        if coords.0 > 0 {
+            neighbours.push((coords.0 - 1, coords.1));
+        }
+        if coords.1 > 0 {
+            neighbours.push((coords.0, coords.1 - 1));
+        }
+
+        for next in neighbours {
+            if distances.get(&next).is_some_and(|&d| d <= cost) {
+                continue;
+            }
+            if let Some(tile) = map.get(&(next.1, next.0)) {
+                if tile.is_traversable {
+                    distances.insert(next, cost);
+                    heap.push(Reverse((cost, next)));
+                }
+            }
+        }
+    }
+
+    //Fill in the rest of the map so every lookup succeeds
+    for y in 0..map_size.1 as u32 {
+        for x in 0..map_size.0 as u32 {
+            distances.entry((x, y)).or_insert(UNREACHABLE);
+        }
+    }
+
+    distances
+}
+
+//FNV-1a hash of everything the distance fields depend on, used to key the cache file
+fn map_hash(game_map: &GameMap) -> u64 {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&(game_map.map_size.0 as u32).to_le_bytes());
+    bytes.extend_from_slice(&(game_map.map_size.1 as u32).to_le_bytes());
+    for y in 0..game_map.map_size.1 as u32 {
+        for x in 0..game_map.map_size.0 as u32 {
+            let tile = game_map.map_tiles.get(&(y, x));
+            bytes.push(tile.map_or(0, |t| t.is_traversable as u8));
+            bytes.extend_from_slice(&tile.map_or(0, |t| t.movement_cost).to_le_bytes());
+        }
+    }
+
+    let objectives = &game_map.objectives;
+    for goal in [objectives.p1_castle, objectives.p2_castle].iter().chain(objectives.barbarian_camps.iter()) {
+        bytes.extend_from_slice(&goal.0.to_le_bytes());
+        bytes.extend_from_slice(&goal.1.to_le_bytes());
     }
-}
\ No newline at end of file
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_map::tests::test_map;
+
+    #[test]
+    fn distances_go_around_impassable_tiles() {
+        let game_map = test_map(1);
+        let distances = distance_field(&game_map.map_tiles, game_map.map_size, &[(0, 0)]);
+
+        assert_eq!(distances.len(), 48);
+        assert_eq!(distances[&(0, 0)], 0);
+        assert_eq!(distances[&(1, 0)], 1);
+        assert_eq!(distances[&(7, 5)], 12);
+        //The mountain itself can't be walked onto, and the tile behind it takes the long way round
+        assert_eq!(distances[&(3, 2)], UNREACHABLE);
+        assert_eq!(distances[&(5, 2)], 7);
+    }
+
+    #[test]
+    fn distances_add_up_movement_costs() {
+        let mut game_map = test_map(1);
+        game_map.map_tiles.get_mut(&(0, 1)).unwrap().movement_cost = 5;
+        game_map.map_tiles.get_mut(&(0, 0)).unwrap().movement_cost = 2;
+        let distances = distance_field(&game_map.map_tiles, game_map.map_size, &[(0, 0)]);
+
+        //Only the tiles stepped onto on the way count, so leaving (1, 0) is cheap but going through it isn't
+        assert_eq!(distances[&(1, 0)], 2);
+        assert_eq!(distances[&(1, 1)], 3);
+        assert_eq!(distances[&(2, 0)], 5);
+    }
+
+    #[test]
+    fn walled_off_goals_are_unreachable() {
+        let mut game_map = test_map(1);
+        for pos in [(0, 1), (1, 1), (1, 0)].iter() {
+            game_map.map_tiles.get_mut(&(pos.1, pos.0)).unwrap().is_traversable = false;
+        }
+        let distances = distance_field(&game_map.map_tiles, game_map.map_size, &[(0, 0)]);
+        assert_eq!(distances[&(0, 0)], 0);
+        assert_eq!(distances[&(2, 0)], UNREACHABLE);
+
+        //Goals that can't be stood on are never reached
+        let distances = distance_field(&game_map.map_tiles, game_map.map_size, &[(3, 2)]);
+        assert!(distances.values().all(|&d| d == UNREACHABLE));
+    }
+
+    #[test]
+    fn generate_covers_every_goal() {
+        let game_map = test_map(1);
+        let distance_map = DistanceMap::generate(&game_map);
+
+        assert!(distance_map.covers(&game_map));
+        assert_eq!(distance_map.to_enemy_castle[&(7, 5)], 0);
+        //Any of a camp's 4 tiles is at the camp
+        assert_eq!(distance_map.to_barbarian_camps[&(5, 0)][&(6, 1)], 0);
+        assert_eq!(distance_map.to_barbarian_camps[&(5, 0)][&(4, 0)], 1);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod combat;
 pub mod distance_map;
 pub mod genetics;
-pub mod population_state;
\ No newline at end of file
+pub mod population_state;
+pub mod profile;
+pub mod search;
\ No newline at end of file
@@ -4,17 +4,12 @@ use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 
-use sdl2::render::Texture;
-
+use crate::ai::combat;
+use crate::ai::profile::AiProfile;
 use crate::tile::Tile;
-use crate::unit::{Unit, Team, QueueObject};
+use crate::unit::{Team, QueueObject, respawn_loc};
 use crate::game_map::GameMap;
-use crate::pixel_coordinates::PixelCoordinates;
-use crate::damage_indicator::DamageIndicator;
-use crate::SDLCore;
-
-const MAP_WIDTH: u32 = 64;
-const MAP_HEIGHT: u32 = 64;
+use crate::net::util::{Event, EVENT_MOVE, EVENT_ATTACK, EVENT_SPAWN_UNIT, EVENT_UNIT_ARCHER, EVENT_UNIT_MAGE, EVENT_UNIT_MELEE};
 
 #[derive(Clone)]
 pub struct PopulationState {
@@ -42,158 +37,113 @@ impl PopulationState {
         false
     }
 
-    // It does not matter if there is a duplicate move after only before
-    // This is used in actually converting the state to action
-    pub fn is_dupe_unit_placement_ending_at(&self, coordinates:&(u32,u32), ending_point: usize) -> bool {
-        for index in 0..ending_point {
-            if self.units_and_utility[index].0 == *coordinates {
-                //println!("{},{} == {},{}; this move already exists...", coordinates.0, coordinates.1, self.units_and_utility[index].0.0, self.units_and_utility[index].0.1);
-                return true;
-            }
-        }
-        false
-    }
+    // Pushes the events for the given (client relative) team's turn: each unit's move, then its attack on the unit in range
+    // that's worth attacking the most, as the GA weighed it (see combat::attack_value)
+    pub fn convert_state_to_action(&self, game_map: &mut GameMap, team: Team, profile: &AiProfile) -> Result<(), String> {
+        //Units are visited in the same sorted order genetic_algorithm built the state in
+        let units = match team {
+            Team::Player => &game_map.player_units,
+            _ => &game_map.enemy_units,
+        };
+        let mut unit_positions: Vec<(u32, u32)> = units.keys().copied().collect();
+        unit_positions.sort_by_key(|pos| (pos.1, pos.0));
 
-    // Currently just returns the movements for each unit (will eventually also handle attacks)
-    pub fn convert_state_to_action<'a> (&self, core: &SDLCore<'a>, unit_textures: &'a HashMap<String, Texture<'a>>, game_map: &mut GameMap<'a>) -> Result<(), String> {
-        let mut actual_units_mut = game_map.enemy_units.values_mut();
+        //The turn is only pushed as events here and applied later (like the player's and barbarians' turns), so the
+        //map tiles are just marked while planning and put back to how they were before the events get applied
+        let mut marked_tiles: HashMap<(u32, u32), Option<Team>> = HashMap::new();
         let mut actual_moves: Vec<((u32, u32), (u32, u32))> = Vec::new();  //Original coordinates followed by new coordinates
-        //println!("{} == {}", actual_units_mut.len(), self.units_and_utility.len()); //Check to make sure same size
         //Both the hashmap of units and the vector of moves should be the same length; if not something went wrong and should panic
-        for index in 0..self.units_and_utility.len() {
+        for (index, ogcoord) in unit_positions.iter().enumerate() {
+            let actual_unit = match team {
+                Team::Player => &game_map.player_units[ogcoord],
+                _ => &game_map.enemy_units[ogcoord],
+            };
+            let possible_moves = actual_unit.get_tiles_in_movement_range(&mut game_map.map_tiles);
             let mut new_move = self.units_and_utility[index].0;
-            let actual_unit = actual_units_mut.next().unwrap(); //Units should be in order so we can just use next to get corresponding unit (nth panics)
 
-            // If this move exists in the moves of the unit, move to it...
-            if !self.is_dupe_unit_placement_ending_at(&new_move, index) {
-                //Would like to update the hashmap of units but borrow checker says otherwise...
-                actual_moves.push(((actual_unit.x, actual_unit.y), new_move));
-            } else { // Else, we need to move to the closest possible tile
+            // If this move isn't one the unit can make anymore (e.g. another unit got there first), we need to move to the closest possible tile
+            if !possible_moves.contains(&new_move) {
                 println!("Best move not possible; need to find closest tile...");
                 print!("Old:{},{} -> ", new_move.0, new_move.1);
                 new_move = actual_unit.get_closest_move(new_move, &mut game_map.map_tiles);
+                if !possible_moves.contains(&new_move) {
+                    new_move = *ogcoord;
+                }
                 println!("New:{},{}\n", new_move.0, new_move.1);
-                actual_moves.push(((actual_unit.x, actual_unit.y), new_move));
-            }
-            // Update map tiles (even though we are not updating units, should still update map to properly restrict movements)
-            // Have to remember that map indexing is swapped
-            if let Some(old_map_tile) = game_map.map_tiles.get_mut(&(actual_unit.y, actual_unit.x)) {
-                old_map_tile.update_team(None);
             }
-            if let Some(new_map_tile) = game_map.map_tiles.get_mut(&(new_move.1, new_move.0)) {
-                new_map_tile.update_team(Some(Team::Enemy));
+            actual_moves.push((*ogcoord, new_move));
+
+            // Update map tiles (even though we are not moving units yet, should still update map to properly restrict movements)
+            mark_tile(&mut game_map.map_tiles, &mut marked_tiles, *ogcoord, None);
+            mark_tile(&mut game_map.map_tiles, &mut marked_tiles, new_move, Some(team));
+            if new_move != *ogcoord {
+                game_map.event_list.push(Event::create(EVENT_MOVE, 0, *ogcoord, new_move, 0));
             }
         }
 
-        //Now need to actually act on these moves now that units are no longer being borrowed
+        //Now handle the attack from each unit's new tile, keeping track of the damage done since none of it is applied yet
+        let mut damage_taken: HashMap<(u32, u32), u32> = HashMap::new();
+        let team_id = team.as_client(&game_map.player_state).to_id();
+        let GameMap { map_tiles, player_units, enemy_units, barbarian_units, objectives, player_state, event_list, rng, .. } = game_map;
+        let (own_units, other_units) = match team {
+            Team::Player => (&*player_units, &*enemy_units),
+            _ => (&*enemy_units, &*player_units),
+        };
         for (ogcoord, newcoord) in actual_moves {
-            let mut active_unit = game_map.enemy_units.remove(&(ogcoord.0, ogcoord.1)).unwrap();
-            active_unit.update_pos(newcoord.0, newcoord.1);
+            let active_unit = &own_units[&ogcoord];
 
-            //Also need to handle the attack at this tile if there is an attack
-            let enemies_to_attack = active_unit.get_tiles_can_attack(&mut game_map.map_tiles);
-            let mut dead_barb: bool = false;
-            if !enemies_to_attack.is_empty() {
-                //The enemy should attack the unit with the least health
-                let mut tile_with_least_health: (u32, u32) = enemies_to_attack[0];
-                let mut least_health: u32 = 1000;
-                for possible_attack in enemies_to_attack.iter() {
-                    if let Some(tile_under_attack) = game_map.map_tiles.get_mut(&(possible_attack.1, possible_attack.0)) {
-                        match tile_under_attack.contained_unit_team {
-                            Some(Team::Player) => {
-                                if let Some(unit) = game_map.player_units.get_mut(&(possible_attack.0, possible_attack.1)) {
-                                    if unit.hp < least_health {
-                                        least_health = unit.hp;
-                                        tile_with_least_health = (possible_attack.0, possible_attack.1);
-                                    }
-                                }
-                            },
-                            _ => {
-                                if let Some(unit) = game_map.barbarian_units.get_mut(&(possible_attack.0, possible_attack.1)) {
-                                    if unit.hp < least_health {
-                                        least_health = unit.hp;
-                                        tile_with_least_health = (possible_attack.0, possible_attack.1);
-                                    }
-                                }
-                            } //This handles the barbarian case and also prevents rust from complaining about unchecked cases,
-                        }
-                    }
-                }
-                if let Some(tile_under_attack) = game_map.map_tiles.get_mut(&(tile_with_least_health.1, tile_with_least_health.0)) {
-                    match tile_under_attack.contained_unit_team {
-                        Some(Team::Player) => {
-                            if let Some(unit) = game_map.player_units.get_mut(&(tile_with_least_health.0, tile_with_least_health.1)) {
-                                let damage_done = active_unit.get_attack_damage(unit);
-                                println!("Unit starting at {} hp.", unit.hp);
-                                if unit.hp <= damage_done {
-                                    game_map.player_units.remove(&(tile_with_least_health.0, tile_with_least_health.1));
-                                    println!("Player unit at {}, {} is dead after taking {} damage.", tile_with_least_health.0, tile_with_least_health.1, damage_done);
-                                    tile_under_attack.update_team(None);
-                                } else {
-                                    unit.receive_damage(damage_done, &active_unit);
-                                    game_map.damage_indicators.push(DamageIndicator::new(core, damage_done, PixelCoordinates::from_matrix_indices(
-                                        unit.y.checked_sub(1).unwrap_or(unit.y),
-                                        unit.x
-                                    ))?);
-                                    println!("Enemy at {}, {} attacking player unit at {}, {} for {} damage. Unit now has {} hp.", active_unit.x, active_unit.y, tile_with_least_health.0, tile_with_least_health.1, damage_done, unit.hp);
-                                }
-                            }
-                        },
-                        _ => {
-                            if let Some(unit) = game_map.barbarian_units.get_mut(&(tile_with_least_health.0, tile_with_least_health.1)) {
-                                let damage_done = active_unit.get_attack_damage(unit);
-                                println!("Barbarian unit starting at {} hp.", unit.hp);
-                                if unit.hp <= damage_done {
-                                    game_map.barbarian_units.remove(&(tile_with_least_health.0, tile_with_least_health.1));
-                                    println!("Barbarian unit at {}, {} is dead after taking {} damage.", tile_with_least_health.0, tile_with_least_health.1, damage_done);
-                                    tile_under_attack.update_team(None);
-                                    dead_barb = true;
-                                } else {
-                                    unit.receive_damage(damage_done, &active_unit);
-                                    game_map.damage_indicators.push(DamageIndicator::new(core, damage_done, PixelCoordinates::from_matrix_indices(
-                                        unit.y.checked_sub(1).unwrap_or(unit.y),
-                                        unit.x
-                                    ))?);
-                                    println!("Enemy at {}, {} attacking barbarian unit at {}, {} for {} damage. Unit now has {} hp.", active_unit.x, active_unit.y, tile_with_least_health.0, tile_with_least_health.1, damage_done, unit.hp);
-                                }
-                            }
-                        } //This handles the enemy case and also prevents rust from complaining about unchecked cases,
-                    }
-                }
-            }
+            //Units should attack the unit that's worth the most to attack (leaving alone units already killed this turn)
+            let target = active_unit.get_tiles_can_attack_from_pos(newcoord, map_tiles).into_iter()
+                .filter_map(|pos| other_units.get(&pos).or_else(|| barbarian_units.get(&pos)))
+                .map(|unit| (unit, unit.hp.saturating_sub(*damage_taken.get(&(unit.x, unit.y)).unwrap_or(&0))))
+                .filter(|(_, hp)| *hp > 0)
+                .filter_map(|(unit, hp)| map_tiles.get(&(unit.y, unit.x)).map(|tile| (unit, hp, combat::attack_value(active_unit, unit, tile, hp as f64, profile).0)))
+                .max_by(|(_, _, a), (_, _, b)| a.partial_cmp(b).unwrap())
+                .map(|(unit, hp, _)| (unit, hp));
+            let (unit, hp) = match target {
+                Some(target) => target,
+                None => continue,
+            };
 
-            //Don't forget to reinsert the unit into the hashmap
-            game_map.enemy_units.insert((newcoord.0, newcoord.1), active_unit);
-            if dead_barb {
+            let tile_under_attack = map_tiles.get(&(unit.y, unit.x)).ok_or("Could not obtain tile under attack")?;
+            let damage_done = active_unit.get_attack_damage(unit, tile_under_attack, rng);
+            let damage_received = unit.damage_taken_from(damage_done, active_unit);
+            *damage_taken.entry((unit.x, unit.y)).or_insert(0) += damage_received;
+            event_list.push(Event::create(EVENT_ATTACK, 0, newcoord, (unit.x, unit.y), damage_done as u8));
+            println!("{} unit at {}, {} attacking unit at {}, {} for {} damage.", team.to_string(), newcoord.0, newcoord.1, unit.x, unit.y, damage_done);
+
+            if unit.team == Team::Barbarians && hp <= damage_received {
                 //Need to check and see if this barbarian was converted - currently a 45% chance
-                let chance = rand::thread_rng().gen_range(0..100);
+                let chance = rng.gen_range(0..100);
                 if chance < 45 {
                     print!("Barbarian has been converted.");
-                    //Create the new unit with default stats and update the position of it accordingly
-                    let castle_coord = &game_map.objectives.p2_castle;
                     //Since all the units are of relatively equal value at base stats, we can randomly choose among them similar to how a player would
-                    let mut new_unit = if chance < 15 {
+                    let kind = if chance < 15 {
                             println!(" Melee selected.");
-                            Unit::new(castle_coord.0+5, castle_coord.1-5, Team::Enemy, 20, 7, 1, 95, 1, 5, unit_textures.get("pl2l").unwrap(), false)
+                            EVENT_UNIT_MELEE
                         } else if chance < 30 {
                             println!(" Ranged selected.");
-                            Unit::new(castle_coord.0+5, castle_coord.1-5, Team::Enemy, 15, 5, 4, 85, 3, 7, unit_textures.get("pl2r").unwrap(), true)
+                            EVENT_UNIT_ARCHER
                         } else {
                             println!(" Mage selected.");
-                            Unit::new(castle_coord.0+5, castle_coord.1-5, Team::Enemy, 10, 6, 3, 75,  5, 9, unit_textures.get("pl2m").unwrap(), true)
+                            EVENT_UNIT_MAGE
                         };
-
-                    let respawn_location = new_unit.respawn_loc(&mut game_map.map_tiles, *castle_coord);
-                    new_unit.update_pos(respawn_location.0, respawn_location.1);
-                    println!("Unit spawned at {}, {}", respawn_location.0, respawn_location.1);
-                    //Don't forget to update the players units and the hash map
-                    game_map.enemy_units.insert(respawn_location, new_unit);
-                    if let Some(new_map_tile) = game_map.map_tiles.get_mut(&(respawn_location.1, respawn_location.0)) {
-                        new_map_tile.update_team(Some(Team::Enemy));
-                    }
+                    let castle = match team {
+                        Team::Player => objectives.p1_castle,
+                        _ => objectives.p2_castle,
+                    };
+                    let respawn_location = respawn_loc(objectives.respawn_target(team, player_state), map_tiles, castle);
+                    mark_tile(map_tiles, &mut marked_tiles, respawn_location, Some(team));
+                    event_list.push(Event::create(EVENT_SPAWN_UNIT, team_id, (0,0), respawn_location, kind));
                 }
             }
         }
+
+        for (pos, original_team) in marked_tiles {
+            if let Some(tile) = map_tiles.get_mut(&(pos.1, pos.0)) {
+                tile.update_team(original_team);
+            }
+        }
         Ok(())
     }
 
@@ -237,7 +187,7 @@ impl SuccinctUnit {
 
 //Since we won't be passing around units, we need to create a generalized way to get units that can be attacked
 //Unlike the regular can attack function we only care about the units distance from that tile here
-pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordinates: (u32, u32), range: u32) -> Vec<u32> {
+pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordinates: (u32, u32), range: u32, team: Team) -> Vec<u32> {
     let mut tiles_in_range: Vec<u32> = Vec::new();
     let mut visited: HashMap<(u32,u32), bool> = HashMap::new();
     let mut heap = BinaryHeap::new();
@@ -255,8 +205,8 @@ pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordin
                     heap.push(QueueObject { coords: (coords.0-1, coords.1), cost:cost-1});
                     visited.insert((coords.0-1, coords.1), true);
                     match entry.get().contained_unit_team {
-                        Some(team) => {
-                            if team != Team::Enemy {
+                        Some(unit_team) => {
+                            if unit_team != team {
                                 tiles_in_range.push(range-(cost-1));
                             }
                         },
@@ -265,21 +215,19 @@ pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordin
                 }
             }
         }
-        if coords.0 < MAP_WIDTH-1 {
-            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
-                //As long as we have not already visited this tile
-                if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
-                    heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
-                    visited.insert((coords.0+1, coords.1), true);
-                    match entry.get().contained_unit_team {
-                        Some(team) => {
-                            if team != Team::Enemy {
-                                tiles_in_range.push(range-(cost-1));
-                            }
-                        },
-                        None => {}
-                    };
-                }
+        if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
+            //As long as we have not already visited this tile
+            if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
+                heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
+                visited.insert((coords.0+1, coords.1), true);
+                match entry.get().contained_unit_team {
+                    Some(unit_team) => {
+                        if unit_team != team {
+                            tiles_in_range.push(range-(cost-1));
+                        }
+                    },
+                    None => {}
+                };
             }
         }
         if coords.1 > 0 {
@@ -289,8 +237,8 @@ pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordin
                     heap.push(QueueObject { coords: (coords.0, coords.1-1), cost:cost-1});
                     visited.insert((coords.0, coords.1-1), true);
                     match entry.get().contained_unit_team {
-                        Some(team) => {
-                            if team != Team::Enemy {
+                        Some(unit_team) => {
+                            if unit_team != team {
                                 tiles_in_range.push(range-(cost-1));
                             }
                         },
@@ -299,23 +247,29 @@ pub fn generalized_tiles_can_attack(map: &mut HashMap<(u32, u32), Tile>, coordin
                 }
             }
         }
-        if coords.1 < MAP_HEIGHT-1 {
-            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
-                //As long as we have not already visited this tile
-                if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
-                    heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
-                    visited.insert((coords.0, coords.1+1), true);
-                    match entry.get().contained_unit_team {
-                        Some(team) => {
-                            if team != Team::Enemy {
-                                tiles_in_range.push(range-(cost-1));
-                            }
-                        },
-                        None => {}
-                    };
-                }
+        if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
+            //As long as we have not already visited this tile
+            if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
+                heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
+                visited.insert((coords.0, coords.1+1), true);
+                match entry.get().contained_unit_team {
+                    Some(unit_team) => {
+                        if unit_team != team {
+                            tiles_in_range.push(range-(cost-1));
+                        }
+                    },
+                    None => {}
+                };
             }
         }
     }
     tiles_in_range
-}
\ No newline at end of file
+}
+//Sets the team on a map tile while planning a turn, remembering the tile's original team the first time it's marked
+//Have to remember that map indexing is swapped
+fn mark_tile(map: &mut HashMap<(u32, u32), Tile>, marked_tiles: &mut HashMap<(u32, u32), Option<Team>>, pos: (u32, u32), team: Option<Team>) {
+    if let Some(tile) = map.get_mut(&(pos.1, pos.0)) {
+        marked_tiles.entry(pos).or_insert(tile.contained_unit_team);
+        tile.update_team(team);
+    }
+}
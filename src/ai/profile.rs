@@ -0,0 +1,244 @@
+use std::fs;
+use std::sync::Mutex;
+
+pub const AI_PROFILES_FORMAT_VERSION: u32 = 1;
+pub const DEFAULT_AI_PROFILES: &str = "data/ai_profiles.txt";
+pub const DEFAULT_DIFFICULTY: &str = "normal";
+
+//Profiles live for the rest of the program once loaded, like the unit classes
+static AI_PROFILES: Mutex<Vec<&'static AiProfile>> = Mutex::new(Vec::new());
+static DIFFICULTY: Mutex<Option<String>> = Mutex::new(None);
+
+//Everything that makes one enemy AI play differently from another
+pub struct AiProfile {
+    pub name: String,
+
+    //Search effort
+    pub population: usize, //Population size of the genetic algorithm
+    pub generations: u32, //Number of generations it runs for
+    pub mutation_chance: f32, //Probability of an individual being mutated
+    pub search_depth: u32, //Turns to look ahead after the AI's own plan (see search::best_plan), 0 just goes with the GA's best plan
+    pub search_width: usize, //How many of the GA's best plans are weighed up on each turn
+    pub think_time: u32, //Milliseconds the AI gets to plan a turn in single player, a fallback for slow machines (see search::Limits)
+
+    //Aggressiveness
+    pub sieging_weight: f64,
+    pub camp_weight: f64,
+    pub attack_value: f64,
+
+    //Fighting (see combat::Battlefield)
+    pub damage_weight: f64, //Value of each point of damage the AI expects to deal
+    pub kill_weight: f64, //Value of each expected kill, times what the unit killed is worth (and the same for losing its own)
+    pub threat_weight: f64, //Cost of each point of damage the AI expects to take on the player's next turn
+
+    //Defensive posture
+    pub min_defense: u32, //Units that should stay near the AI's own castle
+    pub defense_penalty: f64, //What a plan's value is divided by when it leaves fewer defenders than that
+    pub defense_distance: u32, //How close to the castle a unit has to be to count as defending
+
+    pub mistake_chance: u32, //% chance of each unit ignoring the plan and moving somewhere at random
+}
+
+impl AiProfile {
+    //The values the AI was tuned with before there were profiles, used for anything a profile leaves out
+    fn new(name: &str) -> AiProfile {
+        AiProfile {
+            name: name.to_string(),
+            population: 120,
+            generations: 60,
+            mutation_chance: 0.3,
+            search_depth: 1,
+            search_width: 3,
+            think_time: 4000,
+            sieging_weight: 7.5,
+            camp_weight: 2.5,
+            attack_value: 1.0,
+            damage_weight: 0.5,
+            kill_weight: 1.0,
+            threat_weight: 0.3,
+            min_defense: 5,
+            defense_penalty: 5.0,
+            defense_distance: 5,
+            mistake_chance: 0,
+        }
+    }
+}
+
+/* AI profile files are plain text with the format version on the first line, followed by one
+ * `profile <name>` ... `end` block per difficulty with a property on each line. Blank lines and lines
+ * starting with `#` are ignored. See data/ai_profiles.txt for the properties.
+ */
+pub fn load(path: &str) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read AI profiles '{}': {}", path, e))?;
+    let profiles = parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut loaded = AI_PROFILES.lock().unwrap();
+    loaded.clear();
+    for profile in profiles {
+        loaded.push(Box::leak(Box::new(profile)));
+    }
+    Ok(())
+}
+
+pub fn by_name(name: &str) -> Result<&'static AiProfile, String> {
+    AI_PROFILES.lock().unwrap().iter().find(|p| p.name == name).copied().ok_or(format!("Unknown difficulty '{}'", name))
+}
+
+//Every loaded profile, in the order they're listed in the file
+pub fn all() -> Vec<&'static AiProfile> {
+    AI_PROFILES.lock().unwrap().clone()
+}
+
+// sets the difficulty new single player games are played on
+pub fn set_difficulty(name: String) {
+    *DIFFICULTY.lock().unwrap() = Some(name);
+}
+
+// the profile new single player games are played with, DEFAULT_DIFFICULTY unless another one was picked
+pub fn difficulty() -> Result<&'static AiProfile, String> {
+    by_name(DIFFICULTY.lock().unwrap().as_deref().unwrap_or(DEFAULT_DIFFICULTY))
+}
+
+fn parse(contents: &str) -> Result<Vec<AiProfile>, String> {
+    let mut lines = contents.lines().enumerate().map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    match lines.next() {
+        Some((_, first)) if first == format!("castle_quest_ai {}", AI_PROFILES_FORMAT_VERSION) => {},
+        Some((_, first)) if first.starts_with("castle_quest_ai ") => return Err(format!("Unsupported format version '{}'", &first[16..])),
+        _ => return Err(String::from("Not a castle_quest_ai file")),
+    }
+
+    let mut profiles: Vec<AiProfile> = Vec::new();
+    while let Some((line_num, line)) = lines.next() {
+        let name = line.strip_prefix("profile ").map(|n| n.trim()).filter(|n| !n.is_empty()).ok_or(format!("line {}: expected `profile <name>`", line_num))?;
+        let mut profile = AiProfile::new(name);
+
+        loop {
+            let (line_num, line) = lines.next().ok_or(format!("profile '{}' is missing its `end`", name))?;
+            let values: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || format!("line {}: invalid property '{}'", line_num, line);
+
+            match values[..] {
+                ["end"] => break,
+                ["population", n] => profile.population = n.parse().map_err(|_e| invalid())?,
+                ["generations", n] => profile.generations = n.parse().map_err(|_e| invalid())?,
+                ["mutation", p] => profile.mutation_chance = p.parse().map_err(|_e| invalid())?,
+                ["depth", n] => profile.search_depth = n.parse().map_err(|_e| invalid())?,
+                ["width", n] => profile.search_width = n.parse().map_err(|_e| invalid())?,
+                ["think_time", n] => profile.think_time = n.parse().map_err(|_e| invalid())?,
+                ["siege", w] => profile.sieging_weight = w.parse().map_err(|_e| invalid())?,
+                ["camps", w] => profile.camp_weight = w.parse().map_err(|_e| invalid())?,
+                ["attack", w] => profile.attack_value = w.parse().map_err(|_e| invalid())?,
+                ["damage", w] => profile.damage_weight = w.parse().map_err(|_e| invalid())?,
+                ["kills", w] => profile.kill_weight = w.parse().map_err(|_e| invalid())?,
+                ["threat", w] => profile.threat_weight = w.parse().map_err(|_e| invalid())?,
+                ["defenders", n] => profile.min_defense = n.parse().map_err(|_e| invalid())?,
+                ["defense_penalty", w] => profile.defense_penalty = w.parse().map_err(|_e| invalid())?,
+                ["defense_distance", n] => profile.defense_distance = n.parse().map_err(|_e| invalid())?,
+                ["mistakes", n] => profile.mistake_chance = n.parse().map_err(|_e| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+
+        //The GA needs a few individuals to cross over, and the search at least one plan to pick
+        if profile.population < 10 || profile.generations == 0 || profile.search_width == 0 {
+            return Err(format!("profile '{}' needs a population of at least 10, 1 generation and a width of 1", name));
+        }
+        if profile.mistake_chance > 100 || !(0.0..=1.0).contains(&profile.mutation_chance) {
+            return Err(format!("profile '{}' has a chance that isn't a probability", name));
+        }
+        //Plans' values are divided by the defense penalty, so it has to be above 0
+        let weights = [profile.sieging_weight, profile.camp_weight, profile.attack_value, profile.damage_weight, profile.kill_weight, profile.threat_weight, profile.defense_penalty];
+        if weights.iter().any(|w| !w.is_finite()) || profile.defense_penalty <= 0.0 {
+            return Err(format!("profile '{}' needs finite weights and a defense penalty above 0", name));
+        }
+        if profiles.iter().any(|p| p.name == profile.name) {
+            return Err(format!("profile '{}' is defined twice", name));
+        }
+        profiles.push(profile);
+    }
+
+    if profiles.is_empty() {
+        return Err(String::from("No AI profiles defined"));
+    }
+    Ok(profiles)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    static LOAD_PROFILES: Once = Once::new();
+
+    //The profiles are global, so they're only loaded once for all the tests
+    pub fn test_profile(name: &str) -> &'static AiProfile {
+        LOAD_PROFILES.call_once(|| load(DEFAULT_AI_PROFILES).unwrap());
+        by_name(name).unwrap()
+    }
+
+    const PROFILE: &str = "profile tricky
+    population 50
+    generations 10
+    mutation 0.5
+    depth 2
+    width 4
+    think_time 2500
+    kills 2
+    defenders 1
+    mistakes 10
+end
+";
+
+    fn parse_one(profile: &str) -> Result<Vec<AiProfile>, String> {
+        parse(&format!("castle_quest_ai 1\n# comment\n\n{}", profile))
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let profiles = parse_one(PROFILE).unwrap();
+        let tricky = &profiles[0];
+        assert_eq!(tricky.name, "tricky");
+        assert_eq!((tricky.population, tricky.generations, tricky.search_depth, tricky.search_width), (50, 10, 2, 4));
+        assert_eq!((tricky.mutation_chance, tricky.think_time, tricky.min_defense, tricky.mistake_chance), (0.5, 2500, 1, 10));
+        assert_eq!(tricky.kill_weight, 2.0);
+
+        //Anything left out is the normal profile's value
+        let normal = AiProfile::new("normal");
+        assert_eq!((tricky.sieging_weight, tricky.threat_weight, tricky.defense_distance), (normal.sieging_weight, normal.threat_weight, normal.defense_distance));
+    }
+
+    #[test]
+    fn default_profiles_parse() {
+        let profiles = parse(&fs::read_to_string(DEFAULT_AI_PROFILES).unwrap()).unwrap();
+        assert!(profiles.iter().any(|p| p.name == DEFAULT_DIFFICULTY));
+        //Listed in the menu in the file's order
+        assert_eq!(profiles[0].name, "easy");
+    }
+
+    #[test]
+    fn rejects_bad_profiles() {
+        assert!(parse("").is_err());
+        assert!(parse("castle_quest_ai 2\n").is_err());
+        assert!(parse_one("").is_err());
+        assert!(parse_one("profile\nend\n").is_err());
+        assert!(parse_one(&PROFILE.replace("end\n", "")).is_err());
+        assert!(parse_one(&PROFILE.replace("depth 2", "depth deep")).is_err());
+        assert!(parse_one(&PROFILE.replace("depth 2", "lookahead 2")).is_err());
+        assert!(parse_one(&format!("{}{}", PROFILE, PROFILE)).is_err());
+
+        //Settings the GA & search can't work with
+        assert!(parse_one(&PROFILE.replace("population 50", "population 5")).is_err());
+        assert!(parse_one(&PROFILE.replace("generations 10", "generations 0")).is_err());
+        assert!(parse_one(&PROFILE.replace("width 4", "width 0")).is_err());
+        assert!(parse_one(&PROFILE.replace("mistakes 10", "mistakes 101")).is_err());
+        assert!(parse_one(&PROFILE.replace("mutation 0.5", "mutation 1.5")).is_err());
+        assert!(parse_one(&PROFILE.replace("mutation 0.5", "mutation NaN")).is_err());
+        assert!(parse_one(&PROFILE.replace("end", "defense_penalty 0\nend")).is_err());
+        assert!(parse_one(&PROFILE.replace("end", "defense_penalty -2\nend")).is_err());
+        assert!(parse_one(&PROFILE.replace("kills 2", "kills inf")).is_err());
+        assert!(parse_one(&PROFILE.replace("kills 2", "kills NaN")).is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use rand::{seq::IteratorRandom, Rng};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::ai::combat;
+use crate::ai::distance_map::DistanceMap;
+use crate::ai::genetics;
+use crate::ai::population_state::PopulationState;
+use crate::ai::profile::AiProfile;
+use crate::game_map::{self, GameMap};
+use crate::net::util::{Event, EVENT_ATTACK};
+use crate::unit::{Team, Unit};
+
+//(how far ahead & across how many plans the AI searches depends on the difficulty, see AiProfile)
+const INNER_POPULATION: usize = 40; //Most the GA's population can be when finding the replies to a plan
+const INNER_GENERATIONS: u32 = 15; //And the most generations it can run for then
+const CHANCE_BRANCHES: u32 = 3; //Most attacks in a plan whose kill & survive outcomes are looked at separately
+const UNIT_WEIGHT: f64 = 10.0; //What a unit at full hp is worth when scoring a board
+
+/* Expectimax over the plans the genetic algorithm comes up with. Each of the enemy's best plans is played out on the
+ * board, the player's replies are found with the same GA (assuming they pick the one that's worst for the enemy), and
+ * so on for the profile's search depth. The attacks of each plan are chance nodes that branch on whether they kill
+ * (see attack_outcomes). The GA only gets a fraction of the effort for the replies (INNER_POPULATION &
+ * INNER_GENERATIONS), as it runs once for each plan. The barbarians' turns are left out. Every simulated turn is
+ * undone, so the board is as it was once this returns. Finally, each unit may make a mistake (see mistake_chance).
+ * How much searching is done only depends on the profile, so the same board and seed always come to the same plan.
+ * Only if the limits are hit is everything still being looked at cut short and the best plan so far used (see Limits).
+ */
+pub fn best_plan(game_map: &mut GameMap, distance_map: &DistanceMap, profile: &AiProfile, limits: &Limits) -> Result<PopulationState, String> {
+    let depth = profile.search_depth;
+
+    let mut candidates = best_candidates(game_map, distance_map, Team::Enemy, profile, if depth == 0 { 1 } else { profile.search_width }, (profile.population, profile.generations), limits);
+    let mut plan = if depth == 0 || candidates.len() == 1 {
+        candidates.remove(0)
+    } else {
+        let mut best: Option<(f64, PopulationState)> = None;
+        for plan in candidates {
+            if best.is_some() && out_of_time(limits) {
+                println!("Out of time, going with the best plan so far");
+                break;
+            }
+            let value = chance_value(game_map, distance_map, &plan, Team::Enemy, profile, depth, limits)?;
+            println!("Plan worth {} on its own is worth {} looking {} turn(s) ahead", plan.overall_utility, value, depth);
+            if best.as_ref().is_none_or(|(best_value, _)| value > *best_value) {
+                best = Some((value, plan));
+            }
+        }
+        best.unwrap().1
+    };
+
+    if limits.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        println!("Warning: the AI ran out of time, so its plan depends on how fast this machine is and won't replay the same");
+    }
+    make_mistakes(game_map, &mut plan, profile);
+    Ok(plan)
+}
+
+//What cuts a search short. The deadline is only a fallback for machines too slow to get through the profile's search in
+//time, as a plan cut short by it depends on the machine as well as the seed. Cancelling stops the search as soon as it
+//can, once nobody needs the plan anymore
+#[derive(Default)]
+pub struct Limits {
+    pub deadline: Option<Instant>,
+    pub cancelled: Option<Arc<AtomicBool>>,
+}
+
+pub fn out_of_time(limits: &Limits) -> bool {
+    limits.cancelled.as_ref().is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+        || limits.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+//Swaps the moves of some of the enemy's units for random ones, so easier AIs don't always play their best
+fn make_mistakes(game_map: &mut GameMap, plan: &mut PopulationState, profile: &AiProfile) {
+    if profile.mistake_chance == 0 {
+        return;
+    }
+
+    //Units are in the same sorted order genetic_algorithm built the plan in
+    let mut unit_positions: Vec<(u32, u32)> = game_map.enemy_units.keys().copied().collect();
+    unit_positions.sort_by_key(|pos| (pos.1, pos.0));
+    for (index, pos) in unit_positions.iter().enumerate() {
+        if game_map.rng.gen_range(0..100) >= profile.mistake_chance {
+            continue;
+        }
+        let possible_moves = game_map.enemy_units[pos].get_tiles_in_movement_range(&mut game_map.map_tiles);
+        if let Some(new_move) = possible_moves.into_iter().choose(&mut game_map.rng) {
+            println!("Unit at {}, {} makes a mistake and moves to {}, {}", pos.0, pos.1, new_move.0, new_move.1);
+            plan.units_and_utility[index].0 = new_move;
+        }
+    }
+}
+
+//The team's best plans from a GA with the given (population, generations), leaving out the copies that elite selection
+//leaves in the population
+fn best_candidates(game_map: &mut GameMap, distance_map: &DistanceMap, team: Team, profile: &AiProfile, width: usize, effort: (usize, u32), limits: &Limits) -> Vec<PopulationState> {
+    let mut population = genetics::genetic_algorithm(game_map, distance_map, team, profile, effort, limits);
+    population.sort_unstable();
+    population.reverse();
+
+    let mut candidates: Vec<PopulationState> = Vec::new();
+    for state in population {
+        if candidates.len() == width {
+            break;
+        }
+        if !candidates.iter().any(|c| c.units_and_utility.iter().map(|u| u.0).eq(state.units_and_utility.iter().map(|u| u.0))) {
+            candidates.push(state);
+        }
+    }
+    candidates
+}
+
+//Value (for the enemy) of the board when it's the team's turn, with `depth` turns left to look at
+fn turn_value(game_map: &mut GameMap, distance_map: &DistanceMap, team: Team, profile: &AiProfile, depth: u32, limits: &Limits) -> Result<f64, String> {
+    let units = match team {
+        Team::Player => &game_map.player_units,
+        _ => &game_map.enemy_units,
+    };
+    if depth == 0 || units.is_empty() || out_of_time(limits) {
+        return Ok(evaluate(game_map, distance_map, profile));
+    }
+
+    let effort = (profile.population.min(INNER_POPULATION), profile.generations.min(INNER_GENERATIONS));
+    let mut values: Vec<f64> = Vec::new();
+    for plan in best_candidates(game_map, distance_map, team, profile, profile.search_width, effort, limits) {
+        values.push(chance_value(game_map, distance_map, &plan, team, profile, depth - 1, limits)?);
+    }
+
+    //The enemy goes with its best plan, and expects the player to go with the one that's worst for it
+    Ok(match team {
+        Team::Player => values.into_iter().fold(f64::INFINITY, f64::min),
+        _ => values.into_iter().fold(f64::NEG_INFINITY, f64::max),
+    })
+}
+
+//Chance node: the value of playing the plan, over the ways its attacks could turn out
+fn chance_value(game_map: &mut GameMap, distance_map: &DistanceMap, plan: &PopulationState, team: Team, profile: &AiProfile, depth: u32, limits: &Limits) -> Result<f64, String> {
+    let next_team = match team {
+        Team::Player => Team::Enemy,
+        _ => Team::Player,
+    };
+
+    let snapshot = Snapshot::take(game_map);
+    let value = play_moves(game_map, plan, team, profile)
+        .and_then(|attacks| attack_outcomes(game_map, &attacks, profile, CHANCE_BRANCHES, &mut |game_map| turn_value(game_map, distance_map, next_team, profile, depth, limits)));
+    snapshot.restore(game_map);
+    value
+}
+
+//Plays the plan's moves straight onto the board as the team's turn, and returns its attacks for attack_outcomes
+fn play_moves(game_map: &mut GameMap, plan: &PopulationState, team: Team, profile: &AiProfile) -> Result<Vec<Event>, String> {
+    let units = match team {
+        Team::Player => &mut game_map.player_units,
+        _ => &mut game_map.enemy_units,
+    };
+    for unit in units.values_mut() {
+        unit.next_turn();
+    }
+
+    let first_event = game_map.event_list.len();
+    plan.convert_state_to_action(game_map, team, profile)?;
+    let events: Vec<Event> = game_map.event_list.drain(first_event..).collect();
+    let mut attacks: Vec<Event> = Vec::new();
+    for event in events {
+        if event.action == EVENT_ATTACK {
+            attacks.push(event);
+        } else {
+            game_map::apply_event(game_map, event)?;
+        }
+    }
+    Ok(attacks)
+}
+
+/* The expected value_of the board once the attacks have been made. An attack that may or may not kill its target
+ * branches into both: the target is killed, or it takes the damage the attack does on average when it doesn't kill,
+ * and each outcome counts for as much as it's likely. Every branch doubles the boards the rest of the search looks at,
+ * so only the first few uncertain attacks branch, and the others do their average damage (rounded).
+ */
+fn attack_outcomes(game_map: &mut GameMap, attacks: &[Event], profile: &AiProfile, branches: u32, value_of: &mut dyn FnMut(&mut GameMap) -> Result<f64, String>) -> Result<f64, String> {
+    let (attack, rest) = match attacks.split_first() {
+        Some(split) => split,
+        None => {
+            game_map::remove_dead_units(game_map);
+            return value_of(game_map);
+        },
+    };
+
+    let (hp, damage, kill) = attack_odds(game_map, attack, profile)?;
+    if hp == 0 || kill <= 0.0 || kill >= 1.0 || branches == 0 {
+        wound(game_map, attack.to_pos, damage.round() as u32);
+        return attack_outcomes(game_map, rest, profile, branches, value_of);
+    }
+
+    //The damage done on average is made up of the kills (which do all the hp that's left) and the rest
+    let survived_damage = ((damage - kill * hp as f64) / (1.0 - kill)).round() as u32;
+    let mut value = 0.0;
+    for &(chance, damage) in [(kill, hp), (1.0 - kill, survived_damage.min(hp - 1))].iter() {
+        let snapshot = Snapshot::take(game_map);
+        wound(game_map, attack.to_pos, damage);
+        let outcome = attack_outcomes(game_map, rest, profile, branches - 1, value_of);
+        snapshot.restore(game_map);
+        value += chance * outcome?;
+    }
+    Ok(value)
+}
+
+//The target's hp, followed by the damage the attack does on average and its chance of killing (see combat::attack_value)
+fn attack_odds(game_map: &mut GameMap, attack: &Event, profile: &AiProfile) -> Result<(u32, f64, f64), String> {
+    let GameMap { map_tiles, player_units, enemy_units, barbarian_units, .. } = game_map;
+    let attacker = player_units.get_mut(&attack.from_pos).or_else(|| enemy_units.get_mut(&attack.from_pos))
+        .ok_or("Could not get selected attacker unit for event")?;
+    attacker.has_attacked = true;
+    let attacker = attacker.clone();
+
+    //Remember map is flipped indexing
+    let tile = map_tiles.get(&(attack.to_pos.1, attack.to_pos.0)).ok_or("Could not get the attacked tile for event")?;
+    let target = player_units.get(&attack.to_pos).or_else(|| enemy_units.get(&attack.to_pos)).or_else(|| barbarian_units.get(&attack.to_pos))
+        .ok_or("Could not get selected defender unit for event")?;
+    if target.hp == 0 {
+        return Ok((0, 0.0, 0.0));
+    }
+    let (_, damage, kill) = combat::attack_value(&attacker, target, tile, target.hp as f64, profile);
+    Ok((target.hp, damage, kill))
+}
+
+//Takes the damage off the unit at pos, without going below 0 hp
+fn wound(game_map: &mut GameMap, pos: (u32, u32), damage: u32) {
+    let GameMap { player_units, enemy_units, barbarian_units, .. } = game_map;
+    if let Some(unit) = player_units.get_mut(&pos).or_else(|| enemy_units.get_mut(&pos)).or_else(|| barbarian_units.get_mut(&pos)) {
+        unit.hp = unit.hp.saturating_sub(damage);
+    }
+}
+
+//How good the board looks for the enemy: where each side's units stand (as the GA scores it) and how much of each side is
+//left. A kill that's coming is counted twice by the positions, as one side's attack and the other's threat, so each unit
+//left counts for twice what killing it is worth (plus its hp). That way being about to kill a unit isn't worth more than
+//having killed it
+fn evaluate(game_map: &mut GameMap, distance_map: &DistanceMap, profile: &AiProfile) -> f64 {
+    let strength = |units: &HashMap<(u32, u32), Unit>| -> f64 {
+        units.values().map(|u| 2.0 * profile.kill_weight * combat::worth(u) + UNIT_WEIGHT * u.hp as f64 / u.max_hp as f64).sum()
+    };
+    let position = genetics::current_utility(game_map, distance_map, Team::Enemy, profile) - genetics::current_utility(game_map, distance_map, Team::Player, profile);
+    position + strength(&game_map.enemy_units) - strength(&game_map.player_units)
+}
+
+//Everything a simulated turn changes, so the board can be put back afterwards
+struct Snapshot {
+    player_units: HashMap<(u32, u32), Unit>,
+    enemy_units: HashMap<(u32, u32), Unit>,
+    barbarian_units: HashMap<(u32, u32), Unit>,
+    notices: usize,
+}
+
+impl Snapshot {
+    fn take(game_map: &GameMap) -> Snapshot {
+        Snapshot {
+            player_units: game_map.player_units.clone(),
+            enemy_units: game_map.enemy_units.clone(),
+            barbarian_units: game_map.barbarian_units.clone(),
+            notices: game_map.notices.len(),
+        }
+    }
+
+    fn restore(self, game_map: &mut GameMap) {
+        //Remember map is flipped indexing
+        let GameMap { map_tiles, player_units, enemy_units, barbarian_units, notices, .. } = game_map;
+        for pos in player_units.keys().chain(enemy_units.keys()).chain(barbarian_units.keys()) {
+            if let Some(tile) = map_tiles.get_mut(&(pos.1, pos.0)) {
+                tile.update_team(None);
+            }
+        }
+        *player_units = self.player_units;
+        *enemy_units = self.enemy_units;
+        *barbarian_units = self.barbarian_units;
+        for (team, units) in [(Team::Player, &*player_units), (Team::Enemy, &*enemy_units), (Team::Barbarians, &*barbarian_units)].iter() {
+            for pos in units.keys() {
+                if let Some(tile) = map_tiles.get_mut(&(pos.1, pos.0)) {
+                    tile.update_team(Some(*team));
+                }
+            }
+        }
+        notices.truncate(self.notices);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::profile::DEFAULT_DIFFICULTY;
+    use crate::ai::profile::tests::test_profile;
+    use crate::game_map::tests::test_map;
+    use crate::map_data::MapData;
+
+    #[test]
+    fn attacks_are_weighed_on_their_odds() {
+        let profile = test_profile(DEFAULT_DIFFICULTY);
+        let mut game_map = test_map(1);
+        let (_, damage, kill) = combat::attack_value(&game_map.player_units[&(1, 0)], &game_map.enemy_units[&(6, 5)], &game_map.map_tiles[&(5, 6)], 20.0, profile);
+
+        let attack = Event::create(EVENT_ATTACK, 0, (1, 0), (6, 5), 0);
+        assert_eq!(attack_odds(&mut game_map, &attack, profile).unwrap(), (20, damage, kill));
+        assert!(game_map.player_units[&(1, 0)].has_attacked);
+        assert!(attack_odds(&mut game_map, &Event::create(EVENT_ATTACK, 0, (1, 0), (3, 3), 0), profile).is_err());
+
+        //Units that are already dead can't be killed again
+        wound(&mut game_map, (6, 5), 25);
+        assert_eq!(game_map.enemy_units[&(6, 5)].hp, 0);
+        assert_eq!(attack_odds(&mut game_map, &attack, profile).unwrap(), (0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn chance_value_puts_the_board_back() {
+        let profile = test_profile(DEFAULT_DIFFICULTY);
+        let mut game_map = test_map(1);
+        let distance_map = DistanceMap::generate(&game_map);
+        let units = |game_map: &GameMap| -> Vec<((u32, u32), u32, bool)> {
+            let mut units: Vec<((u32, u32), u32, bool)> = game_map.player_units.values().chain(game_map.enemy_units.values())
+                .map(|u| ((u.x, u.y), u.hp, u.has_moved)).collect();
+            units.sort();
+            units
+        };
+        let before = units(&game_map);
+
+        let plan = best_candidates(&mut game_map, &distance_map, Team::Enemy, profile, 1, (20, 3), &Limits::default()).remove(0);
+        let mut other_map = game_map.clone();
+        let value = chance_value(&mut game_map, &distance_map, &plan, Team::Enemy, profile, 1, &Limits::default()).unwrap();
+        assert_eq!(units(&game_map), before);
+
+        //The same board always comes to the same value
+        assert_eq!(chance_value(&mut other_map, &distance_map, &plan, Team::Enemy, profile, 1, &Limits::default()).unwrap(), value);
+    }
+
+    #[test]
+    fn limits_cut_the_search_short() {
+        let profile = test_profile(DEFAULT_DIFFICULTY);
+        let distance_map = DistanceMap::generate(&test_map(1));
+        let moves = |plan: PopulationState| -> Vec<(u32, u32)> { plan.units_and_utility.iter().map(|u| u.0).collect() };
+
+        //Without hitting the limits, the plan only depends on the seed
+        let plan = best_plan(&mut test_map(3), &distance_map, profile, &Limits::default()).unwrap();
+        assert_eq!(moves(best_plan(&mut test_map(3), &distance_map, profile, &Limits::default()).unwrap()), moves(plan));
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let limits = Limits { deadline: None, cancelled: Some(Arc::clone(&cancelled)) };
+        assert!(!out_of_time(&limits));
+        cancelled.store(true, Ordering::Relaxed);
+        assert!(out_of_time(&limits));
+        //There's still a plan once the search is cut short
+        assert_eq!(best_plan(&mut test_map(3), &distance_map, profile, &limits).unwrap().units_and_utility.len(), 2);
+
+        assert!(out_of_time(&Limits { deadline: Some(Instant::now()), cancelled: None }));
+    }
+
+    //A 8x4 field with an enemy melee that can reach either of the player's units
+    const DUEL_MAP: &str = "castle_quest_map 1
+name Duel
+players 2
+size 8 4
+
+legend
+'.' grass_tile walk shoot
+end
+
+tiles
+. . . . . . . .
+. . . . . . . .
+. . . . . . . .
+. . . . . . . .
+end
+
+structures
+castle 1 0 3
+castle 2 7 0
+end
+
+units
+1 archer 0 3
+1 melee 7 3
+2 melee 3 1
+end
+";
+
+    #[test]
+    fn likely_kills_are_worth_going_for() {
+        let profile = test_profile(DEFAULT_DIFFICULTY);
+        crate::game_map::tests::load_unit_classes();
+        let mut game_map = GameMap::new(&MapData::parse(DUEL_MAP).unwrap(), Team::Player, 1).unwrap();
+        let distance_map = DistanceMap::generate(&game_map);
+        //The archer is more likely than not to be killed, the melee isn't, but either way the average damage isn't a kill
+        game_map.player_units.get_mut(&(0, 3)).unwrap().hp = 3;
+        game_map.player_units.get_mut(&(7, 3)).unwrap().hp = 4;
+        let go_for_archer = PopulationState::new(vec![((0, 2), (0.0, false, false, false, false))], 0.0);
+        let go_for_melee = PopulationState::new(vec![((7, 2), (0.0, false, false, false, false))], 0.0);
+
+        let mut averaged = |plan: &PopulationState| -> f64 {
+            let snapshot = Snapshot::take(&game_map);
+            let attacks = play_moves(&mut game_map, plan, Team::Enemy, profile).unwrap();
+            let value = attack_outcomes(&mut game_map, &attacks, profile, 0, &mut |game_map| turn_value(game_map, &distance_map, Team::Player, profile, 1, &Limits::default())).unwrap();
+            snapshot.restore(&mut game_map);
+            value
+        };
+        //Only looking at the average damage, the archer gets away with 1 hp either way and hitting the melee looks better
+        assert!(averaged(&go_for_archer) < averaged(&go_for_melee));
+
+        let archer = chance_value(&mut game_map, &distance_map, &go_for_archer, Team::Enemy, profile, 1, &Limits::default()).unwrap();
+        let melee = chance_value(&mut game_map, &distance_map, &go_for_melee, Team::Enemy, profile, 1, &Limits::default()).unwrap();
+        assert!(archer > melee, "{} {}", archer, melee);
+    }
+}
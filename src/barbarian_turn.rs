@@ -1,18 +1,11 @@
-use rand::{seq::IteratorRandom, thread_rng};
+use rand::seq::IteratorRandom;
 
 use crate::game_map::GameMap;
-use crate::SDLCore;
 use crate::net::util::*;
 
-pub fn handle_barbarian_turn<'a>(_core: &SDLCore<'a>, game_map: &mut GameMap<'a>) -> Result<(), String> {
-	if game_map.banner.banner_visible {
-		return Ok(())
-	}
-
-	//RNG for making unaggroed barbarians roam
-	let mut rng_thread = thread_rng();
-
-	let barbarian = if let Some((_, b)) = game_map.barbarian_units.iter().find(|(_, u)| u.has_moved == false) {
+pub fn handle_barbarian_turn(game_map: &mut GameMap) -> Result<(), String> {
+	//Move barbarians in a fixed order (top to bottom, left to right) so the match stays reproducible from its seed
+	let barbarian = if let Some((_, b)) = game_map.barbarian_units.iter().filter(|(_, u)| !u.has_moved).min_by_key(|(pos, _)| (pos.1, pos.0)) {
 		b
 	} else {
 		// no more units to move: end turn
@@ -30,8 +23,7 @@ pub fn handle_barbarian_turn<'a>(_core: &SDLCore<'a>, game_map: &mut GameMap<'a>
 		}
 
 		let (atk_x, atk_y) = attacks[0];
-		let attacked_unit = game_map.get_unit(&(atk_x, atk_y))?;
-		let damage_done = barbarian.get_attack_damage(attacked_unit);
+		let damage_done = game_map.roll_attack_damage(&(original_x, original_y), &(atk_x, atk_y))?;
 
 		game_map.event_list.push(Event::create(EVENT_MOVE, 0, (original_x, original_y), (*mov_x, *mov_y), 0));
 		game_map.event_list.push(Event::create(EVENT_ATTACK, 0, (*mov_x, *mov_y), (atk_x, atk_y), damage_done as u8));
@@ -42,7 +34,7 @@ pub fn handle_barbarian_turn<'a>(_core: &SDLCore<'a>, game_map: &mut GameMap<'a>
 	let mut directions = vec![0, 1, 2, 3, 4];
 	while directions.len() > 0 {
 		//Pick and remove a random direction from the vector of directions
-		let index = (0..directions.len()).choose(&mut rng_thread).unwrap();
+		let index = (0..directions.len()).choose(&mut game_map.rng).unwrap();
 		let direction_to_move = directions.swap_remove(index);
 
 		let mut potential_x: Option<u32> = Some(original_x);
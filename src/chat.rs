@@ -0,0 +1,147 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::BlendMode;
+
+use std::time::Instant;
+
+use crate::{CAM_H, SDLCore};
+use crate::button::Button;
+use crate::net::util::MAX_CHAT_LENGTH;
+
+const QUICK_MESSAGES: [&str; 4] = ["Good luck!", "Nice move!", "Hurry up!", "Good game!"];
+const SHOWN_MESSAGES: usize = 6; // how many of the latest messages are drawn
+const PANEL_X: i32 = 10;
+const PANEL_W: u32 = 420;
+
+// Chat overlay for multiplayer: a log of the latest messages, and when opened a text box and quick-message buttons.
+// Positions are relative to the screen, the panel is drawn on top of the camera like the end turn button.
+pub struct ChatPanel<'r> {
+	messages: Vec<String>,
+	pub is_open: bool,
+	input: String,
+	input_time: Instant,
+	input_rect: Rect,
+	quick_buttons: Vec<(&'static str, Button<'r>)>,
+}
+
+impl ChatPanel<'_> {
+	pub fn new<'r>(core: &SDLCore<'r>) -> Result<ChatPanel<'r>, String> {
+		let mut quick_buttons = Vec::new();
+		for (i, message) in QUICK_MESSAGES.iter().enumerate() {
+			let x = PANEL_X + (i as i32 % 2) * 215;
+			let y = CAM_H as i32 - 120 + (i as i32 / 2) * 55;
+			quick_buttons.push((*message, Button::new(core, Rect::new(x, y, 205, 50), message)?));
+		}
+
+		Ok(ChatPanel {
+			messages: Vec::new(),
+			is_open: false,
+			input: String::new(),
+			input_time: Instant::now(),
+			input_rect: Rect::new(PANEL_X, CAM_H as i32 - 160, PANEL_W, 34),
+			quick_buttons,
+		})
+	}
+
+	pub fn push(&mut self, message: String) {
+		self.messages.push(message);
+	}
+
+	// Handles typing into the chat, returning a message once it's sent with enter
+	pub fn handle_event(&mut self, event: &Event) -> Option<String> {
+		match event {
+			Event::KeyDown{keycode: Some(Keycode::Return), ..} => {
+				if !self.is_open {
+					self.open();
+					return None;
+				}
+				self.is_open = false;
+				let message = self.input.trim().to_string();
+				self.input.clear();
+				if message.is_empty() { None } else { Some(message) }
+			},
+			Event::KeyDown{keycode: Some(Keycode::Backspace), ..} if self.is_open => {
+				self.input.pop();
+				None
+			},
+			Event::TextInput{text, ..} if self.is_open => {
+				for c in text.chars() {
+					if self.input.chars().count() < MAX_CHAT_LENGTH {
+						self.input.push(c);
+					}
+				}
+				None
+			},
+			_ => None,
+		}
+	}
+
+	// Handles a click while the chat is open, returning a quick message if one was clicked.
+	// Any click closes the chat, unless it was on the text box.
+	pub fn handle_click(&mut self, core: &SDLCore) -> Option<String> {
+		let mouse_state = core.event_pump.mouse_state();
+		if self.input_rect.contains_point((mouse_state.x(), mouse_state.y())) {
+			return None;
+		}
+
+		self.is_open = false;
+		self.quick_buttons.iter()
+			.find(|(_message, button)| button.is_mouse(core))
+			.map(|(message, _button)| message.to_string())
+	}
+
+	fn open(&mut self) {
+		self.is_open = true;
+		self.input_time = Instant::now();
+	}
+
+	pub fn draw(&self, core: &mut SDLCore) -> Result<(), String> {
+		//Messages stack upwards from the text box when open, or from the bottom of the screen
+		let mut bottom = if self.is_open { self.input_rect.y() - 5 } else { CAM_H as i32 - 10 };
+
+		core.wincan.set_blend_mode(BlendMode::Blend);
+		for message in self.messages.iter().rev().take(SHOWN_MESSAGES) {
+			let text_surface = core.regular_font.render(message)
+				.blended_wrapped(Color::RGBA(255, 255, 255, 255), PANEL_W - 20)
+				.map_err(|e| e.to_string())?;
+			let text_texture = core.texture_creator.create_texture_from_surface(&text_surface)
+				.map_err(|e| e.to_string())?;
+			let query = text_texture.query();
+
+			bottom -= query.height as i32 + 6;
+			core.wincan.set_draw_color(Color::RGBA(50, 50, 50, 180));
+			core.wincan.fill_rect(Rect::new(PANEL_X - core.cam.x, bottom - core.cam.y, PANEL_W, query.height + 6))?;
+			core.wincan.copy(&text_texture, None, Rect::new(PANEL_X + 10 - core.cam.x, bottom + 3 - core.cam.y, query.width, query.height))?;
+		}
+
+		if !self.is_open {
+			return Ok(());
+		}
+
+		//Text box, with a blinking cursor like the join code box in the main menu
+		let input_rect = Rect::new(self.input_rect.x() - core.cam.x, self.input_rect.y() - core.cam.y, self.input_rect.width(), self.input_rect.height());
+		core.wincan.set_draw_color(Color::RGBA(240, 240, 240, 220));
+		core.wincan.fill_rect(input_rect)?;
+		core.wincan.set_draw_color(Color::RGBA(0, 0, 0, 255));
+		core.wincan.draw_rect(input_rect)?;
+
+		let display_text = format!("{}{}", self.input, if self.input_time.elapsed().subsec_millis() < 500 { "|" } else { "" });
+		if let Ok((w, h)) = core.regular_font.size_of(&display_text) {
+			if w > 0 {
+				let text_surface = core.regular_font.render(&display_text)
+					.blended(Color::RGBA(0, 0, 0, 255))
+					.map_err(|e| e.to_string())?;
+				let text_texture = core.texture_creator.create_texture_from_surface(&text_surface)
+					.map_err(|e| e.to_string())?;
+				core.wincan.copy(&text_texture, None, Rect::new(input_rect.x() + 10, input_rect.y() + (34 - h as i32) / 2, w, h))?;
+			}
+		}
+
+		for (_message, button) in self.quick_buttons.iter() {
+			button.draw_relative(core)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,263 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{CAM_H, CAM_W};
+use crate::map_data::DEFAULT_MAP;
+use crate::unit_class::DEFAULT_UNIT_CLASSES;
+use crate::net::server::DEFAULT_MAX_ROOMS;
+use crate::ai::profile::{DEFAULT_AI_PROFILES, DEFAULT_DIFFICULTY};
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 26000;
+pub const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:26001";
+pub const DEFAULT_SIMULATE_TURNS: u32 = 100;
+
+pub const USAGE: &str = "usage: castle_quest [command] [options]
+
+commands:
+  play               start the game (default)
+  server             run the multiplayer relay server
+  replay <file>      watch a replay file
+  simulate           play an AI vs AI match without a window and print the result
+  map-check <file>   check that a map file loads and is playable
+
+options:
+  --host <host>         multiplayer server to connect to
+  --port <port>         multiplayer server port (also the port the server listens on)
+  --window <w>x<h>      window size, e.g. 1920x1080
+  --vsync / --no-vsync  lock the frame rate to the display
+  --fullscreen / --windowed
+  --map <file>          map to play on (multiplayer games need the same map as the server)
+  --units <file>        unit class definitions (multiplayer games need the same ones as the server)
+  --seed <n>            seed for a new single player game or simulation
+  --name <name>         name shown in the lobby for rooms you host
+  --turns <n>           rounds to simulate before calling it a draw
+  --difficulty <name>   AI profile for single player games and simulations (easy, normal, hard, ...)
+  --ai-profiles <file>  AI difficulty profile definitions
+  --max-rooms <n>       rooms the server allows at once
+  --admin <address>     address of the server's admin port
+  --config <file>       read options from this file instead of the default config file
+  --help                show this message
+
+Options can also be set in the config file as `option = value` lines, e.g. `host = example.com`
+(`vsync = false`, `fullscreen = true`). Command line options take precedence over the file.";
+
+pub enum Command {
+	Play,
+	Server,
+	Replay(String),
+	Simulate,
+	MapCheck(String),
+}
+
+pub struct Config {
+	pub command: Command,
+	pub host: String,
+	pub port: u16,
+	pub window_size: (u32, u32),
+	pub vsync: bool,
+	pub fullscreen: bool,
+	pub map_path: String,
+	pub units_path: String,
+	pub seed: Option<u64>,
+	pub name: Option<String>,
+	pub turns: u32,
+	pub difficulty: String,
+	pub ai_profiles_path: String,
+	pub max_rooms: usize,
+	pub admin_addr: String,
+}
+
+impl Config {
+	fn new() -> Config {
+		Config {
+			command: Command::Play,
+			host: String::from(DEFAULT_HOST),
+			port: DEFAULT_PORT,
+			window_size: (CAM_W, CAM_H),
+			vsync: true,
+			fullscreen: false,
+			map_path: String::from(DEFAULT_MAP),
+			units_path: String::from(DEFAULT_UNIT_CLASSES),
+			seed: None,
+			name: None,
+			turns: DEFAULT_SIMULATE_TURNS,
+			difficulty: String::from(DEFAULT_DIFFICULTY),
+			ai_profiles_path: String::from(DEFAULT_AI_PROFILES),
+			max_rooms: DEFAULT_MAX_ROOMS,
+			admin_addr: String::from(DEFAULT_ADMIN_ADDR),
+		}
+	}
+
+	// Builds the config from the defaults, then the config file, then the command line arguments (without the program name)
+	pub fn load(args: &[String]) -> Result<Config, String> {
+		let mut config = Config::new();
+
+		let config_path = match args.iter().position(|a| a == "--config") {
+			Some(pos) => Some(PathBuf::from(args.get(pos + 1).ok_or("Expected a file after --config")?)),
+			None => default_config_path().filter(|path| path.exists()),
+		};
+		if let Some(path) = config_path {
+			let contents = fs::read_to_string(&path).map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+			config.read_file(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+		}
+
+		config.read_args(args)?;
+		Ok(config)
+	}
+
+	fn read_file(&mut self, contents: &str) -> Result<(), String> {
+		for (i, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let (key, value) = line.split_once('=').ok_or(format!("line {}: expected `option = value`", i + 1))?;
+			self.set(key.trim(), value.trim()).map_err(|e| format!("line {}: {}", i + 1, e))?;
+		}
+		Ok(())
+	}
+
+	fn read_args(&mut self, args: &[String]) -> Result<(), String> {
+		let mut args = args.iter();
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"play" => self.command = Command::Play,
+				"server" | "--server" => self.command = Command::Server,
+				"simulate" => self.command = Command::Simulate,
+				"replay" => self.command = Command::Replay(args.next().ok_or("Expected a replay file after replay")?.clone()),
+				"map-check" => self.command = Command::MapCheck(args.next().ok_or("Expected a map file after map-check")?.clone()),
+				"--help" | "-h" => return Err(String::new()),
+				"--config" => {
+					args.next();
+				},
+				"--vsync" => self.vsync = true,
+				"--no-vsync" => self.vsync = false,
+				"--fullscreen" => self.fullscreen = true,
+				"--windowed" => self.fullscreen = false,
+				// the old way of picking a server: `castle_quest tcp://host:port`
+				_ if arg.starts_with("tcp://") => self.set_address(&arg[6..])?,
+				_ if arg.starts_with("--") => {
+					let value = args.next().ok_or(format!("Expected a value after {}", arg))?;
+					self.set(&arg[2..], value)?;
+				},
+				_ => return Err(format!("Unknown command '{}'", arg)),
+			}
+		}
+		Ok(())
+	}
+
+	// Sets an option from the config file or a `--option value` argument
+	fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+		let number = |what: &str| format!("Expected {} for {}, found '{}'", what, key, value);
+		match key {
+			"host" => self.host = value.to_string(),
+			"port" => self.port = value.parse().map_err(|_e| number("a port number"))?,
+			"window" => {
+				let (w, h) = value.split_once('x').ok_or_else(|| number("a size like 1280x720"))?;
+				self.window_size = (w.parse().map_err(|_e| number("a size like 1280x720"))?, h.parse().map_err(|_e| number("a size like 1280x720"))?);
+			},
+			"vsync" => self.vsync = parse_bool(key, value)?,
+			"fullscreen" => self.fullscreen = parse_bool(key, value)?,
+			"map" => self.map_path = value.to_string(),
+			"units" => self.units_path = value.to_string(),
+			"seed" => self.seed = Some(value.parse().map_err(|_e| number("a number"))?),
+			"name" => self.name = Some(value.to_string()),
+			"turns" => self.turns = value.parse().map_err(|_e| number("a number"))?,
+			"difficulty" => self.difficulty = value.to_string(),
+			"ai-profiles" | "ai_profiles" => self.ai_profiles_path = value.to_string(),
+			"max-rooms" | "max_rooms" => self.max_rooms = value.parse().map_err(|_e| number("a number"))?,
+			"admin" => self.admin_addr = value.to_string(),
+			_ => return Err(format!("Unknown option '{}'", key)),
+		}
+		Ok(())
+	}
+
+	fn set_address(&mut self, addr: &str) -> Result<(), String> {
+		match addr.rsplit_once(':') {
+			Some((host, port)) => {
+				self.host = host.to_string();
+				self.set("port", port)
+			},
+			None => {
+				self.host = addr.to_string();
+				Ok(())
+			},
+		}
+	}
+
+	pub fn server_addr(&self) -> String {
+		format!("{}:{}", self.host, self.port)
+	}
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+	match value {
+		"true" | "yes" | "on" | "1" => Ok(true),
+		"false" | "no" | "off" | "0" => Ok(false),
+		_ => Err(format!("Expected true or false for {}, found '{}'", key, value)),
+	}
+}
+
+// castle_quest/config in the user's config directory ($XDG_CONFIG_HOME, ~/.config or %APPDATA%)
+pub fn default_config_path() -> Option<PathBuf> {
+	let dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+		.or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+	Some(dir.join("castle_quest").join("config"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(line: &str) -> Vec<String> {
+		line.split_whitespace().map(String::from).collect()
+	}
+
+	// Writes a config file for the test and returns its path
+	fn config_file(name: &str, contents: &str) -> String {
+		let path = env::temp_dir().join(format!("castle_quest_{}_{}.config", name, std::process::id()));
+		fs::write(&path, contents).unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	#[test]
+	fn command_line_overrides_config_file() {
+		let path = config_file("overrides", "# comment\n\nhost = example.com\nport = 1234\nvsync = off\nmax_rooms = 7\n");
+		let config = Config::load(&args(&format!("--config {} --port 4321 --window 800x600 --seed 5 simulate", path))).unwrap();
+		assert_eq!(config.server_addr(), "example.com:4321");
+		assert_eq!(config.window_size, (800, 600));
+		assert_eq!(config.seed, Some(5));
+		assert_eq!(config.max_rooms, 7);
+		assert!(!config.vsync);
+		assert!(matches!(config.command, Command::Simulate));
+
+		let config = Config::load(&args(&format!("--config {} --vsync replay match.replay tcp://other.org:99", path))).unwrap();
+		assert!(config.vsync);
+		assert_eq!(config.server_addr(), "other.org:99");
+		assert!(matches!(config.command, Command::Replay(ref file) if file == "match.replay"));
+	}
+
+	#[test]
+	fn reports_bad_options() {
+		let path = config_file("bad", "host = example.com\nport = many\n");
+		let e = Config::load(&args(&format!("--config {}", path))).err().unwrap();
+		assert!(e.contains("line 2"), "{}", e);
+
+		let path = config_file("no_equals", "fullscreen\n");
+		assert!(Config::load(&args(&format!("--config {}", path))).is_err());
+
+		let path = config_file("empty", "");
+		let load = |line: &str| Config::load(&args(&format!("--config {} {}", path, line)));
+		assert!(load("").is_ok());
+		assert!(load("--fullscreen yes").is_err()); //--fullscreen takes no value, so yes is an unknown command
+		assert!(load("--colour blue").is_err());
+		assert!(load("--window 800").is_err());
+		assert!(load("--turns").is_err());
+		assert!(load("replay").is_err());
+		assert!(Config::load(&args("--config")).is_err());
+		assert!(Config::load(&args("--config /nonexistent/castle_quest.config")).is_err());
+	}
+}
@@ -1,19 +1,124 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
 use crate::ai::*;
 use crate::ai::distance_map::*;
+use crate::ai::population_state::PopulationState;
+use crate::ai::profile::AiProfile;
+use crate::ai::search::Limits;
 use crate::game_map::GameMap;
-use crate::SDLCore;
 use crate::net::util::*;
+use crate::unit::Team;
+
+//Plans the enemy's turn on a worker thread, so the window keeps drawing while the AI thinks
+pub struct EnemyPlanner {
+    worker: Option<Worker>,
+}
+
+struct Worker {
+    plan: Receiver<Result<PopulationState, String>>,
+    thread: JoinHandle<()>,
+    cancelled: Arc<AtomicBool>, //Set to stop the search early once nobody needs the plan
+    started: Instant,
+}
+
+impl EnemyPlanner {
+    pub fn new() -> EnemyPlanner {
+        EnemyPlanner {
+            worker: None,
+        }
+    }
+
+    //How long the AI has been thinking about the current turn, if it is
+    pub fn thinking_for(&self) -> Option<Duration> {
+        self.worker.as_ref().map(|worker| worker.started.elapsed())
+    }
 
-pub fn handle_enemy_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, distance_map: &DistanceMap) -> Result<(), String> {
-    if !game_map.banner.banner_visible {
-        let best_moves = genetics::genetic_algorithm(game_map, distance_map);
+    //Called every frame of the enemy's turn: starts planning on a copy of the board the first time, and plays the
+    //turn once the plan comes back. The profile's think_time is only a fallback deadline for the plan (see search::Limits)
+    pub fn update(&mut self, game_map: &mut GameMap, distance_map: &Arc<DistanceMap>, profile: &'static AiProfile) -> Result<(), String> {
+        let plan = match &self.worker {
+            None => {
+                let mut board = game_map.clone();
+                let distance_map = Arc::clone(distance_map);
+                let cancelled = Arc::new(AtomicBool::new(false));
+                let limits = Limits {
+                    deadline: Some(Instant::now() + Duration::from_millis(profile.think_time as u64)),
+                    cancelled: Some(Arc::clone(&cancelled)),
+                };
+                let (sender, receiver) = mpsc::channel();
+                let thread = thread::spawn(move || {
+                    // the receiver is gone if the game was left in the meantime, then nobody needs the plan
+                    let _ = sender.send(search::best_plan(&mut board, &distance_map, profile, &limits));
+                });
+                self.worker = Some(Worker { plan: receiver, thread, cancelled, started: Instant::now() });
+                return Ok(());
+            },
+            Some(worker) => match worker.plan.try_recv() {
+                Ok(plan) => plan,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => Err(String::from("The enemy AI stopped without a plan")),
+            },
+        };
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.thread.join();
+        }
 
-        //Currently just base movements off the best individual, will convert to minimax later...
-        let best_individual = best_moves.iter().max().unwrap();
-        best_individual.convert_state_to_action(core, core.texture_map, game_map)?;
+        play_enemy_turn(game_map, &plan?, profile)
+    }
+}
 
-        //End turn
-        game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_ENEMY, (0,0), (0,0), 0));
+impl Drop for EnemyPlanner {
+    //Leaving the game mid-turn stops the search rather than leaving it running in the background
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.cancelled.store(true, Ordering::Relaxed);
+            let _ = worker.thread.join();
+        }
     }
+}
+
+//Plans and plays the enemy's turn right away, taking as long as it takes (e.g. for simulations, which have no window to keep drawing)
+pub fn handle_enemy_turn(game_map: &mut GameMap, distance_map: &DistanceMap, profile: &AiProfile) -> Result<(), String> {
+    //Look ahead at how the player could reply to the best plans the GA finds, and go with the best expected one.
+    //The search runs on a copy of the board like EnemyPlanner's, so it doesn't use up the game's rolls either
+    let best_plan = search::best_plan(&mut game_map.clone(), distance_map, profile, &Limits::default())?;
+    play_enemy_turn(game_map, &best_plan, profile)
+}
+
+fn play_enemy_turn(game_map: &mut GameMap, plan: &PopulationState, profile: &AiProfile) -> Result<(), String> {
+    plan.convert_state_to_action(game_map, Team::Enemy, profile)?;
+
+    //End turn
+    game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_ENEMY, (0,0), (0,0), 0));
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::profile::tests::test_profile;
+    use crate::game_map::tests::test_map;
+
+    #[test]
+    fn planner_plays_the_same_turn_as_simulations() {
+        let profile = test_profile("easy");
+        let mut game_map = test_map(5);
+        game_map.player_state.current_turn = Team::Enemy;
+        let distance_map = Arc::new(DistanceMap::generate(&game_map));
+
+        let mut simulated = game_map.clone();
+        handle_enemy_turn(&mut simulated, &distance_map, profile).unwrap();
+
+        let mut planner = EnemyPlanner::new();
+        while game_map.event_list.is_empty() {
+            planner.update(&mut game_map, &distance_map, profile).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let events = |game_map: &GameMap| -> Vec<String> { game_map.event_list.iter().map(|e| e.to_string()).collect() };
+        assert_eq!(events(&game_map), events(&simulated));
+    }
+}
@@ -1,357 +1,202 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::convert::TryInto;
-
-use sdl2::video::WindowContext;
-use sdl2::render::{Texture, TextureCreator};
-use sdl2::image::LoadTexture;
-use sdl2::rect::Rect;
-use sdl2::pixels::Color;
-use sdl2::render::BlendMode;
-
-use crate::cursor::Cursor;
-use crate::banner::Banner;
-use crate::button::Button;
-use crate::damage_indicator::DamageIndicator;
-use crate::unit_interface::UnitInterface;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::map_data::{MapData, StructureKind};
 use crate::objective_manager::ObjectiveManager;
 use crate::player_action::PlayerAction;
 use crate::player_state::PlayerState;
 use crate::tile::{Tile, Structure};
-use crate::unit::{Team, Unit, GUARD_HEALTH_ID, SCOUT_HEALTH_ID};
-use crate::pixel_coordinates::PixelCoordinates;
-use crate::{CAM_H, CAM_W, TILE_SIZE};
-use crate::SDLCore;
+use crate::unit::{Team, Unit, respawn_candidates};
+use crate::unit_class;
 use crate::net::util::*;
 
-pub struct GameMap<'a> {
-	pub map_tiles: HashMap<(u32, u32), Tile<'a>>,
+//Things that happened to the game state which the view layer should present to the user.
+//GameMap itself never touches SDL, so it records these and the view drains them each frame.
+#[derive(Clone)]
+pub enum Notice {
+	Damage((u32, u32), u32), //Unit position (x, y) and the damage it took
+	Heal((u32, u32), u32),   //Unit position (x, y) and the hp it regained
+	TurnStart(Team),         //Team is relative to this client
+	Winner(Team),            //Team is relative to this client
+}
+
+#[derive(Clone)]
+pub struct GameMap {
+	pub map_tiles: HashMap<(u32, u32), Tile>,
 	pub map_size: (usize, usize),
 
 	//Stuff for enemy AI calculations
 	pub objectives: ObjectiveManager,
 
-	pub player_units: HashMap<(u32, u32), Unit<'a>>,
-	pub enemy_units: HashMap<(u32, u32), Unit<'a>>,
-	pub barbarian_units: HashMap<(u32, u32), Unit<'a>>,
-
-	pub possible_moves: Vec<(u32, u32)>,
-	pub possible_attacks: Vec<(u32, u32)>,
-	pub actual_attacks: Vec<(u32, u32)>,
-
-	pub unit_interface: Option<UnitInterface<'a>>,
-	pub choose_unit_interface: Option<UnitInterface<'a>>,
+	pub player_units: HashMap<(u32, u32), Unit>,
+	pub enemy_units: HashMap<(u32, u32), Unit>,
+	pub barbarian_units: HashMap<(u32, u32), Unit>,
 
 	pub player_state: PlayerState,
 
-	//Holds all damage indicators (the numbers that appear above a unit when attacked) that are visible
-	pub damage_indicators: Vec<DamageIndicator>,
-
-	// various UI elements
-	pub banner: Banner,
-	pub cursor: Cursor<'a>,
-	pub end_turn_button: Button<'a>,
-
-	pub camp_textures: Vec<(&'a Texture<'a>, &'a Texture<'a>)>,
+	//Pending notices for the view layer (damage numbers, banners)
+	pub notices: Vec<Notice>,
 
 	pub event_list: Vec<Event>,
 	pub event_list_index: usize,
 
 	pub winning_team: Option<Team>,
+
+	//All game randomness is drawn from rng, which is reseeded from the match seed at the start of every turn
+	//so that a match (or a save of one) can be replayed exactly
+	pub seed: u64,
+	pub rng: StdRng,
+
+	//In multiplayer the server rolls attack damage, so attacks made on this client are queued in server_requests
+	//instead of being applied, and the client waits for the server to send back the resolved attack
+	pub server_authoritative: bool,
+	pub server_requests: Vec<Event>,
+	pub awaiting_server: bool,
+
+	//With fog of war only the enemy & barbarian units within sight of this client's units are shown (see visible_tiles).
+	//In multiplayer the server also keeps units out of sight from the client entirely, so it decides who has won
+	pub fog_of_war: bool,
+	pub server_decides_winner: bool,
+
+	//Changes whenever units are added, moved, hurt or removed, so the view knows when to work out what's in sight again.
+	//Revisions are unique across all maps, a new map never shares one with the map it replaced
+	pub revision: u64,
 }
 
-impl GameMap<'_> {
-	pub fn new<'a>(core: &SDLCore<'a>, player_team: Team) -> GameMap<'a> {
-		//Load map from file
-		let map_data = File::open("maps/map.txt").expect("Unable to open map file");
-		let mut map_data = BufReader::new(map_data);
-		let mut line = String::new();
-
-		//Sets size of the map from the first line of the text file
-		map_data.read_line(&mut line).unwrap();
-		let map_width: usize = line.trim().parse().unwrap();
-		let map_height: usize = line.trim().parse().unwrap();
-
-		//Creates map from file
-		let map_string: Vec<Vec<String>> = map_data.lines()
-			.take(map_width)
-			.map(|x| x.unwrap().chars().collect::<Vec<char>>())
-			.map(|x| x.chunks(2).map(|chunk| chunk[0].to_string()).collect())
-			.collect();
+//How far around its castle a team can always see
+const CASTLE_VISION_RANGE: u32 = 4;
 
-		let end_turn_button = Button::new(core, Rect::new((CAM_W - 240).try_into().unwrap(), (CAM_H - 90).try_into().unwrap(), 200, 50), "End Turn").unwrap();
+static REVISIONS: AtomicU64 = AtomicU64::new(0);
+
+fn next_revision() -> u64 {
+	REVISIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
 
-		let mut map: GameMap<'a> = GameMap {
+impl GameMap {
+	pub fn new(map_data: &MapData, player_team: Team, seed: u64) -> Result<GameMap, String> {
+		println!("Loading map '{}' ({}x{})", map_data.name, map_data.width, map_data.height);
+
+		let mut map = GameMap {
 			map_tiles: HashMap::new(),
-			map_size: (map_width, map_height),
+			map_size: (map_data.width as usize, map_data.height as usize),
 			objectives: ObjectiveManager::init_default(),
 			player_units: HashMap::new(),
 			enemy_units: HashMap::new(),
 			barbarian_units: HashMap::new(),
-			possible_moves: Vec::new(),
-			possible_attacks: Vec::new(),
-			actual_attacks: Vec::new(),
-			unit_interface: None,
-			choose_unit_interface: None,
 			player_state: PlayerState::new(player_team),
-			damage_indicators: Vec::new(),
-			banner: Banner::new(),
-			cursor: Cursor::new(core.texture_map.get("cursor").unwrap()),
-			end_turn_button,
-			camp_textures: Vec::new(),
+			notices: Vec::new(),
 			event_list: Vec::new(),
 			event_list_index: 0,
 			winning_team: None,
+			seed,
+			rng: StdRng::seed_from_u64(seed),
+			server_authoritative: false,
+			server_requests: Vec::new(),
+			awaiting_server: false,
+			fog_of_war: true,
+			server_decides_winner: false,
+			revision: next_revision(),
 		};
 
-		//Set up the HashMap of Tiles that can be interacted with
-		let mut x = 0;
-		let mut y = 0;
+		//Set up the HashMap of Tiles that can be interacted with (keyed by (row, col))
+		for y in 0..map_data.height {
+			for x in 0..map_data.width {
+				map.map_tiles.insert((y, x), Tile::new(y, x, map_data.tile_type(x, y)));
+			}
+		}
+
+		//Players are numbered from the host's perspective, so the peer sees player 2 as its own team
+		let (own_player, other_player) = if player_team == Team::Player { (1, 2) } else { (2, 1) };
+
 		let mut pos_player_castle: (u32, u32) = (0, 0);
 		let mut pos_enemy_castle: (u32, u32) = (0, 0);
 		let mut pos_barbarian_camps: Vec<(u32, u32)> = Vec::new();
-		for row in map_string.iter() {
-			for col in row.iter() {
-				let letter = if player_team == Team::Enemy {
-					match col.as_ref() {
-						"1" => "2",
-						"2" => "1",
-						_ => &col[..],
+		let mut pos_forts: Vec<(u32, u32)> = Vec::new();
+		for structure in map_data.structures.iter() {
+			let (x, y) = structure.pos;
+			match structure.kind {
+				StructureKind::Castle(owner) => {
+					let (castle, texture) = if owner == own_player {
+						pos_player_castle = (x, y);
+						(Structure::PCastle, "blue_castle")
+					} else {
+						pos_enemy_castle = (x, y);
+						(Structure::ECastle, "red_castle")
+					};
+					if let Some(tile) = map.map_tiles.get_mut(&(y, x)) {
+						tile.contained_structure = Some(castle);
+						tile.texture_key = texture.to_string();
 					}
-				} else { &col[..] };
-
-				let texture = core.texture_map.get(letter).unwrap();
-				match letter {
-					"║" | "^" | "v" | "<" | "=" | ">" | "t" => map.map_tiles.insert((x,y), Tile::new(x, y, false, true, None, None, texture)),
-					" " => map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, None, texture)),
-					"b" =>  {
-						pos_barbarian_camps.push((y,x));
-						map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, Some(Structure::Camp), texture))
-					},
-					"f" => {
-						pos_barbarian_camps.push((y,x));
-						map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, Some(Structure::Camp), texture))
+				},
+				StructureKind::Camp | StructureKind::Fort => {
+					pos_barbarian_camps.push((x, y));
+					if let StructureKind::Fort = structure.kind {
+						pos_forts.push((x, y));
 					}
-					"_" => map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, Some(Structure::Camp), texture)),
-					"1" =>  {
-						pos_player_castle = (y, x);
-						map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, Some(Structure::PCastle), texture))
-					},
-					"2" =>  {
-						pos_enemy_castle = (y, x);
-						map.map_tiles.insert((x,y), Tile::new(x, y, true, true, None, Some(Structure::ECastle), texture))
-					},
-					_ => map.map_tiles.insert((x,y), Tile::new(x, y, false, false, None, None, texture)),
-				};
-				y += 1;
-			}
-			x += 1;
-			y = 0;
-		}
-		map.camp_textures.push((core.texture_map.get("pc").unwrap(), core.texture_map.get("ec").unwrap()));
-		map.camp_textures.push((core.texture_map.get("pf").unwrap(), core.texture_map.get("ef").unwrap()));
-
 
-		//Now that the locations of the objectives have been found, update the ObjectiveManager
-		map.objectives = ObjectiveManager::new(pos_player_castle, pos_enemy_castle, pos_barbarian_camps);
-
-		let p1_units_abrev: Vec<(char, (u32,u32))> = vec!(
-			('l', (8,46)), ('l', (10,45)), ('l', (12,46)), ('l', (17,51)), ('l', (17,55)), ('l', (18,53)),
-			('r', (9,49)), ('r', (10,47)), ('r', (14,54)), ('r', (16,53)),
-			('m', (10,50)), ('m', (13,53)), ('m', (12,48)),
-			('g', (10,53)),
-			('s', (10,52)), ('s', (11,53)),
-		);
-		//let p1_units_abrev: Vec<(char, (u32,u32))> = vec!(('l', (14, 40))); //Spawns a player unit right next to some barbarians
-		//let p1_units_abrev: Vec<(char, (u32,u32))> = vec!(('l', (54, 8))); //Spawns a player unit right next to the enemy's castle
-		let p2_units_abrev: Vec<(char, (u32,u32))> = vec!(
-			('l', (46,8)), ('l', (45,10)), ('l', (46,12)), ('l', (51,17)), ('l', (55,17)), ('l', (53,18)),
-			('r', (49,9)), ('r', (47,10)), ('r', (54,14)), ('r', (53,16)),
-			('m', (50,10)), ('m', (53,13)), ('m', (48,12)),
-			('g', (53,10)),
-			('s', (52,10)), ('s', (53,11)),
-		);
-		//let p2_units_abrev: Vec<(char, (u32,u32))> = vec!(('l', (16,44))); //Spawns a single enemy near the player
-		let barb_units_abrev: Vec<(char, (u32,u32))> = vec!(('l', (4,6)), ('l', (6,8)), ('l', (7,7)), ('r', (8,5)), ('l', (59,56)), ('l', (56,56)), ('l', (54,57)), ('r', (56,59)), ('l', (28,15)), ('l', (29,10)), ('l', (32,11)), ('l', (35,15)), ('r', (30,8)), ('r', (36,10)), ('l', (28,52)), ('l', (28,48)), ('l', (33,51)), ('l', (35,48)), ('r', (32,53)), ('r', (33,56)), ('l', (17,38)), ('l', (16,37)), ('r', (23,36)), ('r', (18,30)), ('l', (46,25)), ('l', (47,26)), ('r', (40,27)), ('r', (45,33)),);
-		//let barb_units_abrev: Vec<(char, (u32,u32))> = vec!(('l', (32, 60))); //Spawns a single barbarian near the bottom of the map
-		//let barb_units_abrev: Vec<(char, (u32,u32))> = Vec::new(); //No barbarians
-
-		prepare_player_units(&mut map.player_units, Team::Player, if player_team == Team::Player { &p1_units_abrev } else { &p2_units_abrev }, &core.texture_map, &mut map.map_tiles);
-		prepare_player_units(&mut map.enemy_units, Team::Enemy, if player_team == Team::Player { &p2_units_abrev } else { &p1_units_abrev }, &core.texture_map, &mut map.map_tiles);
-		prepare_player_units(&mut map.barbarian_units, Team::Barbarians, &barb_units_abrev, &core.texture_map, &mut map.map_tiles);
-
-		map.initialize_next_turn(core, Team::Player).unwrap();
-
-		map
-	}
-
-	pub fn draw(&mut self, core: &mut SDLCore) -> Result<(), String> {
-		//Camera controls should stay enabled even when it is not the player's turn,
-		//which is why this code block is not in player_turn.rs
-		if core.input.right_held && !self.banner.banner_visible {
-			let max_move = TILE_SIZE as i32;
-			core.cam.x = (core.cam.x - (core.input.mouse_x_old - core.input.mouse_x).clamp(-max_move, max_move)).clamp(-core.cam.w + core.wincan.window().size().0 as i32, 0);
-			core.cam.y = (core.cam.y - (core.input.mouse_y_old - core.input.mouse_y).clamp(-max_move, max_move)).clamp(-core.cam.h + core.wincan.window().size().1 as i32, 0);
-			core.wincan.set_viewport(core.cam);
-			core.set_animating(true);
-		}
-
-		let (i, j) = PixelCoordinates::matrix_indices_from_pixel(
-            core.input.mouse_x.try_into().unwrap(),
-            core.input.mouse_y.try_into().unwrap(),
-            (-1 * core.cam.x).try_into().unwrap(),
-            (-1 * core.cam.y).try_into().unwrap()
-        );
-
-		match self.player_units.get_mut(&(j,i)) {
-			Some(active_unit) => {
-				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), &active_unit);
-			},
-			_ => {
-				self.cursor.hide_cursor();
-			},
-		}
-		match self.enemy_units.get_mut(&(j,i)) {
-			Some(active_unit) => {
-				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), &active_unit);
-			},
-			_ => {},
-		}
-		match self.barbarian_units.get_mut(&(j,i)) {
-			Some(active_unit) => {
-				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), &active_unit);
-			},
-			_ => {},
-		}
-
-		while self.objectives.taken_over_camps.len() > 0 {
-			let pos_to_change = self.objectives.taken_over_camps.pop().unwrap();
-			let fort_locations: ((u32, u32), (u32, u32)) = ((31, 10), (31, 52));
-
-			if pos_to_change.1 == Team::Player {
-				if pos_to_change.0 == fort_locations.0 || pos_to_change.0 == fort_locations.1 {
-					let texture = self.camp_textures[1].0;
-					self.map_tiles.insert((pos_to_change.0.1,pos_to_change.0.0), Tile::new(pos_to_change.0.1, pos_to_change.0.0, true, true, None, Some(Structure::Camp), texture));
-					self.objectives.p1_takeovers.1 += 1;
-				} else {
-					let texture = self.camp_textures[0].0;
-					self.map_tiles.insert((pos_to_change.0.0,pos_to_change.0.1), Tile::new(pos_to_change.0.0, pos_to_change.0.1, true, true, None, Some(Structure::Camp), texture));
-					self.objectives.p1_takeovers.0 += 1;
-				}
-			} else {
-				if pos_to_change.0 == fort_locations.0 || pos_to_change.0 == fort_locations.1 {
-					let texture = self.camp_textures[1].1;
-					self.map_tiles.insert((pos_to_change.0.1,pos_to_change.0.0), Tile::new(pos_to_change.0.1, pos_to_change.0.0, true, true, None, Some(Structure::Camp), texture));
-					self.objectives.p2_takeovers.1 += 1;
-				} else {
-					let texture = self.camp_textures[0].1;
-					self.map_tiles.insert((pos_to_change.0.0,pos_to_change.0.1), Tile::new(pos_to_change.0.0, pos_to_change.0.1, true, true, None, Some(Structure::Camp), texture));
-					self.objectives.p2_takeovers.0 += 1;
-				}
+					//Camps & forts cover a 2x2 area
+					for (i, j) in [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+						if let Some(tile) = map.map_tiles.get_mut(&(j, i)) {
+							tile.contained_structure = Some(Structure::Camp);
+						}
+					}
+				},
 			}
 		}
 
-		//Draw tiles & sprites
-		for x in 0..self.map_size.0 {
-			for y in 0..self.map_size.1 {
-				let map_tile = self.map_tiles.get(&(y as u32, x as u32));
-				let map_tile_size = match map_tile {
-					Some(Tile{contained_structure: Some(Structure::Camp), ..}) => TILE_SIZE * 2,
-					_ => TILE_SIZE,
-				};
-
-				let pixel_location = PixelCoordinates::from_matrix_indices(y as u32, x as u32);
-				let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, map_tile_size, map_tile_size);
-
-				//Draw map tile at this coordinate
-				if let Some(map_tile) = self.map_tiles.get(&(y as u32, x as u32)) {
-					core.wincan.copy(map_tile.texture, None, dest)?;
-				}
-
-				//Use default sprite size for all non-map sprites
-				let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, TILE_SIZE, TILE_SIZE);
-
-				//Draw player unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
-				if let Some(unit) = self.player_units.get_mut(&(x as u32, y as u32)) {
-					unit.draw(core, &dest)?;
-				}
-
-				//Draw enemy unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
-				if let Some(enemy) = self.enemy_units.get_mut(&(x as u32, y as u32)) {
-					enemy.draw(core, &dest)?;
-				}
-
-				//Draw barbarian unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
-				if let Some(barbarian) = self.barbarian_units.get_mut(&(x as u32, y as u32)) {
-					barbarian.draw(core, &dest)?;
-				}
-			}
-		}
+		//Now that the locations of the objectives have been found, update the ObjectiveManager
+		map.objectives = ObjectiveManager::new(pos_player_castle, pos_enemy_castle, pos_barbarian_camps, pos_forts);
 
-		// draw UI/banners
-		self.cursor.draw(core)?;
-		self.banner.draw(core)?;
-
-		// draw possible move grid
-		match self.player_units.get(&(self.player_state.active_unit_j as u32, self.player_state.active_unit_i as u32)) {
-			Some(_) => {
-				match self.player_state.current_player_action {
-					PlayerAction::MovingUnit => {
-						draw_possible_moves(core, &self.possible_moves, Color::RGBA(0, 89, 178, 50))?;
-					},
-					PlayerAction::AttackingUnit => {
-						draw_possible_moves(core, &self.possible_attacks, Color::RGBA(178, 89, 0, 100))?;
-						draw_possible_moves(core, &self.actual_attacks, Color::RGBA(128, 0, 128, 100))?;
-					},
-					_ => {},
-				}
-			}
-			_ => ()
+		let spawns_for = |owner: u32| -> Vec<(String, (u32, u32))> {
+			map_data.units.iter().filter(|u| u.owner == owner).map(|u| (u.class.clone(), u.pos)).collect()
 		};
+		prepare_player_units(&mut map.player_units, Team::Player, &spawns_for(own_player), &mut map.map_tiles)?;
+		prepare_player_units(&mut map.enemy_units, Team::Enemy, &spawns_for(other_player), &mut map.map_tiles)?;
+		prepare_player_units(&mut map.barbarian_units, Team::Barbarians, &spawns_for(0), &mut map.map_tiles)?;
 
-		//Draw the damage indicators that appear above the units that have received damage
-		for damage_indicator in self.damage_indicators.iter_mut() {
-			damage_indicator.draw(core)?;
-		}
-		//Remove the damage indicators that have expired
-		self.damage_indicators.retain(|damage_indicator| {
-			damage_indicator.is_visible
-		});
+		map.initialize_next_turn(Team::Player);
 
-		if self.player_state.is_turn() {
-			//Draw the scroll sprite UI
-			let result = if let Some(ui) = self.unit_interface.as_mut() {
-				ui.draw(core, core.texture_creator).is_ok()
-			} else {
-				false
-			};
+		Ok(map)
+	}
 
-			if !result {
-				self.unit_interface = None;
-			}
+	//Swaps the tiles of newly captured camps & forts over to the capturing team's texture and counts the takeover
+	pub fn update_captured_camps(&mut self) {
+		while let Some((pos, team)) = self.objectives.taken_over_camps.pop() {
+			self.set_camp_owner(pos, team);
 
-			let result_2 = if let Some(ui) = self.choose_unit_interface.as_mut() {
-				ui.draw(core, core.texture_creator).is_ok()
+			let takeovers = if team == Team::Player { &mut self.objectives.p1_takeovers } else { &mut self.objectives.p2_takeovers };
+			if self.objectives.forts.contains(&pos) {
+				takeovers.1 += 1;
 			} else {
-				false
-			};
-
-			if !result_2 {
-				self.choose_unit_interface = None;
+				takeovers.0 += 1;
 			}
+		}
+	}
 
-			//Draw the button for the player to end their turn, relative to the camera
-			self.end_turn_button.draw_relative(core)?;
+	//Marks the camp or fort at (x, y) as captured by the team and updates its texture to match
+	pub fn set_camp_owner(&mut self, pos: (u32, u32), team: Team) {
+		let texture = match (team, self.objectives.forts.contains(&pos)) {
+			(Team::Player, true) => "player_fort",
+			(Team::Player, false) => "player_camp",
+			(_, true) => "enemy_fort",
+			(_, false) => "enemy_camp",
+		};
+		//Camp positions are (x, y), tiles are keyed by (row, col)
+		if let Some(tile) = self.map_tiles.get_mut(&(pos.1, pos.0)) {
+			tile.texture_key = texture.to_string();
 		}
 
-		Ok(())
+		self.objectives.captured_camps.push((pos, team));
 	}
 
 	// Function that takes a HashMap of units and sets all has_attacked and has_moved to false so that they can move again
-	pub fn initialize_next_turn(&mut self, core: &SDLCore, team: Team) -> Result<(), String> {
+	pub fn initialize_next_turn(&mut self, team: Team) {
 		let client_team = team.as_client(&self.player_state);
-		self.banner.show_turn(client_team);
+		self.reseed_rng();
+		self.notices.push(Notice::TurnStart(client_team));
 
 		//Fix glitch where castle tile says it's occupied when it's not
 		self.correct_map_errors();
@@ -361,8 +206,12 @@ impl GameMap<'_> {
 			Team::Enemy => &self.enemy_units,
 			Team::Barbarians => &self.barbarian_units,
 		});
+		//Camps taken over just now already count towards this turn's healing
+		self.update_captured_camps();
 
-		if self.objectives.has_won(client_team) {
+		if self.server_decides_winner {
+			//The server sends an END_GAME when someone wins
+		} else if self.objectives.has_won(client_team) {
 			self.set_winner(client_team);
 
 		//Check for total party kill and set the other team as the winner
@@ -375,7 +224,7 @@ impl GameMap<'_> {
 			self.set_winner(Team::Player);
 		}
 
-		self.heal_units(core, client_team)?;
+		self.heal_units(client_team);
 
 		match team {
 			Team::Player => {
@@ -394,8 +243,6 @@ impl GameMap<'_> {
 				}
 			}
 		}
-
-		Ok(())
 	}
 
 	//Sets up the winner's banner so it can start displaying, and returns an Option containing the Team corresponding to the winning team
@@ -403,38 +250,40 @@ impl GameMap<'_> {
 		match winner {
 			Team::Player => {
 				println!("You win!");
-				self.banner.show("p1_win_banner");
 			},
 			Team::Enemy => {
 				println!("You Lose!");
-				self.banner.show("p2_win_banner");
 			},
 			Team::Barbarians => {
 				println!("Barbarians win!");
 			},
 		};
 
+		self.notices.push(Notice::Winner(winner));
+
 		// send an END_GAME event to the other client
-		self.event_list.push(Event::create(EVENT_END_GAME, winner.as_client(&self.player_state).to_id(), (0,0), (0,0), 0));
+		if !self.server_decides_winner {
+			self.event_list.push(Event::create(EVENT_END_GAME, winner.as_client(&self.player_state).to_id(), (0,0), (0,0), 0));
+		}
 		self.winning_team = Some(winner);
 	}
 
-	pub fn heal_units(&mut self, core: &SDLCore, team: Team) -> Result<(), String> {
+	pub fn heal_units(&mut self, team: Team) {
 		let unit_map = match team {
 			Team::Player => &mut self.player_units,
 			Team::Enemy => &mut self.enemy_units,
-			_ => return Ok(()),
+			_ => return,
 		};
 
 		let takeovers = match team {
 			Team::Player => &self.objectives.p1_takeovers,
 			Team::Enemy => &self.objectives.p2_takeovers,
-			_ => return Ok(()),
+			_ => return,
 		};
 
 		// calculate heal amount for overtaken objectives (1hp per camp, 2hp per fortress)
 		let mut total_heal = takeovers.0 + takeovers.1 * 2;
-		if total_heal == 0 { return Ok(()); }
+		if total_heal == 0 { return; }
 		println!("Total heals for {} = {}", team.to_string(), total_heal);
 
 		let mut unit_list: Vec<&mut Unit> = unit_map.values_mut().collect();
@@ -451,16 +300,11 @@ impl GameMap<'_> {
 			println!("  Player unit at {:?} healed, {} remaining", (unit.x, unit.y), total_heal);
 
 			if heal > 0 {
-				self.damage_indicators.push(DamageIndicator::new_heal(core, heal, PixelCoordinates::from_matrix_indices(
-					unit.y.checked_sub(1).unwrap_or(unit.y),
-					unit.x
-				))?);
+				self.notices.push(Notice::Heal((unit.x, unit.y), heal));
 			}
 
 			if total_heal == 0 { break; }
 		}
-
-		Ok(())
 	}
 
 	/* For some reason there's a glitch where sometimes the spaces where the enemy units spawn are
@@ -471,8 +315,8 @@ impl GameMap<'_> {
 	pub fn correct_map_errors(&mut self) {
 		//Check the player castle
 		println!("Player castle:");
-		for i in (self.objectives.p1_castle.1 - 1)..=(self.objectives.p1_castle.1 + 1) {
-			for j in (self.objectives.p1_castle.0 - 1)..=(self.objectives.p1_castle.0 + 1) {
+		for i in self.objectives.p1_castle.1.saturating_sub(1)..=(self.objectives.p1_castle.1 + 1) {
+			for j in self.objectives.p1_castle.0.saturating_sub(1)..=(self.objectives.p1_castle.0 + 1) {
 				let tile_contains_unit = if let Some(tile) = self.map_tiles.get(&(i, j)) {
 					if let Some(team) = tile.contained_unit_team {
 						if team == Team::Player {
@@ -500,7 +344,7 @@ impl GameMap<'_> {
 				//If the tile says it contains a Player, but the hashmap of units does not,
 				//the tile needs to be corrected
 				if tile_contains_unit == 1 && !(hashmap_contains_unit == 1) {
-					if let Some(mut tile) = self.map_tiles.get_mut(&(i, j)) {
+					if let Some(tile) = self.map_tiles.get_mut(&(i, j)) {
 						tile.contained_unit_team = None;
 					}
 				}
@@ -512,8 +356,8 @@ impl GameMap<'_> {
 
 		//Check the enemy castle
 		println!("Enemy castle:");
-		for i in (self.objectives.p2_castle.1 - 1)..=(self.objectives.p2_castle.1 + 1) {
-			for j in (self.objectives.p2_castle.0 - 1)..=(self.objectives.p2_castle.0 + 1) {
+		for i in self.objectives.p2_castle.1.saturating_sub(1)..=(self.objectives.p2_castle.1 + 1) {
+			for j in self.objectives.p2_castle.0.saturating_sub(1)..=(self.objectives.p2_castle.0 + 1) {
 				let tile_contains_unit = if let Some(tile) = self.map_tiles.get(&(i, j)) {
 					if let Some(team) = tile.contained_unit_team {
 						if team == Team::Enemy {
@@ -541,7 +385,7 @@ impl GameMap<'_> {
 				//If the tile says it contains an Enemy, but the hashmap of units does not,
 				//the tile needs to be corrected
 				if tile_contains_unit == 1 && !(hashmap_contains_unit == 1) {
-					if let Some(mut tile) = self.map_tiles.get_mut(&(i, j)) {
+					if let Some(tile) = self.map_tiles.get_mut(&(i, j)) {
 						tile.contained_unit_team = None;
 					}
 				}
@@ -551,6 +395,159 @@ impl GameMap<'_> {
 			println!();
 		}
 	}
+	//Derives the rng state for the current turn from the match seed
+	pub fn reseed_rng(&mut self) {
+		let team_index = match self.player_state.current_turn {
+			Team::Player => 0,
+			Team::Enemy => 1,
+			Team::Barbarians => 2,
+		};
+		let turn = self.player_state.round as u64 * 3 + team_index;
+		self.rng = StdRng::seed_from_u64(self.seed.wrapping_add(turn.wrapping_mul(0x9e3779b97f4a7c15)));
+	}
+
+	//Rolls the damage the unit at attacker_pos would do to the unit at defender_pos (both (x, y))
+	pub fn roll_attack_damage(&mut self, attacker_pos: &(u32, u32), defender_pos: &(u32, u32)) -> Result<u32, String> {
+		let GameMap { map_tiles, player_units, enemy_units, barbarian_units, rng, .. } = self;
+
+		let find_unit = |pos: &(u32, u32)| -> Result<&Unit, String> {
+			match map_tiles.get(&(pos.1, pos.0)).and_then(|t| t.contained_unit_team) {
+				Some(Team::Player) => player_units.get(pos),
+				Some(Team::Enemy) => enemy_units.get(pos),
+				Some(Team::Barbarians) => barbarian_units.get(pos),
+				None => None,
+			}.ok_or(format!("No unit at {:?}", pos))
+		};
+
+		let attacker = find_unit(attacker_pos)?;
+		let defender = find_unit(defender_pos)?;
+		let defender_tile = map_tiles.get(&(defender_pos.1, defender_pos.0)).ok_or(format!("No tile at {:?}", defender_pos))?;
+		Ok(attacker.get_attack_damage(defender, defender_tile, rng))
+	}
+
+	//Checks that a move, attack or spawn by the given team is allowed by the rules, used by the server before applying client events
+	pub fn validate_action(&mut self, event: &Event, team: Team) -> Result<(), String> {
+		let GameMap { map_tiles, player_units, enemy_units, barbarian_units, player_state, objectives, .. } = self;
+
+		if player_state.current_turn != team {
+			return Err(format!("It is not {}'s turn", team.to_string()));
+		}
+
+		//Converted barbarians join as melee, archers or mages, on one of the tiles respawn_loc picks from next to the team's castle
+		if event.action == EVENT_SPAWN_UNIT {
+			if ![EVENT_UNIT_MELEE, EVENT_UNIT_ARCHER, EVENT_UNIT_MAGE].contains(&event.value) {
+				return Err(format!("Converted units can not be of kind {}", event.value));
+			}
+			let castle = match team {
+				Team::Player => objectives.p1_castle,
+				Team::Enemy => objectives.p2_castle,
+				Team::Barbarians => return Err("Barbarians do not spawn units".to_string()),
+			};
+			if !respawn_candidates(objectives.respawn_target(team, player_state), castle).contains(&event.to_pos) {
+				return Err(format!("{:?} is too far from the {} castle to spawn on", event.to_pos, team.to_string()));
+			}
+			//Remember map is flipped indexing
+			if !map_tiles.get(&(event.to_pos.1, event.to_pos.0)).is_some_and(|tile| tile.unit_can_move_here()) {
+				return Err(format!("{:?} is not free to spawn on", event.to_pos));
+			}
+			return Ok(());
+		}
+
+		let unit = match team {
+			Team::Player => player_units.get(&event.from_pos),
+			Team::Enemy => enemy_units.get(&event.from_pos),
+			Team::Barbarians => barbarian_units.get(&event.from_pos),
+		}.ok_or(format!("No {} unit at {:?}", team.to_string(), event.from_pos))?;
+
+		match event.action {
+			EVENT_MOVE => {
+				if unit.has_moved {
+					return Err("Unit has already moved this turn".to_string());
+				}
+				if event.from_pos != event.to_pos && !unit.get_tiles_in_movement_range(map_tiles).contains(&event.to_pos) {
+					return Err(format!("{:?} is out of the unit's movement range", event.to_pos));
+				}
+			},
+			EVENT_ATTACK => {
+				if unit.has_attacked {
+					return Err("Unit has already attacked this turn".to_string());
+				}
+				if !unit.get_tiles_can_attack(map_tiles).contains(&event.to_pos) {
+					return Err(format!("{:?} can not be attacked by the unit", event.to_pos));
+				}
+			},
+			_ => {},
+		}
+
+		Ok(())
+	}
+
+	//Tiles (x, y) the team can see: everything within the vision range of its units and around its castle,
+	//except for what's behind tiles that block vision (the blocking tiles themselves can still be seen)
+	pub fn visible_tiles(&self, team: Team) -> HashSet<(u32, u32)> {
+		let mut viewers: Vec<((u32, u32), u32)> = match team {
+			Team::Player => &self.player_units,
+			Team::Enemy => &self.enemy_units,
+			Team::Barbarians => &self.barbarian_units,
+		}.values().map(|unit| ((unit.x, unit.y), unit.vision_range)).collect();
+		match team {
+			Team::Player => viewers.push((self.objectives.p1_castle, CASTLE_VISION_RANGE)),
+			Team::Enemy => viewers.push((self.objectives.p2_castle, CASTLE_VISION_RANGE)),
+			Team::Barbarians => {},
+		}
+
+		let mut visible = HashSet::new();
+		for ((x, y), range) in viewers {
+			let range = range as i64;
+			for dy in -range..=range {
+				for dx in -range..=range {
+					let (tx, ty) = (x as i64 + dx, y as i64 + dy);
+					if dx * dx + dy * dy > range * range || tx < 0 || ty < 0 || tx >= self.map_size.0 as i64 || ty >= self.map_size.1 as i64 {
+						continue;
+					}
+					if self.in_line_of_sight((x, y), (tx as u32, ty as u32)) {
+						visible.insert((tx as u32, ty as u32));
+					}
+				}
+			}
+		}
+		visible
+	}
+
+	//Walks the straight line between two tiles (x, y) and checks that none of the tiles in between block vision
+	fn in_line_of_sight(&self, from: (u32, u32), to: (u32, u32)) -> bool {
+		let (dx, dy) = (to.0 as f64 - from.0 as f64, to.1 as f64 - from.1 as f64);
+		let steps = dx.abs().max(dy.abs()) as u32;
+		(1..steps).all(|i| {
+			let t = i as f64 / steps as f64;
+			let x = (from.0 as f64 + dx * t).round() as u32;
+			let y = (from.1 as f64 + dy * t).round() as u32;
+			self.map_tiles.get(&(y, x)).is_none_or(|tile| !tile.blocks_vision)
+		})
+	}
+
+	//Forgets the enemy & barbarian units this client can't see, for when the server only tells it about the ones in sight
+	pub fn hide_unseen_units(&mut self) {
+		let visible = self.visible_tiles(Team::Player);
+		let unseen: Vec<(u32, u32)> = self.enemy_units.keys().chain(self.barbarian_units.keys())
+			.filter(|pos| !visible.contains(pos))
+			.cloned()
+			.collect();
+		for pos in unseen {
+			self.remove_unit(pos);
+		}
+	}
+
+	pub fn remove_unit(&mut self, pos: (u32, u32)) {
+		self.revision = next_revision();
+		self.player_units.remove(&pos);
+		self.enemy_units.remove(&pos);
+		self.barbarian_units.remove(&pos);
+		if let Some(tile) = self.map_tiles.get_mut(&(pos.1, pos.0)) {
+			tile.update_team(None);
+		}
+	}
+
 	pub fn get_unit(&self, pos: &(u32, u32)) -> Result<&Unit, String> {
 		// for whatever reason, all the event positions are inverted as (y,x), so they need to be flipped to (x,y) to get the map tile
 		let unit_tile = self.map_tiles.get(&(pos.1, pos.0)).ok_or("Could not get map tile at unit position")?;
@@ -564,21 +561,37 @@ impl GameMap<'_> {
 	}
 }
 
-pub fn apply_events<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) -> Result<Vec<Event>, String> {
+pub fn apply_events(game_map: &mut GameMap) -> Result<Vec<Event>, String> {
 	let mut ret: Vec<Event> = Vec::new();
 
 	// process any new events in the event_list
 	let new_index = game_map.event_list.len();
 	for i in game_map.event_list_index..new_index {
 		if let Some(event) = game_map.event_list.get(i).map(|e| e.clone()) {
+			if game_map.server_authoritative && event.from_self && event.action == EVENT_ATTACK {
+				println!("Sending attack #{} to the server: {}", i, event);
+				game_map.server_requests.push(event);
+				game_map.awaiting_server = true;
+				continue;
+			}
+			if event.action == EVENT_ATTACK && !event.from_self {
+				game_map.awaiting_server = false;
+			}
+
 			println!("Applying event #{}: {}", i, event);
-			apply_event(core, game_map, event)?;
+			apply_event(game_map, event)?;
 			ret.push(event.clone());
 		}
 	}
 	game_map.event_list_index = new_index;
 
-	// remove any (dead) units that have reached 0 hp
+	remove_dead_units(game_map);
+
+	Ok(ret)
+}
+
+// remove any (dead) units that have reached 0 hp
+pub fn remove_dead_units(game_map: &mut GameMap) {
 	let mut dead_units: Vec<(u32, u32)> = Vec::new();
 	dead_units.extend(
 		game_map.player_units.values().filter(|u| u.hp == 0).map(|u| (u.x, u.y))
@@ -591,17 +604,18 @@ pub fn apply_events<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) -> Resul
 	);
 
 	for pos in dead_units {
-		game_map.player_units.remove(&pos);
-		game_map.enemy_units.remove(&pos);
-		game_map.barbarian_units.remove(&pos);
-
-		game_map.map_tiles.get_mut(&(pos.1, pos.0)).map(|t| t.update_team(None));
+		game_map.remove_unit(pos);
 	}
-
-	Ok(ret)
 }
 
-pub fn apply_event<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, event: Event) -> Result<(), String> {
+pub fn apply_event(game_map: &mut GameMap, event: Event) -> Result<(), String> {
+	game_map.revision = next_revision();
+
+	// a revealed unit's from_pos holds its hp and flags rather than a tile
+	if event.action == EVENT_REVEAL {
+		return reveal_unit(game_map, event);
+	}
+
 	// for whatever reason, all the event positions are inverted as (y,x), so they need to be flipped to (x,y) to get the map tile
 	let from_tile = (event.from_pos.1, event.from_pos.0);
 	let to_tile = (event.to_pos.1, event.to_pos.0);
@@ -664,18 +678,15 @@ pub fn apply_event<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, event: Ev
 			attacking_unit.starting_x = attacking_unit.x;
 			attacking_unit.starting_y = attacking_unit.y;
 			unit.receive_damage(event.value as u32, &attacking_unit);
-			game_map.damage_indicators.push(DamageIndicator::new(core, event.value as u32, PixelCoordinates::from_matrix_indices(
-				unit.y.checked_sub(1).unwrap_or(unit.y),
-				unit.x
-			))?);
+			game_map.notices.push(Notice::Damage((unit.x, unit.y), event.value as u32));
 		},
 		EVENT_END_TURN => {
 			let next_team = game_map.player_state.advance_turn();
 			println!("Ending turn: preparing turn for {}", next_team.to_string());
-			game_map.initialize_next_turn(core, next_team)?;
+			game_map.initialize_next_turn(next_team);
 		},
 		EVENT_SPAWN_UNIT => {
-			let unit_team = if event.from_self { Team::Player } else { Team::Enemy };
+			let unit_team = Team::from_id(event.id)?.as_client(&game_map.player_state);
 
 			let unit_map = match unit_team {
 				Team::Player => &mut game_map.player_units,
@@ -683,21 +694,15 @@ pub fn apply_event<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, event: Ev
 				Team::Barbarians => &mut game_map.barbarian_units,
 			};
 
-			let (melee, range, mage)  = match unit_team {
-				Team::Player => ("pll", "plr", "plm"),
-				Team::Enemy =>  ("pl2l", "pl2r", "pl2m"),
-				Team::Barbarians => ("bl", "br", ""),
-			};
-
 			let (x, y) = event.to_pos;
-			// update unit team on map tile
-			game_map.map_tiles.get_mut(&(y, x)).unwrap().update_team(Some(unit_team));
+			let mut new_unit = Unit::from_kind(event.value, unit_team, x, y)?;
 
-			let mut new_unit = match event.value {
-				EVENT_UNIT_MELEE =>  Unit::new(x, y, unit_team, 20, 7, 1, 95, 1, 5, core.texture_map.get(melee).unwrap(), false),
-				EVENT_UNIT_ARCHER => Unit::new(x, y, unit_team, 15, 5, 4, 85, 3, 7, core.texture_map.get(range).unwrap(), true),
-				_ =>                 Unit::new(x, y, unit_team, 10, 6, 3, 75, 5, 9, core.texture_map.get(mage).unwrap(), true),
-			};
+			// update unit team on map tile
+			let tile = game_map.map_tiles.get_mut(&(y, x)).ok_or("Could not obtain tile for spawned unit")?;
+			if tile.contained_unit_team.is_some() {
+				return Err(format!("Could not spawn unit: {:?} already contains another unit", (x, y)));
+			}
+			tile.update_team(Some(unit_team));
 
 			new_unit.has_moved = true;
 			new_unit.has_attacked = true;
@@ -705,12 +710,19 @@ pub fn apply_event<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, event: Ev
 			unit_map.insert((x, y), new_unit);
 			println!("Unit spawned at {:?}", (x, y));
 		},
+		//The server rolled a successful conversion for a barbarian killed by one of this client's units
+		EVENT_CONVERT if Team::from_id(event.id)?.as_client(&game_map.player_state) == Team::Player => {
+			game_map.player_state.current_player_action = PlayerAction::ChoosePrimer;
+		},
 		EVENT_END_GAME => {
 			if game_map.winning_team == None {
 				let team = Team::from_id(event.id)?;
 				game_map.set_winner(team.as_client(&game_map.player_state));
 			}
 		},
+		EVENT_HIDE => {
+			game_map.remove_unit(event.from_pos);
+		},
 		_ => {
 
 		},
@@ -719,133 +731,330 @@ pub fn apply_event<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>, event: Ev
 	Ok(())
 }
 
-// Method for preparing the HashMap of player units whilst also properly marking them in the map
-// l melee r ranged m mage
-pub fn prepare_player_units<'a, 'b> (player_units: &mut HashMap<(u32, u32), Unit<'a>>, player_team: Team, units: &Vec<(char, (u32, u32))>, unit_textures: &'a HashMap<String, Texture<'a>>, map: &'b mut HashMap<(u32, u32), Tile>) {
-	let (melee, range, mage, guard, scout)  = match player_team {
-		Team::Player => ("pll", "plr", "plm", "plg", "pls"),
-		Team::Enemy =>  ("pl2l", "pl2r", "pl2m", "pl2g", "pl2s"),
-		Team::Barbarians => ("bl", "br", "", "", ""),
+// Adds (or updates) a unit that has come into this client's sight
+fn reveal_unit(game_map: &mut GameMap, event: Event) -> Result<(), String> {
+	let team = Team::from_id(event.id)?.as_client(&game_map.player_state);
+	let (x, y) = event.to_pos;
+
+	let mut unit = Unit::from_kind(event.value, team, x, y)?;
+	unit.hp = event.from_pos.0.min(unit.max_hp);
+	unit.has_moved = event.from_pos.1 & 1 != 0;
+	unit.has_attacked = event.from_pos.1 & 2 != 0;
+
+	game_map.remove_unit((x, y));
+	game_map.map_tiles.get_mut(&(y, x)).ok_or("Could not obtain tile for revealed unit")?.update_team(Some(team));
+	match team {
+		Team::Player => game_map.player_units.insert((x, y), unit),
+		Team::Enemy => game_map.enemy_units.insert((x, y), unit),
+		Team::Barbarians => game_map.barbarian_units.insert((x, y), unit),
 	};
+	Ok(())
+}
 
+// Method for preparing the HashMap of player units whilst also properly marking them in the map
+pub fn prepare_player_units(player_units: &mut HashMap<(u32, u32), Unit>, player_team: Team, units: &Vec<(String, (u32, u32))>, map: &mut HashMap<(u32, u32), Tile>) -> Result<(), String> {
 	for unit in units {
+		let class = unit_class::by_name(&unit.0)?;
+
 		//Remember map is flipped indexing
-		match player_team {
-			Team::Player => map.get_mut(&(unit.1.1, unit.1.0)).unwrap().update_team(Some(Team::Player)),
-			Team::Enemy => map.get_mut(&(unit.1.1, unit.1.0)).unwrap().update_team(Some(Team::Enemy)),
-			Team::Barbarians => map.get_mut(&(unit.1.1, unit.1.0)).unwrap().update_team(Some(Team::Barbarians)),
-		}
+		map.get_mut(&(unit.1.1, unit.1.0)).ok_or(format!("Unit spawn {:?} is outside of the map", unit.1))?.update_team(Some(player_team));
+		player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, class));
+	}
+	Ok(())
+}
 
-		//Add unit to team. Barbarian units get half as much HP and do half as much max damage
-		match unit.0 {
-			'l' => {
-				if player_team == Team::Barbarians {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 10, 7, 1, 95, 1, 3, unit_textures.get(melee).unwrap(), false));
-				}
-				else {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 20, 7, 1, 95, 1, 5, unit_textures.get(melee).unwrap(), false));
-				}
-			},
-			'r' => {
-				if player_team == Team::Barbarians {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 8, 5, 4, 85, 2, 4, unit_textures.get(range).unwrap(), true));
-				}
-				else {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 15, 5, 4, 85, 3, 7, unit_textures.get(range).unwrap(), true));
-				}
-			},
-			'g' => {
-				if player_team == Team::Barbarians {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 16, 4, 1, 90, 1, 5, unit_textures.get(guard).unwrap(), false));
-				}
-				else {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, GUARD_HEALTH_ID, 4, 1, 90, 1, 5, unit_textures.get(guard).unwrap(), false));
-				}
-			}
-			's' => {
-				if player_team == Team::Barbarians {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 6, 9, 2, 100, 4, 4, unit_textures.get(scout).unwrap(), false));
-				}
-				else {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, SCOUT_HEALTH_ID, 9, 2, 100, 4, 4, unit_textures.get(scout).unwrap(), false));
-				}
-			}
-			_ => {
-				if player_team == Team::Barbarians {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 5, 6, 3, 75,  3, 6, unit_textures.get(mage).unwrap(), true));
-				}
-				else {
-					player_units.insert((unit.1.0, unit.1.1), Unit::new(unit.1.0, unit.1.1, player_team, 10, 6, 3, 75,  5, 9, unit_textures.get(mage).unwrap(), true));
-				}
-			},
+#[cfg(test)]
+pub mod tests {
+	use std::sync::Once;
+
+	use super::*;
+	use crate::unit::respawn_loc;
+
+	//An 8x6 map with player 1's castle in the top left, player 2's in the bottom right, a mountain in the middle and a camp
+	pub const TEST_MAP: &str = "castle_quest_map 1
+name Test
+players 2
+size 8 6
+
+legend
+'.' grass_tile walk shoot
+'#' mountain_tile hide
+end
+
+tiles
+. . . . . . . .
+. . . . . . . .
+. . . # # . . .
+. . . # # . . .
+. . . . . . . .
+. . . . . . . .
+end
+
+structures
+castle 1 0 0
+castle 2 7 5
+camp 5 0
+end
+
+units
+1 melee 1 0
+1 archer 0 1
+2 melee 6 5
+2 mage 7 4
+barbarian melee 6 2
+end
+";
+
+	static LOAD_CLASSES: Once = Once::new();
+
+	//The unit classes are global, so they're only loaded once for all the tests
+	pub fn load_unit_classes() {
+		LOAD_CLASSES.call_once(|| unit_class::load(unit_class::DEFAULT_UNIT_CLASSES).unwrap());
+	}
+
+	pub fn test_map_data() -> MapData {
+		load_unit_classes();
+		MapData::parse(TEST_MAP).unwrap()
+	}
+
+	//Writes TEST_MAP to a file of its own, for the tests of things that load their map from a path
+	pub fn test_map_file(name: &str) -> String {
+		let path = std::env::temp_dir().join(format!("castle_quest_{}_{}.map", name, std::process::id()));
+		std::fs::write(&path, TEST_MAP).unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	pub fn test_map(seed: u64) -> GameMap {
+		GameMap::new(&test_map_data(), Team::Player, seed).unwrap()
+	}
+
+	#[test]
+	fn new_places_units_and_structures() {
+		let map = test_map(1);
+
+		assert_eq!(map.map_size, (8, 6));
+		assert_eq!(map.map_tiles.len(), 48);
+		assert_eq!(map.player_units.len(), 2);
+		assert_eq!(map.enemy_units.len(), 2);
+		assert_eq!(map.barbarian_units.len(), 1);
+		assert_eq!(map.player_units[&(1, 0)].class.name, "melee");
+		assert!(map.map_tiles[&(0, 1)].contained_unit_team == Some(Team::Player));
+		assert!(map.map_tiles[&(4, 7)].contained_unit_team == Some(Team::Enemy));
+		assert!(!map.map_tiles[&(2, 3)].is_traversable);
+
+		assert_eq!(map.objectives.p1_castle, (0, 0));
+		assert_eq!(map.objectives.p2_castle, (7, 5));
+		assert_eq!(map.objectives.barbarian_camps, vec![(5, 0)]);
+		assert!(map.map_tiles[&(1, 6)].contained_structure == Some(Structure::Camp));
+		assert!(map.player_state.current_turn == Team::Player);
+	}
+
+	#[test]
+	fn new_from_player_2s_side() {
+		let map = GameMap::new(&test_map_data(), Team::Enemy, 1).unwrap();
+
+		//Everything is relative to the client, so player 2 sees its own castle & units as the player's
+		assert_eq!(map.objectives.p1_castle, (7, 5));
+		assert_eq!(map.objectives.p2_castle, (0, 0));
+		assert!(map.player_units.contains_key(&(7, 4)));
+		assert!(map.enemy_units.contains_key(&(1, 0)));
+	}
+
+	#[test]
+	fn apply_move() {
+		let mut map = test_map(1);
+		let revision = map.revision;
+
+		apply_event(&mut map, Event::create(EVENT_MOVE, 0, (1, 0), (2, 1), 0)).unwrap();
+		assert!(map.revision != revision);
+		assert!(!map.player_units.contains_key(&(1, 0)));
+		assert!(map.player_units[&(2, 1)].has_moved);
+		assert!(map.map_tiles[&(0, 1)].contained_unit_team.is_none());
+		assert!(map.map_tiles[&(1, 2)].contained_unit_team == Some(Team::Player));
+
+		//A unit only moves once a turn, and never onto another unit
+		assert!(apply_event(&mut map, Event::create(EVENT_MOVE, 0, (2, 1), (2, 2), 0)).is_err());
+		assert!(apply_event(&mut map, Event::create(EVENT_MOVE, 0, (0, 1), (2, 1), 0)).is_err());
+		assert!(apply_event(&mut map, Event::create(EVENT_MOVE, 0, (4, 4), (4, 5), 0)).is_err());
+	}
+
+	#[test]
+	fn apply_attack() {
+		let mut map = test_map(1);
+
+		apply_event(&mut map, Event::create(EVENT_ATTACK, 0, (1, 0), (6, 5), 5)).unwrap();
+		assert_eq!(map.enemy_units[&(6, 5)].hp, 15);
+		assert!(map.player_units[&(1, 0)].has_attacked);
+		assert!(map.notices.iter().any(|n| matches!(n, Notice::Damage((6, 5), 5))));
+
+		//Dead units stay until the events have all been applied
+		map.event_list.push(Event::create(EVENT_ATTACK, 0, (0, 1), (6, 5), 20));
+		apply_events(&mut map).unwrap();
+		assert!(!map.enemy_units.contains_key(&(6, 5)));
+		assert!(map.map_tiles[&(5, 6)].contained_unit_team.is_none());
+
+		assert!(apply_event(&mut map, Event::create(EVENT_ATTACK, 0, (3, 3), (7, 4), 5)).is_err());
+	}
+
+	#[test]
+	fn seed_decides_the_rolls() {
+		let rolls = |map: &mut GameMap| -> Vec<u32> {
+			(0..50).map(|_| map.roll_attack_damage(&(1, 0), &(6, 5)).unwrap()).collect()
 		};
+		let mut map = test_map(7);
+		let mut same_seed = test_map(7);
+		let first_turn = rolls(&mut map);
+		assert_eq!(first_turn, rolls(&mut same_seed));
+		assert_ne!(first_turn, rolls(&mut test_map(8)));
+
+		//Each turn starts from its own seed, however many rolls the turn before used up
+		rolls(&mut same_seed);
+		let end_turn = Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0);
+		apply_event(&mut map, end_turn).unwrap();
+		apply_event(&mut same_seed, end_turn).unwrap();
+		let second_turn = rolls(&mut map);
+		assert_eq!(second_turn, rolls(&mut same_seed));
+		assert_ne!(first_turn, second_turn);
 	}
-}
 
-pub fn draw_possible_moves(core: &mut SDLCore, tiles: &Vec<(u32, u32)>, color:Color) -> Result< (), String> {
-	for (x,y) in tiles.into_iter() {
-		let pixel_location = PixelCoordinates::from_matrix_indices(*y, *x);
-		let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, TILE_SIZE, TILE_SIZE);
-		core.wincan.set_blend_mode(BlendMode::Blend);
-		core.wincan.set_draw_color(color);
-		core.wincan.draw_rect(dest)?;
-		core.wincan.fill_rect(dest)?;
+	#[test]
+	fn apply_end_turn_and_spawn() {
+		let mut map = test_map(1);
+
+		apply_event(&mut map, Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0)).unwrap();
+		assert!(map.player_state.current_turn == Team::Enemy);
+
+		apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, EVENT_ID_ENEMY, (0, 0), (5, 4), EVENT_UNIT_MAGE)).unwrap();
+		let unit = &map.enemy_units[&(5, 4)];
+		assert_eq!(unit.class.name, "mage");
+		assert!(unit.has_moved && unit.has_attacked);
+		assert!(map.map_tiles[&(4, 5)].contained_unit_team == Some(Team::Enemy));
+
+		//Spawn events can come off the network, so bad ones are errors rather than panics
+		assert!(apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, EVENT_ID_ENEMY, (0, 0), (5, 4), EVENT_UNIT_MAGE)).is_err());
+		assert!(apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, EVENT_ID_ENEMY, (0, 0), (50, 4), EVENT_UNIT_MAGE)).is_err());
+		assert!(apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, EVENT_ID_ENEMY, (0, 0), (5, 5), 200)).is_err());
+		assert!(apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, 7, (0, 0), (5, 5), EVENT_UNIT_MAGE)).is_err());
+	}
+
+	#[test]
+	fn respawns_next_to_castles_by_the_map_edge() {
+		let mut map = test_map(1);
+		//The castle at (0, 0) is too close to the edge for the usual spot 5 tiles to the left
+		let towards = map.objectives.respawn_target(Team::Player, &map.player_state);
+		assert_eq!(towards, (0, 5));
+		assert_eq!(respawn_loc(towards, &mut map.map_tiles, map.objectives.p1_castle), (0, 0));
+
+		apply_event(&mut map, Event::create(EVENT_SPAWN_UNIT, EVENT_ID_PLAYER, (0, 0), (0, 0), EVENT_UNIT_MELEE)).unwrap();
+		//(0, 1) is taken by the archer
+		assert_eq!(respawn_loc(towards, &mut map.map_tiles, map.objectives.p1_castle), (0, 2));
 	}
-	Ok(())
-}
 
-//Load map textures
-pub fn load_textures<'r>(textures: &mut HashMap<String, Texture<'r>>, texture_creator: &'r TextureCreator<WindowContext>) -> Result<(), String> {
-	//Mountains
-	textures.insert("▉".to_string(), texture_creator.load_texture("images/tiles/mountain_tile.png")?);
-	textures.insert("▒".to_string(), texture_creator.load_texture("images/tiles/mountain2_tile.png")?);
-	textures.insert("▀".to_string(), texture_creator.load_texture("images/tiles/mountain_side_top.png")?);
-	textures.insert("▐".to_string(), texture_creator.load_texture("images/tiles/mountain_side_vertical_right.png")?);
-	textures.insert("▃".to_string(), texture_creator.load_texture("images/tiles/mountain_side_bottom.png")?);
-	textures.insert("▍".to_string(), texture_creator.load_texture("images/tiles/mountain_side_vertical_left.png")?);
-	textures.insert("▛".to_string(), texture_creator.load_texture("images/tiles/mountain_top_left.png")?);
-	textures.insert("▜".to_string(), texture_creator.load_texture("images/tiles/mountain_top_right.png")?);
-	textures.insert("▙".to_string(), texture_creator.load_texture("images/tiles/mountain_bottom_left.png")?);
-	textures.insert("▟".to_string(), texture_creator.load_texture("images/tiles/mountain_bottom_right.png")?);
-	//Grass
-	textures.insert(" ".to_string(), texture_creator.load_texture("images/tiles/grass_tile.png")?);
-	textures.insert("_".to_string(), texture_creator.load_texture("images/tiles/empty_tile.png")?);
-	//Rivers
-	textures.insert("=".to_string(), texture_creator.load_texture("images/tiles/river_tile.png")?);
-	textures.insert("║".to_string(), texture_creator.load_texture("images/tiles/river_vertical.png")?);
-	textures.insert("^".to_string(), texture_creator.load_texture("images/tiles/river_end_vertical_top.png")?);
-	textures.insert("v".to_string(), texture_creator.load_texture("images/tiles/river_end_vertical_bottom.png")?);
-	textures.insert(">".to_string(), texture_creator.load_texture("images/tiles/river_end_right.png")?);
-	textures.insert("<".to_string(), texture_creator.load_texture("images/tiles/river_end_left.png")?);
-	//Bases
-	textures.insert("b".to_string(), texture_creator.load_texture("images/tiles/barbarian_camp.png")?);
-	textures.insert("pc".to_string(), texture_creator.load_texture("images/tiles/player_camp.png")?);
-	textures.insert("ec".to_string(), texture_creator.load_texture("images/tiles/enemy_camp.png")?);
-	textures.insert("f".to_string(), texture_creator.load_texture("images/tiles/barbarian_fort.png")?);
-	textures.insert("pf".to_string(), texture_creator.load_texture("images/tiles/player_fort.png")?);
-	textures.insert("ef".to_string(), texture_creator.load_texture("images/tiles/enemy_fort.png")?);
-	textures.insert("1".to_string(), texture_creator.load_texture("images/tiles/blue_castle.png")?);
-	textures.insert("2".to_string(), texture_creator.load_texture("images/tiles/red_castle.png")?);
-	//Tree
-	textures.insert("t".to_string(), texture_creator.load_texture("images/tiles/tree_tile.png")?);
-
-	//Load unit textures
-	textures.insert("pll".to_string(), texture_creator.load_texture("images/units/player1_melee.png")?);
-	textures.insert("plr".to_string(), texture_creator.load_texture("images/units/player1_archer.png")?);
-	textures.insert("plm".to_string(), texture_creator.load_texture("images/units/player1_mage.png")?);
-	textures.insert("plg".to_string(), texture_creator.load_texture("images/units/player1_guard.png")?);
-	textures.insert("pls".to_string(), texture_creator.load_texture("images/units/player1_scout.png")?);
-	textures.insert("pl2l".to_string(), texture_creator.load_texture("images/units/player2_melee.png")?);
-	textures.insert("pl2r".to_string(), texture_creator.load_texture("images/units/player2_archer.png")?);
-	textures.insert("pl2m".to_string(), texture_creator.load_texture("images/units/player2_mage.png")?);
-	textures.insert("pl2g".to_string(), texture_creator.load_texture("images/units/player2_guard.png")?);
-	textures.insert("pl2s".to_string(), texture_creator.load_texture("images/units/player2_scout.png")?);
-	textures.insert("bl".to_string(), texture_creator.load_texture("images/units/barbarian_melee.png")?);
-	textures.insert("br".to_string(), texture_creator.load_texture("images/units/barbarian_archer.png")?);
-
-	//Load UI textures
-	textures.insert("cursor".to_string(), texture_creator.load_texture("images/interface/cursor.png")?);
-	textures.insert("unit_interface".to_string(), texture_creator.load_texture("images/interface/unit_interface.png")?);
+	#[test]
+	fn respawn_target_is_behind_each_castle() {
+		//Both castles within 5 tiles of the left edge
+		let mut map = test_map(1);
+		map.objectives = ObjectiveManager::new((2, 10), (4, 1), Vec::new(), Vec::new());
+		assert_eq!(map.objectives.respawn_target(Team::Player, &map.player_state), (0, 15));
+		assert_eq!(map.objectives.respawn_target(Team::Enemy, &map.player_state), (9, 0));
+
+		//Player 2's client sees its own castle as the player's, but still spawns on the same side of it
+		let mut map = GameMap::new(&test_map_data(), Team::Enemy, 1).unwrap();
+		map.objectives = ObjectiveManager::new((4, 1), (2, 10), Vec::new(), Vec::new());
+		assert_eq!(map.objectives.respawn_target(Team::Player, &map.player_state), (9, 0));
+		assert_eq!(map.objectives.respawn_target(Team::Enemy, &map.player_state), (0, 15));
+	}
 
-	Ok(())
+	#[test]
+	fn server_accepts_the_ais_spawns() {
+		let mut map = test_map(1);
+		map.player_state.current_turn = Team::Enemy;
+		let target = map.objectives.respawn_target(Team::Enemy, &map.player_state);
+		let spawn = respawn_loc(target, &mut map.map_tiles, map.objectives.p2_castle);
+		assert!(map.validate_action(&Event::create(EVENT_SPAWN_UNIT, EVENT_ID_ENEMY, (0, 0), spawn, EVENT_UNIT_ARCHER), Team::Enemy).is_ok());
+	}
+
+	#[test]
+	fn validate_action_checks_the_rules() {
+		let mut map = test_map(1);
+		let valid = |map: &mut GameMap, action: u8, from_pos: (u32, u32), to_pos: (u32, u32), value: u8, team: Team| -> bool {
+			map.validate_action(&Event::create(action, 0, from_pos, to_pos, value), team).is_ok()
+		};
+
+		assert!(valid(&mut map, EVENT_MOVE, (1, 0), (2, 1), 0, Team::Player));
+		assert!(!valid(&mut map, EVENT_MOVE, (1, 0), (0, 1), 0, Team::Player)); //Onto another unit
+		assert!(!valid(&mut map, EVENT_MOVE, (1, 0), (3, 2), 0, Team::Player)); //Onto a mountain
+		assert!(!valid(&mut map, EVENT_MOVE, (2, 0), (2, 1), 0, Team::Player)); //Nobody there
+		assert!(!valid(&mut map, EVENT_MOVE, (6, 5), (6, 4), 0, Team::Enemy)); //Not the enemy's turn
+
+		apply_event(&mut map, Event::create(EVENT_MOVE, 0, (1, 0), (5, 2), 0)).unwrap();
+		assert!(!valid(&mut map, EVENT_MOVE, (5, 2), (5, 1), 0, Team::Player));
+		assert!(valid(&mut map, EVENT_ATTACK, (5, 2), (6, 2), 0, Team::Player));
+		assert!(!valid(&mut map, EVENT_ATTACK, (0, 1), (6, 2), 0, Team::Player)); //Out of range
+
+		//Converted units spawn on the way from the castle at (0, 0) towards (0, 5)
+		assert!(valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (0, 2), EVENT_UNIT_MELEE, Team::Player));
+		assert!(valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (0, 0), EVENT_UNIT_MAGE, Team::Player));
+		assert!(!valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (0, 1), EVENT_UNIT_ARCHER, Team::Player)); //Taken
+		assert!(!valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (2, 2), EVENT_UNIT_ARCHER, Team::Player)); //Too far
+		assert!(!valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (0, 2), 1, Team::Player)); //Not a class conversions give
+		assert!(!valid(&mut map, EVENT_SPAWN_UNIT, (0, 0), (7, 3), EVENT_UNIT_MELEE, Team::Enemy));
+	}
+
+	#[test]
+	fn check_objectives_captures_castle() {
+		let mut map = test_map(1);
+		let mut objectives = ObjectiveManager::new((0, 0), (7, 5), vec![(5, 0)], Vec::new());
+		let mut units = HashMap::new();
+		units.insert((7, 5), map.player_units.remove(&(1, 0)).unwrap());
+
+		for _ in 0..2 {
+			objectives.check_objectives(Team::Player, &units);
+			assert!(!objectives.has_won(Team::Player));
+		}
+		objectives.check_objectives(Team::Player, &units);
+		assert!(objectives.has_won(Team::Player));
+		assert!(!objectives.has_won(Team::Enemy));
+
+		//Leaving the castle starts the count over
+		let mut objectives = ObjectiveManager::new((0, 0), (7, 5), Vec::new(), Vec::new());
+		objectives.check_objectives(Team::Player, &units);
+		objectives.check_objectives(Team::Player, &HashMap::new());
+		objectives.check_objectives(Team::Player, &units);
+		objectives.check_objectives(Team::Player, &units);
+		assert!(!objectives.has_won(Team::Player));
+	}
+
+	#[test]
+	fn check_objectives_captures_camp() {
+		let mut map = test_map(1);
+		let mut objectives = ObjectiveManager::new((0, 0), (7, 5), vec![(5, 0)], Vec::new());
+		let mut units = HashMap::new();
+		units.insert((6, 1), map.player_units.remove(&(1, 0)).unwrap());
+
+		for _ in 0..3 {
+			objectives.check_objectives(Team::Player, &units);
+		}
+		assert!(objectives.taken_over_camps.iter().any(|&(camp, team)| camp == (5, 0) && team == Team::Player));
+		assert!(objectives.barbarian_camps.is_empty());
+	}
+
+	#[test]
+	fn captured_camps_heal_the_turn_theyre_taken() {
+		let mut map = test_map(1);
+		let mut unit = map.player_units.remove(&(1, 0)).unwrap();
+		unit.update_pos(6, 1);
+		unit.hp -= 5;
+		map.player_units.insert((6, 1), unit);
+
+		for _ in 0..2 {
+			map.initialize_next_turn(Team::Player);
+		}
+		assert_eq!(map.player_units[&(6, 1)].hp, 15);
+
+		//The third turn on the camp takes it over, and the camp heals 1 hp straight away
+		map.initialize_next_turn(Team::Player);
+		assert_eq!(map.objectives.p1_takeovers, (1, 0));
+		assert_eq!(map.player_units[&(6, 1)].hp, 16);
+	}
 }
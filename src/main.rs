@@ -6,6 +6,8 @@ extern crate sdl2;
 mod ai;
 mod banner;
 mod barbarian_turn;
+mod chat;
+mod config;
 mod credits;
 mod cursor;
 mod damage_indicator;
@@ -13,6 +15,8 @@ mod enemy_turn;
 mod game_map;
 mod input;
 mod main_menu;
+mod map_data;
+mod map_view;
 mod multi_player;
 mod net;
 mod objective_manager;
@@ -20,13 +24,19 @@ mod pixel_coordinates;
 mod player_action;
 mod player_state;
 mod player_turn;
+mod replay;
+mod replay_viewer;
+mod save;
+mod simulate;
 mod single_player;
+mod unit_class;
 mod unit_interface;
 pub mod button;
 pub mod tile;
 pub mod unit;
 
 use std::env;
+use std::process;
 use std::path::Path;
 use std::collections::HashMap;
 
@@ -37,7 +47,10 @@ use sdl2::mixer::{InitFlag, AUDIO_S32SYS, DEFAULT_CHANNELS};
 use crate::main_menu::MainMenu;
 use crate::single_player::SinglePlayer;
 use crate::multi_player::MultiPlayer;
+use crate::replay_viewer::ReplayViewer;
 use crate::input::Input;
+use crate::config::{Command, Config};
+use crate::map_data::MapData;
 
 const TITLE: &str = "Castle Quest";
 const CAM_W: u32 = 1280;
@@ -49,6 +62,7 @@ pub enum GameState {
 	MainMenu,
 	SinglePlayer,
 	MultiPlayer,
+	Replays,
 	Credits,
 	Quit,
 }
@@ -79,7 +93,7 @@ impl SDLCore<'_> {
 
 }
 
-fn runner(vsync:bool) -> Result<(), String> {
+fn runner(config: &Config, start_state: GameState) -> Result<(), String> {
 	println!("\tRunning...");
 
 	// ----- Initialize SDLCore -----
@@ -87,22 +101,27 @@ fn runner(vsync:bool) -> Result<(), String> {
 	let ttf_ctx = sdl2::ttf::init().map_err(|e| e.to_string())?;
 	let video_subsys = sdl_ctx.video()?;
 
-	let window = video_subsys.window(TITLE, CAM_W, CAM_H)
-		.build()
+	let mut window = video_subsys.window(TITLE, config.window_size.0, config.window_size.1);
+	if config.fullscreen {
+		window.fullscreen_desktop();
+	}
+	let window = window.build()
 		.map_err(|e| e.to_string())?;
 
 	let wincan = window.into_canvas().accelerated();
 
 	// Check if we should lock to vsync
-	let wincan = if vsync {
+	let wincan = if config.vsync {
 		wincan.present_vsync()
 	}
 	else {
 		wincan
 	};
 
-	let wincan = wincan.build()
+	let mut wincan = wincan.build()
 		.map_err(|e| e.to_string())?;
+	// the game is laid out for CAM_W x CAM_H and scaled to whatever size the window is
+	wincan.set_logical_size(CAM_W, CAM_H).map_err(|e| e.to_string())?;
 
 	let event_pump = sdl_ctx.event_pump()?;
 	let input = Input::new(&event_pump);
@@ -117,7 +136,7 @@ fn runner(vsync:bool) -> Result<(), String> {
 
 	let mut texture_map = HashMap::new();
 
-	crate::game_map::load_textures(&mut texture_map, &texture_creator)?;
+	crate::map_view::load_textures(&mut texture_map, &texture_creator)?;
 	crate::damage_indicator::load_textures(&mut texture_map, &texture_creator, &bold_font)?;
 	crate::banner::load_textures(&mut texture_map, &texture_creator, &bold_font)?;
 
@@ -135,8 +154,8 @@ fn runner(vsync:bool) -> Result<(), String> {
 		is_animating: false,
 	};
 
-	// ----- Start the game loop in the menu -----
-	let mut game_state = GameState::MainMenu;
+	// ----- Start the game loop (in the menu unless a replay was asked for) -----
+	let mut game_state = start_state;
 
 	loop {
 		game_state = match game_state {
@@ -166,8 +185,9 @@ fn run_game_state<'i, 'r>(core: &'i mut SDLCore<'r>, game_state: &GameState) ->
 		},
 		GameState::SinglePlayer => Box::new(SinglePlayer::new(core)?),
 		GameState::MultiPlayer => Box::new(MultiPlayer::new(core)?),
+		GameState::Replays => Box::new(ReplayViewer::new(core)?),
 		GameState::Credits => {
-			return Ok(credits::credits(core)?);
+			return credits::credits(core);
 		},
 		_ => return Err("Exit game state".to_string())
 	};
@@ -180,31 +200,73 @@ fn run_game_state<'i, 'r>(core: &'i mut SDLCore<'r>, game_state: &GameState) ->
 	}
 }
 
-static mut ARGS: Vec<String> = Vec::new();
-
-// to start client: `cargo run -- tcp://server-address.example.com:0000`
-// to start server: `cargo run -- --server tcp://127.0.0.1:0000`
+// to start the game: `cargo run` (or `cargo run -- play --host server-address.example.com --port 26000`)
+// to start the server: `cargo run -- server --port 26000` (optionally with `--max-rooms 100` and `--admin 127.0.0.1:26001`)
+// to replay a single player match: `cargo run -- --seed 12345`
+// run `cargo run -- --help` for every command and option
 fn main() {
-	// give args a static lifetime
-	// (only unsafe for threading concerns; since we don't use multithreading, this is not a problem)
-	unsafe { ARGS.extend(env::args()) };
-
-	// if address is specified in args, update static variable
-	if let Some(addr) = unsafe { ARGS.last() } {
-		if addr.get(..6) == Some("tcp://") {
-			if let Some(address) = addr.get(6..) {
-				unsafe {
-					net::SERVER_ADDR = address;
-				}
-			}
-		}
+	let args: Vec<String> = env::args().skip(1).collect();
+	let config = match Config::load(&args) {
+		Ok(config) => config,
+		Err(e) if e.is_empty() => {
+			println!("{}", config::USAGE);
+			return;
+		},
+		Err(e) => {
+			println!("{}\n\n{}", e, config::USAGE);
+			process::exit(2);
+		},
+	};
+
+	net::set_server_addr(config.server_addr());
+	if let Some(name) = &config.name {
+		net::client::set_player_name(name.clone());
+	}
+	net::server::set_max_rooms(config.max_rooms);
+	net::server::set_admin_addr(config.admin_addr.clone());
+	single_player::set_seed(config.seed);
+	map_data::set_map_path(config.map_path.clone());
+	if let Err(e) = unit_class::load(&config.units_path) {
+		println!("{}", e);
+		process::exit(1);
+	}
+	ai::profile::set_difficulty(config.difficulty.clone());
+	if let Err(e) = ai::profile::load(&config.ai_profiles_path).and_then(|_| ai::profile::difficulty()) {
+		println!("{}", e);
+		process::exit(1);
 	}
 
-	if unsafe { ARGS.iter() }.any(|s| s == "--server") {
-		net::server::run();
-	} else {
-		if let Err(e) = runner(true) {
-			println!("Exiting: {}", e);
+	let result = match &config.command {
+		Command::Play => runner(&config, GameState::MainMenu),
+		Command::Replay(path) => {
+			replay::set_replay_path(Some(path.clone()));
+			runner(&config, GameState::Replays)
+		},
+		Command::Server => {
+			net::server::run();
+			Ok(())
+		},
+		Command::Simulate => simulate::run(&config.map_path, config.seed.unwrap_or_else(rand::random), config.turns),
+		Command::MapCheck(path) => check_map(path),
+	};
+
+	if let Err(e) = result {
+		println!("Exiting: {}", e);
+		process::exit(1);
+	}
+}
+
+fn check_map(path: &str) -> Result<(), String> {
+	let map_data = MapData::load(path)?;
+	println!("{}: '{}', {}x{}, {} players, {} structures, {} units", path, map_data.name, map_data.width, map_data.height, map_data.players, map_data.structures.len(), map_data.units.len());
+
+	let problems = map_data.check();
+	if !problems.is_empty() {
+		for problem in &problems {
+			println!("  {}", problem);
 		}
+		return Err(format!("{} problem(s) found", problems.len()));
 	}
+	println!("OK");
+	Ok(())
 }
@@ -2,16 +2,23 @@ use sdl2::image::LoadTexture;
 use sdl2::mouse::MouseState;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use sdl2::render::Texture;
+use sdl2::render::{BlendMode, Texture};
 
 use std::convert::TryInto;
 use std::time::Instant;
 
 use crate::{CAM_H, CAM_W, Drawable, GameState};
+use crate::ai::profile;
 use crate::button::Button;
 use crate::net::client;
+use crate::net::util::{RoomInfo, TimeControl, TIME_CONTROLS};
+use crate::replay;
+use crate::save;
 use crate::SDLCore;
 
+// most buttons stacked in one column of a sub-menu
+const MENU_ROWS: usize = 6;
+
 pub struct MainMenu<'i, 'r> {
 	core: &'i mut SDLCore<'r>,
 
@@ -21,14 +28,41 @@ pub struct MainMenu<'i, 'r> {
 
 	// main menu buttons
 	singleplayer_button: Button<'i>,
+	load_button: Button<'i>,
 	multiplayer_button: Button<'i>,
+	replays_button: Button<'i>,
 	credits_button: Button<'i>,
 
+	// single player sub-menu buttons, one per difficulty
+	is_difficulty_open: bool,
+	difficulty_buttons: Vec<(String, Button<'i>)>,
+
+	// load game sub-menu buttons, one per save slot
+	is_load_open: bool,
+	load_slot_buttons: Vec<(u32, Button<'i>)>,
+
+	// replays sub-menu buttons, one per recent replay file
+	is_replays_open: bool,
+	replay_buttons: Vec<(String, Button<'i>)>,
+
 	// multiplayer sub-menu buttons
 	is_multiplayer_open: bool,
 	multiplayer_rect: Rect,
 	multiplayer_create_button: Button<'i>,
+	multiplayer_private_button: Button<'i>,
+	// cycles through the time controls for new rooms
+	multiplayer_timer_button: Button<'i>,
+	time_control: usize,
+	multiplayer_browse_button: Button<'i>,
 	multiplayer_join_button: Button<'i>,
+	multiplayer_spectate_button: Button<'i>,
+	// only shown if there's a dropped game to get back into
+	multiplayer_rejoin_button: Option<Button<'i>>,
+
+	// lobby: the public rooms on the server, fetched whenever it's opened
+	is_lobby_open: bool,
+	lobby_rooms: Vec<(RoomInfo, Rect)>,
+	lobby_error: Option<String>,
 
 	// join code
 	join_code_rect: Rect,
@@ -54,14 +88,50 @@ impl MainMenu<'_, '_> {
 		let bg_interface = core.texture_creator.load_texture("images/interface/unit_interface.png")?;
 
 		// main menu buttons
-		let singleplayer_button = Button::new(core, Rect::new(40, 600, 380, 100), "Single Player")?;
-		let multiplayer_button = Button::new(core, Rect::new(450, 600, 380, 100), "Multiplayer")?;
-		let credits_button = Button::new(core, Rect::new(860, 600, 380, 100), "Credits")?;
+		let singleplayer_button = Button::new(core, Rect::new(25, 600, 230, 100), "Single Player")?;
+		let load_button = Button::new(core, Rect::new(275, 600, 230, 100), "Load Game")?;
+		let multiplayer_button = Button::new(core, Rect::new(525, 600, 230, 100), "Multiplayer")?;
+		let replays_button = Button::new(core, Rect::new(775, 600, 230, 100), "Replays")?;
+		let credits_button = Button::new(core, Rect::new(1025, 600, 230, 100), "Credits")?;
+
+		// single player sub-menu buttons, laid out to fit however many difficulties there are
+		let mut difficulty_buttons = Vec::new();
+		let ai_profiles = profile::all();
+		for (ai_profile, rect) in ai_profiles.iter().zip(menu_grid(ai_profiles.len())) {
+			let mut text: Vec<char> = ai_profile.name.chars().collect();
+			text[0] = text[0].to_ascii_uppercase();
+			difficulty_buttons.push((ai_profile.name.clone(), Button::new(core, rect, &text.into_iter().collect::<String>())?));
+		}
+
+		// load game sub-menu buttons
+		let mut load_slot_buttons = Vec::new();
+		for slot in save::AUTOSAVE_SLOT..=save::NUM_SLOTS {
+			let summary = save::slot_summary(slot).unwrap_or_else(|| "Empty".to_string());
+			let text = format!("{}: {}", save::slot_name(slot), summary);
+			load_slot_buttons.push((slot, Button::new(core, centered_rect!(core, _, 95 + 140 * slot as i32, 500, 100), &text)?));
+		}
+
+		// replays sub-menu buttons (only the most recent replays fit on screen)
+		let mut replay_buttons = Vec::new();
+		for (i, path) in replay::list_replays().into_iter().take(4).enumerate() {
+			let name = path.rsplit('/').next().unwrap_or(&path).trim_end_matches(".replay").to_string();
+			replay_buttons.push((path, Button::new(core, centered_rect!(core, _, 95 + 140 * i as i32, 500, 100), &name)?));
+		}
 
 		// multiplayer sub-menu buttons
 		let multiplayer_rect = centered_rect!(core, 800, 650);
-		let multiplayer_create_button = Button::new(core, centered_rect!(core, _, 90, 400, 100), "Create Room")?;
-		let multiplayer_join_button = Button::new(core, centered_rect!(core, _, 520, 400, 100), "Join Room")?;
+		// create/private and join/spectate share a row each
+		let join_row = centered_rect!(core, _, 520, 400, 100);
+		let multiplayer_create_button = Button::new(core, Rect::new(join_row.x(), 55, 195, 90), "Create Room")?;
+		let multiplayer_private_button = Button::new(core, Rect::new(join_row.x() + 205, 55, 195, 90), "Private Room")?;
+		let multiplayer_timer_button = Button::new(core, centered_rect!(core, _, 155, 400, 50), &timer_label(0))?;
+		let multiplayer_browse_button = Button::new(core, centered_rect!(core, _, 215, 400, 80), "Browse Rooms")?;
+		let multiplayer_join_button = Button::new(core, Rect::new(join_row.x(), 520, 195, 100), "Join Room")?;
+		let multiplayer_spectate_button = Button::new(core, Rect::new(join_row.x() + 205, 520, 195, 100), "Spectate")?;
+		let multiplayer_rejoin_button = match client::Session::load() {
+			Some(session) => Some(Button::new(core, centered_rect!(core, _, 305, 400, 80), &format!("Rejoin Room {:04}", session.code))?),
+			None => None,
+		};
 
 		let join_code_rect = centered_rect!(core, _, 400, 400, 60);
 
@@ -72,13 +142,34 @@ impl MainMenu<'_, '_> {
 			bg_interface,
 
 			singleplayer_button,
+			load_button,
 			multiplayer_button,
+			replays_button,
 			credits_button,
 
+			is_difficulty_open: false,
+			difficulty_buttons,
+
+			is_load_open: false,
+			load_slot_buttons,
+
+			is_replays_open: false,
+			replay_buttons,
+
 			is_multiplayer_open: false,
 			multiplayer_rect,
 			multiplayer_create_button,
+			multiplayer_private_button,
+			multiplayer_timer_button,
+			time_control: 0,
+			multiplayer_browse_button,
 			multiplayer_join_button,
+			multiplayer_spectate_button,
+			multiplayer_rejoin_button,
+
+			is_lobby_open: false,
+			lobby_rooms: Vec::new(),
+			lobby_error: None,
 
 			join_code_rect,
 			join_code: String::from(""),
@@ -87,6 +178,61 @@ impl MainMenu<'_, '_> {
 		})
 	}
 
+	fn open_lobby(&mut self) {
+		self.is_lobby_open = true;
+		self.lobby_rooms.clear();
+		self.lobby_error = None;
+
+		match client::list_rooms() {
+			Ok(rooms) => {
+				// only as many rooms as fit on screen
+				let core = &*self.core;
+				for (i, room) in rooms.into_iter().take(9).enumerate() {
+					let rect = centered_rect!(core, _, 60 + 65 * i as i32, 700, 55);
+					self.lobby_rooms.push((room, rect));
+				}
+				if self.lobby_rooms.is_empty() {
+					self.lobby_error = Some(String::from("No open rooms right now"));
+				}
+			},
+			Err(e) => {
+				println!("Could not list rooms: {}", e);
+				self.lobby_error = Some(String::from("Could not reach the server"));
+			},
+		}
+	}
+
+	fn draw_lobby(&mut self, mouse_pos: (i32, i32)) -> Result<(), String> {
+		let mut lines: Vec<(Rect, String, bool)> = self.lobby_rooms.iter().map(|(room, rect)| {
+			let age = match room.age_secs {
+				0..=59 => String::from("just now"),
+				60..=3599 => format!("{} min ago", room.age_secs / 60),
+				_ => format!("{} h ago", room.age_secs / 3600),
+			};
+			let text = format!("{:04}   {}   {}   {}   {}/2 players   {}{}", room.code, room.host_name, room.map_name, room.time_control.describe(), room.players, age, if room.players < 2 { "" } else { "   (spectate)" });
+			(*rect, text, rect.contains_point(mouse_pos))
+		}).collect();
+		if let Some(error) = &self.lobby_error {
+			let core = &*self.core;
+			lines.push((centered_rect!(core, _, 60, 700, 55), error.clone(), false));
+		}
+
+		for (rect, text, hovered) in lines {
+			self.core.wincan.set_blend_mode(BlendMode::Blend);
+			self.core.wincan.set_draw_color(if hovered { Color::RGBA(100,100,100,100) } else { Color::RGBA(50,50,50,100) });
+			self.core.wincan.fill_rect(rect)?;
+
+			let (w, h) = self.core.regular_font.size_of(&text).map_err(|_e| "Could not determine text size")?;
+			let text_surface = self.core.regular_font.render(&text)
+				.blended(Color::RGBA(255,255,255,255))
+				.map_err(|e| e.to_string())?;
+			let text_texture = self.core.texture_creator.create_texture_from_surface(&text_surface)
+				.map_err(|e| e.to_string())?;
+			self.core.wincan.copy(&text_texture, None, Rect::new(rect.x() + 20, rect.y() + (rect.height() as i32 - h as i32) / 2, w, h))?;
+		}
+		Ok(())
+	}
+
 }
 
 impl Drawable for MainMenu<'_, '_> {
@@ -98,30 +244,98 @@ impl Drawable for MainMenu<'_, '_> {
 		let mouse_state: MouseState = self.core.event_pump.mouse_state();
 		let mouse_pos = (mouse_state.x(), mouse_state.y());
 
+		if self.core.input.left_clicked && self.is_lobby_open {
+			if let Some((room, _rect)) = self.lobby_rooms.iter().find(|(_room, rect)| rect.contains_point(mouse_pos)) {
+				// join the room if there's a seat left, otherwise watch it
+				if room.players < 2 {
+					client::set_code(Some(room.code));
+				} else {
+					client::set_spectate(room.code);
+				}
+				return Ok(GameState::MultiPlayer);
+			} else {
+				self.is_lobby_open = false;
+			}
+		}
+
 		if self.core.input.left_clicked && self.is_multiplayer_open {
 			if self.join_code_rect.contains_point(mouse_pos) {
 				self.join_code_selected = true;
 				self.join_code_selected_time = Instant::now();
-			} else if self.multiplayer_create_button.is_mouse(self.core) {
+			} else if self.multiplayer_create_button.is_mouse(self.core) || self.multiplayer_private_button.is_mouse(self.core) {
 				// create a new multiplayer room
 				client::set_code(None);
+				client::set_private(self.multiplayer_private_button.is_mouse(self.core));
+				let (_name, turn_secs, match_secs) = TIME_CONTROLS[self.time_control];
+				client::set_time_control(TimeControl { turn_secs, match_secs });
 				return Ok(GameState::MultiPlayer);
+			} else if self.multiplayer_timer_button.is_mouse(self.core) {
+				self.time_control = (self.time_control + 1) % TIME_CONTROLS.len();
+				let core = &*self.core;
+				self.multiplayer_timer_button = Button::new(core, centered_rect!(core, _, 155, 400, 50), &timer_label(self.time_control))?;
+			} else if self.multiplayer_browse_button.is_mouse(self.core) {
+				self.open_lobby();
+				self.is_multiplayer_open = false;
 			} else if self.multiplayer_join_button.is_mouse(self.core) {
 				// join multiplayer room with code
 				let code: u32 = self.join_code.parse().map_err(|_e| "Couldn't parse join code")?;
 				client::set_code(Some(code));
 				return Ok(GameState::MultiPlayer);
+			} else if self.multiplayer_spectate_button.is_mouse(self.core) {
+				// watch multiplayer room with code
+				let code: u32 = self.join_code.parse().map_err(|_e| "Couldn't parse join code")?;
+				client::set_spectate(code);
+				return Ok(GameState::MultiPlayer);
+			} else if self.multiplayer_rejoin_button.as_ref().is_some_and(|b| b.is_mouse(self.core)) {
+				// get back into the last multiplayer game
+				client::set_rejoin();
+				return Ok(GameState::MultiPlayer);
 			} else {
 				self.join_code_selected = false;
 				self.is_multiplayer_open = false;
 			}
 		}
 
-		if self.core.input.left_clicked && !self.is_multiplayer_open {
-			if self.singleplayer_button.is_mouse(self.core) {
+		if self.core.input.left_clicked && self.is_difficulty_open {
+			if let Some((name, _button)) = self.difficulty_buttons.iter().find(|(_name, button)| button.is_mouse(self.core)) {
+				profile::set_difficulty(name.clone());
+				save::set_load_slot(None);
 				return Ok(GameState::SinglePlayer);
+			} else {
+				self.is_difficulty_open = false;
+			}
+		}
+
+		if self.core.input.left_clicked && self.is_load_open {
+			if let Some((slot, _button)) = self.load_slot_buttons.iter().find(|(_slot, button)| button.is_mouse(self.core)) {
+				// only non-empty slots can be loaded
+				if save::slot_summary(*slot).is_some() {
+					save::set_load_slot(Some(*slot));
+					return Ok(GameState::SinglePlayer);
+				}
+			} else {
+				self.is_load_open = false;
+			}
+		}
+
+		if self.core.input.left_clicked && self.is_replays_open {
+			if let Some((path, _button)) = self.replay_buttons.iter().find(|(_path, button)| button.is_mouse(self.core)) {
+				replay::set_replay_path(Some(path.clone()));
+				return Ok(GameState::Replays);
+			} else {
+				self.is_replays_open = false;
+			}
+		}
+
+		if self.core.input.left_clicked && !self.is_multiplayer_open && !self.is_difficulty_open && !self.is_load_open && !self.is_replays_open && !self.is_lobby_open {
+			if self.singleplayer_button.is_mouse(self.core) {
+				self.is_difficulty_open = true;
+			} else if self.load_button.is_mouse(self.core) {
+				self.is_load_open = true;
 			} else if self.multiplayer_button.is_mouse(self.core) {
 				self.is_multiplayer_open = true;
+			} else if self.replays_button.is_mouse(self.core) && !self.replay_buttons.is_empty() {
+				self.is_replays_open = true;
 			} else if self.credits_button.is_mouse(self.core) {
 				return Ok(GameState::Credits);
 			}
@@ -162,16 +376,50 @@ impl Drawable for MainMenu<'_, '_> {
 		}
 
 		// buttons
-		if !self.is_multiplayer_open {
+		if self.is_difficulty_open {
+			// single player sub-menu background
+			self.core.wincan.copy(&self.bg_interface, None, self.multiplayer_rect)?;
+
+			for (_name, button) in self.difficulty_buttons.iter() {
+				button.draw(self.core)?;
+			}
+		} else if self.is_load_open {
+			// load game sub-menu background
+			self.core.wincan.copy(&self.bg_interface, None, self.multiplayer_rect)?;
+
+			for (_slot, button) in self.load_slot_buttons.iter() {
+				button.draw(self.core)?;
+			}
+		} else if self.is_replays_open {
+			// replays sub-menu background
+			self.core.wincan.copy(&self.bg_interface, None, self.multiplayer_rect)?;
+
+			for (_path, button) in self.replay_buttons.iter() {
+				button.draw(self.core)?;
+			}
+		} else if self.is_lobby_open {
+			// lobby sub-menu background
+			self.core.wincan.copy(&self.bg_interface, None, self.multiplayer_rect)?;
+			self.draw_lobby(mouse_pos)?;
+		} else if !self.is_multiplayer_open {
 			self.singleplayer_button.draw(self.core)?;
+			self.load_button.draw(self.core)?;
 			self.multiplayer_button.draw(self.core)?;
+			self.replays_button.draw(self.core)?;
 			self.credits_button.draw(self.core)?;
 		} else {
 			// multiplayer sub-menu background
 			self.core.wincan.copy(&self.bg_interface, None, self.multiplayer_rect)?;
 
 			self.multiplayer_create_button.draw(self.core)?;
+			self.multiplayer_private_button.draw(self.core)?;
+			self.multiplayer_timer_button.draw(self.core)?;
+			self.multiplayer_browse_button.draw(self.core)?;
 			self.multiplayer_join_button.draw(self.core)?;
+			self.multiplayer_spectate_button.draw(self.core)?;
+			if let Some(button) = &self.multiplayer_rejoin_button {
+				button.draw(self.core)?;
+			}
 
 			// Draw join code box
 			self.core.wincan.set_draw_color(Color::RGBA(0,0,0,255));
@@ -196,3 +444,24 @@ impl Drawable for MainMenu<'_, '_> {
 	}
 
 }
+
+// rects for count buttons in a sub-menu: a column of up to MENU_ROWS, with more columns side by side once that's full
+fn menu_grid(count: usize) -> Vec<Rect> {
+	let columns = count.div_ceil(MENU_ROWS).max(1) as u32;
+	let rows = (count as u32).div_ceil(columns).max(1);
+	let (area_w, area_h) = (760, 600);
+	let width = ((area_w - 10 * (columns - 1)) / columns).min(500);
+	let (row_h, height) = (area_h / rows, (area_h / rows - 10).min(100));
+
+	let left = (CAM_W - (width * columns + 10 * (columns - 1))) as i32 / 2;
+	let top = (CAM_H - area_h) as i32 / 2;
+	(0..count as u32).map(|i| {
+		let (column, row) = (i / rows, i % rows);
+		Rect::new(left + ((width + 10) * column) as i32, top + (row_h * row + (row_h - height) / 2) as i32, width, height)
+	}).collect()
+}
+
+// e.g. "Timer: Blitz"
+fn timer_label(time_control: usize) -> String {
+	format!("Timer: {}", TIME_CONTROLS[time_control].0)
+}
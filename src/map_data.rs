@@ -0,0 +1,452 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::sync::Mutex;
+
+use crate::unit_class;
+
+pub const MAP_FORMAT_VERSION: u32 = 1;
+pub const DEFAULT_MAP: &str = "maps/default.map";
+
+static MAP_PATH: Mutex<Option<String>> = Mutex::new(None);
+
+// sets the map new games are played on
+pub fn set_map_path(path: String) {
+	*MAP_PATH.lock().unwrap() = Some(path);
+}
+
+// the map new games are played on, DEFAULT_MAP unless another map was configured
+pub fn map_path() -> String {
+	MAP_PATH.lock().unwrap().clone().unwrap_or_else(|| String::from(DEFAULT_MAP))
+}
+
+/* Map files are plain text split into sections. The first line identifies the format version,
+ * followed by a header (name, players, size) and then `legend`, `tiles`, `structures` and `units`
+ * sections which each end with a line containing only `end`. Blank lines and lines starting with
+ * `#` are ignored outside of the tiles section. See maps/default.map for an example.
+ */
+pub enum MapError {
+	Io(String, String),
+	UnsupportedVersion(u32),
+	MissingSection(&'static str),
+	Parse(usize, String),
+	UnknownGlyph(usize, char),
+	OutOfBounds(usize, (u32, u32)),
+}
+
+impl fmt::Display for MapError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MapError::Io(path, e) => write!(f, "Could not read map file '{}': {}", path, e),
+			MapError::UnsupportedVersion(v) => write!(f, "Unsupported map format version {} (expected {})", v, MAP_FORMAT_VERSION),
+			MapError::MissingSection(s) => write!(f, "Map file is missing its '{}' section", s),
+			MapError::Parse(line, e) => write!(f, "Map parse error on line {}: {}", line, e),
+			MapError::UnknownGlyph(line, c) => write!(f, "Map parse error on line {}: glyph '{}' is not in the legend", line, c),
+			MapError::OutOfBounds(line, pos) => write!(f, "Map parse error on line {}: position {:?} is outside of the map", line, pos),
+		}
+	}
+}
+
+impl fmt::Debug for MapError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Display::fmt(self, f)
+	}
+}
+
+impl From<MapError> for String {
+	fn from(e: MapError) -> String {
+		e.to_string()
+	}
+}
+
+// Terrain properties for one glyph of the legend
+pub struct TileType {
+	pub name: String, // shown when hovering the tile
+	pub texture: String,
+	pub is_traversable: bool,
+	pub can_attack_through: bool,
+	pub blocks_vision: bool,
+	pub movement_cost: u32,
+	pub evasion: u32,
+	pub defense: u32,
+}
+
+pub enum StructureKind {
+	Castle(u32), // owned by player 1 or 2
+	Camp,
+	Fort,
+}
+
+pub struct StructureDef {
+	pub kind: StructureKind,
+	pub pos: (u32, u32), // (x, y); camps & forts are anchored at their top left tile
+}
+
+pub struct UnitSpawn {
+	pub owner: u32, // 1 or 2 for the players, 0 for barbarians
+	pub class: String,
+	pub pos: (u32, u32), // (x, y)
+}
+
+pub struct MapData {
+	pub name: String,
+	pub players: u32,
+	pub width: u32,
+	pub height: u32,
+	pub legend: HashMap<char, TileType>,
+	pub tiles: Vec<Vec<char>>, // tiles[y][x]
+	pub structures: Vec<StructureDef>,
+	pub units: Vec<UnitSpawn>,
+}
+
+impl MapData {
+	pub fn load(path: &str) -> Result<MapData, MapError> {
+		let contents = fs::read_to_string(path).map_err(|e| MapError::Io(path.to_string(), e.to_string()))?;
+		MapData::parse(&contents)
+	}
+
+	pub fn parse(contents: &str) -> Result<MapData, MapError> {
+		let mut lines = contents.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+		// version line
+		let (line_num, first) = lines.next().ok_or(MapError::Parse(1, "File is empty".to_string()))?;
+		let version = match first.trim().split(' ').collect::<Vec<&str>>()[..] {
+			["castle_quest_map", v] => v.parse::<u32>().map_err(|_e| MapError::Parse(line_num, "Invalid version number".to_string()))?,
+			_ => return Err(MapError::Parse(line_num, "Not a castle_quest_map file".to_string())),
+		};
+		if version != MAP_FORMAT_VERSION {
+			return Err(MapError::UnsupportedVersion(version));
+		}
+
+		let mut name: Option<String> = None;
+		let mut players: u32 = 2;
+		let mut size: Option<(u32, u32)> = None;
+		let mut legend: Option<HashMap<char, TileType>> = None;
+		let mut tiles: Option<Vec<Vec<char>>> = None;
+		let mut structures: Vec<StructureDef> = Vec::new();
+		let mut units: Vec<UnitSpawn> = Vec::new();
+
+		while let Some((line_num, line)) = lines.next() {
+			let trimmed = line.trim();
+			if trimmed.is_empty() || trimmed.starts_with('#') {
+				continue;
+			}
+
+			let (keyword, rest) = match trimmed.find(' ') {
+				Some(i) => (&trimmed[..i], trimmed[i+1..].trim()),
+				None => (trimmed, ""),
+			};
+
+			match keyword {
+				"name" => name = Some(rest.to_string()),
+				"players" => players = parse_num(line_num, rest)?,
+				"size" => {
+					let values = parse_nums(line_num, rest, 2)?;
+					if values[0] == 0 || values[1] == 0 {
+						return Err(MapError::Parse(line_num, "Map size must be non-zero".to_string()));
+					}
+					size = Some((values[0], values[1]));
+				},
+				"legend" => {
+					let mut entries = HashMap::new();
+					for (line_num, line) in section(&mut lines) {
+						let (glyph, tile_type) = parse_legend_entry(line_num, line)?;
+						entries.insert(glyph, tile_type);
+					}
+					legend = Some(entries);
+				},
+				"tiles" => {
+					let (width, height) = size.ok_or(MapError::Parse(line_num, "'size' must come before 'tiles'".to_string()))?;
+					let entries = legend.as_ref().ok_or(MapError::Parse(line_num, "'legend' must come before 'tiles'".to_string()))?;
+
+					let mut rows = Vec::new();
+					for (line_num, line) in lines.by_ref() {
+						if line.trim() == "end" {
+							break;
+						}
+
+						// each tile is a glyph followed by a spacer character
+						let row: Vec<char> = line.chars().collect::<Vec<char>>().chunks(2).map(|chunk| chunk[0]).collect();
+						if row.len() != width as usize {
+							return Err(MapError::Parse(line_num, format!("Expected {} tiles in row but found {}", width, row.len())));
+						}
+						if let Some(glyph) = row.iter().find(|c| !entries.contains_key(c)) {
+							return Err(MapError::UnknownGlyph(line_num, *glyph));
+						}
+						rows.push(row);
+					}
+
+					if rows.len() != height as usize {
+						return Err(MapError::Parse(line_num, format!("Expected {} rows of tiles but found {}", height, rows.len())));
+					}
+					tiles = Some(rows);
+				},
+				"structures" => {
+					for (line_num, line) in section(&mut lines) {
+						let values: Vec<&str> = line.split_whitespace().collect();
+						let def = match values[..] {
+							["castle", owner, x, y] => StructureDef { kind: StructureKind::Castle(parse_num(line_num, owner)?), pos: (parse_num(line_num, x)?, parse_num(line_num, y)?) },
+							["camp", x, y] => StructureDef { kind: StructureKind::Camp, pos: (parse_num(line_num, x)?, parse_num(line_num, y)?) },
+							["fort", x, y] => StructureDef { kind: StructureKind::Fort, pos: (parse_num(line_num, x)?, parse_num(line_num, y)?) },
+							_ => return Err(MapError::Parse(line_num, format!("Invalid structure '{}'", line))),
+						};
+						check_bounds(line_num, def.pos, size)?;
+						if let StructureKind::Camp | StructureKind::Fort = def.kind {
+							check_bounds(line_num, (def.pos.0 + 1, def.pos.1 + 1), size)?;
+						}
+						structures.push(def);
+					}
+				},
+				"units" => {
+					for (line_num, line) in section(&mut lines) {
+						let values: Vec<&str> = line.split_whitespace().collect();
+						let spawn = match values[..] {
+							[owner, class, x, y] => UnitSpawn {
+								owner: if owner == "barbarian" { 0 } else { parse_num(line_num, owner)? },
+								class: class.to_string(),
+								pos: (parse_num(line_num, x)?, parse_num(line_num, y)?),
+							},
+							_ => return Err(MapError::Parse(line_num, format!("Invalid unit spawn '{}'", line))),
+						};
+						if let Err(e) = unit_class::by_name(&spawn.class) {
+							return Err(MapError::Parse(line_num, e));
+						}
+						check_bounds(line_num, spawn.pos, size)?;
+						units.push(spawn);
+					}
+				},
+				_ => return Err(MapError::Parse(line_num, format!("Unknown keyword '{}'", keyword))),
+			}
+		}
+
+		let (width, height) = size.ok_or(MapError::MissingSection("size"))?;
+		let map = MapData {
+			name: name.ok_or(MapError::MissingSection("name"))?,
+			players,
+			width,
+			height,
+			legend: legend.ok_or(MapError::MissingSection("legend"))?,
+			tiles: tiles.ok_or(MapError::MissingSection("tiles"))?,
+			structures,
+			units,
+		};
+
+		if map.players != 2 {
+			return Err(MapError::Parse(1, format!("Only 2 player maps are supported, found {}", map.players)));
+		}
+		if map.castle(1).is_none() || map.castle(2).is_none() {
+			return Err(MapError::MissingSection("structures (castles for players 1 and 2)"));
+		}
+
+		Ok(map)
+	}
+
+	// Returns the castle position of the given player, if one is defined
+	pub fn castle(&self, owner: u32) -> Option<(u32, u32)> {
+		self.structures.iter().find_map(|s| match s.kind {
+			StructureKind::Castle(o) if o == owner => Some(s.pos),
+			_ => None,
+		})
+	}
+
+	pub fn tile_type(&self, x: u32, y: u32) -> &TileType {
+		// every glyph was checked against the legend while parsing
+		&self.legend[&self.tiles[y as usize][x as usize]]
+	}
+
+	// Looks for problems that parse() can't catch because the map is well formed but not playable
+	pub fn check(&self) -> Vec<String> {
+		let mut problems = Vec::new();
+
+		let mut occupied = HashSet::new();
+		for unit in self.units.iter() {
+			let (x, y) = unit.pos;
+			if !self.tile_type(x, y).is_traversable {
+				problems.push(format!("{} of player {} starts on an impassable tile at {:?}", unit.class, unit.owner, unit.pos));
+			}
+			if !occupied.insert(unit.pos) {
+				problems.push(format!("More than one unit starts at {:?}", unit.pos));
+			}
+		}
+
+		for owner in 1..=2 {
+			if let Some((x, y)) = self.castle(owner) {
+				if !self.tile_type(x, y).is_traversable {
+					problems.push(format!("The castle of player {} is on an impassable tile", owner));
+				}
+			}
+		}
+
+		//Walk the traversable tiles from player 1's castle; player 2's castle must be among them
+		if let (Some(start), Some(goal)) = (self.castle(1), self.castle(2)) {
+			let mut seen = HashSet::new();
+			let mut queue = VecDeque::new();
+			seen.insert(start);
+			queue.push_back(start);
+			while let Some((x, y)) = queue.pop_front() {
+				let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+				for (nx, ny) in neighbours.iter().copied() {
+					if nx < self.width && ny < self.height && self.tile_type(nx, ny).is_traversable && seen.insert((nx, ny)) {
+						queue.push_back((nx, ny));
+					}
+				}
+			}
+			if !seen.contains(&goal) {
+				problems.push(String::from("There is no path between the two castles"));
+			}
+		}
+
+		problems
+	}
+}
+
+// Collects the lines of a section up to its terminating `end`, skipping blanks & comments
+fn section<'a, I: Iterator<Item = (usize, &'a str)>>(lines: &mut I) -> Vec<(usize, &'a str)> {
+	let mut ret = Vec::new();
+	for (line_num, line) in lines {
+		let trimmed = line.trim();
+		if trimmed == "end" {
+			break;
+		}
+		if trimmed.is_empty() || trimmed.starts_with('#') {
+			continue;
+		}
+		ret.push((line_num, trimmed));
+	}
+	ret
+}
+
+// Legend entries look like: '▉' mountain_tile walk shoot hide cost=2 evade=20 defense=1 name=Mountain
+// The glyph is quoted since the grass glyph is a space. Underscores in the name are shown as spaces
+fn parse_legend_entry(line_num: usize, line: &str) -> Result<(char, TileType), MapError> {
+	let chars: Vec<char> = line.chars().collect();
+	if chars.len() < 3 || chars[0] != '\'' || chars[2] != '\'' {
+		return Err(MapError::Parse(line_num, "Legend entries must start with a quoted glyph, e.g. ' '".to_string()));
+	}
+
+	let rest: String = chars[3..].iter().collect();
+	let mut values = rest.split_whitespace();
+	let texture = values.next().ok_or(MapError::Parse(line_num, "Legend entry is missing a texture".to_string()))?;
+
+	let mut tile_type = TileType {
+		name: texture.replace('_', " "),
+		texture: texture.to_string(),
+		is_traversable: false,
+		can_attack_through: false,
+		blocks_vision: false,
+		movement_cost: 1,
+		evasion: 0,
+		defense: 0,
+	};
+	for flag in values {
+		match flag.split_once('=') {
+			None if flag == "walk" => tile_type.is_traversable = true,
+			None if flag == "shoot" => tile_type.can_attack_through = true,
+			None if flag == "hide" => tile_type.blocks_vision = true,
+			Some(("cost", v)) => tile_type.movement_cost = parse_num(line_num, v)?.max(1),
+			Some(("evade", v)) => tile_type.evasion = parse_num(line_num, v)?.min(100),
+			Some(("defense", v)) => tile_type.defense = parse_num(line_num, v)?,
+			Some(("name", v)) if !v.is_empty() => tile_type.name = v.replace('_', " "),
+			_ => return Err(MapError::Parse(line_num, format!("Unknown legend flag '{}'", flag))),
+		}
+	}
+
+	Ok((chars[1], tile_type))
+}
+
+fn parse_num(line_num: usize, s: &str) -> Result<u32, MapError> {
+	s.trim().parse::<u32>().map_err(|_e| MapError::Parse(line_num, format!("'{}' is not a valid number", s)))
+}
+
+fn parse_nums(line_num: usize, s: &str, count: usize) -> Result<Vec<u32>, MapError> {
+	let values = s.split_whitespace().map(|v| parse_num(line_num, v)).collect::<Result<Vec<u32>, MapError>>()?;
+	if values.len() != count {
+		return Err(MapError::Parse(line_num, format!("Expected {} values but found {}", count, values.len())));
+	}
+	Ok(values)
+}
+
+fn check_bounds(line_num: usize, pos: (u32, u32), size: Option<(u32, u32)>) -> Result<(), MapError> {
+	let (width, height) = size.ok_or(MapError::Parse(line_num, "'size' must come before any positions".to_string()))?;
+	if pos.0 >= width || pos.1 >= height {
+		return Err(MapError::OutOfBounds(line_num, pos));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::game_map::tests::{load_unit_classes, TEST_MAP};
+
+	fn parse_edited(from: &str, to: &str) -> Result<MapData, MapError> {
+		load_unit_classes();
+		assert!(TEST_MAP.contains(from));
+		MapData::parse(&TEST_MAP.replacen(from, to, 1))
+	}
+
+	#[test]
+	fn parses_test_map() {
+		let map = parse_edited("", "").unwrap();
+		assert_eq!(map.name, "Test");
+		assert_eq!((map.width, map.height), (8, 6));
+		assert_eq!(map.castle(1), Some((0, 0)));
+		assert_eq!(map.castle(2), Some((7, 5)));
+		assert_eq!(map.structures.len(), 3);
+		assert_eq!(map.units.len(), 5);
+		assert!(map.tile_type(3, 2).blocks_vision);
+		assert!(map.check().is_empty());
+	}
+
+	#[test]
+	fn legend_names_tiles() {
+		let map = parse_edited("'#' mountain_tile hide", "'#' mountain_tile hide cost=3 name=Tall_mountain").unwrap();
+		assert_eq!(map.tile_type(3, 2).name, "Tall mountain");
+		assert_eq!(map.tile_type(3, 2).movement_cost, 3);
+		//Without a name, the texture is shown
+		assert_eq!(map.tile_type(0, 0).name, "grass tile");
+		assert!(matches!(parse_edited("'#' mountain_tile hide", "'#' mountain_tile hide name="), Err(MapError::Parse(8, _))));
+	}
+
+	#[test]
+	fn default_map_is_playable() {
+		let map = MapData::load(DEFAULT_MAP).unwrap();
+		assert!(map.check().is_empty(), "{:?}", map.check());
+		assert_eq!(map.legend[&'F'].name, "Forest");
+		assert!(!map.legend[&'t'].is_traversable);
+	}
+
+	#[test]
+	fn rejects_other_files_and_versions() {
+		assert!(matches!(MapData::parse(""), Err(MapError::Parse(1, _))));
+		assert!(matches!(parse_edited("castle_quest_map 1", "castle_quest_units 1"), Err(MapError::Parse(1, _))));
+		assert!(matches!(parse_edited("castle_quest_map 1", "castle_quest_map 2"), Err(MapError::UnsupportedVersion(2))));
+	}
+
+	#[test]
+	fn reports_the_line_of_errors() {
+		assert!(matches!(parse_edited(". . . # # . . .", ". . . # x . . ."), Err(MapError::UnknownGlyph(14, 'x'))));
+		assert!(matches!(parse_edited(". . . # # . . .", ". . . # #"), Err(MapError::Parse(14, _))));
+		assert!(matches!(parse_edited("'#' mountain_tile hide", "'#' mountain_tile fly"), Err(MapError::Parse(8, _))));
+		assert!(matches!(parse_edited("2 mage 7 4", "2 mage 8 4"), Err(MapError::OutOfBounds(30, (8, 4)))));
+		assert!(matches!(parse_edited("camp 5 0", "camp 7 0"), Err(MapError::OutOfBounds(23, (8, 1)))));
+		assert!(matches!(parse_edited("2 mage 7 4", "2 wizard 7 4"), Err(MapError::Parse(30, _))));
+		assert!(matches!(parse_edited("size 8 6", "size 8 0"), Err(MapError::Parse(4, _))));
+		assert!(matches!(parse_edited("players 2", "players 3"), Err(MapError::Parse(1, _))));
+	}
+
+	#[test]
+	fn reports_missing_sections() {
+		assert!(matches!(parse_edited("name Test", ""), Err(MapError::MissingSection("name"))));
+		assert!(matches!(parse_edited("castle 2 7 5", ""), Err(MapError::MissingSection(_))));
+	}
+
+	#[test]
+	fn check_finds_unplayable_maps() {
+		let map = parse_edited("1 archer 0 1", "1 archer 3 2").unwrap();
+		assert_eq!(map.check().len(), 1);
+
+		let walled_off = parse_edited(". . . # # . . .\n. . . # # . . .", "# # # # # # # #\n. . . # # . . .").unwrap();
+		assert!(walled_off.check().iter().any(|p| p.contains("no path")));
+	}
+}
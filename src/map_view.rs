@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use sdl2::video::WindowContext;
+use sdl2::render::{Texture, TextureCreator};
+use sdl2::image::LoadTexture;
+use sdl2::rect::Rect;
+use sdl2::pixels::Color;
+use sdl2::render::BlendMode;
+
+use crate::cursor::Cursor;
+use crate::banner::Banner;
+use crate::button::Button;
+use crate::damage_indicator::DamageIndicator;
+use crate::unit_interface::UnitInterface;
+use crate::game_map::{GameMap, Notice};
+use crate::player_action::PlayerAction;
+use crate::tile::{Tile, Structure};
+use crate::unit::{Team, Unit};
+use crate::unit_class;
+use crate::pixel_coordinates::PixelCoordinates;
+use crate::{CAM_H, CAM_W, TILE_SIZE};
+use crate::SDLCore;
+
+// Source rects within each unit's sprite sheet
+const DEFAULT_SPRITE_SRC: (i32, i32) = (0, 0);
+const RED_SPRITE_SRC: (i32, i32) = (32, 0);
+const GRAY_SPRITE_SRC: (i32, i32) = (64, 0);
+
+//How long the mouse has to rest on a tile before its terrain is shown
+const TOOLTIP_DELAY: Duration = Duration::from_millis(600);
+
+/* Renders a GameMap and holds all of the UI state that goes along with it.
+ * The GameMap itself is pure game state; anything that needs SDL (textures, fonts,
+ * animations, interface elements) lives here instead.
+ */
+pub struct MapView<'a> {
+	pub possible_moves: Vec<(u32, u32)>,
+	pub possible_attacks: Vec<(u32, u32)>,
+	pub actual_attacks: Vec<(u32, u32)>,
+
+	pub unit_interface: Option<UnitInterface<'a>>,
+	pub choose_unit_interface: Option<UnitInterface<'a>>,
+
+	//Holds all damage indicators (the numbers that appear above a unit when attacked) that are visible
+	pub damage_indicators: Vec<DamageIndicator>,
+
+	// various UI elements
+	pub banner: Banner,
+	pub cursor: Cursor<'a>,
+	pub end_turn_button: Button<'a>,
+
+	//False when nobody is playing on this view (e.g. watching a replay), hides the turn interface
+	pub show_turn_controls: bool,
+	//Shown next to the end turn button, e.g. the time left in a timed multiplayer turn or that the AI is still thinking
+	pub status_text: Option<String>,
+	//The tile (x, y) under the mouse and since when, for the tile info tooltip
+	hovered_tile: Option<((u32, u32), Instant)>,
+	//What this client's units can see, and the GameMap revision it was worked out for
+	visible_tiles: Option<(u64, HashSet<(u32, u32)>)>,
+}
+
+impl MapView<'_> {
+	pub fn new<'a>(core: &SDLCore<'a>) -> Result<MapView<'a>, String> {
+		let end_turn_button = Button::new(core, Rect::new((CAM_W - 240).try_into().unwrap(), (CAM_H - 90).try_into().unwrap(), 200, 50), "End Turn")?;
+
+		Ok(MapView {
+			possible_moves: Vec::new(),
+			possible_attacks: Vec::new(),
+			actual_attacks: Vec::new(),
+			unit_interface: None,
+			choose_unit_interface: None,
+			damage_indicators: Vec::new(),
+			banner: Banner::new(),
+			cursor: Cursor::new(core.texture_map.get("cursor").ok_or("Could not find cursor texture")?),
+			end_turn_button,
+			show_turn_controls: true,
+			status_text: None,
+			hovered_tile: None,
+			visible_tiles: None,
+		})
+	}
+
+	// Turns the notices recorded by the game state into banners & damage indicators
+	fn handle_notices(&mut self, core: &SDLCore, game_map: &mut GameMap) -> Result<(), String> {
+		for notice in game_map.notices.drain(..) {
+			match notice {
+				Notice::Damage((x, y), damage) => {
+					self.damage_indicators.push(DamageIndicator::new(core, damage, PixelCoordinates::from_matrix_indices(
+						y.checked_sub(1).unwrap_or(y),
+						x
+					))?);
+				},
+				Notice::Heal((x, y), heal) => {
+					self.damage_indicators.push(DamageIndicator::new_heal(core, heal, PixelCoordinates::from_matrix_indices(
+						y.checked_sub(1).unwrap_or(y),
+						x
+					))?);
+				},
+				Notice::TurnStart(team) => self.banner.show_turn(team),
+				Notice::Winner(Team::Player) => self.banner.show("p1_win_banner"),
+				Notice::Winner(Team::Enemy) => self.banner.show("p2_win_banner"),
+				Notice::Winner(Team::Barbarians) => {},
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn draw(&mut self, core: &mut SDLCore, game_map: &mut GameMap) -> Result<(), String> {
+		self.handle_notices(core, game_map)?;
+
+		//Camera controls should stay enabled even when it is not the player's turn,
+		//which is why this code block is not in player_turn.rs
+		if core.input.right_held && !self.banner.banner_visible {
+			let max_move = TILE_SIZE as i32;
+			core.cam.x -= (core.input.mouse_x_old - core.input.mouse_x).clamp(-max_move, max_move);
+			core.cam.y -= (core.input.mouse_y_old - core.input.mouse_y).clamp(-max_move, max_move);
+			clamp_camera(core);
+			core.wincan.set_viewport(core.cam);
+			core.set_animating(true);
+		}
+
+		let (i, j) = PixelCoordinates::matrix_indices_from_pixel(
+			core.input.mouse_x.try_into().unwrap(),
+			core.input.mouse_y.try_into().unwrap(),
+			(-core.cam.x).try_into().unwrap(),
+			(-core.cam.y).try_into().unwrap()
+		);
+
+		if self.hovered_tile.map(|(pos, _)| pos) != Some((j, i)) {
+			self.hovered_tile = Some(((j, i), Instant::now()));
+		}
+
+		//With fog of war, enemy & barbarian units are only shown on the tiles this client's units can see
+		if game_map.fog_of_war && self.visible_tiles.as_ref().map(|(revision, _)| *revision) != Some(game_map.revision) {
+			self.visible_tiles = Some((game_map.revision, game_map.visible_tiles(Team::Player)));
+		}
+		let visible = if game_map.fog_of_war { self.visible_tiles.as_ref().map(|(_, tiles)| tiles) } else { None };
+		let in_sight = |pos: &(u32, u32)| visible.as_ref().is_none_or(|tiles| tiles.contains(pos));
+
+		match game_map.player_units.get(&(j,i)) {
+			Some(active_unit) => {
+				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), active_unit);
+			},
+			_ => {
+				self.cursor.hide_cursor();
+			},
+		}
+		match game_map.enemy_units.get(&(j,i)) {
+			Some(active_unit) if in_sight(&(j,i)) => {
+				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), active_unit);
+			},
+			_ => {},
+		}
+		match game_map.barbarian_units.get(&(j,i)) {
+			Some(active_unit) if in_sight(&(j,i)) => {
+				self.cursor.set_cursor(&PixelCoordinates::from_matrix_indices(i, j), active_unit);
+			},
+			_ => {},
+		}
+
+		let texture_map = core.texture_map;
+
+		//Draw tiles & sprites
+		for x in 0..game_map.map_size.0 {
+			for y in 0..game_map.map_size.1 {
+				let map_tile = game_map.map_tiles.get(&(y as u32, x as u32));
+				let map_tile_size = match map_tile {
+					Some(Tile{contained_structure: Some(Structure::Camp), ..}) => TILE_SIZE * 2,
+					_ => TILE_SIZE,
+				};
+
+				let pixel_location = PixelCoordinates::from_matrix_indices(y as u32, x as u32);
+				let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, map_tile_size, map_tile_size);
+
+				//Draw map tile at this coordinate
+				if let Some(map_tile) = map_tile {
+					let texture = texture_map.get(&map_tile.texture_key).ok_or(format!("Could not find tile texture '{}'", map_tile.texture_key))?;
+					core.wincan.copy(texture, None, dest)?;
+				}
+
+				//Use default sprite size for all non-map sprites
+				let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, TILE_SIZE, TILE_SIZE);
+
+				//Draw player unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
+				if let Some(unit) = game_map.player_units.get_mut(&(x as u32, y as u32)) {
+					draw_unit(core, unit, &dest)?;
+				}
+
+				if !in_sight(&(x as u32, y as u32)) {
+					continue;
+				}
+
+				//Draw enemy unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
+				if let Some(enemy) = game_map.enemy_units.get_mut(&(x as u32, y as u32)) {
+					draw_unit(core, enemy, &dest)?;
+				}
+
+				//Draw barbarian unit at this coordinate (Don't forget row is y and col is x because 2d arrays)
+				if let Some(barbarian) = game_map.barbarian_units.get_mut(&(x as u32, y as u32)) {
+					draw_unit(core, barbarian, &dest)?;
+				}
+			}
+		}
+
+		//Darken the tiles that are out of sight
+		if let Some(visible) = visible {
+			let fogged: Vec<(u32, u32)> = game_map.map_tiles.values()
+				.map(|tile| (tile.y, tile.x))
+				.filter(|pos| !visible.contains(pos))
+				.collect();
+			draw_possible_moves(core, &fogged, Color::RGBA(0, 0, 0, 90))?;
+		}
+
+		// draw UI/banners
+		self.cursor.draw(core)?;
+		self.banner.draw(core)?;
+
+		// draw possible move grid
+		if game_map.player_units.contains_key(&(game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32)) {
+			match game_map.player_state.current_player_action {
+				PlayerAction::MovingUnit => {
+					draw_possible_moves(core, &self.possible_moves, Color::RGBA(0, 89, 178, 50))?;
+				},
+				PlayerAction::AttackingUnit => {
+					draw_possible_moves(core, &self.possible_attacks, Color::RGBA(178, 89, 0, 100))?;
+					draw_possible_moves(core, &self.actual_attacks, Color::RGBA(128, 0, 128, 100))?;
+				},
+				_ => {},
+			}
+		}
+
+		//Draw the damage indicators that appear above the units that have received damage
+		for damage_indicator in self.damage_indicators.iter_mut() {
+			damage_indicator.draw(core)?;
+		}
+		//Remove the damage indicators that have expired
+		self.damage_indicators.retain(|damage_indicator| {
+			damage_indicator.is_visible
+		});
+
+		if game_map.player_state.is_turn() && self.show_turn_controls {
+			//Draw the scroll sprite UI
+			let result = if let Some(ui) = self.unit_interface.as_mut() {
+				ui.draw(core, core.texture_creator).is_ok()
+			} else {
+				false
+			};
+
+			if !result {
+				self.unit_interface = None;
+			}
+
+			let result_2 = if let Some(ui) = self.choose_unit_interface.as_mut() {
+				ui.draw(core, core.texture_creator).is_ok()
+			} else {
+				false
+			};
+
+			if !result_2 {
+				self.choose_unit_interface = None;
+			}
+
+			//Draw the button for the player to end their turn, relative to the camera
+			self.end_turn_button.draw_relative(core)?;
+		}
+
+		if let Some((pos, since)) = self.hovered_tile {
+			let tile = game_map.map_tiles.get(&(pos.1, pos.0));
+			if let (Some(tile), true) = (tile, since.elapsed() >= TOOLTIP_DELAY && !self.banner.banner_visible) {
+				draw_tile_info(core, tile)?;
+			}
+		}
+
+		if let Some(text) = &self.status_text {
+			let (w, h) = core.bold_font.size_of(text).map_err(|_e| "Could not determine text size")?;
+			let texture = core.texture_creator.create_texture_from_surface(
+				core.bold_font.render(text)
+					.blended(Color::RGBA(255, 255, 255, 255))
+					.map_err(|e| e.to_string())?
+			).map_err(|e| e.to_string())?;
+			//Right aligned just left of the end turn button
+			let backdrop = Rect::new(CAM_W as i32 - 260 - w as i32 - 20 - core.cam.x, CAM_H as i32 - 90 - core.cam.y, w + 20, 50);
+			core.wincan.set_blend_mode(BlendMode::Blend);
+			core.wincan.set_draw_color(Color::RGBA(50, 50, 50, 100));
+			core.wincan.fill_rect(backdrop)?;
+			core.wincan.copy(&texture, None, Rect::new(backdrop.x() + 10, backdrop.y() + (50 - h as i32) / 2, w, h))?;
+		}
+
+		Ok(())
+	}
+}
+
+pub fn draw_unit(core: &mut SDLCore, unit: &mut Unit, dest: &Rect) -> Result<(), String> {
+	let src = if unit.has_attacked && unit.has_moved {
+		//Draw the darkened sprite
+		GRAY_SPRITE_SRC
+	}
+	else if unit.is_attacked {
+		unit.time_since_damaged += unit.last_damaged_drawn.elapsed().as_secs_f32();
+		unit.last_damaged_drawn = Instant::now();
+
+		//Remove red tint after 1 second
+		if unit.time_since_damaged >= 1.0 {
+			unit.is_attacked = false;
+			unit.time_since_damaged = 0.0;
+		}
+
+		//Draw the sprite that's tinted red
+		RED_SPRITE_SRC
+	}
+	else {
+		//Draw the default sprite
+		DEFAULT_SPRITE_SRC
+	};
+
+	let should_animate = dest.intersection(core.cam).is_some() && (unit.draw_x - dest.x as f64).abs() > 0.01 || (unit.draw_y - dest.y as f64).abs() > 0.01;
+	if should_animate && unit.draw_x > 0.0 && unit.draw_y > 0.0 {
+		unit.draw_x = (unit.draw_x + dest.x as f64) / 2.0;
+		unit.draw_y = (unit.draw_y + dest.y as f64) / 2.0;
+	} else {
+		unit.draw_x = dest.x as f64;
+		unit.draw_y = dest.y as f64;
+	}
+
+	let rect = Rect::new(unit.draw_x as i32, unit.draw_y as i32, dest.width(), dest.height());
+
+	//Draw the sprite
+	let texture = core.texture_map.get(&unit.texture_key).ok_or(format!("Could not find unit texture '{}'", unit.texture_key))?;
+	core.wincan.copy(texture, Rect::new(src.0, src.1, TILE_SIZE, TILE_SIZE), rect)?;
+
+	Ok(())
+}
+
+//Tooltip with the terrain of a tile, e.g. "tree: move cost 2, evasion 20%, defense 1"
+fn draw_tile_info(core: &mut SDLCore, tile: &Tile) -> Result<(), String> {
+	let text = if tile.is_traversable {
+		format!("{}: move cost {}, evasion {}%, defense {}", tile.name, tile.movement_cost, tile.evasion, tile.defense)
+	} else {
+		format!("{}: impassable", tile.name)
+	};
+
+	let (w, h) = core.regular_font.size_of(&text).map_err(|_e| "Could not determine text size")?;
+	let texture = core.texture_creator.create_texture_from_surface(
+		core.regular_font.render(&text)
+			.blended(Color::RGBA(255, 255, 255, 255))
+			.map_err(|e| e.to_string())?
+	).map_err(|e| e.to_string())?;
+
+	//Just below the tile (remember the tile is keyed (row, col), so its x is the row)
+	let pixel_location = PixelCoordinates::from_matrix_indices(tile.x, tile.y);
+	let backdrop = Rect::new(pixel_location.x as i32, pixel_location.y as i32 + TILE_SIZE as i32, w + 10, h + 6);
+	core.wincan.set_blend_mode(BlendMode::Blend);
+	core.wincan.set_draw_color(Color::RGBA(50, 50, 50, 180));
+	core.wincan.fill_rect(backdrop)?;
+	core.wincan.copy(&texture, None, Rect::new(backdrop.x() + 5, backdrop.y() + 3, w, h))?;
+	Ok(())
+}
+
+pub fn draw_possible_moves(core: &mut SDLCore, tiles: &[(u32, u32)], color:Color) -> Result< (), String> {
+	for (x,y) in tiles.iter() {
+		let pixel_location = PixelCoordinates::from_matrix_indices(*y, *x);
+		let dest = Rect::new(pixel_location.x as i32, pixel_location.y as i32, TILE_SIZE, TILE_SIZE);
+		core.wincan.set_blend_mode(BlendMode::Blend);
+		core.wincan.set_draw_color(color);
+		core.wincan.draw_rect(dest)?;
+		core.wincan.fill_rect(dest)?;
+	}
+	Ok(())
+}
+
+//Keeps the camera within the bounds of the map. Maps smaller than the window stay pinned to the top left corner
+pub fn clamp_camera(core: &mut SDLCore) {
+	let (window_w, window_h) = core.wincan.logical_size();
+	let min_x = (-core.cam.w + window_w as i32).min(0);
+	let min_y = (-core.cam.h + window_h as i32).min(0);
+	core.cam.x = core.cam.x.clamp(min_x, 0);
+	core.cam.y = core.cam.y.clamp(min_y, 0);
+}
+
+//Centers the camera on the given (x, y) tile, as far as the map bounds allow
+pub fn center_camera(core: &mut SDLCore, pos: (u32, u32)) {
+	let (window_w, window_h) = core.wincan.logical_size();
+	core.cam.x = (window_w / 2) as i32 - (pos.0 * TILE_SIZE) as i32;
+	core.cam.y = (window_h / 2) as i32 - (pos.1 * TILE_SIZE) as i32;
+	clamp_camera(core);
+}
+
+//Load map textures
+pub fn load_textures<'r>(textures: &mut HashMap<String, Texture<'r>>, texture_creator: &'r TextureCreator<WindowContext>) -> Result<(), String> {
+	//Tiles are keyed by their file name so map legends can refer to them
+	//Mountains
+	textures.insert("mountain_tile".to_string(), texture_creator.load_texture("images/tiles/mountain_tile.png")?);
+	textures.insert("mountain2_tile".to_string(), texture_creator.load_texture("images/tiles/mountain2_tile.png")?);
+	textures.insert("mountain_side_top".to_string(), texture_creator.load_texture("images/tiles/mountain_side_top.png")?);
+	textures.insert("mountain_side_vertical_right".to_string(), texture_creator.load_texture("images/tiles/mountain_side_vertical_right.png")?);
+	textures.insert("mountain_side_bottom".to_string(), texture_creator.load_texture("images/tiles/mountain_side_bottom.png")?);
+	textures.insert("mountain_side_vertical_left".to_string(), texture_creator.load_texture("images/tiles/mountain_side_vertical_left.png")?);
+	textures.insert("mountain_top_left".to_string(), texture_creator.load_texture("images/tiles/mountain_top_left.png")?);
+	textures.insert("mountain_top_right".to_string(), texture_creator.load_texture("images/tiles/mountain_top_right.png")?);
+	textures.insert("mountain_bottom_left".to_string(), texture_creator.load_texture("images/tiles/mountain_bottom_left.png")?);
+	textures.insert("mountain_bottom_right".to_string(), texture_creator.load_texture("images/tiles/mountain_bottom_right.png")?);
+	//Grass
+	textures.insert("grass_tile".to_string(), texture_creator.load_texture("images/tiles/grass_tile.png")?);
+	textures.insert("empty_tile".to_string(), texture_creator.load_texture("images/tiles/empty_tile.png")?);
+	//Rivers
+	textures.insert("river_tile".to_string(), texture_creator.load_texture("images/tiles/river_tile.png")?);
+	textures.insert("river_vertical".to_string(), texture_creator.load_texture("images/tiles/river_vertical.png")?);
+	textures.insert("river_end_vertical_top".to_string(), texture_creator.load_texture("images/tiles/river_end_vertical_top.png")?);
+	textures.insert("river_end_vertical_bottom".to_string(), texture_creator.load_texture("images/tiles/river_end_vertical_bottom.png")?);
+	textures.insert("river_end_right".to_string(), texture_creator.load_texture("images/tiles/river_end_right.png")?);
+	textures.insert("river_end_left".to_string(), texture_creator.load_texture("images/tiles/river_end_left.png")?);
+	//Bases
+	textures.insert("barbarian_camp".to_string(), texture_creator.load_texture("images/tiles/barbarian_camp.png")?);
+	textures.insert("player_camp".to_string(), texture_creator.load_texture("images/tiles/player_camp.png")?);
+	textures.insert("enemy_camp".to_string(), texture_creator.load_texture("images/tiles/enemy_camp.png")?);
+	textures.insert("barbarian_fort".to_string(), texture_creator.load_texture("images/tiles/barbarian_fort.png")?);
+	textures.insert("player_fort".to_string(), texture_creator.load_texture("images/tiles/player_fort.png")?);
+	textures.insert("enemy_fort".to_string(), texture_creator.load_texture("images/tiles/enemy_fort.png")?);
+	textures.insert("blue_castle".to_string(), texture_creator.load_texture("images/tiles/blue_castle.png")?);
+	textures.insert("red_castle".to_string(), texture_creator.load_texture("images/tiles/red_castle.png")?);
+	//Trees & forests
+	textures.insert("tree_tile".to_string(), texture_creator.load_texture("images/tiles/tree_tile.png")?);
+	textures.insert("forest_tile".to_string(), texture_creator.load_texture("images/tiles/forest_tile.png")?);
+	//Hills
+	textures.insert("hills_tile".to_string(), texture_creator.load_texture("images/tiles/hills_tile.png")?);
+	//Roads & bridges
+	textures.insert("road_tile".to_string(), texture_creator.load_texture("images/tiles/road_tile.png")?);
+	textures.insert("road_vertical".to_string(), texture_creator.load_texture("images/tiles/road_vertical.png")?);
+	textures.insert("bridge_tile".to_string(), texture_creator.load_texture("images/tiles/bridge_tile.png")?);
+	textures.insert("bridge_vertical".to_string(), texture_creator.load_texture("images/tiles/bridge_vertical.png")?);
+
+	//Load unit textures from the files the unit classes name, so they need to be loaded first
+	for class in unit_class::all() {
+		for sprite in class.sprites.iter() {
+			if !textures.contains_key(sprite) {
+				textures.insert(sprite.clone(), texture_creator.load_texture(sprite).map_err(|e| format!("Could not load sprite of class '{}': {}", class.name, e))?);
+			}
+		}
+	}
+
+	//Load UI textures
+	textures.insert("cursor".to_string(), texture_creator.load_texture("images/interface/cursor.png")?);
+	textures.insert("unit_interface".to_string(), texture_creator.load_texture("images/interface/unit_interface.png")?);
+
+	Ok(())
+}
@@ -1,13 +1,18 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use sdl2::image::LoadTexture;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::Texture;
 
-use crate::net::client::{Client, ClientBuffer};
+use crate::chat::ChatPanel;
+use crate::net::client::{Client, Session};
 
-use crate::game_map::GameMap;
+use crate::game_map::{self, GameMap, Notice};
+use crate::map_data::{self, MapData};
+use crate::map_view::{self, MapView};
+use crate::net::util::{EVENT_END_GAME, EVENT_END_TURN};
+use crate::replay::Replay;
 use crate::{Drawable, GameState};
 use crate::unit::Team;
 use crate::{SDLCore, TILE_SIZE};
@@ -15,7 +20,6 @@ use crate::{SDLCore, TILE_SIZE};
 pub struct MultiPlayer<'i, 'r> {
 	core: &'i mut SDLCore<'r>,
 	client: Client,
-	client_buffer: ClientBuffer,
 
 	bg_texture: Texture<'i>,
 	bg_interface: Texture<'i>,
@@ -28,7 +32,10 @@ pub struct MultiPlayer<'i, 'r> {
     room_text: Texture<'i>,
 	room_text_rect: Rect,
 
-	game_map: GameMap<'i>,
+	game_map: GameMap,
+	view: MapView<'i>,
+	chat: ChatPanel<'i>,
+	replay: Replay,
 }
 
 impl MultiPlayer<'_, '_> {
@@ -68,25 +75,24 @@ impl MultiPlayer<'_, '_> {
 		).map_err(|e| e.to_string())?;
 		let room_text_rect = centered_rect!(core, _, 350, room_w, room_h);
 
-		let game_map = GameMap::new(core, if client.is_host { Team::Player } else { Team::Enemy });
+		let seed = rand::random();
+		let game_map = rebuild_game_map(&client, seed)?;
+		let mut replay = Replay::new(&map_data::map_path(), seed, game_map.player_state.team)?;
+		replay.fog_of_war = true;
+		replay.record(&client.history);
+		let mut view = MapView::new(core)?;
+		view.show_turn_controls = !client.is_spectator;
+		let chat = ChatPanel::new(core)?;
 
 		//Set camera size based on map size
 		core.cam.w = (game_map.map_size.0 as u32 * TILE_SIZE) as i32;
 		core.cam.h = (game_map.map_size.1 as u32 * TILE_SIZE) as i32;
-		if client.is_host {
-			//Start camera in lower left corner, to start with the player castle in view
-			core.cam.x = 0;
-			core.cam.y = -core.cam.h + core.wincan.window().size().1 as i32;
-		} else {
-			//Start camera in lower left corner, to start with the player castle in view
-			core.cam.x = -core.cam.w + core.wincan.window().size().0 as i32;
-			core.cam.y = 0;
-		}
+		//Start with the player's own castle in view
+		map_view::center_camera(core, game_map.objectives.p1_castle);
 
 		Ok(MultiPlayer {
 			core,
 			client,
-			client_buffer: ClientBuffer::new(),
 
 			bg_texture,
 			bg_interface,
@@ -101,6 +107,9 @@ impl MultiPlayer<'_, '_> {
 			room_text_rect,
 
 			game_map,
+			view,
+			chat,
+			replay,
 		})
 	}
 
@@ -112,17 +121,83 @@ impl Drawable for MultiPlayer<'_, '_> {
 		for event in self.core.event_pump.poll_iter() {
 			match event {
 				sdl2::event::Event::Quit{..} | sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::Escape), ..} => {
+					self.client.leave();
 					return Err("Quit keycode".to_string());
 				},
-				_ => {},
+				// give up on waiting for someone to join
+				sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::Backspace), ..} if !self.client.is_joined => {
+					self.client.leave();
+					return Ok(GameState::MainMenu);
+				},
+				// spectators can switch to watching the other player's side
+				sdl2::event::Event::KeyDown{keycode: Some(sdl2::keyboard::Keycode::Tab), ..} if self.client.is_spectator => {
+					match Client::spectate(self.client.code, !self.client.is_host) {
+						Ok(client) => {
+							self.game_map = rebuild_game_map(&client, self.replay.seed)?;
+							self.client = client;
+						},
+						Err(e) => println!("Could not switch sides: {}", e),
+					}
+				},
+				_ => if !self.client.is_spectator {
+					if let Some(message) = self.chat.handle_event(&event) {
+						self.client.send_chat(&message)?;
+						self.chat.push(format!("You: {}", message));
+					}
+				},
 			}
 		}
 
-		// receive a new event from the server
+		while let Some((from_host, message)) = self.client.poll_chat() {
+			let name = match (self.client.is_spectator, from_host) {
+				(false, _) => "Opponent",
+				(true, true) => "Host",
+				(true, false) => "Guest",
+			};
+			self.chat.push(format!("{}: {}", name, message));
+		}
+
+		if let Err(e) = self.client.heartbeat() {
+			println!("Error sending heartbeat: {}", e);
+		}
+
+		// receive a new event from the server (events wait in the client until any animation is done)
 		if !self.core.is_animating {
-			match self.client_buffer.poll(&mut self.client) {
-				Ok(Some(event)) => self.game_map.event_list.push(event),
-				Err(e) => println!("Error polling server: {}", e),
+			match self.client.poll() {
+				Ok(Some(event)) => {
+					// the server ends turns that run out of time, possibly in the middle of something
+					if event.action == EVENT_END_TURN && self.game_map.player_state.is_turn() {
+						crate::player_turn::clear_turn_state(&mut self.game_map, &mut self.view);
+						self.view.choose_unit_interface = None;
+					}
+					self.game_map.event_list.push(event);
+				},
+				Err(e) if self.client.is_closed() => {
+					println!("{}", e);
+					if !self.client.is_spectator {
+						Session::clear();
+					}
+					return Ok(GameState::MainMenu);
+				},
+				Err(e) => {
+					println!("{}, trying to rejoin room {}", e, self.client.code);
+					let client = if self.client.is_spectator {
+						Client::spectate(self.client.code, self.client.is_host)
+					} else {
+						Client::rejoin(&self.client.session)
+					};
+					match client {
+						Ok(client) => {
+							// start over from the server's history, in case anything was lost along with the connection
+							self.game_map = rebuild_game_map(&client, self.replay.seed)?;
+							self.client = client;
+						},
+						Err(e) => {
+							println!("Could not rejoin: {}", e);
+							return Ok(GameState::MainMenu);
+						},
+					}
+				},
 				_ => {},
 			}
 		} else {
@@ -150,38 +225,107 @@ impl Drawable for MultiPlayer<'_, '_> {
 			return Ok(GameState::MultiPlayer);
 		}
 
-		//If no one has won so far...
-		if self.game_map.winning_team.is_none() {
+		// in a timed room, show how long the current player has left; once it's run out, wait for the server to end the turn
+		let current_turn = self.game_map.player_state.current_turn;
+		let time_left = self.client.time_left(current_turn == Team::Player).filter(|_| current_turn != Team::Barbarians && self.game_map.winning_team.is_none());
+		self.view.status_text = time_left.map(|(turn_left, match_left)| match match_left {
+			Some(match_left) => format!("{} ({} left)", format_time(turn_left), format_time(match_left)),
+			None => format_time(turn_left),
+		});
+		let out_of_time = time_left.is_some_and(|(turn_left, _)| turn_left == Duration::from_secs(0));
+
+		//If no one has won so far (and this client is playing)...
+		if self.game_map.winning_team.is_none() && !self.client.is_spectator {
 			//Handle the current team's move
 			// handle the current player's turn
-			if self.game_map.player_state.is_turn() {
-				crate::player_turn::handle_player_turn(&self.core, &mut self.game_map)?;
-			}
-
-			// handle the barbarians' turn (only on the host client)
-			if self.client.is_host && self.game_map.player_state.current_turn == Team::Barbarians {
-				crate::barbarian_turn::handle_barbarian_turn(&self.core, &mut self.game_map)?;
+			// (the barbarians' turn is played by the server, which is the only one that can see all of them)
+			if self.game_map.player_state.is_turn() && !out_of_time {
+				crate::player_turn::handle_player_turn(self.core, &mut self.game_map, &mut self.view)?;
 			}
 		}
 
 		//Record user inputs
 		self.core.input.update(&self.core.event_pump);
 
-		crate::game_map::apply_events(&self.core, &mut self.game_map)?.iter()
-			.filter(|e| e.from_self).copied()
-			.for_each(|e| self.client_buffer.send(e));
+		//While the chat is open it takes the keyboard and mouse, so typing doesn't also play the game
+		if self.chat.is_open {
+			self.core.input.keystate.clear();
+			if self.core.input.left_clicked {
+				self.core.input.left_clicked = false;
+				if let Some(message) = self.chat.handle_click(self.core) {
+					self.client.send_chat(&message)?;
+					self.chat.push(format!("You: {}", message));
+				}
+			}
+		}
+
+		let events = crate::game_map::apply_events(&mut self.game_map)?;
+		if !self.client.is_spectator {
+			for event in events.iter().filter(|e| e.from_self) {
+				self.client.send(*event)?;
+			}
+			// attacks are only applied once the server sends them back with its damage roll
+			for request in self.game_map.server_requests.drain(..) {
+				self.client.send(request)?;
+			}
+		}
+
+		self.replay.record(&events);
+		if events.iter().any(|e| e.action == EVENT_END_TURN || e.action == EVENT_END_GAME) {
+			if let Err(e) = self.replay.write() {
+				println!("Could not write replay: {}", e);
+			}
+		}
 
 		// render the current game board
-		self.game_map.draw(self.core)?;
+		self.view.draw(self.core, &mut self.game_map)?;
+		self.chat.draw(self.core)?;
 
 		self.core.wincan.set_viewport(self.core.cam);
 		self.core.wincan.present();
 
-		if !self.game_map.winning_team.is_none() && !self.game_map.banner.banner_visible && self.core.input.left_clicked {
+		if self.game_map.winning_team.is_some() && !self.view.banner.banner_visible && self.core.input.left_clicked {
+			self.client.leave();
 			Ok(GameState::MainMenu)
 		} else {
 			Ok(GameState::MultiPlayer)
 		}
 	}
 
-}
\ No newline at end of file
+}
+
+// e.g. 1:05
+fn format_time(time: Duration) -> String {
+	let secs = (time.as_millis() as u64).div_ceil(1000); // round up, so the clock only shows 0:00 once time is up
+	format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// Sets up the game for a room, replaying its history when rejoining or spectating a game in progress
+fn rebuild_game_map(client: &Client, seed: u64) -> Result<GameMap, String> {
+	let map_data = MapData::load(&map_data::map_path())?;
+	let team = if client.is_host { Team::Player } else { Team::Enemy };
+	let mut game_map = GameMap::new(&map_data, team, seed)?;
+	// spectators are sent attacks already resolved by the server, including the watched player's own
+	game_map.server_authoritative = !client.is_spectator;
+	// the server only tells the client about the units it can see, so it's also the one to decide who has won
+	game_map.server_decides_winner = true;
+	game_map.hide_unseen_units();
+
+	let history = &client.history;
+	for event in history {
+		game_map::apply_event(&mut game_map, *event)?;
+		game_map::remove_dead_units(&mut game_map);
+	}
+
+	if !history.is_empty() {
+		// only the current turn's banner is still relevant, and a replayed END_GAME must not be sent again
+		game_map.notices.clear();
+		game_map.event_list.clear();
+		game_map.event_list_index = 0;
+		match game_map.winning_team {
+			Some(winner) => game_map.notices.push(Notice::Winner(winner)),
+			None => game_map.notices.push(Notice::TurnStart(game_map.player_state.current_turn.as_client(&game_map.player_state))),
+		}
+	}
+	Ok(game_map)
+}
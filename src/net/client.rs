@@ -1,156 +1,376 @@
-use std::io::prelude::*;
-use std::net::{TcpStream};
-use std::time::{Instant, Duration};
+use std::fs;
+use std::net::{TcpStream, Shutdown};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::net::SERVER_ADDR;
+use crate::net;
 use crate::net::util::*;
+use crate::save::SAVE_DIR;
 
-static mut CODE: Option<u32> = None;
+static CODE: Mutex<Option<u32>> = Mutex::new(None);
+static REJOIN: AtomicBool = AtomicBool::new(false);
+static SPECTATE: AtomicBool = AtomicBool::new(false);
+static PRIVATE: AtomicBool = AtomicBool::new(false);
+static TIME_CONTROL: Mutex<TimeControl> = Mutex::new(TimeControl { turn_secs: 0, match_secs: 0 });
+static PLAYER_NAME: Mutex<Option<String>> = Mutex::new(None);
 
 // sets a static CODE variable representing the multiplayer room to join
 //   if Some(code) -> joins a room with code
 //   if None       -> creates a new room
 pub fn set_code(code: Option<u32>) {
-	unsafe {
-		CODE = code;
-	}
+	*CODE.lock().unwrap() = code;
+	REJOIN.store(false, Ordering::SeqCst);
+	SPECTATE.store(false, Ordering::SeqCst);
 }
 
-pub struct ClientBuffer {
-	send_buffer: Vec<Event>,
-	last_poll: Instant,
+// makes the next client watch the room with code instead of playing in it
+pub fn set_spectate(code: u32) {
+	*CODE.lock().unwrap() = Some(code);
+	REJOIN.store(false, Ordering::SeqCst);
+	SPECTATE.store(true, Ordering::SeqCst);
 }
 
-impl ClientBuffer {
-	pub fn new() -> ClientBuffer {
-		ClientBuffer {
-			send_buffer: Vec::new(),
-			last_poll: Instant::now(),
-		}
+// makes rooms created from now on private (left out of the lobby) or public
+pub fn set_private(private: bool) {
+	PRIVATE.store(private, Ordering::SeqCst);
+}
+
+// sets the time controls of rooms created from now on
+pub fn set_time_control(time_control: TimeControl) {
+	*TIME_CONTROL.lock().unwrap() = time_control;
+}
+
+// sets the name shown in the lobby for rooms this player hosts
+pub fn set_player_name(name: String) {
+	*PLAYER_NAME.lock().unwrap() = Some(name);
+}
+
+// the name set with --name, or else the name of the user running the game
+pub fn player_name() -> String {
+	match PLAYER_NAME.lock().unwrap().clone() {
+		Some(name) => name,
+		None => env::var("USER").or_else(|_e| env::var("USERNAME")).unwrap_or_else(|_e| String::from("Player")),
 	}
+}
 
-	pub fn send(&mut self, event: Event) {
-		self.send_buffer.push(event);
+// Asks the server for the rooms shown in the lobby
+pub fn list_rooms() -> Result<Vec<RoomInfo>, String> {
+	let mut stream = Client::connect()?;
+	write_frame(&mut stream, MSG_LIST, &[])?;
+	match read_frame(&mut stream)? {
+		(MSG_ROOMS, payload) => RoomInfo::decode_list(&payload),
+		(MSG_ERROR, payload) => Err(String::from_utf8_lossy(&payload).to_string()),
+		_ => Err(String::from("Invalid response from server")),
 	}
+}
 
-	pub fn poll(&mut self, client: &mut Client) -> Result<Option<Event>, String> {
-		// if there are any new events, send one instead of polling
-		if let Some(event) = self.send_buffer.first() {
-			client.send(event.clone())?;
-			self.send_buffer.remove(0);
-			return Ok(None);
-		}
+// makes the next client rejoin the room of the last Session instead of creating/joining one
+pub fn set_rejoin() {
+	REJOIN.store(true, Ordering::SeqCst);
+}
 
-		// don't send polls if <1s since last (empty) call
-		if Instant::now().duration_since(self.last_poll).as_secs() < 1 {
-			return Ok(None);
-		}
+// The tokens needed to get back into a room, kept on disk so they survive the game crashing
+#[derive(Copy, Clone)]
+pub struct Session {
+	pub code: u32,
+	pub room_token: u32,
+	pub user_token: u32,
+}
 
-		let ret = client.poll()?;
+impl Session {
+	fn path() -> String {
+		format!("{}/multiplayer.session", SAVE_DIR)
+	}
 
-		// only update last_poll once an empty poll is received
-		if let Some(e) = ret {
-			if e.action == EVENT_NONE {
-				self.last_poll = Instant::now();
-			}
+	pub fn load() -> Option<Session> {
+		let contents = fs::read_to_string(Session::path()).ok()?;
+		let values: Vec<u32> = contents.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+		match values[..] {
+			[code, room_token, user_token] => Some(Session { code, room_token, user_token }),
+			_ => None,
 		}
+	}
 
-		Ok(ret)
+	fn save(&self) -> Result<(), String> {
+		fs::create_dir_all(SAVE_DIR).map_err(|e| e.to_string())?;
+		fs::write(Session::path(), format!("{} {} {}\n", self.code, self.room_token, self.user_token)).map_err(|e| e.to_string())
+	}
+
+	// forgets the session once its game is over
+	pub fn clear() {
+		let _ = fs::remove_file(Session::path());
 	}
 }
 
 pub struct Client {
 	pub code: u32,
-	room_token: u32,
-	user_token: u32,
 	pub is_host: bool,
 	pub is_joined: bool,
-	addr: String,
+	// spectators only watch: is_host says which player's side they're watching from
+	pub is_spectator: bool,
+	pub session: Session,
+	// on rejoin: every event of the game so far, with from_self set for the ones this client sent
+	pub history: Vec<Event>,
+
+	stream: TcpStream,
+	// events and chat messages (sent by the host, text) pushed by the server, read off the connection by a background thread
+	events: Receiver<Event>,
+	chats: Receiver<(bool, String)>,
+	// why the server closed the room, once it has
+	closed: Arc<Mutex<Option<String>>>,
+	// the last clock sent by the server in a timed room, and when it arrived
+	clock: Arc<Mutex<Option<(Instant, Clock)>>>,
+	last_sent: Instant,
 }
 
 impl Client {
 	pub fn new() -> Result<Client, String> {
-		let addr = unsafe {
-			String::from(SERVER_ADDR)
-		};
+		if REJOIN.load(Ordering::SeqCst) {
+			let session = Session::load().ok_or("No multiplayer game to rejoin")?;
+			return Client::rejoin(&session);
+		}
 
-		let code = match unsafe { CODE } {
-			Some(c) => c,
-			_ => 0,
-		};
+		let code = CODE.lock().unwrap().unwrap_or(0);
+		if SPECTATE.load(Ordering::SeqCst) {
+			return Client::spectate(code, true);
+		}
 
-		// construct the client and either create/join the room
-		let mut client = Client { code, room_token: 0, user_token: 0, is_host: code == 0, is_joined: false, addr };
-		let mut stream = client.connect(if client.is_host { MSG_CREATE } else { MSG_JOIN })?;
+		// version handshake, then either create/join the room
+		let mut stream = Client::connect()?;
+		if code == 0 {
+			let mut request = vec![PRIVATE.load(Ordering::SeqCst) as u8];
+			request.extend_from_slice(&TIME_CONTROL.lock().unwrap().to_bytes());
+			request.extend(player_name().chars().take(MAX_NAME_LENGTH).collect::<String>().as_bytes());
+			write_frame(&mut stream, MSG_CREATE, &request)?;
+		} else {
+			write_frame(&mut stream, MSG_JOIN, &to_u32_bytes(code))?;
+		}
 
-		let mut buffer = [0; 12];
-		stream.read(&mut buffer).map_err(|_e| "Could not read connection response")?;
+		let client = Client::welcome(stream, false)?;
+		// check if the returned room code matches the intended join code (i.e. whether the room was actually joined)
+		if code != 0 && code != client.code {
+			return Err(String::from("Invalid room code returned"))
+		}
+		Ok(client)
+	}
 
-		// check if the returned room code matches the intended join code in send_bytes (i.e. whether the room was actually joined)
-		let user_token = from_u32_bytes(&buffer[0..4]);
-		let new_code = from_u32_bytes(&buffer[4..8]);
-		let new_token = from_u32_bytes(&buffer[8..12]);
+	// Gets back into the room of a dropped game; the client's history holds the game so far
+	pub fn rejoin(session: &Session) -> Result<Client, String> {
+		let mut stream = Client::connect()?;
+		let mut request = [0; 12];
+		set_range!(request[0..4] = to_u32_bytes(session.code));
+		set_range!(request[4..8] = to_u32_bytes(session.room_token));
+		set_range!(request[8..12] = to_u32_bytes(session.user_token));
+		write_frame(&mut stream, MSG_REJOIN, &request)?;
 
-		if !client.is_host && code != new_code {
+		let client = Client::welcome(stream, false)?;
+		if client.code != session.code {
 			return Err(String::from("Invalid room code returned"))
-		} else {
-			println!("Entered a room with code {:?}", new_code);
-			client.code = new_code;
-			client.room_token = new_token;
-			client.user_token = user_token;
 		}
-
-		// successfully joined a room & constructed a client
 		Ok(client)
 	}
 
-	// creates a new TcpStream connection & sends/validates the request header
-	fn connect(&self, action: u8) -> Result<TcpStream, String> {
-		let mut stream = TcpStream::connect(&self.addr).map_err(|_e| "Could not initialize TCP stream")?;
-		stream.set_read_timeout(Some(Duration::from_secs(1))).map_err(|_e| "Could set read timeout")?;
-		stream.set_write_timeout(Some(Duration::from_secs(1))).map_err(|_e| "Could set write timeout")?;
+	// Watches a room from the host's or the peer's side; the client's history holds the game so far
+	pub fn spectate(code: u32, watch_host: bool) -> Result<Client, String> {
+		let mut stream = Client::connect()?;
+		let mut request = [0; 5];
+		set_range!(request[0..4] = to_u32_bytes(code));
+		request[4] = watch_host as u8;
+		write_frame(&mut stream, MSG_SPECTATE, &request)?;
+
+		let client = Client::welcome(stream, true)?;
+		if client.code != code {
+			return Err(String::from("Invalid room code returned"))
+		}
+		Ok(client)
+	}
 
-		let mut send_bytes = [0; 13];
-		send_bytes[0] = action;
-		set_range!(send_bytes[1..5] = to_u32_bytes(self.user_token));
-		set_range!(send_bytes[5..9] = to_u32_bytes(self.code));
-		set_range!(send_bytes[9..13] = to_u32_bytes(self.room_token));
+	fn connect() -> Result<TcpStream, String> {
+		let addr = net::server_addr();
 
-		stream.write_all(&send_bytes).map_err(|_e| "Could not send connection info")?;
+		let mut stream = TcpStream::connect(&addr).map_err(|_e| "Could not initialize TCP stream")?;
+		stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT)).map_err(|_e| "Could not set read timeout")?;
+		stream.set_write_timeout(Some(HEARTBEAT_TIMEOUT)).map_err(|_e| "Could not set write timeout")?;
+		stream.set_nodelay(true).map_err(|_e| "Could not disable Nagle's algorithm")?;
 
+		write_frame(&mut stream, MSG_HELLO, &to_u32_bytes(PROTOCOL_VERSION))?;
 		Ok(stream)
 	}
 
-	pub fn send(&self, event: Event) -> Result<(), String> {
-		let mut stream = self.connect(MSG_EVENT)?;
+	// Reads the server's reply to a create/join/rejoin request (any history comes first), then starts listening for events
+	fn welcome(mut stream: TcpStream, is_spectator: bool) -> Result<Client, String> {
+		let mut history = Vec::new();
+		let (session, is_host) = loop {
+			match read_frame(&mut stream)? {
+				(MSG_HISTORY, payload) => history.push(history_event(&payload)?),
+				(MSG_WELCOME, payload) if payload.len() == 13 => break (Session {
+					user_token: from_u32_bytes(&payload[0..4]),
+					code: from_u32_bytes(&payload[4..8]),
+					room_token: from_u32_bytes(&payload[8..12]),
+				}, payload[12] == 1),
+				(MSG_ERROR, payload) => return Err(String::from_utf8_lossy(&payload).to_string()),
+				_ => return Err(String::from("Invalid response from server")),
+			}
+		};
+		println!("Entered a room with code {:?}", session.code);
+
+		// keep the tokens around in case the game has to be rejoined later
+		if !is_spectator {
+			if let Err(e) = session.save() {
+				println!("Could not save multiplayer session: {}", e);
+			}
+		}
+
+		// read everything the server pushes on a separate thread, so the game never blocks on the network
+		let (sender, events) = mpsc::channel();
+		let (chat_sender, chats) = mpsc::channel();
+		let closed = Arc::new(Mutex::new(None));
+		let closed_reason = Arc::clone(&closed);
+		let clock = Arc::new(Mutex::new(None));
+		let latest_clock = Arc::clone(&clock);
+		let mut reader = stream.try_clone().map_err(|_e| "Could not clone stream")?;
+		thread::spawn(move || {
+			loop {
+				match read_frame(&mut reader) {
+					Ok((MSG_EVENT, payload)) => {
+						match Event::from_payload(&payload) {
+							Ok(event) => if sender.send(event).is_err() { break },
+							Err(e) => println!("Server sent an invalid event: {}", e),
+						}
+					},
+					// spectators are sent every event of the game this way
+					Ok((MSG_HISTORY, payload)) => {
+						match history_event(&payload) {
+							Ok(event) => if sender.send(event).is_err() { break },
+							Err(e) => println!("Server sent an invalid event: {}", e),
+						}
+					},
+					Ok((MSG_CHAT, payload)) if !payload.is_empty() => {
+						let _ = chat_sender.send((payload[0] == 1, String::from_utf8_lossy(&payload[1..]).to_string()));
+					},
+					Ok((MSG_CLOCK, payload)) => {
+						match Clock::from_payload(&payload) {
+							Ok(clock) => *latest_clock.lock().unwrap() = Some((Instant::now(), clock)),
+							Err(e) => println!("Server sent an invalid clock: {}", e),
+						}
+					},
+					Ok((MSG_ERROR, payload)) => println!("Server error: {}", String::from_utf8_lossy(&payload)),
+					Ok((MSG_LEAVE, payload)) => {
+						let reason = String::from_utf8_lossy(&payload).to_string();
+						println!("Room closed: {}", reason);
+						*closed_reason.lock().unwrap() = Some(reason);
+						break;
+					},
+					Ok(_) => {}, // heartbeat
+					Err(e) => {
+						println!("Disconnected from server: {}", e);
+						break;
+					},
+				}
+			}
+		});
 
-		let buffer = event.to_bytes();
-		stream.write_all(&buffer).map_err(|_e| "Could not write event buffer")?;
+		// successfully joined a room & constructed a client
+		Ok(Client {
+			code: session.code,
+			is_host,
+			is_joined: false,
+			is_spectator,
+			session,
+			history,
+			stream,
+			events,
+			chats,
+			closed,
+			clock,
+			last_sent: Instant::now(),
+		})
+	}
 
-		let mut response_buffer = [0; 1];
-		stream.read(&mut response_buffer).map_err(|_e| "Could not read event response")?;
+	pub fn send(&mut self, event: Event) -> Result<(), String> {
+		write_frame(&mut self.stream, MSG_EVENT, &event.to_bytes())?;
+		self.last_sent = Instant::now();
+		Ok(())
+	}
 
-		// check that event was pushed successfully
-		if response_buffer[0] == 1 {
-			Ok(())
-		} else {
-			Err(String::from("Event did not receive a successful response from the server"))
+	pub fn send_chat(&mut self, text: &str) -> Result<(), String> {
+		write_frame(&mut self.stream, MSG_CHAT, text.as_bytes())?;
+		self.last_sent = Instant::now();
+		Ok(())
+	}
+
+	// returns the next chat message pushed by the server as (sent by the host, text), if there is one
+	pub fn poll_chat(&mut self) -> Option<(bool, String)> {
+		self.chats.try_recv().ok()
+	}
+
+	// leaves the room for good, which also ends the game for the other player
+	pub fn leave(&mut self) {
+		if let Err(e) = write_frame(&mut self.stream, MSG_LEAVE, &[]) {
+			println!("Could not leave room: {}", e);
+		}
+		if !self.is_spectator {
+			Session::clear();
 		}
 	}
 
-	pub fn poll(&mut self) -> Result<Option<Event>, String> {
-		let mut stream = self.connect(MSG_POLL)?;
+	// whether the server has closed the room, in which case there is nothing to rejoin
+	pub fn is_closed(&self) -> bool {
+		self.closed.lock().unwrap().is_some()
+	}
 
-		let mut buffer = [0; 19];
-		stream.read(&mut buffer).map_err(|_e| "Could not read poll response")?;
+	// the time left in the current turn, and on the current player's match clock if the room has one (None if turns aren't timed)
+	pub fn time_left(&self, host_turn: bool) -> Option<(Duration, Option<Duration>)> {
+		let (received, clock) = (*self.clock.lock().unwrap())?;
+		let elapsed = received.elapsed();
+		let match_ms = if host_turn { clock.host_ms } else { clock.peer_ms };
+		let match_left = if match_ms == NO_TIME_LIMIT { None } else { Some(Duration::from_millis(match_ms as u64).saturating_sub(elapsed)) };
+		Some((Duration::from_millis(clock.turn_ms as u64).saturating_sub(elapsed), match_left))
+	}
 
-		match Event::from_bytes(&buffer) {
-			Event{action: EVENT_NONE, ..} => Ok(None),
-			Event{action: EVENT_JOIN, ..} => {
+	// lets the server know the client is still there if nothing else has been sent in a while
+	pub fn heartbeat(&mut self) -> Result<(), String> {
+		if self.last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+			write_frame(&mut self.stream, MSG_HEARTBEAT, &[])?;
+			self.last_sent = Instant::now();
+		}
+		Ok(())
+	}
+
+	// returns the next event pushed by the server, if there is one
+	pub fn poll(&mut self) -> Result<Option<Event>, String> {
+		match self.events.try_recv() {
+			Ok(Event{action: EVENT_JOIN, ..}) => {
 				self.is_joined = true;
 				Ok(None)
 			},
-			e => Ok(Some(e))
+			Ok(e) => Ok(Some(e)),
+			Err(TryRecvError::Empty) => Ok(None),
+			Err(TryRecvError::Disconnected) => match self.closed.lock().unwrap().clone() {
+				Some(reason) => Err(reason),
+				None => Err(String::from("Lost connection to the server")),
+			},
 		}
 	}
-}
\ No newline at end of file
+}
+
+// Reads a MSG_HISTORY payload: whether this client (or the watched player) sent the event + the event
+fn history_event(payload: &[u8]) -> Result<Event, String> {
+	if payload.len() != 20 {
+		return Err(format!("Invalid history event: expected 20 bytes but got {}", payload.len()));
+	}
+	let mut event = Event::from_payload(&payload[1..])?;
+	event.from_self = payload[0] == 1;
+	Ok(event)
+}
+
+impl Drop for Client {
+	fn drop(&mut self) {
+		// also stops the reader thread
+		let _ = self.stream.shutdown(Shutdown::Both);
+	}
+}
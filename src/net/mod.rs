@@ -1,4 +1,15 @@
-pub static mut SERVER_ADDR: &str = "atrocious.horrific.dev:26000";
+use std::sync::Mutex;
+
+static SERVER_ADDR: Mutex<String> = Mutex::new(String::new());
+
+// sets the host:port of the multiplayer server (from the config), which the server also takes its port from
+pub fn set_server_addr(addr: String) {
+	*SERVER_ADDR.lock().unwrap() = addr;
+}
+
+pub fn server_addr() -> String {
+	SERVER_ADDR.lock().unwrap().clone()
+}
 
 #[macro_use] pub mod util;
 pub mod client;
@@ -1,235 +1,1527 @@
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream, IpAddr};
-use std::collections::HashMap;
-use std::time::{Instant, Duration};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, IpAddr, Shutdown};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rand::Rng;
 use rand::prelude::*;
 
-use crate::net::SERVER_ADDR;
+use crate::barbarian_turn;
+use crate::game_map::{self, GameMap};
+use crate::map_data::{self, MapData};
+use crate::net;
 use crate::net::util::*;
+use crate::unit::Team;
+
+const CONVERT_CHANCE: u32 = 45; // % chance that killing a barbarian lets the attacker convert it
+
+const ROOM_CODES: u32 = 9999; // room codes are 4 digits, 0001 to 9999
+pub const DEFAULT_MAX_ROOMS: usize = 1000;
+const MAX_ROOMS_PER_IP: usize = 3;
+
+// the sweeper closes rooms nobody is connected to after IDLE_ROOM_TIMEOUT, and finished games after FINISHED_ROOM_TIMEOUT
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const IDLE_ROOM_TIMEOUT: Duration = Duration::from_secs(60 * 30);
+const FINISHED_ROOM_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+
+// timed turns are checked every CLOCK_INTERVAL, and only ended CLOCK_GRACE after the player's clock shows zero,
+// so a move made in the last moment isn't lost to network delay
+const CLOCK_INTERVAL: Duration = Duration::from_millis(250);
+const CLOCK_GRACE: Duration = Duration::from_secs(2);
+
+static MAX_ROOMS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_ROOMS);
+static ADMIN_ADDR: Mutex<String> = Mutex::new(String::new());
+
+// sets how many rooms the server allows at once (at most one per room code)
+pub fn set_max_rooms(max_rooms: usize) {
+	MAX_ROOMS.store(max_rooms.min(ROOM_CODES as usize), Ordering::SeqCst);
+}
+
+// sets the address of the admin port, which should only be reachable by the operator
+pub fn set_admin_addr(addr: String) {
+	*ADMIN_ADDR.lock().unwrap() = addr;
+}
+
+// frames waiting to be written to a connection by its writer thread
+type Outbox = Sender<(u8, Vec<u8>)>;
+
+// who is on the other end of a connection
+#[derive(Copy, Clone)]
+enum Seat {
+	Host,
+	Peer,
+	Spectator(bool), // watching from the host's side or not
+}
 
 struct Server {
 	addr: String,
-	rand: ThreadRng,
 
-	// rooms: map code -> Room info
-	rooms: HashMap<u32, Room>,
+	// every room plays on this map, the server keeps its own copy of each game to check moves against
+	map_data: Arc<MapData>,
+
+	// rooms: map code -> Room info, shared between the connection threads
+	rooms: Arc<Mutex<HashMap<u32, Room>>>,
+	max_rooms: usize,
+
+	// for the admin port
+	stats: Arc<Stats>,
+	bans: Arc<Mutex<HashSet<IpAddr>>>,
+}
+
+// counters reported on the admin port
+struct Stats {
+	started: Instant,
+	connections: AtomicU64,
+	events_relayed: AtomicU64,
+	errors: AtomicU64, // rejected events and failed connections
+}
+
+impl Stats {
+	fn new() -> Stats {
+		Stats {
+			started: Instant::now(),
+			connections: AtomicU64::new(0),
+			events_relayed: AtomicU64::new(0),
+			errors: AtomicU64::new(0),
+		}
+	}
 }
 
 impl Server {
-	fn new(addr: &str) -> Server {
+	fn new(addr: &str, map_data: MapData, max_rooms: usize) -> Server {
 		Server {
 			addr: String::from(addr),
-			rand: rand::thread_rng(),
-			rooms: HashMap::new(),
+			map_data: Arc::new(map_data),
+			rooms: Arc::new(Mutex::new(HashMap::new())),
+			max_rooms,
+			stats: Arc::new(Stats::new()),
+			bans: Arc::new(Mutex::new(HashSet::new())),
 		}
 	}
 
-	fn listen(&mut self) {
+	fn listen_admin(&self, addr: &str) {
+		let listener = match TcpListener::bind(addr) {
+			Ok(listener) => listener,
+			Err(e) => {
+				println!("Could not open admin port at {}: {}", addr, e);
+				return;
+			},
+		};
+		println!("Admin port at {}", addr);
+
+		let (rooms, stats, bans) = (Arc::clone(&self.rooms), Arc::clone(&self.stats), Arc::clone(&self.bans));
+		thread::spawn(move || {
+			for stream in listener.incoming().filter_map(|s| s.ok()) {
+				let (rooms, stats, bans) = (Arc::clone(&rooms), Arc::clone(&stats), Arc::clone(&bans));
+				thread::spawn(move || {
+					if let Err(e) = handle_admin(stream, rooms, stats, bans) {
+						println!("Admin connection error: {}", e);
+					}
+				});
+			}
+		});
+	}
+
+	fn listen(&self) {
 		let listener = TcpListener::bind(String::from(&self.addr)).unwrap();
+
+		let rooms = Arc::clone(&self.rooms);
+		thread::spawn(move || sweep_rooms(rooms));
+		let rooms = Arc::clone(&self.rooms);
+		thread::spawn(move || run_clocks(rooms));
+
 		let incoming = listener.incoming()
 			.filter_map(|s| match s {
 				Ok(stream) => Some(stream),
 				Err(e) => {
-					println!("Incoming stream error: {}", e.to_string());
+					println!("Incoming stream error: {}", e);
 					None
 				}
 			});
 
-		// listen for any new connections
-		for mut stream in incoming {
-			match self.handle_request(&mut stream) {
-				Err(e) => println!("Request error: {}", e),
-				_ => {},
+		// every connection gets its own thread, so a slow client only ever holds up itself
+		for stream in incoming {
+			let banned = match stream.peer_addr() {
+				Ok(addr) => self.bans.lock().map(|bans| bans.contains(&addr.ip())).unwrap_or(false),
+				Err(_e) => true,
+			};
+			if banned {
+				let _ = stream.shutdown(Shutdown::Both);
+				continue;
 			}
+
+			let rooms = Arc::clone(&self.rooms);
+			let map_data = Arc::clone(&self.map_data);
+			let max_rooms = self.max_rooms;
+			let stats = Arc::clone(&self.stats);
+			stats.connections.fetch_add(1, Ordering::Relaxed);
+			thread::spawn(move || {
+				if let Err(e) = handle_connection(stream, rooms, map_data, max_rooms, Arc::clone(&stats)) {
+					println!("Connection error: {}", e);
+					stats.errors.fetch_add(1, Ordering::Relaxed);
+				}
+			});
 		}
 	}
+}
 
-	fn handle_request<'s>(&mut self, stream: &'s mut TcpStream) -> Result<(), String> {
-		stream.set_read_timeout(Some(Duration::from_secs(1))).map_err(|_e| "Could set read timeout")?;
-		stream.set_write_timeout(Some(Duration::from_secs(1))).map_err(|_e| "Could set write timeout")?;
+// Runs a single client connection: version handshake, create/join/spectate a room, then events until the client disconnects
+fn handle_connection(mut stream: TcpStream, rooms: Arc<Mutex<HashMap<u32, Room>>>, map_data: Arc<MapData>, max_rooms: usize, stats: Arc<Stats>) -> Result<(), String> {
+	let addr = stream.peer_addr().map_err(|_e| "Could not read connection address.")?.ip();
+	stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT)).map_err(|_e| "Could not set read timeout")?;
+	stream.set_write_timeout(Some(HEARTBEAT_TIMEOUT)).map_err(|_e| "Could not set write timeout")?;
+	stream.set_nodelay(true).map_err(|_e| "Could not disable Nagle's algorithm")?;
 
-		let mut buffer = [0; 13]; // parse request header: 1 byte (MSG_ type) + 4 bytes (u32 user token) + 4 bytes (u32 room code) + 4 bytes (u32 room token)
-		stream.read(&mut buffer).map_err(|_e| "Could not read request stream.")?;
+	// check that the client speaks the same protocol version
+	match read_frame(&mut stream)? {
+		(MSG_HELLO, payload) if payload.len() == 4 => {
+			let version = from_u32_bytes(&payload);
+			if version != PROTOCOL_VERSION {
+				let e = format!("Server expects protocol version {} but client uses {}", PROTOCOL_VERSION, version);
+				write_frame(&mut stream, MSG_ERROR, e.as_bytes())?;
+				return Err(e);
+			}
+		},
+		_ => return Err(String::from("Invalid handshake: expected a hello message")),
+	}
 
-		// parse variables from request header: [addr, user_token, code, token]
-		let addr = stream.peer_addr().map_err(|_e| "Could not read request address.")?.ip();
-		let user_token = from_u32_bytes(&buffer[1..5]);
-		let code = from_u32_bytes(&buffer[5..9]);
-		let token = from_u32_bytes(&buffer[9..13]);
-		
-		// ensure that room code is within the expected range
-		if code < 0 || code > 9999 || (code == 0 && buffer[0] != MSG_CREATE) {
-			return Err(String::from("Invalid request: code not valid"));
-		}
+	// create, join, rejoin or spectate a room, or just list the public ones
+	let (msg, payload) = read_frame(&mut stream)?;
+	let request = parse_request(msg, &payload);
+	if let Ok(Request::List) = request {
+		let list = room_list(&*rooms.lock().map_err(|_e| "Room list lock poisoned")?);
+		write_frame(&mut stream, MSG_ROOMS, &RoomInfo::encode_list(&list))?;
+		let _ = stream.shutdown(Shutdown::Both);
+		return Ok(());
+	}
+	// frames for the client queue up here until it has been sent the history and welcome
+	let (outbox, frames) = mpsc::channel();
+	let entered = request.and_then(|request| {
+		let mut rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+		enter_room(&mut rooms, addr, &map_data, request, outbox.clone(), max_rooms)
+	});
+	let Entry { code, seat, user_token, room_token, connection, history } = match entered {
+		Ok(entry) => entry,
+		Err(e) => {
+			write_frame(&mut stream, MSG_ERROR, e.as_bytes())?;
+			return Err(e);
+		},
+	};
 
-		if buffer[0] == MSG_CREATE {
-			// creating a room
-			let mut code: u32;
-			let token: u32;
-			let host_token: u32;
-			loop {
-				code = self.rand.gen_range(1..10000);
+	// only once the client has everything from before it connected are events pushed to it, from a separate writer thread
+	match write_welcome(&mut stream, history, code, seat, user_token, room_token).and_then(|_| stream.try_clone().map_err(|_e| String::from("Could not clone stream"))) {
+		Ok(writer) => {
+			thread::spawn(move || write_frames(writer, frames));
+		},
+		Err(e) => {
+			if let Some(room) = rooms.lock().map_err(|_e| "Room list lock poisoned")?.get_mut(&code) {
+				room.disconnect(seat, connection);
+			}
+			return Err(e);
+		},
+	}
 
-				// overwrite room entry if older than 24h
-				if let Some(room) = self.rooms.get(&code) {
-					if Instant::now().duration_since(room.last_poll).as_secs() > 60*60*24 {
-						self.rooms.remove(&code);
-					} else {
-						continue;
-					}
+	let result = loop {
+		let (msg, payload) = match read_frame(&mut stream) {
+			Ok(frame) => frame,
+			Err(e) => break Err(e),
+		};
+
+		match msg {
+			MSG_EVENT => {
+				let event = match Event::from_payload(&payload) {
+					Ok(event) => event,
+					Err(e) => break Err(e),
+				};
+				let mut rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+				let room = rooms.get_mut(&code).ok_or("Room no longer exists")?;
+				match room.handle_event(seat, event) {
+					Ok(()) => {
+						stats.events_relayed.fetch_add(1, Ordering::Relaxed);
+					},
+					Err(e) => {
+						println!("Rejected event from {} in room {}: {}", addr, code, e);
+						stats.errors.fetch_add(1, Ordering::Relaxed);
+						let _ = outbox.send((MSG_ERROR, e.clone().into_bytes()));
+						// the client already applied the event, so it has to rejoin to get back in step with the server
+						if let Seat::Host | Seat::Peer = seat {
+							break Err(e);
+						}
+					},
 				}
+			},
+			MSG_CHAT => {
+				let mut rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+				let room = rooms.get_mut(&code).ok_or("Room no longer exists")?;
+				if let Err(e) = room.chat(seat, payload) {
+					let _ = outbox.send((MSG_ERROR, e.into_bytes()));
+				}
+			},
+			MSG_LEAVE => {
+				leave_room(&mut *rooms.lock().map_err(|_e| "Room list lock poisoned")?, code, seat);
+				break Ok(());
+			},
+			MSG_HEARTBEAT => {},
+			_ => break Err(format!("Unexpected message type {}", msg)),
+		}
+	};
 
-				// once an unused room code is found, create the room
-				println!("{} is creating a room with code {:?}", addr.to_string(), code);
-				let room = Room::new(addr);
-				token = room.token;
-				host_token = room.host_token;
-				self.rooms.insert(code, room);
-				break;
-			}
+	println!("{} left room {:?}", addr, code);
+	if let Some(room) = rooms.lock().map_err(|_e| "Room list lock poisoned")?.get_mut(&code) {
+		room.disconnect(seat, connection);
+	}
+	let _ = stream.shutdown(Shutdown::Both);
+	result
+}
 
-			// respond with new code + token of created room
-			let mut send_buffer = [0; 12];
-			set_range!(send_buffer[0..4] = to_u32_bytes(host_token));
-			set_range!(send_buffer[4..8] = to_u32_bytes(code));
-			set_range!(send_buffer[8..12] = to_u32_bytes(token));
-			stream.write_all(&send_buffer).map_err(|_e| "Could not write code response to stream")?;
-			stream.flush().map_err(|_e| "Could not flush stream")?;
-			return Ok(());
-		}
+// What a client asks for once it has said hello
+enum Request {
+	List,
+	Create { is_private: bool, time_control: TimeControl, host_name: String },
+	Join(u32),
+	Spectate(u32, bool), // room code, watching from the host's side or not
+	Rejoin { code: u32, room_token: u32, user_token: u32 },
+}
 
-		// not creating a room: get existing room from HashMap
-		let room = self.rooms.get_mut(&code).ok_or("Could not find a matching room")?;
+fn parse_request(msg: u8, payload: &[u8]) -> Result<Request, String> {
+	match (msg, payload.len()) {
+		(MSG_LIST, _) => Ok(Request::List),
+		(MSG_CREATE, len) if len >= 5 => Ok(Request::Create {
+			is_private: payload[0] == 1,
+			time_control: TimeControl::from_bytes(&payload[1..5]),
+			host_name: String::from_utf8_lossy(&payload[5..]).trim().chars().take(MAX_NAME_LENGTH).collect(),
+		}),
+		(MSG_JOIN, 4) => Ok(Request::Join(from_u32_bytes(payload))),
+		(MSG_SPECTATE, 5) => Ok(Request::Spectate(from_u32_bytes(&payload[0..4]), payload[4] == 1)),
+		(MSG_REJOIN, 12) => Ok(Request::Rejoin {
+			code: from_u32_bytes(&payload[0..4]),
+			room_token: from_u32_bytes(&payload[4..8]),
+			user_token: from_u32_bytes(&payload[8..12]),
+		}),
+		_ => Err(String::from("Invalid request: expected to create, join or rejoin a room")),
+	}
+}
 
-		if buffer[0] == MSG_JOIN {
-			// joining a room
-			println!("{} is joining room {:?}", addr.to_string(), code);
+// Where a client ended up in the room list, and the history it has to be sent before its welcome
+struct Entry {
+	code: u32,
+	seat: Seat,
+	user_token: u32,
+	room_token: u32,
+	connection: u32,
+	history: Vec<(bool, Event)>,
+}
+
+// Creates, joins, rejoins or spectates a room and connects the client's outbox to it.
+// The room list has to stay locked throughout, so that every event is either in the history or sent on afterwards
+fn enter_room(rooms: &mut HashMap<u32, Room>, addr: IpAddr, map_data: &MapData, request: Request, outbox: Outbox, max_rooms: usize) -> Result<Entry, String> {
+	let mut history = Vec::new();
+	let (code, seat, user_token, room_token) = match request {
+		Request::Create { is_private, time_control, host_name } => {
+			create_room(rooms, addr, map_data, host_name, is_private, time_control, max_rooms)?
+		},
+		Request::Join(code) => {
+			println!("{} is joining room {:?}", addr, code);
+			let room = rooms.get_mut(&code).ok_or("Could not find a matching room")?;
 			room.try_join(addr)?;
+			(code, Seat::Peer, room.peer_token, room.token)
+		},
+		Request::Spectate(code, watch_host) => {
+			println!("{} is spectating room {:?}", addr, code);
+			let room = rooms.get_mut(&code).ok_or("Could not find a matching room")?;
+			history = room.history_for(watch_host);
+			// spectators get no tokens, there is nothing for them to rejoin
+			(code, Seat::Spectator(watch_host), 0, 0)
+		},
+		Request::Rejoin { code, room_token, user_token } => {
+			println!("{} is rejoining room {:?}", addr, code);
+			let room = rooms.get_mut(&code).ok_or("Could not find a matching room")?;
+			let (is_host, user_token) = room.try_rejoin(room_token, user_token)?;
+			history = room.history_for(is_host);
+			(code, if is_host { Seat::Host } else { Seat::Peer }, user_token, room.token)
+		},
+		Request::List => return Err(String::from("Invalid request: expected to create, join or rejoin a room")),
+	};
 
-			// respond with joined room code + token to indicate success
-			let mut send_buffer = [0; 12];
-			set_range!(send_buffer[0..4] = to_u32_bytes(room.peer_token));
-			set_range!(send_buffer[4..8] = to_u32_bytes(code));
-			set_range!(send_buffer[8..12] = to_u32_bytes(room.token));
-			stream.write_all(&send_buffer).map_err(|_e| "Could not write join response to stream")?;
-			stream.flush().map_err(|_e| "Could not flush stream")?;
-			return Ok(());
-		}
+	let room = rooms.get_mut(&code).ok_or("Room no longer exists")?;
+	let connection = room.connect(seat, outbox);
+	Ok(Entry { code, seat, user_token, room_token, connection, history })
+}
 
-		// performing an operation on an already-joined room: ensure that token is valid
-		if token != room.token {
-			return Err(String::from("Invalid request: incorrect token"));
-		}
+// A rejoining client or spectator first gets everything that happened in the room, so it can rebuild its game.
+// Then every client is welcomed with its user token + code + token of the room + whether it is (or watches) the host
+fn write_welcome(stream: &mut TcpStream, history: Vec<(bool, Event)>, code: u32, seat: Seat, user_token: u32, room_token: u32) -> Result<(), String> {
+	for (from_client, event) in history {
+		let mut frame = vec![from_client as u8];
+		frame.extend_from_slice(&event.to_bytes());
+		write_frame(stream, MSG_HISTORY, &frame)?;
+	}
 
-		if user_token != room.host_token && user_token != room.peer_token {
-			return Err(String::from("Invalid request: invalid user token"));
+	let mut welcome = [0; 13];
+	set_range!(welcome[0..4] = to_u32_bytes(user_token));
+	set_range!(welcome[4..8] = to_u32_bytes(code));
+	set_range!(welcome[8..12] = to_u32_bytes(room_token));
+	welcome[12] = match seat {
+		Seat::Host | Seat::Spectator(true) => 1,
+		_ => 0,
+	};
+	write_frame(stream, MSG_WELCOME, &welcome)
+}
+
+// Writes queued frames to the client, sending a heartbeat whenever the connection has been idle for too long
+fn write_frames(mut stream: TcpStream, frames: Receiver<(u8, Vec<u8>)>) {
+	loop {
+		let (msg, payload) = match frames.recv_timeout(HEARTBEAT_INTERVAL) {
+			Ok(frame) => frame,
+			Err(RecvTimeoutError::Timeout) => (MSG_HEARTBEAT, Vec::new()),
+			Err(RecvTimeoutError::Disconnected) => break,
+		};
+
+		if write_frame(&mut stream, msg, &payload).is_err() {
+			let _ = stream.shutdown(Shutdown::Both);
+			break;
 		}
-		let is_host = user_token == room.host_token;
+	}
+}
+
+// Creates a room with an unused code, returning (code, seat, user token, room token)
+fn create_room(rooms: &mut HashMap<u32, Room>, addr: IpAddr, map_data: &MapData, host_name: String, is_private: bool, time_control: TimeControl, max_rooms: usize) -> Result<(u32, Seat, u32, u32), String> {
+	if rooms.len() >= max_rooms {
+		return Err(String::from("The server is full, try again later"));
+	}
+	if rooms.values().filter(|room| room.host_addr == addr).count() >= MAX_ROOMS_PER_IP {
+		return Err(format!("Only {} rooms can be open from the same address", MAX_ROOMS_PER_IP));
+	}
+
+	// look for an unused code from a random starting point, so codes are hard to guess but the search always ends
+	let start = rand::thread_rng().gen_range(0..ROOM_CODES);
+	let code = (0..ROOM_CODES)
+		.map(|i| (start + i) % ROOM_CODES + 1)
+		.find(|code| !rooms.contains_key(code))
+		.ok_or("The server is full, try again later")?;
+
+	println!("{} is creating a room with code {:?} ({})", addr, code, time_control.describe());
+	let room = Room::new(map_data, addr, host_name, is_private, time_control)?;
+	let tokens = (room.host_token, room.token);
+	rooms.insert(code, room);
+	Ok((code, Seat::Host, tokens.0, tokens.1))
+}
+
+// A player leaving ends the game for everyone; spectators can come and go
+fn leave_room(rooms: &mut HashMap<u32, Room>, code: u32, seat: Seat) {
+	if let Seat::Spectator(_) = seat {
+		return;
+	}
+	if let Some(room) = rooms.remove(&code) {
+		room.close(if let Seat::Host = seat { "The host closed the room" } else { "Your opponent left the room" });
+	}
+}
 
-		if buffer[0] == MSG_EVENT {
-			// sending an event
-			let mut event_buffer = [0; 19];
-			stream.read(&mut event_buffer).map_err(|_e| "Could not read event stream.")?;
+// Closes rooms that nobody has been connected to for a while, and games that have been over for a while
+fn sweep_rooms(rooms: Arc<Mutex<HashMap<u32, Room>>>) {
+	loop {
+		thread::sleep(SWEEP_INTERVAL);
 
-			// push event into room
-			let event = Event::from_bytes(&event_buffer);
-			room.push_event(is_host, addr, event)?;
+		let mut rooms = match rooms.lock() {
+			Ok(rooms) => rooms,
+			Err(_e) => return,
+		};
+		close_expired_rooms(&mut rooms);
+	}
+}
 
-			// respond with 1 byte to indicate success
-			stream.write_all(&[1]).map_err(|_e| "Could not write event response to stream")?;
-		} else if buffer[0] == MSG_POLL {
-			// polling for events
-			let event = room.pop_event(is_host, addr)?;
-			let event_buffer = event.to_bytes();
+// Closes the rooms that have expired, returning their codes
+fn close_expired_rooms(rooms: &mut HashMap<u32, Room>) -> Vec<u32> {
+	let expired: Vec<u32> = rooms.iter()
+		.filter(|(_code, room)| room.is_expired())
+		.map(|(code, _room)| *code)
+		.collect();
 
-			// respond with event contents
-			stream.write_all(&event_buffer).map_err(|_e| "Could not write poll response to stream")?;
+	for code in expired.iter() {
+		if let Some(room) = rooms.remove(code) {
+			println!("Closing room {:?}", code);
+			room.close("The room was closed for being idle");
 		}
+	}
+	expired
+}
 
-		stream.flush().map_err(|_e| "Could not flush stream")?;
-		Ok(())
+// Ends the turns of players who have run out of time
+fn run_clocks(rooms: Arc<Mutex<HashMap<u32, Room>>>) {
+	loop {
+		thread::sleep(CLOCK_INTERVAL);
+
+		let mut rooms = match rooms.lock() {
+			Ok(rooms) => rooms,
+			Err(_e) => return,
+		};
+		for (code, room) in rooms.iter_mut() {
+			if let Err(e) = room.check_clock() {
+				println!("Could not end timed out turn in room {:?}: {}", code, e);
+			}
+		}
+	}
+}
+
+// The public rooms for the lobby, newest first
+fn room_list(rooms: &HashMap<u32, Room>) -> Vec<RoomInfo> {
+	let mut list: Vec<RoomInfo> = rooms.iter()
+		.filter(|(_code, room)| !room.is_private && room.game_map.winning_team.is_none())
+		.map(|(code, room)| RoomInfo {
+			code: *code,
+			host_name: room.host_name.clone(),
+			map_name: room.map_name.clone(),
+			players: if room.peer_addr.is_some() { 2 } else { 1 },
+			age_secs: room.created.elapsed().as_secs() as u32,
+			time_control: room.time_control,
+		})
+		.collect();
+	list.sort_by_key(|room| room.age_secs);
+	list.truncate(MAX_LISTED_ROOMS);
+	list
+}
+
+const ADMIN_HELP: &str = "commands:
+  stats         uptime, rooms, players, events relayed and errors
+  rooms         one line per room
+  close <code>  close a room, disconnecting everyone in it
+  ban <ip>      refuse connections from an address and close the rooms it hosts
+  unban <ip>    lift a ban
+  bans          list banned addresses
+  quit          close this admin connection";
+
+// Runs a connection to the admin port: one command per line, answered with one or more lines of text
+fn handle_admin(stream: TcpStream, rooms: Arc<Mutex<HashMap<u32, Room>>>, stats: Arc<Stats>, bans: Arc<Mutex<HashSet<IpAddr>>>) -> Result<(), String> {
+	let mut writer = stream.try_clone().map_err(|_e| "Could not clone stream")?;
+	writeln!(writer, "castle quest server admin, type help for commands").map_err(|e| e.to_string())?;
+
+	for line in BufReader::new(stream).lines() {
+		let line = line.map_err(|e| e.to_string())?;
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let reply = match words[..] {
+			["quit"] => break,
+			[] => continue,
+			_ => admin_command(&words, &rooms, &stats, &bans)?,
+		};
+		writeln!(writer, "{}", reply).map_err(|e| e.to_string())?;
 	}
+	Ok(())
+}
+
+// Carries out a command from the admin port, returning the reply
+fn admin_command(words: &[&str], rooms: &Mutex<HashMap<u32, Room>>, stats: &Stats, bans: &Mutex<HashSet<IpAddr>>) -> Result<String, String> {
+	let reply = match words {
+		["stats"] => {
+			let rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+			let players: usize = rooms.values().map(|room| room.host_outbox.iter().count() + room.peer_outbox.iter().count()).sum();
+			let spectators: usize = rooms.values().map(|room| room.spectators.len()).sum();
+			format!("uptime {}s\nrooms {}\nplayers {}\nspectators {}\nconnections {}\nevents relayed {}\nerrors {}\nbans {}",
+				stats.started.elapsed().as_secs(),
+				rooms.len(),
+				players,
+				spectators,
+				stats.connections.load(Ordering::Relaxed),
+				stats.events_relayed.load(Ordering::Relaxed),
+				stats.errors.load(Ordering::Relaxed),
+				bans.lock().map_err(|_e| "Ban list lock poisoned")?.len())
+		},
+		["rooms"] => {
+			let rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+			let mut codes: Vec<&u32> = rooms.keys().collect();
+			codes.sort();
+			let lines: Vec<String> = codes.into_iter().map(|code| {
+				let room = &rooms[code];
+				format!("{:04} host {} ({}){} players {}/{} spectators {} events {} turn {} age {}s idle {}s{}",
+					code,
+					room.host_name,
+					room.host_addr,
+					if room.is_private { " private" } else { "" },
+					room.host_outbox.iter().count() + room.peer_outbox.iter().count(),
+					if room.peer_addr.is_some() { 2 } else { 1 },
+					room.spectators.len(),
+					room.host_history.len(),
+					room.game_map.player_state.current_turn.to_string(),
+					room.created.elapsed().as_secs(),
+					room.last_active.elapsed().as_secs(),
+					if room.game_map.winning_team.is_some() { " finished" } else { "" })
+			}).collect();
+			if lines.is_empty() { String::from("no rooms") } else { lines.join("\n") }
+		},
+		["close", code] => match code.parse::<u32>() {
+			Ok(code) => match rooms.lock().map_err(|_e| "Room list lock poisoned")?.remove(&code) {
+				Some(room) => {
+					room.close("The room was closed by the server");
+					format!("closed room {:04}", code)
+				},
+				None => format!("no room {:04}", code),
+			},
+			Err(_e) => String::from("invalid room code"),
+		},
+		["ban", ip] => match ip.parse::<IpAddr>() {
+			Ok(ip) => {
+				bans.lock().map_err(|_e| "Ban list lock poisoned")?.insert(ip);
+
+				let mut rooms = rooms.lock().map_err(|_e| "Room list lock poisoned")?;
+				let hosted: Vec<u32> = rooms.iter().filter(|(_code, room)| room.host_addr == ip).map(|(code, _room)| *code).collect();
+				for code in hosted.iter() {
+					if let Some(room) = rooms.remove(code) {
+						room.close("The room was closed by the server");
+					}
+				}
+				format!("banned {}, closed {} rooms", ip, hosted.len())
+			},
+			Err(_e) => String::from("invalid address"),
+		},
+		["unban", ip] => match ip.parse::<IpAddr>() {
+			Ok(ip) => if bans.lock().map_err(|_e| "Ban list lock poisoned")?.remove(&ip) {
+				format!("unbanned {}", ip)
+			} else {
+				format!("{} is not banned", ip)
+			},
+			Err(_e) => String::from("invalid address"),
+		},
+		["bans"] => {
+			let bans: Vec<String> = bans.lock().map_err(|_e| "Ban list lock poisoned")?.iter().map(|ip| ip.to_string()).collect();
+			if bans.is_empty() { String::from("no bans") } else { bans.join("\n") }
+		},
+		_ => String::from(ADMIN_HELP),
+	};
+	Ok(reply)
 }
 
 struct Room {
 	token: u32,
 	host_addr: IpAddr,
+	host_name: String,
+	map_name: String,
+	// private rooms can only be joined with their code
+	is_private: bool,
+	created: Instant,
 	peer_addr: Option<IpAddr>,
 	host_token: u32,
 	peer_token: u32,
+
+	// connections of the players currently in the room, with an id so a dropped connection can't disconnect a newer one
+	host_outbox: Option<(u32, Outbox)>,
+	peer_outbox: Option<(u32, Outbox)>,
+	spectators: Vec<(u32, bool, Outbox)>, // (connection id, watching the host, outbox)
+	connections: u32,
+	// events for a player that isn't connected, delivered once they connect
 	host_events: Vec<Event>,
 	peer_events: Vec<Event>,
-	last_poll: Instant,
+	last_active: Instant,
+
+	// every event each player has seen, in order and with whether it came from them, for rebuilding the game on rejoin
+	host_history: Vec<(bool, Event)>,
+	peer_history: Vec<(bool, Event)>,
+	// where the units each player can't control are as far as that player knows, to keep them from seeing past the fog
+	host_known: HashSet<(u32, u32)>,
+	peer_known: HashSet<(u32, u32)>,
+	winner_announced: bool,
+
+	// the room's copy of the game, kept from the host's perspective (host = Team::Player, peer = Team::Enemy)
+	game_map: GameMap,
+	// successful barbarian conversions that each player still has to spawn a unit for this turn
+	host_conversions: u32,
+	peer_conversions: u32,
+
+	time_control: TimeControl,
+	// when the current turn started, if it's a timed turn of a game in progress
+	turn_started: Option<Instant>,
+	// what's left of each player's match time
+	host_time: Duration,
+	peer_time: Duration,
 }
 
 impl Room {
 
-	fn new(addr: IpAddr) -> Room {
-		Room {
+	fn new(map_data: &MapData, host_addr: IpAddr, host_name: String, is_private: bool, time_control: TimeControl) -> Result<Room, String> {
+		let mut room = Room {
 			token: random(),
-			host_addr: addr,
+			host_addr,
+			host_name,
+			map_name: map_data.name.clone(),
+			is_private,
+			created: Instant::now(),
 			peer_addr: None,
 			host_token: random(),
 			peer_token: random(),
+			host_outbox: None,
+			peer_outbox: None,
+			spectators: Vec::new(),
+			connections: 0,
 			host_events: Vec::new(),
-			peer_events: vec![Event::new(EVENT_JOIN)], // initial join event for host -> peer
-			last_poll: Instant::now(),
-		}
+			peer_events: Vec::new(),
+			last_active: Instant::now(),
+			host_history: Vec::new(),
+			peer_history: Vec::new(),
+			host_known: HashSet::new(),
+			peer_known: HashSet::new(),
+			winner_announced: false,
+			game_map: GameMap::new(map_data, Team::Player, random())?,
+			host_conversions: 0,
+			peer_conversions: 0,
+			time_control,
+			turn_started: None,
+			host_time: Duration::from_secs(time_control.match_secs as u64),
+			peer_time: Duration::from_secs(time_control.match_secs as u64),
+		};
+		// the clients start out knowing about the same units, see GameMap::hide_unseen_units
+		room.host_known = room.units_in_sight(true);
+		room.peer_known = room.units_in_sight(false);
+		Ok(room)
 	}
 
 	fn try_join(&mut self, addr: IpAddr) -> Result<(), String> {
 		if self.peer_addr == None {
 			self.peer_addr = Some(addr);
-			self.push_event(false, addr, Event::new(EVENT_JOIN))?; // new join event for peer -> host
+			// let both players (and anyone watching) know that the game can start
+			self.send(true, Event::new(EVENT_JOIN));
+			self.send(false, Event::new(EVENT_JOIN));
+			for (_, _, outbox) in &self.spectators {
+				let _ = outbox.send((MSG_EVENT, Event::new(EVENT_JOIN).to_bytes().to_vec()));
+			}
+			self.start_clock();
 			Ok(())
 		} else {
 			Err(String::from("Room already full"))
 		}
 	}
 
-	fn push_event(&mut self, is_host: bool, addr: IpAddr, event: Event) -> Result<(), String> {
-		if is_host && self.host_addr == addr {
-			// as host, push an event to the peer
-			self.peer_events.push(event);
-		} else if !is_host && self.peer_addr == Some(addr) {
-			// as peer, push an event to the host
-			self.host_events.push(event);
+	// Checks the tokens of a player coming back to the room, returning (is_host, user token).
+	// Any connection the player still has is dropped; events from here on wait in the queue until they reconnect.
+	fn try_rejoin(&mut self, room_token: u32, user_token: u32) -> Result<(bool, u32), String> {
+		if room_token != self.token {
+			return Err(String::from("Invalid room token"));
+		}
+		let is_host = if user_token == self.host_token {
+			true
+		} else if user_token == self.peer_token && self.peer_addr.is_some() {
+			false
 		} else {
-			return Err(String::from("Cannot push_event: Peer has not joined the room"));
+			return Err(String::from("Invalid user token"));
+		};
+
+		// the history sent on rejoin already contains anything that was queued
+		if is_host {
+			self.host_outbox = None;
+			self.host_events.clear();
+		} else {
+			self.peer_outbox = None;
+			self.peer_events.clear();
+		}
+		// the game can continue straight away if the other player is there
+		if self.peer_addr.is_some() {
+			self.send(is_host, Event::new(EVENT_JOIN));
 		}
+		Ok((is_host, user_token))
+	}
+
+	// The room's history as seen by the host or peer: (whether the event came from them, event)
+	fn history_for(&self, is_host: bool) -> Vec<(bool, Event)> {
+		if is_host { self.host_history.clone() } else { self.peer_history.clone() }
+	}
+
+	// Attaches a connection to the room and delivers any queued events, returning the connection's id
+	fn connect(&mut self, seat: Seat, outbox: Outbox) -> u32 {
+		self.connections += 1;
+		let queued: Vec<Event> = match seat {
+			Seat::Host => self.host_events.drain(..).collect(),
+			Seat::Peer => self.peer_events.drain(..).collect(),
+			Seat::Spectator(_) if self.peer_addr.is_some() => vec![Event::new(EVENT_JOIN)],
+			Seat::Spectator(_) => Vec::new(),
+		};
+		for event in queued {
+			let _ = outbox.send((MSG_EVENT, event.to_bytes().to_vec()));
+		}
+		if let Some(clock) = self.clock() {
+			let _ = outbox.send((MSG_CLOCK, clock.to_bytes().to_vec()));
+		}
+
+		match seat {
+			Seat::Host => self.host_outbox = Some((self.connections, outbox)),
+			Seat::Peer => self.peer_outbox = Some((self.connections, outbox)),
+			Seat::Spectator(watch_host) => self.spectators.push((self.connections, watch_host, outbox)),
+		}
+		self.last_active = Instant::now();
+		self.connections
+	}
+
+	fn disconnect(&mut self, seat: Seat, connection: u32) {
+		let outbox = match seat {
+			Seat::Host => &mut self.host_outbox,
+			Seat::Peer => &mut self.peer_outbox,
+			Seat::Spectator(_) => {
+				self.spectators.retain(|(id, _, _)| *id != connection);
+				return;
+			},
+		};
+		if outbox.as_ref().map(|(id, _)| *id) == Some(connection) {
+			*outbox = None;
+		}
+		self.last_active = Instant::now();
+	}
+
+	fn is_connected(&self) -> bool {
+		self.host_outbox.is_some() || self.peer_outbox.is_some()
+	}
+
+	// Whether the sweeper should close the room: nobody has been connected to it for a while, or its game has been over for a while
+	fn is_expired(&self) -> bool {
+		let idle = self.last_active.elapsed();
+		(self.game_map.winning_team.is_some() && idle > FINISHED_ROOM_TIMEOUT)
+			|| (!self.is_connected() && idle > IDLE_ROOM_TIMEOUT)
+	}
+
+	// Lets everyone in the room know it's gone; the room should already be removed from the room list
+	fn close(&self, reason: &str) {
+		let outboxes = self.host_outbox.iter().chain(self.peer_outbox.iter()).map(|(_, outbox)| outbox)
+			.chain(self.spectators.iter().map(|(_, _, outbox)| outbox));
+		for outbox in outboxes {
+			let _ = outbox.send((MSG_LEAVE, reason.as_bytes().to_vec()));
+		}
+	}
+
+	// Delivers an event to the host or peer, or holds on to it if they aren't connected
+	fn send(&mut self, to_host: bool, event: Event) {
+		let (outbox, queue) = if to_host { (&self.host_outbox, &mut self.host_events) } else { (&self.peer_outbox, &mut self.peer_events) };
+		let delivered = match outbox {
+			Some((_, outbox)) => outbox.send((MSG_EVENT, event.to_bytes().to_vec())).is_ok(),
+			None => false,
+		};
+		if !delivered {
+			queue.push(event);
+		}
+	}
+
+	// Adds an event to the host's or peer's history and shows it to the spectators watching them
+	fn record(&mut self, to_host: bool, from_self: bool, event: Event) {
+		if to_host { &mut self.host_history } else { &mut self.peer_history }.push((from_self, event));
+
+		let bytes = event.to_bytes();
+		self.spectators.retain(|(_, watch_host, outbox)| {
+			if *watch_host != to_host {
+				return true;
+			}
+			let mut frame = vec![from_self as u8];
+			frame.extend_from_slice(&bytes);
+			outbox.send((MSG_HISTORY, frame)).is_ok()
+		});
+	}
+
+	// Sends a player an event about someone else's units, keeping track of which of those units they know about
+	fn tell(&mut self, to_host: bool, event: Event) {
+		self.send(to_host, event);
+		self.record(to_host, false, event);
+
+		let target_died = self.game_map.get_unit(&event.to_pos).is_err();
+		let known = if to_host { &mut self.host_known } else { &mut self.peer_known };
+		match event.action {
+			EVENT_MOVE => {
+				known.remove(&event.from_pos);
+				known.insert(event.to_pos);
+			},
+			EVENT_ATTACK if target_died => {
+				known.remove(&event.to_pos);
+			},
+			EVENT_REVEAL | EVENT_SPAWN_UNIT => {
+				known.insert(event.to_pos);
+			},
+			EVENT_HIDE => {
+				known.remove(&event.from_pos);
+			},
+			_ => {},
+		}
+	}
+
+	// Positions of the units a player can see, other than their own
+	fn units_in_sight(&self, is_host: bool) -> HashSet<(u32, u32)> {
+		let visible = self.game_map.visible_tiles(if is_host { Team::Player } else { Team::Enemy });
+		let others = if is_host { &self.game_map.enemy_units } else { &self.game_map.player_units };
+		others.keys().chain(self.game_map.barbarian_units.keys())
+			.filter(|pos| visible.contains(pos))
+			.cloned()
+			.collect()
+	}
+
+	// An EVENT_REVEAL for the unit at pos (with its team from the host's perspective, which the client turns around as needed)
+	fn reveal(&self, pos: (u32, u32)) -> Option<Event> {
+		let unit = self.game_map.get_unit(&pos).ok()?;
+		let flags = unit.has_moved as u32 + 2 * unit.has_attacked as u32;
+		Some(Event::create(EVENT_REVEAL, unit.team.to_id(), (unit.hp, flags), pos, unit.kind()))
+	}
 
+	// Tells a player about the units that came into or went out of their sight
+	fn update_sight(&mut self, to_host: bool) {
+		let in_sight = self.units_in_sight(to_host);
+		let known = if to_host { self.host_known.clone() } else { self.peer_known.clone() };
+		for pos in known.difference(&in_sight) {
+			self.tell(to_host, Event::create(EVENT_HIDE, 0, *pos, *pos, 0));
+		}
+		for pos in in_sight.difference(&known) {
+			if let Some(event) = self.reveal(*pos) {
+				self.tell(to_host, event);
+			}
+		}
+	}
+
+	// Passes an event on to a player that didn't make it, as far as they can see what happened.
+	// defender is the team of the unit attacked, if any, from before the event was applied.
+	fn deliver(&mut self, to_host: bool, event: Event, defender: Option<Team>) {
+		let own_team = if to_host { Team::Player } else { Team::Enemy };
+		let known = if to_host { &self.host_known } else { &self.peer_known };
+		let (knows_from, knows_to) = (known.contains(&event.from_pos), known.contains(&event.to_pos));
+
+		match event.action {
+			EVENT_MOVE => {
+				let sees_to = self.units_in_sight(to_host).contains(&event.to_pos);
+				match (knows_from, sees_to) {
+					(true, true) => self.tell(to_host, event),
+					(true, false) => self.tell(to_host, Event::create(EVENT_HIDE, 0, event.from_pos, event.from_pos, 0)),
+					(false, true) => if let Some(reveal) = self.reveal(event.to_pos) {
+						self.tell(to_host, reveal);
+					},
+					(false, false) => {},
+				}
+			},
+			EVENT_ATTACK => {
+				// an attack on a unit the player can see gives away where the attacker is
+				if defender == Some(own_team) || knows_to {
+					if !knows_from {
+						if let Some(reveal) = self.reveal(event.from_pos) {
+							self.tell(to_host, reveal);
+						}
+					}
+					self.tell(to_host, event);
+				}
+			},
+			EVENT_SPAWN_UNIT => {
+				if self.units_in_sight(to_host).contains(&event.to_pos) {
+					self.tell(to_host, event);
+				}
+			},
+			_ => self.tell(to_host, event),
+		}
+	}
+
+	// Applies a game event to the room's game and lets each player know about it, as far as they can see it.
+	// from_host is who sent it, or None for the server's own events (the barbarians' moves, turns running out of time)
+	fn play(&mut self, from_host: Option<bool>, event: Event) -> Result<(), String> {
+		let defender = match event.action {
+			EVENT_ATTACK => self.game_map.get_unit(&event.to_pos).ok().map(|unit| unit.team),
+			_ => None,
+		};
+		self.apply_event(event)?;
+
+		for to_host in [true, false].iter().cloned() {
+			match from_host {
+				// the attacker's client also gets the resolved attack, since it doesn't apply its own attacks
+				Some(from_host) if from_host == to_host && event.action == EVENT_ATTACK => self.tell(to_host, event),
+				Some(from_host) if from_host == to_host => self.record(to_host, true, event),
+				_ => self.deliver(to_host, event, defender),
+			}
+			self.update_sight(to_host);
+		}
 		Ok(())
 	}
 
-	fn pop_event(&mut self, is_host: bool, addr: IpAddr) -> Result<Event, String> {
-		self.last_poll = Instant::now();
+	// The server plays the barbarians' turn itself, since it's the only one that can see all of them
+	fn play_barbarians(&mut self) -> Result<(), String> {
+		while self.game_map.player_state.current_turn == Team::Barbarians && self.game_map.winning_team.is_none() {
+			self.game_map.event_list.clear();
+			self.game_map.event_list_index = 0;
+			barbarian_turn::handle_barbarian_turn(&mut self.game_map)?;
 
-		let events = if is_host && self.host_addr == addr {
-			&mut self.host_events
-		} else if !is_host && self.peer_addr == Some(addr) {
-			&mut self.peer_events
-		} else {
-			return Err(String::from("Cannot pop_event: Peer has not joined the room"));
+			let events: Vec<Event> = self.game_map.event_list.drain(..).collect();
+			for event in events {
+				self.play(None, event)?;
+			}
+		}
+		Ok(())
+	}
+
+	// Lets both players know once the room's game has a winner. Neither client can see all of the other's units,
+	// so they leave deciding this to the server
+	fn announce_winner(&mut self) {
+		if let (Some(winner), false) = (self.game_map.winning_team, self.winner_announced) {
+			self.winner_announced = true;
+			let event = Event::create(EVENT_END_GAME, winner.to_id(), (0,0), (0,0), 0);
+			self.tell(true, event);
+			self.tell(false, event);
+		}
+	}
+
+	// Passes a chat message from one of the players on to the other player and the spectators.
+	// Chat isn't part of the game, so it's not kept for players who aren't connected.
+	fn chat(&mut self, seat: Seat, payload: Vec<u8>) -> Result<(), String> {
+		let is_host = match seat {
+			Seat::Host => true,
+			Seat::Peer => false,
+			Seat::Spectator(_) => return Err(String::from("Spectators cannot chat")),
 		};
+		let text = String::from_utf8(payload).map_err(|_e| "Chat message is not valid utf-8")?;
+		let text = text.trim();
+		if text.is_empty() || text.chars().count() > MAX_CHAT_LENGTH {
+			return Err(format!("Chat messages must be between 1 and {} characters", MAX_CHAT_LENGTH));
+		}
+		self.last_active = Instant::now();
 
-		if events.is_empty() {
-			return Ok(Event::new(EVENT_NONE))
+		let mut frame = vec![is_host as u8];
+		frame.extend_from_slice(text.as_bytes());
+		let other = if is_host { &self.peer_outbox } else { &self.host_outbox };
+		if let Some((_, outbox)) = other {
+			let _ = outbox.send((MSG_CHAT, frame.clone()));
 		}
+		for (_, _, outbox) in &self.spectators {
+			let _ = outbox.send((MSG_CHAT, frame.clone()));
+		}
+		Ok(())
+	}
+
+	// Checks an event from one of the players against the room's copy of the game before passing it on.
+	// Attack damage and barbarian conversions are rolled here rather than trusted from the client.
+	fn handle_event(&mut self, seat: Seat, mut event: Event) -> Result<(), String> {
+		let is_host = match seat {
+			Seat::Host => true,
+			Seat::Peer => false,
+			Seat::Spectator(_) => return Err(String::from("Spectators cannot send events")),
+		};
+		if !is_host && self.peer_addr.is_none() {
+			return Err(String::from("Cannot handle_event: Peer has not joined the room"));
+		}
+		self.last_active = Instant::now();
+
+		let team = if is_host { Team::Player } else { Team::Enemy };
+
+		match event.action {
+			EVENT_MOVE => {
+				if let Err(e) = self.game_map.validate_action(&event, team) {
+					// the unit most likely ran into someone hidden by the fog, who the player gets to see now
+					if let Some(reveal) = self.reveal(event.to_pos).filter(|reveal| Team::from_id(reveal.id) != Ok(team)) {
+						self.tell(is_host, reveal);
+					}
+					return Err(e);
+				}
+				self.play(Some(is_host), event)?;
+			},
+			EVENT_ATTACK => {
+				self.game_map.validate_action(&event, team)?;
+
+				let defender = self.game_map.get_unit(&event.to_pos)?;
+				let (defender_team, defender_hp) = (defender.team, defender.hp);
+				let damage = self.game_map.roll_attack_damage(&event.from_pos, &event.to_pos)?;
+				event.value = damage as u8;
 
-		Ok(events.remove(0))
+				// killing a barbarian gives the attacking player a chance to convert it to their side
+				if defender_team == Team::Barbarians && defender_hp <= damage && self.game_map.rng.gen_range(0..100) < CONVERT_CHANCE {
+					if is_host {
+						self.host_conversions += 1;
+					} else {
+						self.peer_conversions += 1;
+					}
+					// sent ahead of the attack, since the attacking client waits for the attack before continuing its turn
+					self.send(is_host, Event::create(EVENT_CONVERT, team.to_id(), (0,0), (0,0), 0));
+				}
+
+				self.play(Some(is_host), event)?;
+			},
+			EVENT_SPAWN_UNIT => {
+				let conversions = if is_host { self.host_conversions } else { self.peer_conversions };
+				if conversions == 0 {
+					return Err(String::from("Invalid event: no converted unit to spawn"));
+				}
+				self.game_map.validate_action(&event, team)?;
+
+				if is_host {
+					self.host_conversions -= 1;
+				} else {
+					self.peer_conversions -= 1;
+				}
+				// spawned units belong to the sender
+				event.id = team.to_id();
+				self.play(Some(is_host), event)?;
+			},
+			EVENT_END_TURN => {
+				if self.game_map.player_state.current_turn != team {
+					return Err(format!("Invalid event: it is not {}'s turn", team.to_string()));
+				}
+				self.play(Some(is_host), event)?;
+				self.play_barbarians()?;
+			},
+			EVENT_JOIN | EVENT_CONVERT | EVENT_REVEAL | EVENT_HIDE | EVENT_END_GAME => {
+				return Err(format!("Invalid event: only the server sends events of type {}", event.action));
+			},
+			_ => {
+				return Err(format!("Invalid event: unknown event type {}", event.action));
+			},
+		}
+
+		self.announce_winner();
+		Ok(())
+	}
+
+	fn apply_event(&mut self, event: Event) -> Result<(), String> {
+		if event.action == EVENT_END_TURN {
+			self.stop_clock();
+		}
+		game_map::apply_event(&mut self.game_map, event)?;
+		game_map::remove_dead_units(&mut self.game_map);
+		if event.action == EVENT_END_TURN {
+			// conversions have to be spawned in the turn they were rolled, they don't build up
+			self.host_conversions = 0;
+			self.peer_conversions = 0;
+			self.start_clock();
+		}
+
+		// nothing displays the server's notices
+		self.game_map.notices.clear();
+		Ok(())
+	}
+
+	// Starts timing the current turn if it's a timed player's turn, and lets everyone know how much time is left
+	fn start_clock(&mut self) {
+		let current_turn = self.game_map.player_state.current_turn;
+		if !self.time_control.is_timed() || current_turn == Team::Barbarians || self.game_map.winning_team.is_some() {
+			return;
+		}
+		self.turn_started = Some(Instant::now());
+
+		if let Some(clock) = self.clock() {
+			let bytes = clock.to_bytes().to_vec();
+			let outboxes = self.host_outbox.iter().chain(self.peer_outbox.iter()).map(|(_, outbox)| outbox)
+				.chain(self.spectators.iter().map(|(_, _, outbox)| outbox));
+			for outbox in outboxes {
+				let _ = outbox.send((MSG_CLOCK, bytes.clone()));
+			}
+		}
+	}
+
+	// Takes the time the current turn took off the player's match time
+	fn stop_clock(&mut self) {
+		if let Some(started) = self.turn_started.take() {
+			let time = if self.game_map.player_state.current_turn == Team::Player { &mut self.host_time } else { &mut self.peer_time };
+			*time = time.saturating_sub(started.elapsed());
+		}
+	}
+
+	// How long the current turn may last: the time per turn, or whatever is left of the player's match time if that's less
+	fn turn_limit(&self) -> Duration {
+		let mut limit = if self.time_control.turn_secs > 0 { Duration::from_secs(self.time_control.turn_secs as u64) } else { Duration::MAX };
+		if self.time_control.match_secs > 0 {
+			limit = limit.min(if self.game_map.player_state.current_turn == Team::Player { self.host_time } else { self.peer_time });
+		}
+		limit
+	}
+
+	fn clock(&self) -> Option<Clock> {
+		let started = self.turn_started?;
+		let match_ms = |time: Duration| if self.time_control.match_secs > 0 { time.as_millis() as u32 } else { NO_TIME_LIMIT };
+		Some(Clock {
+			turn_ms: self.turn_limit().saturating_sub(started.elapsed()).as_millis() as u32,
+			host_ms: match_ms(self.host_time),
+			peer_ms: match_ms(self.peer_time),
+		})
+	}
+
+	// Ends the current turn for the player once their time (and the grace period) has run out
+	fn check_clock(&mut self) -> Result<(), String> {
+		match self.turn_started {
+			Some(started) if self.game_map.winning_team.is_none() && started.elapsed() >= self.turn_limit().saturating_add(CLOCK_GRACE) => {},
+			_ => return Ok(()),
+		}
+
+		let team = self.game_map.player_state.current_turn;
+		println!("{} ran out of time", team.to_string());
+		// the player whose turn it was gets the end of their turn too, since they didn't send it
+		self.play(None, Event::create(EVENT_END_TURN, team.to_id(), (0,0), (0,0), 0))?;
+		self.play_barbarians()?;
+		self.announce_winner();
+		Ok(())
 	}
 }
 
 pub fn run() {
-	let addr = unsafe {
-		String::from(SERVER_ADDR)
+	let addr = net::server_addr();
+
+	let map_data = match MapData::load(&map_data::map_path()) {
+		Ok(map_data) => map_data,
+		Err(e) => {
+			println!("Could not start server: {}", e);
+			return;
+		},
 	};
 
 	let port = *(addr.split(":").collect::<Vec<&str>>().last().unwrap());
-	let mut server = Server::new(format!("0.0.0.0:{}", port).as_ref());
+	let max_rooms = MAX_ROOMS.load(Ordering::SeqCst);
+	let server = Server::new(format!("0.0.0.0:{}", port).as_ref(), map_data, max_rooms);
 
-	println!("Listening at {}", &addr);
+	server.listen_admin(&ADMIN_ADDR.lock().unwrap().clone());
+	println!("Listening at {} (at most {} rooms)", &addr, max_rooms);
 	server.listen();
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::game_map::tests::test_map_data;
+
+	//The other end of a client's outbox
+	type Frames = Receiver<(u8, Vec<u8>)>;
+
+	const UNTIMED: TimeControl = TimeControl { turn_secs: 0, match_secs: 0 };
+
+	fn ip(n: u8) -> IpAddr {
+		IpAddr::from([10, 0, 0, n])
+	}
+
+	//Enters a room the way handle_connection does, keeping the other end of the client's outbox
+	fn enter(rooms: &mut HashMap<u32, Room>, addr: IpAddr, request: Request) -> Result<(Entry, Frames), String> {
+		let (outbox, frames) = mpsc::channel();
+		enter_room(rooms, addr, &test_map_data(), request, outbox, DEFAULT_MAX_ROOMS).map(|entry| (entry, frames))
+	}
+
+	fn create(rooms: &mut HashMap<u32, Room>, addr: IpAddr, time_control: TimeControl) -> (Entry, Frames) {
+		enter(rooms, addr, Request::Create { is_private: false, time_control, host_name: String::from("host") }).unwrap()
+	}
+
+	//The events waiting in an outbox, leaving out everything else
+	fn events(frames: &Frames) -> Vec<Event> {
+		frames.try_iter().filter(|(msg, _)| *msg == MSG_EVENT).map(|(_, payload)| Event::from_payload(&payload).unwrap()).collect()
+	}
+
+	#[test]
+	fn parses_requests() {
+		let mut create = vec![1];
+		create.extend_from_slice(&TimeControl { turn_secs: 60, match_secs: 600 }.to_bytes());
+		create.extend_from_slice(b"  a very long host name  ");
+		match parse_request(MSG_CREATE, &create) {
+			Ok(Request::Create { is_private, time_control, host_name }) => {
+				assert!(is_private);
+				assert_eq!((time_control.turn_secs, time_control.match_secs), (60, 600));
+				assert_eq!(host_name, "a very long host");
+			},
+			_ => panic!("expected a create request"),
+		}
+
+		assert!(matches!(parse_request(MSG_JOIN, &to_u32_bytes(42)), Ok(Request::Join(42))));
+		assert!(matches!(parse_request(MSG_SPECTATE, &[0, 0, 0, 42, 1]), Ok(Request::Spectate(42, true))));
+		assert!(matches!(parse_request(MSG_REJOIN, &[0, 0, 0, 42, 0, 0, 0, 7, 0, 0, 0, 9]),
+			Ok(Request::Rejoin { code: 42, room_token: 7, user_token: 9 })));
+		assert!(matches!(parse_request(MSG_LIST, &[]), Ok(Request::List)));
+
+		//Payloads of the wrong length, and messages that don't belong in the handshake
+		assert!(parse_request(MSG_CREATE, &[0, 0, 0, 0]).is_err());
+		assert!(parse_request(MSG_JOIN, &[0, 0, 42]).is_err());
+		assert!(parse_request(MSG_SPECTATE, &to_u32_bytes(42)).is_err());
+		assert!(parse_request(MSG_REJOIN, &to_u32_bytes(42)).is_err());
+		assert!(parse_request(MSG_EVENT, &[0; 19]).is_err());
+	}
+
+	#[test]
+	fn hosts_and_peers_enter_rooms() {
+		let mut rooms = HashMap::new();
+		let (host, host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		assert!(matches!(host.seat, Seat::Host));
+		assert!((1..=ROOM_CODES).contains(&host.code));
+		assert_eq!((host.user_token, host.room_token), (rooms[&host.code].host_token, rooms[&host.code].token));
+		assert!(host.history.is_empty());
+
+		let (peer, peer_frames) = enter(&mut rooms, ip(2), Request::Join(host.code)).unwrap();
+		assert!(matches!(peer.seat, Seat::Peer));
+		assert_eq!((peer.user_token, peer.room_token), (rooms[&host.code].peer_token, host.room_token));
+		assert!(peer.connection != host.connection);
+		assert_eq!(rooms[&host.code].peer_addr, Some(ip(2)));
+
+		//Both players hear that the game can start
+		assert!(events(&host_frames).iter().any(|event| event.action == EVENT_JOIN));
+		assert!(events(&peer_frames).iter().any(|event| event.action == EVENT_JOIN));
+
+		assert!(enter(&mut rooms, ip(3), Request::Join(host.code)).is_err());
+		assert!(enter(&mut rooms, ip(3), Request::Join(host.code % ROOM_CODES + 1)).is_err());
+		assert!(enter(&mut rooms, ip(3), Request::List).is_err());
+	}
+
+	#[test]
+	fn dropped_players_rejoin_where_they_left_off() {
+		let mut rooms = HashMap::new();
+		let (host, _host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		let (peer, _peer_frames) = enter(&mut rooms, ip(2), Request::Join(host.code)).unwrap();
+		let code = host.code;
+
+		//The peer drops out, and misses the host's turn
+		rooms.get_mut(&code).unwrap().disconnect(Seat::Peer, peer.connection);
+		assert!(rooms[&code].peer_outbox.is_none());
+		assert!(rooms[&code].is_connected());
+		let room = rooms.get_mut(&code).unwrap();
+		room.handle_event(Seat::Host, Event::create(EVENT_MOVE, 0, (1, 0), (2, 1), 0)).unwrap();
+		room.handle_event(Seat::Host, Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0)).unwrap();
+		assert!(room.peer_events.iter().any(|event| event.action == EVENT_END_TURN));
+
+		let rejoin = |room_token, user_token| Request::Rejoin { code, room_token, user_token };
+		assert!(enter(&mut rooms, ip(2), rejoin(peer.room_token.wrapping_add(1), peer.user_token)).is_err());
+		assert!(enter(&mut rooms, ip(2), rejoin(peer.room_token, peer.user_token.wrapping_add(1))).is_err());
+
+		let (rejoined, frames) = enter(&mut rooms, ip(2), rejoin(peer.room_token, peer.user_token)).unwrap();
+		assert!(matches!(rejoined.seat, Seat::Peer));
+		assert_eq!((rejoined.user_token, rejoined.room_token), (peer.user_token, peer.room_token));
+		//The history has what was queued, so it isn't sent twice
+		assert!(rejoined.history.iter().any(|(from_self, event)| !from_self && event.action == EVENT_END_TURN));
+		assert!(rooms[&code].peer_events.is_empty());
+		assert_eq!(events(&frames).iter().map(|event| event.action).collect::<Vec<u8>>(), vec![EVENT_JOIN]);
+
+		//The old connection closing late doesn't cut off the new one
+		rooms.get_mut(&code).unwrap().disconnect(Seat::Peer, peer.connection);
+		assert!(rooms[&code].peer_outbox.is_some());
+		rooms.get_mut(&code).unwrap().disconnect(Seat::Peer, rejoined.connection);
+		assert!(rooms[&code].peer_outbox.is_none());
+	}
+
+	#[test]
+	fn spectators_watch_one_side() {
+		let mut rooms = HashMap::new();
+		let (host, _host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		let code = host.code;
+		let (_watcher, watcher_frames) = enter(&mut rooms, ip(3), Request::Spectate(code, true)).unwrap();
+		let (_peer, _peer_frames) = enter(&mut rooms, ip(2), Request::Join(code)).unwrap();
+		rooms.get_mut(&code).unwrap().handle_event(Seat::Host, Event::create(EVENT_MOVE, 0, (1, 0), (2, 1), 0)).unwrap();
+
+		//Someone coming in late gets the game so far, and hears the game has started
+		let (late, late_frames) = enter(&mut rooms, ip(4), Request::Spectate(code, true)).unwrap();
+		assert!(matches!(late.seat, Seat::Spectator(true)));
+		assert_eq!((late.user_token, late.room_token), (0, 0));
+		let bytes = |history: &[(bool, Event)]| history.iter().map(|(from_self, event)| (*from_self, event.to_bytes())).collect::<Vec<_>>();
+		assert_eq!(bytes(&late.history), bytes(&rooms[&code].host_history));
+		assert_eq!(events(&late_frames).iter().map(|event| event.action).collect::<Vec<u8>>(), vec![EVENT_JOIN]);
+
+		//The watcher from the start saw the join and then the host's move, as the host saw it
+		let frames: Vec<(u8, Vec<u8>)> = watcher_frames.try_iter().collect();
+		assert_eq!(frames[0].0, MSG_EVENT);
+		assert!(frames.iter().any(|(msg, payload)| *msg == MSG_HISTORY && payload[0] == 1 && payload[1] == EVENT_MOVE));
+		assert!(rooms[&code].peer_history.iter().all(|(_, event)| event.action != EVENT_MOVE || event.from_pos != (1, 0)));
+
+		let room = rooms.get_mut(&code).unwrap();
+		assert!(room.handle_event(Seat::Spectator(true), Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0)).is_err());
+		assert!(room.chat(Seat::Spectator(true), b"hi".to_vec()).is_err());
+
+		room.disconnect(Seat::Spectator(true), late.connection);
+		assert_eq!(room.spectators.len(), 1);
+		assert!(enter(&mut rooms, ip(4), Request::Spectate(code % ROOM_CODES + 1, false)).is_err());
+	}
+
+	#[test]
+	fn chat_reaches_the_other_player_and_spectators() {
+		let mut rooms = HashMap::new();
+		let (host, host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		let (_peer, peer_frames) = enter(&mut rooms, ip(2), Request::Join(host.code)).unwrap();
+		let (_watcher, watcher_frames) = enter(&mut rooms, ip(3), Request::Spectate(host.code, false)).unwrap();
+		let chats = |frames: &Frames| frames.try_iter().filter(|(msg, _)| *msg == MSG_CHAT).map(|(_, payload)| payload).collect::<Vec<Vec<u8>>>();
+
+		let room = rooms.get_mut(&host.code).unwrap();
+		room.chat(Seat::Host, b"  good luck ".to_vec()).unwrap();
+		assert_eq!(chats(&peer_frames), vec![b"\x01good luck".to_vec()]);
+		assert_eq!(chats(&watcher_frames), vec![b"\x01good luck".to_vec()]);
+		assert!(chats(&host_frames).is_empty());
+
+		room.chat(Seat::Peer, "gg ✓".as_bytes().to_vec()).unwrap();
+		assert_eq!(chats(&host_frames), vec![[&[0][..], "gg ✓".as_bytes()].concat()]);
+
+		//Empty, too long or not text at all
+		assert!(room.chat(Seat::Host, b"   ".to_vec()).is_err());
+		assert!(room.chat(Seat::Host, "✓".repeat(MAX_CHAT_LENGTH).into_bytes()).is_ok());
+		assert!(room.chat(Seat::Host, "✓".repeat(MAX_CHAT_LENGTH + 1).into_bytes()).is_err());
+		assert!(room.chat(Seat::Host, vec![0xff, 0xfe]).is_err());
+
+		//Chat isn't held for players who aren't there
+		room.disconnect(Seat::Host, host.connection);
+		room.chat(Seat::Peer, b"hello?".to_vec()).unwrap();
+		assert!(room.host_events.is_empty());
+	}
+
+	#[test]
+	fn lobby_lists_open_public_rooms_newest_first() {
+		let mut rooms = HashMap::new();
+		let (old, _) = create(&mut rooms, ip(1), TimeControl { turn_secs: 60, match_secs: 0 });
+		let (new, _) = create(&mut rooms, ip(2), UNTIMED);
+		let (private, _) = enter(&mut rooms, ip(3), Request::Create { is_private: true, time_control: UNTIMED, host_name: String::new() }).unwrap();
+		let (finished, _) = create(&mut rooms, ip(4), UNTIMED);
+		enter(&mut rooms, ip(5), Request::Join(new.code)).unwrap();
+		rooms.get_mut(&old.code).unwrap().created -= Duration::from_secs(120);
+		rooms.get_mut(&finished.code).unwrap().game_map.winning_team = Some(Team::Player);
+
+		let list = room_list(&rooms);
+		assert_eq!(list.iter().map(|room| room.code).collect::<Vec<u32>>(), vec![new.code, old.code]);
+		assert_eq!((list[0].players, list[1].players), (2, 1));
+		assert_eq!((list[1].host_name.as_str(), list[1].map_name.as_str()), ("host", "Test"));
+		assert!(list[1].age_secs >= 120);
+		assert_eq!(list[1].time_control.turn_secs, 60);
+		assert!(!list.iter().any(|room| room.code == private.code));
+
+		//Only so many fit in the lobby
+		for n in 0..MAX_LISTED_ROOMS as u8 {
+			create(&mut rooms, ip(10 + n), UNTIMED);
+		}
+		assert_eq!(room_list(&rooms).len(), MAX_LISTED_ROOMS);
+	}
+
+	#[test]
+	fn rooms_are_limited_per_server_and_address() {
+		let mut rooms = HashMap::new();
+		for _ in 0..MAX_ROOMS_PER_IP {
+			create(&mut rooms, ip(1), UNTIMED);
+		}
+		assert!(enter(&mut rooms, ip(1), Request::Create { is_private: false, time_control: UNTIMED, host_name: String::new() }).is_err());
+		create(&mut rooms, ip(2), UNTIMED);
+
+		//A server that's full turns everyone away
+		let (outbox, _frames) = mpsc::channel();
+		let max_rooms = rooms.len();
+		let request = Request::Create { is_private: false, time_control: UNTIMED, host_name: String::new() };
+		assert!(enter_room(&mut rooms, ip(3), &test_map_data(), request, outbox, max_rooms).is_err());
+		assert_eq!(rooms.len(), MAX_ROOMS_PER_IP + 1);
+	}
+
+	#[test]
+	fn idle_and_finished_rooms_expire() {
+		let mut rooms = HashMap::new();
+		let (connected, _) = create(&mut rooms, ip(1), UNTIMED);
+		let (abandoned, _) = create(&mut rooms, ip(2), UNTIMED);
+		let (finished, _) = create(&mut rooms, ip(3), UNTIMED);
+		let (recent, _) = create(&mut rooms, ip(4), UNTIMED);
+		rooms.get_mut(&abandoned.code).unwrap().disconnect(Seat::Host, abandoned.connection);
+		rooms.get_mut(&recent.code).unwrap().disconnect(Seat::Host, recent.connection);
+		rooms.get_mut(&finished.code).unwrap().game_map.winning_team = Some(Team::Enemy);
+		for room in rooms.values_mut() {
+			room.last_active -= FINISHED_ROOM_TIMEOUT + Duration::from_secs(1);
+		}
+		rooms.get_mut(&recent.code).unwrap().last_active = Instant::now();
+
+		//A room with someone in it only goes once its game is over
+		assert_eq!(close_expired_rooms(&mut rooms), vec![finished.code]);
+		for room in rooms.values_mut() {
+			room.last_active -= IDLE_ROOM_TIMEOUT;
+		}
+		let mut expired = close_expired_rooms(&mut rooms);
+		expired.sort();
+		let mut idle = vec![abandoned.code, recent.code];
+		idle.sort();
+		assert_eq!(expired, idle);
+		assert_eq!(rooms.keys().collect::<Vec<&u32>>(), vec![&connected.code]);
+	}
+
+	#[test]
+	fn players_leaving_close_the_room() {
+		let mut rooms = HashMap::new();
+		let (host, host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		let (peer, _) = enter(&mut rooms, ip(2), Request::Join(host.code)).unwrap();
+		let (watcher, watcher_frames) = enter(&mut rooms, ip(3), Request::Spectate(host.code, true)).unwrap();
+
+		leave_room(&mut rooms, host.code, watcher.seat);
+		assert!(rooms.contains_key(&host.code));
+
+		leave_room(&mut rooms, host.code, peer.seat);
+		assert!(rooms.is_empty());
+		let reasons = |frames: &Frames| frames.try_iter().filter(|(msg, _)| *msg == MSG_LEAVE).map(|(_, payload)| String::from_utf8(payload).unwrap()).collect::<Vec<String>>();
+		assert_eq!(reasons(&host_frames), vec!["Your opponent left the room"]);
+		assert_eq!(reasons(&watcher_frames), vec!["Your opponent left the room"]);
+	}
+
+	#[test]
+	fn admin_commands() {
+		let mut rooms = HashMap::new();
+		let (host, _host_frames) = create(&mut rooms, ip(1), UNTIMED);
+		let (banned, banned_frames) = create(&mut rooms, ip(2), UNTIMED);
+		enter(&mut rooms, ip(3), Request::Create { is_private: true, time_control: UNTIMED, host_name: String::from("other") }).unwrap();
+		let (rooms, stats, bans) = (Mutex::new(rooms), Stats::new(), Mutex::new(HashSet::new()));
+		stats.events_relayed.fetch_add(5, Ordering::Relaxed);
+		let run = |line: &str| admin_command(&line.split_whitespace().collect::<Vec<&str>>(), &rooms, &stats, &bans).unwrap();
+
+		let report = run("stats");
+		assert!(report.contains("\nrooms 3\n") && report.contains("\nplayers 3\n") && report.contains("\nevents relayed 5\n"), "{}", report);
+		let listing = run("rooms");
+		assert_eq!(listing.lines().count(), 3);
+		assert!(listing.contains(&format!("{:04} host host (10.0.0.1) players 1/1", host.code)), "{}", listing);
+		assert!(listing.contains(" private "));
+
+		assert_eq!(run("ban 10.0.0.2"), "banned 10.0.0.2, closed 1 rooms");
+		assert!(!rooms.lock().unwrap().contains_key(&banned.code));
+		assert!(banned_frames.try_iter().any(|(msg, _)| msg == MSG_LEAVE));
+		assert_eq!(run("bans"), "10.0.0.2");
+		assert_eq!(run("unban 10.0.0.2"), "unbanned 10.0.0.2");
+		assert_eq!(run("unban 10.0.0.2"), "10.0.0.2 is not banned");
+		assert_eq!(run("bans"), "no bans");
+
+		assert_eq!(run(&format!("close {}", host.code)), format!("closed room {:04}", host.code));
+		assert_eq!(run(&format!("close {}", host.code)), format!("no room {:04}", host.code));
+		assert_eq!(rooms.lock().unwrap().len(), 1);
+
+		//Anything it doesn't understand gets the help
+		assert_eq!(run("close"), ADMIN_HELP);
+		assert_eq!(run("close abc"), "invalid room code");
+		assert_eq!(run("ban nowhere"), "invalid address");
+		assert_eq!(run("help"), ADMIN_HELP);
+	}
+
+	#[test]
+	fn timed_turns_are_clocked() {
+		let mut rooms = HashMap::new();
+		let (untimed, _) = create(&mut rooms, ip(1), UNTIMED);
+		enter(&mut rooms, ip(2), Request::Join(untimed.code)).unwrap();
+		assert!(rooms[&untimed.code].turn_started.is_none());
+		assert!(rooms[&untimed.code].clock().is_none());
+
+		let (host, host_frames) = create(&mut rooms, ip(1), TimeControl { turn_secs: 60, match_secs: 600 });
+		let code = host.code;
+		assert!(rooms[&code].turn_started.is_none());
+		let (_peer, peer_frames) = enter(&mut rooms, ip(2), Request::Join(code)).unwrap();
+		assert!(host_frames.try_iter().any(|(msg, _)| msg == MSG_CLOCK));
+		assert!(peer_frames.try_iter().any(|(msg, _)| msg == MSG_CLOCK));
+
+		//The host takes 100s over their turn, which comes off their match time
+		let room = rooms.get_mut(&code).unwrap();
+		assert_eq!(room.turn_limit(), Duration::from_secs(60));
+		room.turn_started = Some(Instant::now() - Duration::from_secs(100));
+		room.handle_event(Seat::Host, Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0)).unwrap();
+		assert!(room.game_map.player_state.current_turn == Team::Enemy);
+		assert!(room.host_time <= Duration::from_secs(500) && room.host_time > Duration::from_secs(490));
+		assert_eq!(room.peer_time, Duration::from_secs(600));
+		let clock = room.clock().unwrap();
+		assert!(clock.turn_ms <= 60_000 && clock.turn_ms > 50_000);
+		assert_eq!((clock.host_ms / 1000, clock.peer_ms), (room.host_time.as_millis() as u32 / 1000, 600_000));
+
+		//Once the match time is nearly gone, it's all the turn gets
+		room.peer_time = Duration::from_secs(5);
+		assert_eq!(room.turn_limit(), Duration::from_secs(5));
+	}
+
+	#[test]
+	fn turns_end_when_the_time_runs_out() {
+		let mut rooms = HashMap::new();
+		let (host, host_frames) = create(&mut rooms, ip(1), TimeControl { turn_secs: 30, match_secs: 0 });
+		let (_peer, peer_frames) = enter(&mut rooms, ip(2), Request::Join(host.code)).unwrap();
+		let room = rooms.get_mut(&host.code).unwrap();
+		assert_eq!(room.clock().unwrap().host_ms, NO_TIME_LIMIT);
+
+		//Not while the grace period lasts
+		room.turn_started = Some(Instant::now() - Duration::from_secs(31));
+		room.check_clock().unwrap();
+		assert!(room.game_map.player_state.current_turn == Team::Player);
+
+		room.turn_started = Some(Instant::now() - Duration::from_secs(30) - CLOCK_GRACE);
+		room.check_clock().unwrap();
+		assert!(room.game_map.player_state.current_turn == Team::Enemy);
+		assert!(room.turn_started.unwrap().elapsed() < Duration::from_secs(1));
+		//Neither client sent the end of the turn, so both are told about it
+		assert!(events(&host_frames).iter().any(|event| event.action == EVENT_END_TURN && event.id == EVENT_ID_PLAYER));
+		assert!(events(&peer_frames).iter().any(|event| event.action == EVENT_END_TURN && event.id == EVENT_ID_PLAYER));
+
+		//A finished game's clock stops
+		room.game_map.winning_team = Some(Team::Enemy);
+		room.turn_started = Some(Instant::now() - Duration::from_secs(60));
+		room.check_clock().unwrap();
+		assert!(room.game_map.player_state.current_turn == Team::Enemy);
+	}
+}
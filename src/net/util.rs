@@ -1,7 +1,42 @@
-pub const MSG_CREATE: u8 = 0; // create a new room
-pub const MSG_JOIN: u8 = 1;   // join an existing room
-pub const MSG_EVENT: u8 = 2;  // push an event to a room
-pub const MSG_POLL: u8 = 3;   // poll for new events in a room
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// bumped whenever the framing or message layout changes, so old clients are turned away during the handshake
+pub const PROTOCOL_VERSION: u32 = 10;
+
+// heartbeats are sent whenever a connection has been idle for HEARTBEAT_INTERVAL;
+// a connection that hears nothing for HEARTBEAT_TIMEOUT is considered dead
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const MSG_CREATE: u8 = 0;    // create a new room (payload: u8 private + 4 byte TimeControl + utf-8 host name)
+pub const MSG_JOIN: u8 = 1;      // join an existing room (payload: u32 room code)
+pub const MSG_EVENT: u8 = 2;     // an event, in either direction (payload: 19 byte event)
+pub const MSG_HEARTBEAT: u8 = 3; // keeps an idle connection alive
+pub const MSG_HELLO: u8 = 4;     // first message from a client (payload: u32 PROTOCOL_VERSION)
+pub const MSG_WELCOME: u8 = 5;   // reply to create/join/rejoin (payload: u32 user token + u32 room code + u32 room token + u8 is host)
+pub const MSG_ERROR: u8 = 6;     // utf-8 error message from the server
+pub const MSG_REJOIN: u8 = 7;    // rejoin a room after losing connection (payload: u32 room code + u32 room token + u32 user token)
+pub const MSG_HISTORY: u8 = 8;   // an event with who sent it, for rejoining clients and spectators (payload: u8 sent by this client/the watched player + 19 byte event)
+pub const MSG_SPECTATE: u8 = 9;  // watch a room without playing (payload: u32 room code + u8 watch from the host's side)
+pub const MSG_CHAT: u8 = 10;     // chat message (payload from a client: utf-8 text; from the server: u8 sent by the host + utf-8 text)
+pub const MSG_LIST: u8 = 11;     // ask for the public rooms; the server replies with MSG_ROOMS and closes the connection
+pub const MSG_ROOMS: u8 = 12;    // the public rooms (payload: RoomInfo list, see RoomInfo::encode_list)
+pub const MSG_LEAVE: u8 = 13;    // from a client: leaving the room for good; from the server: the room was closed (payload: utf-8 reason)
+pub const MSG_CLOCK: u8 = 14;    // the clocks of a timed room, sent whenever a turn starts (payload: 12 byte Clock)
+
+pub const MAX_CHAT_LENGTH: usize = 40; // in characters, so a message fits the chat box
+pub const MAX_NAME_LENGTH: usize = 16; // in characters
+pub const MAX_LISTED_ROOMS: usize = 50;
+
+// the time controls a room can be created with: (name, seconds per turn, seconds per player for the whole match), 0 = no limit
+pub const TIME_CONTROLS: [(&str, u16, u16); 4] = [
+	("No Timer", 0, 0),
+	("2 Min Turns", 120, 0),
+	("Rapid", 60, 20 * 60),
+	("Blitz", 30, 10 * 60),
+];
+pub const NO_TIME_LIMIT: u32 = u32::MAX;
 
 pub const EVENT_NONE: u8 = 0; // there are no events to poll
 pub const EVENT_JOIN: u8 = 1; // a player has joined the room
@@ -9,12 +44,16 @@ pub const EVENT_MOVE: u8 = 2;
 pub const EVENT_ATTACK: u8 = 3;
 pub const EVENT_END_TURN: u8 = 4;
 pub const EVENT_END_GAME: u8 = 5;
-pub const EVENT_SPAWN_UNIT: u8 = 6;
+pub const EVENT_SPAWN_UNIT: u8 = 6; // a converted unit joins the team in id: value is its kind and to_pos where it spawns
+pub const EVENT_CONVERT: u8 = 7; // the server rolled a successful barbarian conversion for the team in id
+pub const EVENT_REVEAL: u8 = 8; // a unit came into sight: id is its team, value its kind, to_pos where it is and from_pos (hp, 1 if moved + 2 if attacked)
+pub const EVENT_HIDE: u8 = 9; // the unit at from_pos went out of sight
 
 pub const EVENT_ID_ENEMY: u8 = 0;
 pub const EVENT_ID_PLAYER: u8 = 1;
 pub const EVENT_ID_BARBARIAN: u8 = 2;
 
+// ids of the classes in data/unit_classes.txt that the game refers to directly
 pub const EVENT_UNIT_ARCHER: u8 = 0;
 pub const _EVENT_UNIT_GUARD: u8 = 1;
 pub const EVENT_UNIT_MAGE: u8 = 2;
@@ -84,6 +123,15 @@ impl Event {
 		}
 	}
 
+	pub fn from_payload(payload: &[u8]) -> Result<Event, String> {
+		if payload.len() != 19 {
+			return Err(format!("Invalid event: expected 19 bytes but got {}", payload.len()));
+		}
+		let mut arr = [0; 19];
+		arr.copy_from_slice(payload);
+		Ok(Event::from_bytes(&arr))
+	}
+
 	pub fn to_bytes(&self) -> [u8; 19] {
 		let mut arr = [0; 19];
 		arr[0] = self.action;
@@ -100,6 +148,128 @@ impl Event {
 	}
 }
 
+// Time limits agreed when a room is created, in seconds (0 = no limit).
+// Turns that run out are ended by the server; once a player's match time is used up, each of their turns ends straight away.
+#[derive(Copy, Clone, PartialEq)]
+pub struct TimeControl {
+	pub turn_secs: u16,
+	pub match_secs: u16,
+}
+
+impl TimeControl {
+	pub fn from_bytes(arr: &[u8]) -> TimeControl {
+		TimeControl {
+			turn_secs: (arr[0] as u16) << 8 | arr[1] as u16,
+			match_secs: (arr[2] as u16) << 8 | arr[3] as u16,
+		}
+	}
+
+	pub fn to_bytes(self) -> [u8; 4] {
+		[(self.turn_secs >> 8) as u8, self.turn_secs as u8, (self.match_secs >> 8) as u8, self.match_secs as u8]
+	}
+
+	pub fn is_timed(&self) -> bool {
+		self.turn_secs > 0 || self.match_secs > 0
+	}
+
+	// e.g. "60s turns, 20 min each"
+	pub fn describe(&self) -> String {
+		match (self.turn_secs, self.match_secs) {
+			(0, 0) => String::from("no timer"),
+			(turn, 0) => format!("{}s turns", turn),
+			(0, total) => format!("{} min each", total / 60),
+			(turn, total) => format!("{}s turns, {} min each", turn, total / 60),
+		}
+	}
+}
+
+// The clocks of a timed room in milliseconds, NO_TIME_LIMIT for a clock the room doesn't have
+#[derive(Copy, Clone)]
+pub struct Clock {
+	pub turn_ms: u32, // left in the current turn before the server ends it
+	// left on each player's match clock at the start of the current turn
+	pub host_ms: u32,
+	pub peer_ms: u32,
+}
+
+impl Clock {
+	pub fn from_payload(payload: &[u8]) -> Result<Clock, String> {
+		if payload.len() != 12 {
+			return Err(format!("Invalid clock: expected 12 bytes but got {}", payload.len()));
+		}
+		Ok(Clock {
+			turn_ms: from_u32_bytes(&payload[0..4]),
+			host_ms: from_u32_bytes(&payload[4..8]),
+			peer_ms: from_u32_bytes(&payload[8..12]),
+		})
+	}
+
+	pub fn to_bytes(self) -> [u8; 12] {
+		let mut arr = [0; 12];
+		set_range!(arr[0..4] = to_u32_bytes(self.turn_ms));
+		set_range!(arr[4..8] = to_u32_bytes(self.host_ms));
+		set_range!(arr[8..12] = to_u32_bytes(self.peer_ms));
+		arr
+	}
+}
+
+// A room as shown in the lobby
+pub struct RoomInfo {
+	pub code: u32,
+	pub host_name: String,
+	pub map_name: String,
+	pub players: u8,
+	pub age_secs: u32, // time since the room was created
+	pub time_control: TimeControl,
+}
+
+impl RoomInfo {
+	// each room: u32 code + u8 players + u32 age + 4 byte TimeControl + u8 length + host name + u8 length + map name
+	pub fn encode_list(rooms: &[RoomInfo]) -> Vec<u8> {
+		let mut payload = Vec::new();
+		for room in rooms {
+			payload.extend_from_slice(&to_u32_bytes(room.code));
+			payload.push(room.players);
+			payload.extend_from_slice(&to_u32_bytes(room.age_secs));
+			payload.extend_from_slice(&room.time_control.to_bytes());
+			for name in [&room.host_name, &room.map_name].iter() {
+				let bytes = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+				payload.push(bytes.len() as u8);
+				payload.extend_from_slice(bytes);
+			}
+		}
+		payload
+	}
+
+	pub fn decode_list(payload: &[u8]) -> Result<Vec<RoomInfo>, String> {
+		let invalid = || String::from("Invalid room list from server");
+		let mut rooms = Vec::new();
+		let mut i = 0;
+		while i < payload.len() {
+			let fixed = payload.get(i..i + 13).ok_or_else(invalid)?;
+			i += 13;
+
+			let mut names = Vec::new();
+			for _ in 0..2 {
+				let len = *payload.get(i).ok_or_else(invalid)? as usize;
+				let name = payload.get(i + 1..i + 1 + len).ok_or_else(invalid)?;
+				names.push(String::from_utf8_lossy(name).to_string());
+				i += 1 + len;
+			}
+
+			rooms.push(RoomInfo {
+				code: from_u32_bytes(&fixed[0..4]),
+				players: fixed[4],
+				age_secs: from_u32_bytes(&fixed[5..9]),
+				time_control: TimeControl::from_bytes(&fixed[9..13]),
+				map_name: names.pop().unwrap(),
+				host_name: names.pop().unwrap(),
+			});
+		}
+		Ok(rooms)
+	}
+}
+
 impl std::fmt::Display for Event {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		let action = match self.action {
@@ -110,6 +280,9 @@ impl std::fmt::Display for Event {
 			EVENT_END_TURN => "end turn",
 			EVENT_END_GAME => "end game",
 			EVENT_SPAWN_UNIT => "spawn unit",
+			EVENT_CONVERT => "convert",
+			EVENT_REVEAL => "reveal",
+			EVENT_HIDE => "hide",
 			_ => "unknown",
 		};
 
@@ -132,3 +305,103 @@ pub fn from_u32_bytes(arr: &[u8]) -> u32 {
 	| (arr[2] as u32) << 8
 	| (arr[3] as u32)
 }
+
+// Messages are sent as frames: 1 byte (MSG_ type) + 2 bytes (u16 payload length) + payload
+pub fn write_frame<W: Write>(stream: &mut W, msg: u8, payload: &[u8]) -> Result<(), String> {
+	if payload.len() > u16::MAX as usize {
+		return Err(String::from("Frame payload is too large"));
+	}
+
+	let mut frame = Vec::with_capacity(payload.len() + 3);
+	frame.push(msg);
+	frame.push((payload.len() >> 8) as u8);
+	frame.push(payload.len() as u8);
+	frame.extend_from_slice(payload);
+
+	stream.write_all(&frame).map_err(|e| format!("Could not write frame: {}", e))?;
+	stream.flush().map_err(|e| format!("Could not flush stream: {}", e))
+}
+
+pub fn read_frame<R: Read>(stream: &mut R) -> Result<(u8, Vec<u8>), String> {
+	let mut header = [0; 3];
+	stream.read_exact(&mut header).map_err(|e| format!("Could not read frame: {}", e))?;
+
+	let mut payload = vec![0; ((header[1] as usize) << 8) | header[2] as usize];
+	stream.read_exact(&mut payload).map_err(|e| format!("Could not read frame payload: {}", e))?;
+
+	Ok((header[0], payload))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frames_round_trip() {
+		let mut stream = Vec::new();
+		write_frame(&mut stream, MSG_EVENT, &Event::create(EVENT_MOVE, 0, (1, 0), (300, 2), 7).to_bytes()).unwrap();
+		write_frame(&mut stream, MSG_HEARTBEAT, &[]).unwrap();
+		write_frame(&mut stream, MSG_CHAT, &[b'x'; 300]).unwrap();
+		assert_eq!(stream[..3], [MSG_EVENT, 0, 19]);
+
+		let mut reader = &stream[..];
+		let (msg, payload) = read_frame(&mut reader).unwrap();
+		assert_eq!(msg, MSG_EVENT);
+		let event = Event::from_payload(&payload).unwrap();
+		assert_eq!((event.action, event.from_pos, event.to_pos, event.value), (EVENT_MOVE, (1, 0), (300, 2), 7));
+		assert!(!event.from_self);
+		assert_eq!(read_frame(&mut reader).unwrap(), (MSG_HEARTBEAT, Vec::new()));
+		assert_eq!(read_frame(&mut reader).unwrap(), (MSG_CHAT, vec![b'x'; 300]));
+		assert!(reader.is_empty());
+	}
+
+	#[test]
+	fn rejects_bad_frames() {
+		assert!(write_frame(&mut Vec::new(), MSG_CHAT, &vec![0; u16::MAX as usize + 1]).is_err());
+
+		//A connection that drops partway through a header or payload
+		assert!(read_frame(&mut &[MSG_EVENT, 0][..]).is_err());
+		assert!(read_frame(&mut &[MSG_EVENT, 0, 19, 1, 2, 3][..]).is_err());
+
+		assert!(Event::from_payload(&[0; 18]).is_err());
+		assert!(Event::from_payload(&[0; 20]).is_err());
+	}
+
+	#[test]
+	fn clocks_round_trip() {
+		let time_control = TimeControl { turn_secs: 90, match_secs: 1200 };
+		assert!(TimeControl::from_bytes(&time_control.to_bytes()) == time_control);
+		assert_eq!(time_control.describe(), "90s turns, 20 min each");
+		assert!(!TimeControl { turn_secs: 0, match_secs: 0 }.is_timed());
+
+		let clock = Clock::from_payload(&Clock { turn_ms: 1500, host_ms: NO_TIME_LIMIT, peer_ms: 70_000 }.to_bytes()).unwrap();
+		assert_eq!((clock.turn_ms, clock.host_ms, clock.peer_ms), (1500, NO_TIME_LIMIT, 70_000));
+		assert!(Clock::from_payload(&[0; 11]).is_err());
+	}
+
+
+	#[test]
+	fn room_lists_round_trip() {
+		let rooms = vec![
+			RoomInfo { code: 42, host_name: String::from("Ann"), map_name: String::from("Test"), players: 1, age_secs: 30, time_control: TimeControl { turn_secs: 60, match_secs: 0 } },
+			RoomInfo { code: 9999, host_name: String::new(), map_name: String::from("Höhe"), players: 2, age_secs: 0, time_control: TimeControl { turn_secs: 0, match_secs: 0 } },
+		];
+		let payload = RoomInfo::encode_list(&rooms);
+		let decoded = RoomInfo::decode_list(&payload).unwrap();
+
+		assert_eq!(decoded.len(), 2);
+		assert_eq!((decoded[0].code, decoded[0].host_name.as_str(), decoded[0].map_name.as_str()), (42, "Ann", "Test"));
+		assert_eq!((decoded[0].players, decoded[0].age_secs), (1, 30));
+		assert!(decoded[0].time_control == rooms[0].time_control);
+		assert_eq!((decoded[1].code, decoded[1].host_name.as_str(), decoded[1].map_name.as_str()), (9999, "", "Höhe"));
+		assert!(RoomInfo::decode_list(&[]).unwrap().is_empty());
+
+		//Cut off anywhere, the list is rejected rather than read past its end
+		let first = RoomInfo::encode_list(&rooms[..1]).len();
+		for len in 1..payload.len() {
+			if len != first {
+				assert!(RoomInfo::decode_list(&payload[..len]).is_err(), "accepted {} bytes", len);
+			}
+		}
+	}
+}
@@ -1,9 +1,12 @@
 use std::collections::HashMap;
 
+use crate::player_state::PlayerState;
 use crate::unit::{Team, Unit};
 
 const TURNS_TO_CAPTURE: u32 = 3;
+const RESPAWN_DISTANCE: u32 = 5; //How far behind its castle a team's converted units spawn
 
+#[derive(Clone)]
 pub struct ObjectiveManager {
     pub p1_castle: (u32, u32),
     pub p1_castle_turns: u32,
@@ -14,7 +17,9 @@ pub struct ObjectiveManager {
     pub p2_takeovers: (u32, u32),
 
     pub barbarian_camps: Vec<(u32, u32)>,
+    pub forts: Vec<(u32, u32)>, //Subset of the camps which are forts, these heal twice as much once taken over
     pub taken_over_camps: Vec<((u32, u32), Team)>,
+    pub captured_camps: Vec<((u32, u32), Team)>, //Camps that have already been taken over, and by whom
     pub barbarian_camps_turns: HashMap<(u32, u32), u32>, //Keeps track of how many consecutive turns each camp has been occupied
     pub barbarian_camps_teams: HashMap<(u32, u32), Option<Team>>, //Keeps track of which team is occupying each camp
 }
@@ -34,13 +39,15 @@ impl ObjectiveManager {
             p2_castle_turns: 0,
             p2_takeovers: (0, 0),
             barbarian_camps: Vec::new(),
+            forts: Vec::new(),
             taken_over_camps: Vec::new(),
+            captured_camps: Vec::new(),
             barbarian_camps_turns: HashMap::new(),
             barbarian_camps_teams: HashMap::new(),
         }
     }
 
-    pub fn new(p1_castle_location: (u32, u32), p2_castle_location: (u32, u32), barb_camp_locations: Vec<(u32, u32)>) -> ObjectiveManager {
+    pub fn new(p1_castle_location: (u32, u32), p2_castle_location: (u32, u32), barb_camp_locations: Vec<(u32, u32)>, fort_locations: Vec<(u32, u32)>) -> ObjectiveManager {
         let mut barbarian_camps_turns: HashMap<(u32, u32), u32> = HashMap::new();
         let mut barbarian_camps_teams: HashMap<(u32, u32), Option<Team>> = HashMap::new();
 
@@ -57,13 +64,29 @@ impl ObjectiveManager {
             p2_castle_turns: 0,
             p2_takeovers: (0, 0),
             barbarian_camps: barb_camp_locations,
+            forts: fort_locations,
             taken_over_camps: Vec::new(),
+            captured_camps: Vec::new(),
             barbarian_camps_turns,
             barbarian_camps_teams,
         };
     }
 
-    pub fn check_objectives<'a>(&mut self, team: Team, team_units: &HashMap<(u32, u32), Unit<'a>>) {
+    //Where the (client relative) team's converted units spawn as close to as they can (see unit::respawn_loc): a few
+    //tiles behind its castle, which is down & left for player 1 and up & right for player 2. Every client, the server and
+    //the AI have to agree on this, so it's worked out from which player the team really is
+    pub fn respawn_target(&self, team: Team, player_state: &PlayerState) -> (u32, u32) {
+        let castle = match team {
+            Team::Player => self.p1_castle,
+            _ => self.p2_castle,
+        };
+        match team.as_client(player_state) {
+            Team::Player => (castle.0.saturating_sub(RESPAWN_DISTANCE), castle.1 + RESPAWN_DISTANCE),
+            _ => (castle.0 + RESPAWN_DISTANCE, castle.1.saturating_sub(RESPAWN_DISTANCE)),
+        }
+    }
+
+    pub fn check_objectives(&mut self, team: Team, team_units: &HashMap<(u32, u32), Unit>) {
         //Check if enemy is occupying player castle
         if team == Team::Enemy {
             match team_units.get(&self.p1_castle) {
@@ -132,7 +155,7 @@ impl ObjectiveManager {
         }
     }
 
-    fn camp_is_occupied_by_team<'a>(camp_coord: &(u32, u32), team_units: &HashMap<(u32, u32), Unit<'a>>) -> bool {
+    fn camp_is_occupied_by_team(camp_coord: &(u32, u32), team_units: &HashMap<(u32, u32), Unit>) -> bool {
         //Since camps are 2x2, have to check each tile in the camp for a unit
         //camp_coord is the coordinates of the top left tile in the camp
         return  team_units.contains_key(&camp_coord) ||
@@ -1,9 +1,11 @@
 use crate::player_action::PlayerAction;
 use crate::unit::Team;
 
+#[derive(Clone)]
 pub struct PlayerState {
     pub team: Team,
     pub current_turn: Team,
+    pub round: u32, //Incremented each time every team has taken a turn
 
     //Matrix coordinates of the currently selected unit. When these are both equal to -1, no unit is selected
     pub active_unit_i: i32,
@@ -18,6 +20,7 @@ impl PlayerState {
         PlayerState {
             team,
             current_turn: Team::Player,
+            round: 1,
             active_unit_i: -1,
             active_unit_j: -1,
             current_player_action: PlayerAction::Default,
@@ -28,7 +31,10 @@ impl PlayerState {
         match self.current_turn {
             Team::Player => self.current_turn = Team::Enemy,
             Team::Enemy => self.current_turn = Team::Barbarians,
-            Team::Barbarians => self.current_turn = Team::Player,
+            Team::Barbarians => {
+                self.current_turn = Team::Player;
+                self.round += 1;
+            },
         }
 
         self.current_turn
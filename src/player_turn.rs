@@ -5,6 +5,7 @@ use sdl2::keyboard::Keycode;
 use std::convert::TryInto;
 
 use crate::game_map::GameMap;
+use crate::map_view::MapView;
 use crate::pixel_coordinates::PixelCoordinates;
 use crate::player_action::PlayerAction;
 use crate::SDLCore;
@@ -12,8 +13,9 @@ use crate::unit::Team;
 use crate::unit_interface::UnitInterface;
 use crate::net::util::*;
 
-pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) -> Result<(), String> {
-    if game_map.banner.banner_visible {
+pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap, view: &mut MapView<'a>) -> Result<(), String> {
+    //Wait for the banner to finish, and in multiplayer for the server to resolve the last attack
+    if view.banner.banner_visible || game_map.awaiting_server {
         return Ok(());
     }
 
@@ -22,16 +24,16 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
         PlayerAction::ChoosingNewUnit => false,
         _ => true,
     }{
-        end_player_turn(game_map);
+        end_player_turn(game_map, view);
         return Ok(());
     }
 
     //Check if user clicked the end turn button
-    if core.input.left_clicked && game_map.end_turn_button.is_mouse(core) && match game_map.player_state.current_player_action {
+    if core.input.left_clicked && view.end_turn_button.is_mouse(core) && match game_map.player_state.current_player_action {
         PlayerAction::ChoosingNewUnit => false,
         _ => true,
     }{
-        end_player_turn(game_map);
+        end_player_turn(game_map, view);
         return Ok(());
     }
 
@@ -60,7 +62,7 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
                         game_map.player_state.active_unit_j = j as i32;
 
                         //If the user did click on a unit, allow the player to move the unit
-                        game_map.unit_interface = Some(UnitInterface::from_unit(active_unit, core.texture_map.get("unit_interface").unwrap()));
+                        view.unit_interface = Some(UnitInterface::from_unit(active_unit, core.texture_map.get("unit_interface").unwrap()));
                         game_map.player_state.current_player_action = PlayerAction::ChoosingUnitAction;
                     }
                 },
@@ -71,26 +73,31 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
             if core.input.left_clicked {
                 // Handle clicking based on unit interface
                 let active_unit = game_map.player_units.get(&(game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32)).unwrap();
-                game_map.player_state.current_player_action = game_map.unit_interface.as_ref().unwrap().get_click_selection(glob_x, glob_y);
+                game_map.player_state.current_player_action = view.unit_interface.as_ref().unwrap().get_click_selection(glob_x, glob_y);
                 match game_map.player_state.current_player_action {
                     PlayerAction::Default => {
                         // Deselect the active unit
                         game_map.player_state.active_unit_i = -1;
                         game_map.player_state.active_unit_j = -1;
                         // Close interface
-                        game_map.unit_interface.as_mut().unwrap().animate_close();
+                        view.unit_interface.as_mut().unwrap().animate_close();
                     },
                     PlayerAction::ChoosingUnitAction => {},
                     PlayerAction::MovingUnit => {
-                        game_map.possible_moves = active_unit.get_tiles_in_movement_range(&mut game_map.map_tiles);
+                        view.possible_moves = active_unit.get_tiles_in_movement_range(&mut game_map.map_tiles);
                         // Close interface
-                        game_map.unit_interface.as_mut().unwrap().animate_close();
+                        view.unit_interface.as_mut().unwrap().animate_close();
                     },
                     PlayerAction::AttackingUnit => {
-                        game_map.possible_attacks = active_unit.get_tiles_in_attack_range(&mut game_map.map_tiles);
-                        game_map.actual_attacks = active_unit.get_tiles_can_attack(&mut game_map.map_tiles);
+                        view.possible_attacks = active_unit.get_tiles_in_attack_range(&mut game_map.map_tiles);
+                        view.actual_attacks = active_unit.get_tiles_can_attack(&mut game_map.map_tiles);
+                        // Units hidden by the fog can't be targeted
+                        if game_map.fog_of_war {
+                            let visible = game_map.visible_tiles(Team::Player);
+                            view.actual_attacks.retain(|pos| visible.contains(pos));
+                        }
                         // Close interface
-                        game_map.unit_interface.as_mut().unwrap().animate_close();
+                        view.unit_interface.as_mut().unwrap().animate_close();
                     },
                     _ => {},
                 }
@@ -105,7 +112,7 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
             }
             else if core.input.left_clicked {
                 // Ensure valid tile to move to
-                if game_map.possible_moves.contains(&(j,i)) {
+                if view.possible_moves.contains(&(j,i)) {
                     game_map.event_list.push(Event::create(EVENT_MOVE, 0, (game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32), (j, i), 0));
                 }
 
@@ -124,20 +131,21 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
             } else if core.input.left_clicked {
                 // Attack unit clicked on
                 // The player should only be able to attack if the tile they clicked on contains an opposing unit within their range
-                if game_map.actual_attacks.contains(&(j, i)) {
-                    let active_unit = game_map.get_unit(&(game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32))?;
+                if view.actual_attacks.contains(&(j, i)) {
+                    let active_pos = (game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32);
+                    let atk_damage = game_map.roll_attack_damage(&active_pos, &(j, i))?;
                     let atk_unit = game_map.get_unit(&(j, i))?;
                     let atk_team = atk_unit.team;
-                    let atk_damage = active_unit.get_attack_damage(atk_unit);
                     let atk_kill = atk_unit.hp <= atk_damage;
                     println!("Player: Attacking unit at {:?} with {} damage.", (j, i), atk_damage);
 
                     game_map.event_list.push(Event::create(EVENT_ATTACK, 0, (game_map.player_state.active_unit_j as u32, game_map.player_state.active_unit_i as u32), (j, i), atk_damage as u8));
 
                     // If the attacked unit was a barbarian, and died on this turn...
-                    if atk_kill && atk_team == Team::Barbarians {
+                    // (in multiplayer the server decides this instead and sends an EVENT_CONVERT)
+                    if atk_kill && atk_team == Team::Barbarians && !game_map.server_authoritative {
                         //Need to check and see if this barbarian was converted - currently a 45% chance
-                        let chance = rand::thread_rng().gen_range(0..100);
+                        let chance = game_map.rng.gen_range(0..100);
                         if chance < 45 {
                             game_map.player_state.current_player_action = PlayerAction::ChoosePrimer;
                         }
@@ -155,15 +163,16 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
                 }                }
             }
             PlayerAction::ChoosePrimer => {
-                game_map.choose_unit_interface = Some(UnitInterface::from_conversion(core, core.texture_map.get("unit_interface").unwrap()));
+                view.choose_unit_interface = Some(UnitInterface::from_conversion(core, core.texture_map.get("unit_interface").unwrap()));
                 game_map.player_state.current_player_action = PlayerAction::ChoosingNewUnit;
             }
             PlayerAction::ChoosingNewUnit => {
                 let castle_coord = &game_map.objectives.p1_castle;
                 if core.input.left_clicked {
                     // Handle clicking based on unit interface
-                    game_map.player_state.current_player_action = game_map.choose_unit_interface.as_ref().unwrap().get_choose_unit_click_selection(glob_x, glob_y);
-                    let respawn_location = crate::unit::respawn_loc((castle_coord.0-5, castle_coord.1+5), &mut game_map.map_tiles, *castle_coord);
+                    game_map.player_state.current_player_action = view.choose_unit_interface.as_ref().unwrap().get_choose_unit_click_selection(glob_x, glob_y);
+                    let respawn_target = game_map.objectives.respawn_target(Team::Player, &game_map.player_state);
+                    let respawn_location = crate::unit::respawn_loc(respawn_target, &mut game_map.map_tiles, *castle_coord);
                     let unit_id = match game_map.player_state.current_player_action {
                         PlayerAction::ChosenRanger => EVENT_UNIT_ARCHER,
                         PlayerAction::ChosenMelee => EVENT_UNIT_MELEE,
@@ -172,9 +181,10 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
                     };
 
                     if unit_id != 100 {
-                        game_map.event_list.push(Event::create(EVENT_SPAWN_UNIT, unit_id, (0,0), respawn_location, unit_id));
+                        let team_id = Team::Player.as_client(&game_map.player_state).to_id();
+                        game_map.event_list.push(Event::create(EVENT_SPAWN_UNIT, team_id, (0,0), respawn_location, unit_id));
 
-                        game_map.choose_unit_interface.as_mut().unwrap().animate_close();
+                        view.choose_unit_interface.as_mut().unwrap().animate_close();
                         game_map.player_state.current_player_action = PlayerAction::Default;
                     }
                 }
@@ -185,15 +195,19 @@ pub fn handle_player_turn<'a>(core: &SDLCore<'a>, game_map: &mut GameMap<'a>) ->
         Ok(())
     }
 
-    pub fn end_player_turn<'a>(game_map: &mut GameMap<'a>) {
+    pub fn end_player_turn(game_map: &mut GameMap, view: &mut MapView) {
+        clear_turn_state(game_map, view);
+        game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0));
+    }
+
+    //Also used when the server ends a turn that ran out of time
+    pub fn clear_turn_state(game_map: &mut GameMap, view: &mut MapView) {
         //Clear the player UI if it is still visible
-        game_map.unit_interface = None;
-        game_map.cursor.hide_cursor();
+        view.unit_interface = None;
+        view.cursor.hide_cursor();
 
         //Deselect the active unit
         game_map.player_state.active_unit_i = -1;
         game_map.player_state.active_unit_j = -1;
         game_map.player_state.current_player_action = PlayerAction::Default;
-
-        game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0));
     }
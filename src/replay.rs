@@ -0,0 +1,220 @@
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::map_data::MapData;
+use crate::net::util::Event;
+use crate::unit::Team;
+
+pub const REPLAY_FORMAT_VERSION: u32 = 3;
+pub const REPLAY_DIR: &str = "replays";
+
+static REPLAY_PATH: Mutex<Option<String>> = Mutex::new(None);
+
+// sets a static REPLAY_PATH variable representing the replay file the Replays game state should play back
+pub fn set_replay_path(path: Option<String>) {
+	*REPLAY_PATH.lock().unwrap() = path;
+}
+
+pub fn replay_path() -> Option<String> {
+	REPLAY_PATH.lock().unwrap().clone()
+}
+
+// Returns the paths of the saved replays, newest first
+pub fn list_replays() -> Vec<String> {
+	let mut replays: Vec<(SystemTime, String)> = match fs::read_dir(REPLAY_DIR) {
+		Ok(entries) => entries.filter_map(|e| e.ok())
+			.filter(|e| e.path().extension().is_some_and(|ext| ext == "replay"))
+			.map(|e| (e.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH), e.path().to_string_lossy().to_string()))
+			.collect(),
+		Err(_e) => Vec::new(),
+	};
+	replays.sort_by(|a, b| b.cmp(a));
+	replays.into_iter().map(|(_time, path)| path).collect()
+}
+
+/* Replay files are plain text with the format version on the first line, followed by everything
+ * needed to rebuild the starting board and then every event in the order it was applied:
+ *   castle_quest_replay 3
+ *   map <map file path>
+ *   map_lines <number of lines>, followed by that many lines of the map file as it was when the match started
+ *   seed <match seed>
+ *   team <team the match was recorded from>
+ *   fog 1 (only in replays that start out without the units that team couldn't see)
+ *   event <action> <id> <from x> <from y> <to x> <to y> <value> <from self>
+ */
+pub struct Replay {
+	pub path: String,
+	pub map_path: String,
+	//The map file is copied into the replay, so editing or removing it later doesn't break playback
+	pub map_contents: String,
+	pub seed: u64,
+	pub team: Team,
+	//Multiplayer matches only ever knew about the units in sight, see GameMap::hide_unseen_units
+	pub fog_of_war: bool,
+	pub events: Vec<Event>,
+}
+
+impl Replay {
+	// Starts recording a new match; the file is named after the time the match started
+	pub fn new(map_path: &str, seed: u64, team: Team) -> Result<Replay, String> {
+		let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+		let map_contents = fs::read_to_string(map_path).map_err(|e| format!("Could not read map file '{}': {}", map_path, e))?;
+		Ok(Replay {
+			path: format!("{}/match_{}.replay", REPLAY_DIR, started),
+			map_path: map_path.to_string(),
+			map_contents,
+			seed,
+			team,
+			fog_of_war: false,
+			events: Vec::new(),
+		})
+	}
+
+	pub fn record(&mut self, events: &[Event]) {
+		self.events.extend_from_slice(events);
+	}
+
+	// The map the match was played on
+	pub fn map_data(&self) -> Result<MapData, String> {
+		MapData::parse(&self.map_contents).map_err(|e| format!("Map of replay '{}' is invalid: {}", self.path, e))
+	}
+
+	// Writes everything recorded so far, replacing the previous copy of this match's replay
+	pub fn write(&self) -> Result<(), String> {
+		fs::create_dir_all(REPLAY_DIR).map_err(|e| e.to_string())?;
+		let tmp_path = format!("{}.tmp", self.path);
+		fs::write(&tmp_path, self.contents()).map_err(|e| e.to_string())?;
+		fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string())
+	}
+
+	fn contents(&self) -> String {
+		let mut lines: Vec<String> = Vec::new();
+		lines.push(format!("castle_quest_replay {}", REPLAY_FORMAT_VERSION));
+		lines.push(format!("map {}", self.map_path));
+		lines.push(format!("map_lines {}", self.map_contents.lines().count()));
+		lines.extend(self.map_contents.lines().map(|l| l.to_string()));
+		lines.push(format!("seed {}", self.seed));
+		lines.push(format!("team {}", self.team.to_string()));
+		if self.fog_of_war {
+			lines.push(String::from("fog 1"));
+		}
+		for e in self.events.iter() {
+			lines.push(format!("event {} {} {} {} {} {} {} {}", e.action, e.id, e.from_pos.0, e.from_pos.1, e.to_pos.0, e.to_pos.1, e.value, e.from_self as u8));
+		}
+		lines.join("\n") + "\n"
+	}
+
+	pub fn load(path: &str) -> Result<Replay, String> {
+		let contents = fs::read_to_string(path).map_err(|e| format!("Could not read replay file '{}': {}", path, e))?;
+		Replay::parse(&contents, path)
+	}
+
+	fn parse(contents: &str, path: &str) -> Result<Replay, String> {
+		let mut lines = contents.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+		match lines.next().map(|(_, l)| l.trim().split(' ').collect::<Vec<&str>>()) {
+			Some(values) if values.len() == 2 && values[0] == "castle_quest_replay" => {
+				if values[1] != REPLAY_FORMAT_VERSION.to_string() {
+					return Err(format!("Unsupported replay format version {}", values[1]));
+				}
+			},
+			_ => return Err(format!("'{}' is not a replay file", path)),
+		}
+
+		let mut map_path: Option<String> = None;
+		let mut map_contents: Option<String> = None;
+		let mut seed: Option<u64> = None;
+		let mut team: Option<Team> = None;
+		let mut fog_of_war = false;
+		let mut events: Vec<Event> = Vec::new();
+
+		while let Some((line_num, line)) = lines.next() {
+			let values: Vec<&str> = line.split_whitespace().collect();
+			let invalid = || format!("Invalid line {} in replay file: '{}'", line_num, line);
+
+			match values[..] {
+				[] => continue,
+				["map", path] => map_path = Some(path.to_string()),
+				["map_lines", n] => {
+					let n = n.parse::<usize>().map_err(|_e| invalid())?;
+					let map_lines: Vec<&str> = lines.by_ref().take(n).map(|(_, l)| l).collect();
+					if map_lines.len() != n {
+						return Err(String::from("Replay file ends in the middle of its map"));
+					}
+					map_contents = Some(map_lines.join("\n") + "\n");
+				},
+				["seed", s] => seed = Some(s.parse::<u64>().map_err(|_e| invalid())?),
+				["team", t] => team = Some(Team::from_name(t)?),
+				["fog", f] => fog_of_war = f == "1",
+				["event", ..] if values.len() == 9 => {
+					let nums = values[1..].iter().map(|v| v.parse::<u32>()).collect::<Result<Vec<u32>, _>>().map_err(|_e| invalid())?;
+					let mut event = Event::create(nums[0] as u8, nums[1] as u8, (nums[2], nums[3]), (nums[4], nums[5]), nums[6] as u8);
+					event.from_self = nums[7] != 0;
+					events.push(event);
+				},
+				_ => return Err(invalid()),
+			}
+		}
+
+		Ok(Replay {
+			path: path.to_string(),
+			map_path: map_path.ok_or("Replay file is missing its map")?,
+			map_contents: map_contents.ok_or("Replay file is missing its map")?,
+			seed: seed.ok_or("Replay file is missing its seed")?,
+			team: team.ok_or("Replay file is missing its team")?,
+			fog_of_war,
+			events,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::barbarian_turn::handle_barbarian_turn;
+	use crate::game_map::{apply_events, GameMap, tests::{load_unit_classes, test_map_file}};
+	use crate::net::util::*;
+
+	#[test]
+	fn replay_round_trip() {
+		load_unit_classes();
+		let map_path = test_map_file("replay");
+		let mut replay = Replay::new(&map_path, 9, Team::Player).unwrap();
+		let mut game_map = GameMap::new(&replay.map_data().unwrap(), Team::Player, 9).unwrap();
+		game_map.event_list.push(Event::create(EVENT_MOVE, 0, (1, 0), (5, 1), 0));
+		game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0));
+		game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_ENEMY, (0, 0), (0, 0), 0));
+		replay.record(&apply_events(&mut game_map).unwrap());
+		handle_barbarian_turn(&mut game_map).unwrap();
+		replay.record(&apply_events(&mut game_map).unwrap());
+
+		//The map travels with the replay, so it plays back the same even once the map file is gone
+		std::fs::remove_file(&map_path).unwrap();
+		let loaded = Replay::parse(&replay.contents(), "test.replay").unwrap();
+		assert_eq!(loaded.map_contents, replay.map_contents);
+		assert!(loaded.seed == 9 && loaded.team == Team::Player && !loaded.fog_of_war);
+		assert_eq!(loaded.events.len(), replay.events.len());
+
+		let mut played_back = GameMap::new(&loaded.map_data().unwrap(), loaded.team, loaded.seed).unwrap();
+		played_back.event_list.extend_from_slice(&loaded.events);
+		apply_events(&mut played_back).unwrap();
+		let units = |map: &GameMap| -> Vec<((u32, u32), u32)> {
+			let mut units: Vec<((u32, u32), u32)> = map.player_units.values().chain(map.enemy_units.values()).chain(map.barbarian_units.values())
+				.map(|u| ((u.x, u.y), u.hp)).collect();
+			units.sort();
+			units
+		};
+		assert_eq!(units(&played_back), units(&game_map));
+		assert!(played_back.player_state.current_turn == game_map.player_state.current_turn);
+	}
+
+	#[test]
+	fn rejects_bad_replays() {
+		let contents = "castle_quest_replay 3\nmap test.map\nseed 1\nteam player\nmap_lines 5\nmap 1\n";
+		assert!(Replay::parse(contents, "test.replay").is_err());
+		assert!(Replay::parse("castle_quest_replay 2\nmap test.map\nseed 1\nteam player\n", "test.replay").is_err());
+		assert!(Replay::parse("castle_quest_replay 3\nmap test.map\nmap_lines 1\nmap 1\nseed 1\nteam player\n", "test.replay").is_ok());
+		assert!(Replay::parse("castle_quest_replay 3\nmap test.map\nmap_lines 1\nmap 1\nseed x\nteam player\n", "test.replay").is_err());
+	}
+}
@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::BlendMode;
+
+use crate::game_map::{self, GameMap};
+use crate::map_view::{self, MapView};
+use crate::replay::{self, Replay};
+use crate::{Drawable, GameState};
+use crate::SDLCore;
+use crate::TILE_SIZE;
+
+//Playback speeds in events per second, switched between with the up/down arrow keys
+const PLAYBACK_SPEEDS: [u64; 5] = [1, 2, 4, 8, 16];
+const DEFAULT_SPEED: usize = 2;
+
+/* Plays a recorded match back by feeding its events through apply_event one at a time.
+ * Controls: space pauses/resumes, the right arrow steps forward one event (and pauses),
+ * up/down change the speed and backspace returns to the main menu.
+ */
+pub struct ReplayViewer<'i, 'r> {
+	core: &'i mut SDLCore<'r>,
+
+	replay: Replay,
+	game_map: GameMap,
+	view: MapView<'i>,
+
+	next_event: usize,
+	paused: bool,
+	speed: usize,
+	last_event_time: Instant,
+}
+
+impl ReplayViewer<'_, '_> {
+	pub fn new<'i, 'r>(core: &'i mut SDLCore<'r>) -> Result<ReplayViewer<'i, 'r>, String> {
+		let path = replay::replay_path().ok_or("No replay selected")?;
+		let replay = Replay::load(&path)?;
+		println!("Playing replay {} ({} events, seed {})", path, replay.events.len(), replay.seed);
+
+		let map_data = replay.map_data()?;
+		let mut game_map = GameMap::new(&map_data, replay.team, replay.seed)?;
+		//Show everything the replay knows about
+		game_map.fog_of_war = false;
+		if replay.fog_of_war {
+			game_map.hide_unseen_units();
+		}
+		let mut view = MapView::new(core)?;
+		view.show_turn_controls = false;
+
+		//Set camera size based on map size
+		core.cam.w = (game_map.map_size.0 as u32 * TILE_SIZE) as i32;
+		core.cam.h = (game_map.map_size.1 as u32 * TILE_SIZE) as i32;
+		map_view::center_camera(core, game_map.objectives.p1_castle);
+
+		Ok(ReplayViewer {
+			core,
+			replay,
+			game_map,
+			view,
+			next_event: 0,
+			paused: false,
+			speed: DEFAULT_SPEED,
+			last_event_time: Instant::now(),
+		})
+	}
+
+	fn play_next_event(&mut self) {
+		if let Some(event) = self.replay.events.get(self.next_event) {
+			self.game_map.event_list.push(*event);
+			self.next_event += 1;
+		}
+		self.last_event_time = Instant::now();
+	}
+
+	fn draw_status(&mut self) -> Result<(), String> {
+		let status = format!("{} | {}x | Event {}/{} | Space: pause  Right: step  Up/Down: speed  Backspace: menu",
+			if self.paused { "Paused" } else { "Playing" },
+			PLAYBACK_SPEEDS[self.speed],
+			self.next_event,
+			self.replay.events.len());
+
+		let (w, h) = self.core.regular_font.size_of(&status).map_err(|_e| "Could not determine text size")?;
+		let text_surface = self.core.regular_font.render(&status)
+			.blended(Color::RGBA(255, 255, 255, 255))
+			.map_err(|e| e.to_string())?;
+		let text_texture = self.core.texture_creator.create_texture_from_surface(&text_surface)
+			.map_err(|e| e.to_string())?;
+
+		//Draw relative to the camera so the status stays in the top left corner
+		let bg_rect = Rect::new(-self.core.cam.x, -self.core.cam.y, w + 20, h + 10);
+		self.core.wincan.set_blend_mode(BlendMode::Blend);
+		self.core.wincan.set_draw_color(Color::RGBA(50, 50, 50, 180));
+		self.core.wincan.fill_rect(bg_rect)?;
+		self.core.wincan.copy(&text_texture, None, Rect::new(bg_rect.x() + 10, bg_rect.y() + 5, w, h))?;
+		Ok(())
+	}
+}
+
+impl Drawable for ReplayViewer<'_, '_> {
+	fn draw(&mut self) -> Result<GameState, String> {
+		self.core.wincan.clear();
+
+		let mut step = false;
+		for event in self.core.event_pump.poll_iter() {
+			match event {
+				Event::Quit{..} | Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
+					return Err("Quit keycode".to_string());
+				},
+				Event::KeyDown{keycode: Some(Keycode::Backspace), ..} => {
+					return Ok(GameState::MainMenu);
+				},
+				Event::KeyDown{keycode: Some(Keycode::Space), ..} => {
+					self.paused = !self.paused;
+				},
+				Event::KeyDown{keycode: Some(Keycode::Right), ..} => {
+					self.paused = true;
+					step = true;
+				},
+				Event::KeyDown{keycode: Some(Keycode::Up), ..} => {
+					self.speed = (self.speed + 1).min(PLAYBACK_SPEEDS.len() - 1);
+				},
+				Event::KeyDown{keycode: Some(Keycode::Down), ..} => {
+					self.speed = self.speed.saturating_sub(1);
+				},
+				_ => {},
+			}
+		}
+
+		//Wait for each turn banner to finish, like in a live match
+		if !self.view.banner.banner_visible {
+			let interval = Duration::from_millis(1000 / PLAYBACK_SPEEDS[self.speed]);
+			if step || (!self.paused && self.last_event_time.elapsed() >= interval) {
+				self.play_next_event();
+			}
+		}
+
+		//Record user inputs
+		self.core.input.update(&self.core.event_pump);
+
+		game_map::apply_events(&mut self.game_map)?;
+
+		self.view.draw(self.core, &mut self.game_map)?;
+		self.draw_status()?;
+
+		self.core.wincan.set_viewport(self.core.cam);
+		self.core.wincan.present();
+
+		Ok(GameState::Replays)
+	}
+}
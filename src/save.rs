@@ -0,0 +1,265 @@
+use std::fs;
+use std::sync::Mutex;
+
+use crate::game_map::{GameMap, Notice};
+use crate::map_data::MapData;
+use crate::unit::{Team, Unit};
+
+pub const SAVE_FORMAT_VERSION: u32 = 2;
+pub const SAVE_DIR: &str = "saves";
+pub const AUTOSAVE_SLOT: u32 = 0;
+pub const NUM_SLOTS: u32 = 3; //Manual save slots, numbered 1..=NUM_SLOTS
+
+static LOAD_SLOT: Mutex<Option<u32>> = Mutex::new(None);
+
+// sets a static LOAD_SLOT variable representing the save the next single player game should start from
+//   if Some(slot) -> loads the game saved in slot
+//   if None       -> starts a new game
+pub fn set_load_slot(slot: Option<u32>) {
+	*LOAD_SLOT.lock().unwrap() = slot;
+}
+
+pub fn load_slot() -> Option<u32> {
+	*LOAD_SLOT.lock().unwrap()
+}
+
+pub fn slot_path(slot: u32) -> String {
+	if slot == AUTOSAVE_SLOT {
+		format!("{}/autosave.sav", SAVE_DIR)
+	} else {
+		format!("{}/slot{}.sav", SAVE_DIR, slot)
+	}
+}
+
+pub fn slot_name(slot: u32) -> String {
+	if slot == AUTOSAVE_SLOT {
+		"Autosave".to_string()
+	} else {
+		format!("Slot {}", slot)
+	}
+}
+
+// Short description of a save slot for the menu, or None if the slot is empty/unreadable
+pub fn slot_summary(slot: u32) -> Option<String> {
+	let contents = fs::read_to_string(slot_path(slot)).ok()?;
+	let round = contents.lines().find_map(|line| line.strip_prefix("round "))?;
+	Some(format!("Round {}", round.trim()))
+}
+
+/* Save files are plain text, one value per line, with the format version on the first line:
+ *   castle_quest_save 2
+ *   map <map file path>
+ *   turn <team>
+ *   round <n>
+ *   seed <match seed>
+ *   castle_turns <p1 castle> <p2 castle>
+ *   takeovers <p1 camps> <p1 forts> <p2 camps> <p2 forts>
+ *   camp <x> <y> <turns occupied> <occupying team|none>
+ *   captured <x> <y> <team>
+ *   unit <team> <Unit::to_save_string()>
+ */
+pub fn save_game(game_map: &GameMap, map_path: &str, slot: u32) -> Result<(), String> {
+	fs::create_dir_all(SAVE_DIR).map_err(|e| e.to_string())?;
+
+	//Write to a temporary file first so a crash mid-save can't corrupt the existing save
+	let path = slot_path(slot);
+	let tmp_path = format!("{}.tmp", path);
+	fs::write(&tmp_path, save_string(game_map, map_path)).map_err(|e| e.to_string())?;
+	fs::rename(&tmp_path, &path).map_err(|e| e.to_string())?;
+
+	println!("Game saved to {}", path);
+	Ok(())
+}
+
+fn save_string(game_map: &GameMap, map_path: &str) -> String {
+	let objectives = &game_map.objectives;
+	let mut lines: Vec<String> = Vec::new();
+
+	lines.push(format!("castle_quest_save {}", SAVE_FORMAT_VERSION));
+	lines.push(format!("map {}", map_path));
+	lines.push(format!("turn {}", game_map.player_state.current_turn.to_string()));
+	lines.push(format!("round {}", game_map.player_state.round));
+	lines.push(format!("seed {}", game_map.seed));
+	lines.push(format!("castle_turns {} {}", objectives.p1_castle_turns, objectives.p2_castle_turns));
+	lines.push(format!("takeovers {} {} {} {}", objectives.p1_takeovers.0, objectives.p1_takeovers.1, objectives.p2_takeovers.0, objectives.p2_takeovers.1));
+
+	for camp in objectives.barbarian_camps.iter() {
+		let turns = objectives.barbarian_camps_turns.get(camp).copied().unwrap_or(0);
+		let team = match objectives.barbarian_camps_teams.get(camp) {
+			Some(Some(team)) => team.to_string(),
+			_ => "none".to_string(),
+		};
+		lines.push(format!("camp {} {} {} {}", camp.0, camp.1, turns, team));
+	}
+	for (camp, team) in objectives.captured_camps.iter() {
+		lines.push(format!("captured {} {} {}", camp.0, camp.1, team.to_string()));
+	}
+
+	for units in [&game_map.player_units, &game_map.enemy_units, &game_map.barbarian_units].iter() {
+		//Sort so that saving the same state twice gives the same file
+		let mut unit_list: Vec<&Unit> = units.values().collect();
+		unit_list.sort_by_key(|u| (u.y, u.x));
+		for unit in unit_list {
+			lines.push(format!("unit {} {}", unit.team.to_string(), unit.to_save_string()));
+		}
+	}
+
+	lines.join("\n") + "\n"
+}
+
+// Loads the game saved in the given slot, returning it along with the path of the map it was played on
+pub fn load_game(slot: u32) -> Result<(GameMap, String), String> {
+	let path = slot_path(slot);
+	let contents = fs::read_to_string(&path).map_err(|e| format!("Could not read save file '{}': {}", path, e))?;
+	let loaded = parse_save(&contents, &path)?;
+	println!("Loaded game from {}", path);
+	Ok(loaded)
+}
+
+fn parse_save(contents: &str, path: &str) -> Result<(GameMap, String), String> {
+	let mut lines = contents.lines();
+
+	match lines.next().map(|l| l.trim().split(' ').collect::<Vec<&str>>()) {
+		Some(values) if values.len() == 2 && values[0] == "castle_quest_save" => {
+			if values[1] != SAVE_FORMAT_VERSION.to_string() {
+				return Err(format!("Unsupported save format version {}", values[1]));
+			}
+		},
+		_ => return Err(format!("'{}' is not a save file", path)),
+	}
+
+	let map_path = lines.next().and_then(|l| l.strip_prefix("map ")).ok_or("Save file is missing its map")?.trim().to_string();
+	let map_data = MapData::load(&map_path)?;
+	let mut game_map = GameMap::new(&map_data, Team::Player, 0)?;
+
+	//Start from an empty board, units & captured camps are restored from the save
+	game_map.player_units.clear();
+	game_map.enemy_units.clear();
+	game_map.barbarian_units.clear();
+	for tile in game_map.map_tiles.values_mut() {
+		tile.update_team(None);
+	}
+
+	for (i, line) in lines.enumerate() {
+		let line_num = i + 3;
+		let (keyword, rest) = match line.find(' ') {
+			Some(i) => (&line[..i], &line[i+1..]),
+			None => continue,
+		};
+		let values: Vec<&str> = rest.split_whitespace().collect();
+		let nums = || values.iter().map(|v| v.parse::<u32>()).collect::<Result<Vec<u32>, _>>()
+			.map_err(|_e| format!("Invalid value on line {} of save file", line_num));
+
+		match (keyword, values.len()) {
+			("turn", 1) => game_map.player_state.current_turn = Team::from_name(values[0])?,
+			("round", 1) => game_map.player_state.round = nums()?[0],
+			("seed", 1) => game_map.seed = values[0].parse::<u64>().map_err(|_e| format!("Invalid seed on line {} of save file", line_num))?,
+			("castle_turns", 2) => {
+				let nums = nums()?;
+				game_map.objectives.p1_castle_turns = nums[0];
+				game_map.objectives.p2_castle_turns = nums[1];
+			},
+			("takeovers", 4) => {
+				let nums = nums()?;
+				game_map.objectives.p1_takeovers = (nums[0], nums[1]);
+				game_map.objectives.p2_takeovers = (nums[2], nums[3]);
+			},
+			("camp", 4) => {
+				let pos = (values[0].parse::<u32>().map_err(|e| e.to_string())?, values[1].parse::<u32>().map_err(|e| e.to_string())?);
+				let turns = values[2].parse::<u32>().map_err(|e| e.to_string())?;
+				let team = if values[3] == "none" { None } else { Some(Team::from_name(values[3])?) };
+				game_map.objectives.barbarian_camps_turns.insert(pos, turns);
+				game_map.objectives.barbarian_camps_teams.insert(pos, team);
+			},
+			("captured", 3) => {
+				let pos = (values[0].parse::<u32>().map_err(|e| e.to_string())?, values[1].parse::<u32>().map_err(|e| e.to_string())?);
+				let team = Team::from_name(values[2])?;
+				game_map.objectives.barbarian_camps.retain(|camp| *camp != pos);
+				game_map.objectives.barbarian_camps_turns.remove(&pos);
+				game_map.objectives.barbarian_camps_teams.remove(&pos);
+				game_map.set_camp_owner(pos, team);
+			},
+			("unit", _) => {
+				let team = Team::from_name(values[0])?;
+				let unit = Unit::from_save_string(team, &rest[values[0].len()..])?;
+
+				//Remember map is flipped indexing
+				let tile = game_map.map_tiles.get_mut(&(unit.y, unit.x)).ok_or(format!("Unit on line {} of save file is outside of the map", line_num))?;
+				tile.update_team(Some(team));
+
+				let unit_map = match team {
+					Team::Player => &mut game_map.player_units,
+					Team::Enemy => &mut game_map.enemy_units,
+					Team::Barbarians => &mut game_map.barbarian_units,
+				};
+				unit_map.insert((unit.x, unit.y), unit);
+			},
+			_ => return Err(format!("Invalid line {} in save file: '{}'", line_num, line)),
+		}
+	}
+
+	//Pick up the random sequence where the saved turn started it
+	game_map.reseed_rng();
+
+	//Replace the new game's turn banner with one for the restored turn
+	game_map.notices.clear();
+	let current_turn = game_map.player_state.current_turn;
+	game_map.notices.push(Notice::TurnStart(current_turn.as_client(&game_map.player_state)));
+
+	Ok((game_map, map_path))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::game_map::{apply_events, tests::{test_map_data, test_map_file}};
+	use crate::net::util::*;
+
+	#[test]
+	fn save_round_trip() {
+		let map_path = test_map_file("save");
+		let mut game_map = GameMap::new(&test_map_data(), Team::Player, 42).unwrap();
+		game_map.event_list.push(Event::create(EVENT_MOVE, 0, (1, 0), (5, 1), 0));
+		game_map.event_list.push(Event::create(EVENT_ATTACK, 0, (0, 1), (6, 2), 3));
+		game_map.event_list.push(Event::create(EVENT_END_TURN, EVENT_ID_PLAYER, (0, 0), (0, 0), 0));
+		apply_events(&mut game_map).unwrap();
+		//As if the enemy had taken the camp over
+		game_map.objectives.taken_over_camps.push(((5, 0), Team::Enemy));
+		game_map.objectives.barbarian_camps.clear();
+		game_map.update_captured_camps();
+
+		let saved = save_string(&game_map, &map_path);
+		let (loaded, loaded_path) = parse_save(&saved, "test").unwrap();
+
+		assert_eq!(loaded_path, map_path);
+		assert!(loaded.player_state.current_turn == Team::Enemy);
+		assert_eq!(loaded.seed, 42);
+		assert_eq!(loaded.barbarian_units[&(6, 2)].hp, game_map.barbarian_units[&(6, 2)].hp);
+		assert!(loaded.player_units[&(5, 1)].has_moved);
+		assert!(loaded.map_tiles[&(1, 5)].contained_unit_team == Some(Team::Player));
+		assert!(loaded.map_tiles[&(0, 1)].contained_unit_team.is_none());
+		assert_eq!(loaded.objectives.p2_takeovers, (1, 0));
+		assert!(loaded.objectives.barbarian_camps.is_empty());
+		assert_eq!(loaded.map_tiles[&(0, 5)].texture_key, "enemy_camp");
+		//Everything that's saved comes back the same
+		assert_eq!(save_string(&loaded, &map_path), saved);
+
+		//The loaded game carries on with the same rolls
+		let mut loaded = loaded;
+		game_map.reseed_rng();
+		assert_eq!(game_map.roll_attack_damage(&(6, 5), &(5, 1)), loaded.roll_attack_damage(&(6, 5), &(5, 1)));
+	}
+
+	#[test]
+	fn rejects_bad_saves() {
+		let map_path = test_map_file("bad_save");
+		assert!(parse_save("castle_quest_replay 2\n", "test").is_err());
+		assert!(parse_save("castle_quest_save 1\n", "test").is_err());
+		assert!(parse_save("castle_quest_save 2\nturn player\n", "test").is_err());
+
+		let saved = save_string(&GameMap::new(&test_map_data(), Team::Player, 1).unwrap(), &map_path);
+		assert!(parse_save(&saved.replace("round 1", "round one"), "test").is_err());
+		assert!(parse_save(&saved.replace("turn player", "turn nobody"), "test").is_err());
+		assert!(parse_save(&(saved + "gold 100\n"), "test").is_err());
+	}
+}
@@ -0,0 +1,58 @@
+use crate::ai::distance_map::DistanceMap;
+use crate::ai::profile;
+use crate::barbarian_turn;
+use crate::enemy_turn;
+use crate::game_map::{self, GameMap};
+use crate::map_data::MapData;
+use crate::unit::Team;
+
+// Plays an AI vs AI match without a window and prints how it went.
+// Each side gets its own GameMap, mirrored like the two clients of a multiplayer match: the AI plays the
+// enemy team of each map, and whatever happens on one map is passed on to the other.
+pub fn run(map_path: &str, seed: u64, max_rounds: u32) -> Result<(), String> {
+	let map_data = MapData::load(map_path)?;
+	println!("Simulating '{}' with seed {}", map_data.name, seed);
+
+	// maps[0] is player 1's side (and runs the barbarians), maps[1] player 2's
+	let mut maps = [GameMap::new(&map_data, Team::Player, seed)?, GameMap::new(&map_data, Team::Enemy, seed)?];
+	let distance_maps = [DistanceMap::new(&maps[0]), DistanceMap::new(&maps[1])];
+	let profile = profile::difficulty()?;
+
+	let mut rounds = 1;
+	while maps[0].winning_team.is_none() && rounds <= max_rounds {
+		let current_turn = maps[0].player_state.current_turn;
+		let side = match current_turn {
+			Team::Player => 1, // player 1 is the enemy of player 2's map
+			Team::Enemy | Team::Barbarians => 0,
+		};
+
+		match current_turn {
+			Team::Barbarians => barbarian_turn::handle_barbarian_turn(&mut maps[0])?,
+			_ => enemy_turn::handle_enemy_turn(&mut maps[side], &distance_maps[side], profile)?,
+		}
+
+		let events = game_map::apply_events(&mut maps[side])?;
+		let other = &mut maps[1 - side];
+		for mut event in events.into_iter().filter(|e| e.from_self) {
+			event.from_self = false;
+			other.event_list.push(event);
+		}
+		game_map::apply_events(other)?;
+
+		if current_turn == Team::Barbarians && maps[0].player_state.current_turn != Team::Barbarians {
+			rounds += 1;
+		}
+	}
+
+	match maps[0].winning_team {
+		Some(Team::Player) => println!("Player 1 won after {} rounds", rounds),
+		Some(Team::Enemy) => println!("Player 2 won after {} rounds", rounds),
+		Some(Team::Barbarians) => println!("The barbarians won after {} rounds", rounds),
+		None => println!("No winner after {} rounds", max_rounds),
+	}
+	for (i, map) in maps.iter().enumerate() {
+		println!("Player {}: {} units left", i + 1, map.player_units.len());
+	}
+	println!("Barbarians: {} units left", maps[0].barbarian_units.len());
+	Ok(())
+}
@@ -1,8 +1,15 @@
+use std::sync::{Arc, Mutex};
+
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
 use crate::ai::*;
 use crate::game_map::GameMap;
+use crate::map_data::{self, MapData};
+use crate::map_view::{self, MapView};
+use crate::net::util::{EVENT_END_GAME, EVENT_END_TURN};
+use crate::replay::Replay;
+use crate::save;
 use crate::{Drawable, GameState};
 use crate::TILE_SIZE;
 use crate::player_turn;
@@ -11,31 +18,69 @@ use crate::barbarian_turn;
 use crate::SDLCore;
 use crate::unit::Team;
 
+static SEED: Mutex<Option<u64>> = Mutex::new(None);
+
+// sets a static SEED variable used for new single player games
+//   if Some(seed) -> every new game uses that seed, so matches can be reproduced
+//   if None       -> each new game picks a random seed
+pub fn set_seed(seed: Option<u64>) {
+	*SEED.lock().unwrap() = seed;
+}
+
 pub struct SinglePlayer<'i, 'r> {
 	core: &'i mut SDLCore<'r>,
 
-	game_map: GameMap<'i>,
+	game_map: GameMap,
+	map_path: String,
+	view: MapView<'i>,
 
-	distance_map: distance_map::DistanceMap,
+	//Replays start from the map's initial board, so games loaded from a save aren't recorded
+	replay: Option<Replay>,
+
+	//Shared with the thread the enemy AI plans on
+	distance_map: Arc<distance_map::DistanceMap>,
+	enemy_planner: enemy_turn::EnemyPlanner,
+	//How well the enemy AI plays, picked in the main menu
+	ai_profile: &'static profile::AiProfile,
 }
 
 impl SinglePlayer<'_,'_> {
 	pub fn new<'i, 'r>(core: &'i mut SDLCore<'r>) -> Result<SinglePlayer<'i, 'r>, String> {
-		let game_map = GameMap::new(core, Team::Player);
+		let (game_map, map_path, replay) = match save::load_slot() {
+			Some(slot) => {
+				save::set_load_slot(None);
+				let (game_map, map_path) = save::load_game(slot)?;
+				(game_map, map_path, None)
+			},
+			None => {
+				let map_path = map_data::map_path();
+				let map_data = MapData::load(&map_path)?;
+				let seed = SEED.lock().unwrap().unwrap_or_else(rand::random);
+				println!("Match seed: {}", seed);
+				(GameMap::new(&map_data, Team::Player, seed)?, map_path.clone(), Some(Replay::new(&map_path, seed, Team::Player)?))
+			},
+		};
+		let view = MapView::new(core)?;
 
 		//Set camera size based on map size
 		core.cam.w = (game_map.map_size.0 as u32 * TILE_SIZE) as i32;
 		core.cam.h = (game_map.map_size.1 as u32 * TILE_SIZE) as i32;
-		//Start camera in lower left corner, to start with the player castle in view
-		core.cam.x = 0;
-		core.cam.y = -core.cam.h + core.wincan.window().size().1 as i32;
+		//Start with the player castle in view
+		map_view::center_camera(core, game_map.objectives.p1_castle);
 
-		let distance_map = distance_map::DistanceMap::new();
+		let distance_map = Arc::new(distance_map::DistanceMap::new(&game_map));
+		let ai_profile = profile::difficulty()?;
+		println!("Difficulty: {}", ai_profile.name);
 
 		Ok(SinglePlayer {
 			core,
 			game_map,
+			map_path,
+			view,
+			replay,
 			distance_map,
+			enemy_planner: enemy_turn::EnemyPlanner::new(),
+			ai_profile,
 		})
 	}
 }
@@ -51,31 +96,66 @@ impl Drawable for SinglePlayer<'_,'_> {
 				Event::Quit{..} | Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
 					return Err("Quit keycode".to_string());
 				},
+				//F1-F3 save the game to the matching slot
+				Event::KeyDown{keycode: Some(key @ (Keycode::F1 | Keycode::F2 | Keycode::F3)), ..} => {
+					let slot = match key {
+						Keycode::F1 => 1,
+						Keycode::F2 => 2,
+						_ => 3,
+					};
+					if self.game_map.winning_team.is_none() {
+						if let Err(e) = save::save_game(&self.game_map, &self.map_path, slot) {
+							println!("Could not save game: {}", e);
+						}
+					}
+				},
 				_ => {},
 			}
 		}
 
-		//If no one has won so far...
-		if self.game_map.winning_team.is_none() {
+		//If no one has won so far (and the turn banner has finished)...
+		if self.game_map.winning_team.is_none() && !self.view.banner.banner_visible {
 			//Handle the current team's move
 			match self.game_map.player_state.current_turn {
-				Team::Player => player_turn::handle_player_turn(&self.core, &mut self.game_map)?,
-				Team::Enemy => enemy_turn::handle_enemy_turn(&self.core, &mut self.game_map, &self.distance_map)?,
-				Team::Barbarians => barbarian_turn::handle_barbarian_turn(&self.core, &mut self.game_map)?,
+				Team::Player => player_turn::handle_player_turn(self.core, &mut self.game_map, &mut self.view)?,
+				Team::Enemy => self.enemy_planner.update(&mut self.game_map, &self.distance_map, self.ai_profile)?,
+				Team::Barbarians => barbarian_turn::handle_barbarian_turn(&mut self.game_map)?,
 			}
 		}
 
+		//Let the player know the game hasn't frozen while the enemy plans its turn
+		self.view.status_text = self.enemy_planner.thinking_for().map(|elapsed| {
+			format!("Enemy is thinking{:<3}", ".".repeat((elapsed.as_millis() / 400 % 4) as usize))
+		});
+
 		//Record user inputs
 		self.core.input.update(&self.core.event_pump);
 
-		crate::game_map::apply_events(&self.core, &mut self.game_map)?;
+		let events = crate::game_map::apply_events(&mut self.game_map)?;
+
+		//Keep the replay file up to date at the end of every turn, so it survives crashes
+		if let Some(replay) = self.replay.as_mut() {
+			replay.record(&events);
+			if events.iter().any(|e| e.action == EVENT_END_TURN || e.action == EVENT_END_GAME) {
+				if let Err(e) = replay.write() {
+					println!("Could not write replay: {}", e);
+				}
+			}
+		}
+
+		//Autosave at the start of every turn
+		if self.game_map.winning_team.is_none() && events.iter().any(|e| e.action == EVENT_END_TURN) {
+			if let Err(e) = save::save_game(&self.game_map, &self.map_path, save::AUTOSAVE_SLOT) {
+				println!("Autosave failed: {}", e);
+			}
+		}
 
-		self.game_map.draw(self.core)?;
+		self.view.draw(self.core, &mut self.game_map)?;
 
 		self.core.wincan.set_viewport(self.core.cam);
 		self.core.wincan.present();
 
-		if !self.game_map.winning_team.is_none() && !self.game_map.banner.banner_visible && self.core.input.left_clicked {
+		if self.game_map.winning_team.is_some() && !self.view.banner.banner_visible && self.core.input.left_clicked {
 			Ok(GameState::MainMenu)
 		} else {
 			Ok(GameState::SinglePlayer)
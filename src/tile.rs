@@ -1,7 +1,8 @@
-use sdl2::render::Texture;
 use std::fmt;
+use crate::map_data::TileType;
 use crate::unit::{Team};
 
+#[derive(Clone)]
 pub enum Structure {
 	Camp,
 	PCastle,
@@ -18,26 +19,38 @@ impl PartialEq for Structure {
     }
 }
 
-pub struct Tile<'a> {
+#[derive(Clone)]
+pub struct Tile {
     pub x: u32,
     pub y: u32,
     pub is_traversable: bool,
     pub can_attack_through: bool, // e.x. archers and mages can attack over rivers and through trees
+    pub blocks_vision: bool, // e.x. mountains and trees hide the units behind them
+    pub movement_cost: u32, // movement points it takes to step onto the tile
+    pub evasion: u32, // % taken off the accuracy of attacks on a unit standing here
+    pub defense: u32, // taken off the damage of attacks on a unit standing here
     pub contained_unit_team: Option<Team>, // Storing a unit causes some pains with lifetimes and references, so store an enum that is better than a boolean
     pub contained_structure: Option<Structure>,
-    pub texture: &'a Texture<'a>,
+    pub texture_key: String, // key into SDLCore.texture_map, resolved by the view when drawing
+    pub name: String, // the terrain's name from the map legend
 }
 
-impl Tile <'_>{
-    pub fn new<'a> (x:u32, y:u32, is_traversable: bool, can_attack_through: bool, contained_unit_team: Option<Team>, contained_structure: Option<Structure>, texture: &'a Texture) -> Tile<'a> {
+impl Tile {
+    //An empty tile of the given terrain, units and structures are placed on it afterwards
+    pub fn new(x:u32, y:u32, tile_type: &TileType) -> Tile {
         Tile {
             x,
             y,
-            is_traversable,
-            can_attack_through,
-            contained_unit_team,
-            contained_structure,
-            texture,
+            is_traversable: tile_type.is_traversable,
+            can_attack_through: tile_type.can_attack_through,
+            blocks_vision: tile_type.blocks_vision,
+            movement_cost: tile_type.movement_cost,
+            evasion: tile_type.evasion,
+            defense: tile_type.defense,
+            contained_unit_team: None,
+            contained_structure: None,
+            texture_key: tile_type.texture.clone(),
+            name: tile_type.name.clone(),
         }
     }
     pub fn update_team(&mut self, new_team: Option<Team>) {
@@ -52,7 +65,7 @@ impl Tile <'_>{
 
 }
 
-impl fmt::Display for Tile<'_> {
+impl fmt::Display for Tile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Tile(x:{}, y:{}, is_traversable:{})", self.x, self.y, self.is_traversable)
     }
@@ -1,24 +1,15 @@
 use rand::Rng;
 
-use sdl2::rect::Rect;
-use sdl2::render::Texture;
-
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::fmt;
 use std::time::Instant;
 
-use crate::SDLCore;
 use crate::tile::Tile;
 use crate::player_state::PlayerState;
 use crate::net::util::*;
-
-const MAP_WIDTH: u32 = 64;
-const MAP_HEIGHT: u32 = 64;
-
-pub const GUARD_HEALTH_ID: u32 = 25;
-pub const SCOUT_HEALTH_ID: u32 = 9;
+use crate::unit_class::{self, UnitClass};
 
 pub enum Team {
 	Player,
@@ -55,6 +46,15 @@ impl Team {
             _ => Err("Invalid team id".to_string())
         }
     }
+
+    pub fn from_name(name: &str) -> Result<Team, String> {
+        match name {
+            "player" => Ok(Team::Player),
+            "enemy" => Ok(Team::Enemy),
+            "barbarians" => Ok(Team::Barbarians),
+            _ => Err(format!("Invalid team name '{}'", name))
+        }
+    }
 }
 impl ToString for Team {
     fn to_string(&self) -> String {
@@ -113,12 +113,14 @@ impl PartialOrd for QueueObject {
     }
 }
 
-pub struct Unit<'a> {
+#[derive(Clone)]
+pub struct Unit {
     pub draw_x: f64,
     pub draw_y: f64,
     pub x: u32,
     pub y: u32,
     pub team: Team,
+    pub class: &'static UnitClass,
     pub hp: u32,
     pub max_hp: u32,
     movement_range: u32,
@@ -126,74 +128,98 @@ pub struct Unit<'a> {
     accuracy: u32,
     min_damage: u32,
     max_damage: u32,
-    pub texture: &'a Texture<'a>,
+    pub texture_key: String, // key into SDLCore.texture_map, resolved by the view when drawing
     pub has_attacked: bool,
     pub has_moved: bool,
 
-    default_sprite_src: Rect,
-    red_sprite_src: Rect,
-    gray_sprite_src: Rect,
-
-    is_attacked: bool,
-    last_damaged_drawn: Instant,
-    time_since_damaged: f32,
+    //Render state used by the view layer to tint the sprite red after taking damage
+    pub is_attacked: bool,
+    pub last_damaged_drawn: Instant,
+    pub time_since_damaged: f32,
 
     ranged_attacker: bool,
+    pub vision_range: u32,
 
     //Used for barbarians to make sure they roam within a small radius
     pub starting_x: u32,
     pub starting_y: u32,
 }
 
-impl Unit <'_>{
-    pub fn new<'a> (x:u32, y:u32, team: Team, hp: u32, movement_range: u32, attack_range: u32, accuracy: u32, min_damage:u32, max_damage: u32, texture: &'a Texture, ranged_attacker: bool) -> Unit<'a> {
+impl Unit {
+    //Barbarians get their class' barbarian hp & damage
+    pub fn new(x: u32, y: u32, team: Team, class: &'static UnitClass) -> Unit {
+        let (hp, min_damage, max_damage) = if team == Team::Barbarians {
+            class.barbarian_stats
+        } else {
+            (class.hp, class.min_damage, class.max_damage)
+        };
         Unit {
             draw_x: -1.0,
             draw_y: -1.0,
             x,
             y,
             team,
+            class,
             hp,
             max_hp: hp,
-            movement_range,
-            attack_range,
-            accuracy,
+            movement_range: class.movement_range,
+            attack_range: class.attack_range,
+            accuracy: class.accuracy,
             min_damage,
             max_damage,
-            texture,
+            texture_key: class.sprite(team).to_string(),
 
             has_attacked: false,
             has_moved: false,
 
-            default_sprite_src: Rect::new(0, 0, 32, 32),
-            red_sprite_src: Rect::new(32, 0, 32, 32),
-            gray_sprite_src: Rect::new(64, 0, 32, 32),
-
             is_attacked: false,
             last_damaged_drawn: Instant::now(),
             time_since_damaged: 0.0,
 
-            ranged_attacker,
+            ranged_attacker: class.ranged_attacker,
+            vision_range: class.vision_range,
 
             starting_x: x,
             starting_y: y,
         }
     }
 
-    pub fn get_attack_damage(&self, other: &Unit) -> u32 {
-        let chance = rand::thread_rng().gen_range(0..100);
-        let scout_debuff = if other.max_hp == SCOUT_HEALTH_ID {
-            20
-        } else {
-            0
-        };
-        if chance < self.accuracy.checked_sub(scout_debuff).unwrap_or(0) {
-            rand::thread_rng().gen_range(self.min_damage..=self.max_damage)
+    //Creates a fresh unit of the class with the given id, as used by events
+    pub fn from_kind(kind: u8, team: Team, x: u32, y: u32) -> Result<Unit, String> {
+        Ok(Unit::new(x, y, team, unit_class::by_id(kind)?))
+    }
+
+    //The id of the unit's class, as used by events
+    pub fn kind(&self) -> u8 {
+        self.class.id
+    }
+
+    //other's tile makes the attack less likely to hit (evasion) and take some of the damage off (defense)
+    pub fn get_attack_damage(&self, other: &Unit, other_tile: &Tile, rng: &mut impl Rng) -> u32 {
+        let chance = rng.gen_range(0..100);
+        if chance < self.hit_chance(other, other_tile) {
+            //A hit always does at least 1 damage
+            rng.gen_range(self.min_damage..=self.max_damage).saturating_sub(other_tile.defense).max(1)
         } else {
             0
         }
     }
 
+    // % chance of an attack on other hitting
+    pub fn hit_chance(&self, other: &Unit, other_tile: &Tile) -> u32 {
+        self.accuracy.saturating_sub(other.class.evasion() + other_tile.evasion)
+    }
+
+    // the damage other could take from a hit, one entry per (equally likely) roll
+    pub fn hit_damages(&self, other: &Unit, other_tile: &Tile) -> Vec<u32> {
+        (self.min_damage..=self.max_damage).map(|damage| other.damage_taken_from(damage.saturating_sub(other_tile.defense).max(1), self)).collect()
+    }
+
+    // the damage an attack by the unit does on average, before the target's tile & abilities
+    pub fn average_damage(&self) -> f64 {
+        self.accuracy.min(100) as f64 / 100.0 * (self.min_damage + self.max_damage) as f64 / 2.0
+    }
+
     pub fn update_pos(&mut self, x: u32, y: u32) {
         self.x = x;
         self.y = y;
@@ -208,59 +234,46 @@ impl Unit <'_>{
         respawn_loc((self.x, self.y), map, where_to_spawn)
     }
 
+    //Dijkstra outwards from the unit, where stepping onto a tile costs its movement_cost.
+    //Tiles past the right/bottom edge of the map aren't in the tile HashMap, so only the
+    //left/top edges need a check to avoid underflowing the coordinates
     pub fn get_tiles_in_movement_range(&self, map: &mut HashMap<(u32, u32), Tile>,) -> Vec<(u32, u32)> {
-        let mut tiles_in_range: Vec<(u32, u32)> = Vec::new();
-        let mut visited: HashMap<(u32,u32), bool> = HashMap::new();
+        //Most moves left after reaching each tile
+        let mut best: HashMap<(u32, u32), u32> = HashMap::new();
         let mut heap = BinaryHeap::new();
         heap.push(QueueObject{coords: (self.x, self.y), cost: self.movement_range});
-        visited.insert((self.x, self.y), true);
-        tiles_in_range.push((self.x, self.y));
+        best.insert((self.x, self.y), self.movement_range);
         while let Some(QueueObject { coords, cost }) = heap.pop() {
-            if cost == 0 {
+            //Already reached this tile with more moves left
+            if best.get(&coords).is_some_and(|&moves_left| moves_left > cost) {
                 continue
             }
-            //Since we know that we can make a move here need to check each of the 4 sides of the current position to see if we can make a move
+
+            let mut neighbours = vec![(coords.0 + 1, coords.1), (coords.0, coords.1 + 1)];
             if coords.0 > 0 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0-1 as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() && !visited.contains_key(&(coords.0-1, coords.1)){
-                        heap.push(QueueObject { coords: (coords.0-1, coords.1), cost:cost-1});
-                        visited.insert((coords.0-1, coords.1), true);
-                        tiles_in_range.push((coords.0-1, coords.1));
-                    }
-                }
-            }
-            if coords.0 < MAP_WIDTH-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() && !visited.contains_key(&(coords.0+1, coords.1)){
-                        heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
-                        visited.insert((coords.0+1, coords.1), true);
-                        tiles_in_range.push((coords.0+1, coords.1));
-                    }
-                }
+                neighbours.push((coords.0 - 1, coords.1));
             }
             if coords.1 > 0 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1-1 as u32, coords.0 as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() && !visited.contains_key(&(coords.0, coords.1-1)){
-                        heap.push(QueueObject { coords: (coords.0, coords.1-1), cost:cost-1});
-                        visited.insert((coords.0, coords.1-1), true);
-                        tiles_in_range.push((coords.0, coords.1-1));
-                    }
-                }
+                neighbours.push((coords.0, coords.1 - 1));
             }
-            if coords.1 < MAP_HEIGHT-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() && !visited.contains_key(&(coords.0, coords.1+1)){
-                        heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
-                        visited.insert((coords.0, coords.1+1), true);
-                        tiles_in_range.push((coords.0, coords.1+1));
+
+            for next in neighbours {
+                //Remember map is flipped indexing
+                if let Some(tile) = map.get(&(next.1, next.0)) {
+                    //As long as a unit can move to this tile and has the moves left to do so
+                    if tile.unit_can_move_here() && cost >= tile.movement_cost {
+                        let moves_left = cost - tile.movement_cost;
+                        if best.get(&next).is_none_or(|&previous| moves_left > previous) {
+                            best.insert(next, moves_left);
+                            heap.push(QueueObject { coords: next, cost: moves_left });
+                        }
                     }
                 }
             }
         }
+        //Sorted, since the barbarians pick from these at random and matches have to play out the same from their seed
+        let mut tiles_in_range: Vec<(u32, u32)> = best.keys().cloned().collect();
+        tiles_in_range.sort_by_key(|&(x, y)| (y, x));
         tiles_in_range
     }
 
@@ -332,14 +345,12 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.0 < MAP_WIDTH-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
-                        heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
-                        visited.insert((coords.0+1, coords.1), true);
-                        tiles_in_range.push((coords.0+1, coords.1));
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
+                    heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
+                    visited.insert((coords.0+1, coords.1), true);
+                    tiles_in_range.push((coords.0+1, coords.1));
                 }
             }
             if coords.1 > 0 {
@@ -352,14 +363,12 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.1 < MAP_HEIGHT-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
-                        heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
-                        visited.insert((coords.0, coords.1+1), true);
-                        tiles_in_range.push((coords.0, coords.1+1));
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
+                    heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
+                    visited.insert((coords.0, coords.1+1), true);
+                    tiles_in_range.push((coords.0, coords.1+1));
                 }
             }
         }
@@ -394,21 +403,19 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.0 < MAP_WIDTH-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
-                        heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
-                        visited.insert((coords.0+1, coords.1), true);
-                        match entry.get().contained_unit_team {
-                            Some(team) => {
-                                if team != self.team {
-                                    tiles_in_range.push((coords.0+1, coords.1));
-                                }
-                            },
-                            None => {}
-                        };
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
+                    heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
+                    visited.insert((coords.0+1, coords.1), true);
+                    match entry.get().contained_unit_team {
+                        Some(team) => {
+                            if team != self.team {
+                                tiles_in_range.push((coords.0+1, coords.1));
+                            }
+                        },
+                        None => {}
+                    };
                 }
             }
             if coords.1 > 0 {
@@ -428,21 +435,19 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.1 < MAP_HEIGHT-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
-                        heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
-                        visited.insert((coords.0, coords.1+1), true);
-                        match entry.get().contained_unit_team {
-                            Some(team) => {
-                                if team != self.team {
-                                    tiles_in_range.push((coords.0, coords.1+1));
-                                }
-                            },
-                            None => {}
-                        };
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
+                    heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
+                    visited.insert((coords.0, coords.1+1), true);
+                    match entry.get().contained_unit_team {
+                        Some(team) => {
+                            if team != self.team {
+                                tiles_in_range.push((coords.0, coords.1+1));
+                            }
+                        },
+                        None => {}
+                    };
                 }
             }
         }
@@ -477,21 +482,19 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.0 < MAP_WIDTH-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
-                        heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
-                        visited.insert((coords.0+1, coords.1), true);
-                        match entry.get().contained_unit_team {
-                            Some(team) => {
-                                if team != self.team {
-                                    tiles_in_range.push((coords.0+1, coords.1));
-                                }
-                            },
-                            None => {}
-                        };
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1 as u32, coords.0+1 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0+1, coords.1)){
+                    heap.push(QueueObject { coords: (coords.0+1, coords.1), cost:cost-1});
+                    visited.insert((coords.0+1, coords.1), true);
+                    match entry.get().contained_unit_team {
+                        Some(team) => {
+                            if team != self.team {
+                                tiles_in_range.push((coords.0+1, coords.1));
+                            }
+                        },
+                        None => {}
+                    };
                 }
             }
             if coords.1 > 0 {
@@ -511,79 +514,42 @@ impl Unit <'_>{
                     }
                 }
             }
-            if coords.1 < MAP_HEIGHT-1 {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
-                    //As long as we have not already visited this tile
-                    if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
-                        heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
-                        visited.insert((coords.0, coords.1+1), true);
-                        match entry.get().contained_unit_team {
-                            Some(team) => {
-                                if team != self.team {
-                                    tiles_in_range.push((coords.0, coords.1+1));
-                                }
-                            },
-                            None => {}
-                        };
-                    }
+            if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((coords.1+1 as u32, coords.0 as u32)) {
+                //As long as we have not already visited this tile
+                if entry.get().can_attack_through && !visited.contains_key(&(coords.0, coords.1+1)){
+                    heap.push(QueueObject { coords: (coords.0, coords.1+1), cost:cost-1});
+                    visited.insert((coords.0, coords.1+1), true);
+                    match entry.get().contained_unit_team {
+                        Some(team) => {
+                            if team != self.team {
+                                tiles_in_range.push((coords.0, coords.1+1));
+                            }
+                        },
+                        None => {}
+                    };
                 }
             }
         }
         tiles_in_range
     }
 
-    pub fn receive_damage(&mut self, damage: u32, other: &Unit) {
-        let mut do_damage = damage;
-        if self.max_hp == GUARD_HEALTH_ID && other.ranged_attacker && damage > 1 {
-            do_damage /= 2;
+    // how much of an attack's damage the unit actually takes
+    pub fn damage_taken_from(&self, damage: u32, other: &Unit) -> u32 {
+        if self.class.has_ranged_shield() && other.ranged_attacker && damage > 1 {
+            damage / 2
+        } else {
+            damage
         }
-        self.hp = self.hp.checked_sub(do_damage).unwrap_or(0);
+    }
+
+    pub fn receive_damage(&mut self, damage: u32, other: &Unit) {
+        self.hp = self.hp.saturating_sub(self.damage_taken_from(damage, other));
 
         //Make the unit turn red after taking damage
         self.is_attacked = true;
         self.last_damaged_drawn = Instant::now();
     }
 
-    pub fn draw(&mut self, core: &mut SDLCore, dest: &Rect) -> Result<(), String> {
-        let src = if self.has_attacked && self.has_moved {
-            //Draw the darkened sprite
-            self.gray_sprite_src
-        }
-        else if self.is_attacked {
-            self.time_since_damaged += self.last_damaged_drawn.elapsed().as_secs_f32();
-            self.last_damaged_drawn = Instant::now();
-
-            //Remove red tint after 1 second
-            if self.time_since_damaged >= 1.0 {
-                self.is_attacked = false;
-                self.time_since_damaged = 0.0;
-            }
-
-            //Draw the sprite that's tinted red
-            self.red_sprite_src
-        }
-        else {
-            //Draw the default sprite
-            self.default_sprite_src
-        };
-
-        let should_animate = dest.intersection(core.cam) != None && (self.draw_x - dest.x as f64).abs() > 0.01 || (self.draw_y - dest.y as f64).abs() > 0.01;
-        if should_animate && self.draw_x > 0.0 && self.draw_y > 0.0 {
-            self.draw_x = (self.draw_x + dest.x as f64) / 2.0;
-            self.draw_y = (self.draw_y + dest.y as f64) / 2.0;
-        } else {
-            self.draw_x = dest.x as f64;
-            self.draw_y = dest.y as f64;
-        }
-
-        let rect = Rect::new(self.draw_x as i32, self.draw_y as i32, dest.width(), dest.height());
-
-        //Draw the sprite
-        core.wincan.copy(self.texture, src, rect)?;
-
-        Ok(())
-    }
-
     // heals the unit & returns the amount of hp applied
     pub fn heal(&mut self, total_heal: u32) -> u32 {
         println!("Current unit hp: {}/{}", self.hp, self.max_hp);
@@ -592,64 +558,77 @@ impl Unit <'_>{
 
         heal
     }
+
+    // Space separated representation of the unit used by save files (see save.rs), its stats come from the class
+    pub fn to_save_string(&self) -> String {
+        format!("{} {} {} {} {} {} {} {}",
+            self.class.name, self.x, self.y, self.hp,
+            self.has_moved as u8, self.has_attacked as u8,
+            self.starting_x, self.starting_y,
+        )
+    }
+
+    pub fn from_save_string(team: Team, s: &str) -> Result<Unit, String> {
+        let values: Vec<&str> = s.split_whitespace().collect();
+        if values.len() != 8 {
+            return Err(format!("Invalid unit '{}'", s));
+        }
+
+        let nums: Vec<u32> = values[1..].iter().map(|v| v.parse::<u32>()).collect::<Result<Vec<u32>, _>>().map_err(|e| e.to_string())?;
+        let mut unit = Unit::new(nums[0], nums[1], team, unit_class::by_name(values[0])?);
+        unit.hp = nums[2].min(unit.max_hp);
+        unit.has_moved = nums[3] == 1;
+        unit.has_attacked = nums[4] == 1;
+        unit.starting_x = nums[5];
+        unit.starting_y = nums[6];
+
+        Ok(unit)
+    }
 }
 
-impl fmt::Display for Unit<'_> {
+impl fmt::Display for Unit {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Unit(x:{}, y:{}, hp:{})", self.x, self.y, self.hp)
     }
 }
 
 pub fn respawn_loc(castle_coords: (u32, u32), map: &mut HashMap<(u32, u32), Tile>, where_to_spawn: (u32,u32)) -> (u32, u32) {
-    if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((where_to_spawn.1, where_to_spawn.0)) {
-        //As long as a unit can move to this tile return it otherwise find closest available
-        if entry.get().unit_can_move_here() {
-            where_to_spawn
-        } else {
-            let mut y_increment: i32 = 1;
-            let mut x_increment: i32 = 1;
-            let mut current_x:i32 = where_to_spawn.0 as i32;
-            let mut current_y:i32 = where_to_spawn.1 as i32;
-
-            if where_to_spawn.1 > castle_coords.1 { //If the coorodinate is below then our increment should be -1;
-                y_increment = -1;
-            } else if where_to_spawn.1 == castle_coords.1 { //If the coordinates are at the same y focus on moving x first
-                y_increment = 0;
-            }
-            if where_to_spawn.0 > castle_coords.0 { //If the coorodinate is to the right then our increment should be -1;
-                x_increment = -1;
-            } else if where_to_spawn.0 == castle_coords.0 { //If the coordinates are at the same x focus on moving y first
-                x_increment = 0;
-            }
-            loop {
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry((current_y as u32, (current_x + x_increment) as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() {
-                        return ((current_x + x_increment) as u32, current_y as u32)
-                    }
-                }
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry(((current_y + y_increment) as u32, current_x as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() {
-                        return (current_x as u32, (current_y + y_increment) as u32)
-                    }
-                }
-                if let std::collections::hash_map::Entry::Occupied(entry) = map.entry(((current_y + y_increment) as u32, (current_x + x_increment) as u32)) {
-                    //As long as a unit can move to this tile and we have not already visited this tile
-                    if entry.get().unit_can_move_here() {
-                        return ((current_x + x_increment) as u32, (current_y + y_increment) as u32)
-                    }
-                }
-                current_x += x_increment;
-                current_y += y_increment;
-                if current_x == castle_coords.0 as i32 && current_y == castle_coords.1 as i32 {
-                    break;
-                }
+    if !map.contains_key(&(where_to_spawn.1, where_to_spawn.0)) {
+        panic!("Trying to spawn unit off map")
+    }
+    //As long as a unit can move to the tile return it, otherwise find the closest available.
+    //In the event that no closer moves are found, stay at current position
+    respawn_candidates(castle_coords, where_to_spawn).into_iter()
+        .find(|pos| map.get(&(pos.1, pos.0)).is_some_and(|tile| tile.unit_can_move_here()))
+        .unwrap_or(castle_coords)
+}
+
+//Every tile respawn_loc could pick, closest first: where_to_spawn, then the tiles next to each step from there
+//towards castle_coords, and castle_coords itself. The server checks spawns against these
+pub fn respawn_candidates(castle_coords: (u32, u32), where_to_spawn: (u32,u32)) -> Vec<(u32, u32)> {
+    let increment = |from: u32, to: u32| -> i64 {
+        match from.cmp(&to) {
+            Ordering::Greater => -1, //If the coordinate is below/to the right then our increment should be -1
+            Ordering::Equal => 0, //If the coordinates are level focus on moving along the other axis
+            Ordering::Less => 1,
+        }
+    };
+    let x_increment = increment(where_to_spawn.0, castle_coords.0);
+    let y_increment = increment(where_to_spawn.1, castle_coords.1);
+    let steps = (where_to_spawn.0 as i64 - castle_coords.0 as i64).abs().max((where_to_spawn.1 as i64 - castle_coords.1 as i64).abs());
+
+    let mut candidates = vec![where_to_spawn];
+    let mut current_x = where_to_spawn.0 as i64;
+    let mut current_y = where_to_spawn.1 as i64;
+    for _ in 0..steps {
+        for (x, y) in [(current_x + x_increment, current_y), (current_x, current_y + y_increment), (current_x + x_increment, current_y + y_increment)] {
+            if x >= 0 && y >= 0 {
+                candidates.push((x as u32, y as u32));
             }
-            //In the event that no closer moves are found, stay at current position
-            (castle_coords.0, castle_coords.1)
         }
-    } else {
-        panic!("Trying to spawn unit off map")
+        current_x += x_increment;
+        current_y += y_increment;
     }
+    candidates.push(castle_coords);
+    candidates
 }
@@ -0,0 +1,233 @@
+use std::fs;
+use std::sync::Mutex;
+
+use crate::net::util::{EVENT_UNIT_ARCHER, EVENT_UNIT_MAGE, EVENT_UNIT_MELEE};
+use crate::unit::Team;
+
+pub const UNIT_CLASSES_FORMAT_VERSION: u32 = 1;
+pub const DEFAULT_UNIT_CLASSES: &str = "data/unit_classes.txt";
+
+//How many tiles away a unit can see if its class doesn't say (see GameMap::visible_tiles)
+pub const DEFAULT_VISION_RANGE: u32 = 5;
+
+//Classes the game refers to by id (converted barbarians join as one of these), which every class file has to define
+const REQUIRED_CLASSES: [u8; 3] = [EVENT_UNIT_MELEE, EVENT_UNIT_ARCHER, EVENT_UNIT_MAGE];
+
+//Classes live for the rest of the program once loaded, so units can simply hold on to a reference
+static UNIT_CLASSES: Mutex<Vec<&'static UnitClass>> = Mutex::new(Vec::new());
+
+pub enum Ability {
+	RangedShield, // takes half damage from ranged attacks
+	Evasive(u32), // attacks on the unit are this many % less likely to hit
+}
+
+pub struct UnitClass {
+	pub id: u8, // how the class is referred to in events (see EVENT_SPAWN_UNIT and EVENT_REVEAL)
+	pub name: String,
+	pub hp: u32,
+	pub movement_range: u32,
+	pub attack_range: u32,
+	pub accuracy: u32,
+	pub min_damage: u32,
+	pub max_damage: u32,
+	pub vision_range: u32,
+	pub ranged_attacker: bool,
+	pub sprites: [String; 3], // image files for player 1, player 2 and the barbarians, which double as their texture keys
+	pub barbarian_stats: (u32, u32, u32), // (hp, min damage, max damage) of barbarians of this class
+	pub abilities: Vec<Ability>,
+}
+
+impl UnitClass {
+	//Texture key for a unit of this class on the given (client relative) team
+	pub fn sprite(&self, team: Team) -> &str {
+		match team {
+			Team::Player => &self.sprites[0],
+			Team::Enemy => &self.sprites[1],
+			Team::Barbarians => &self.sprites[2],
+		}
+	}
+
+	pub fn has_ranged_shield(&self) -> bool {
+		self.abilities.iter().any(|a| matches!(a, Ability::RangedShield))
+	}
+
+	pub fn evasion(&self) -> u32 {
+		self.abilities.iter().map(|a| match a { Ability::Evasive(e) => *e, _ => 0 }).sum()
+	}
+}
+
+/* Unit class files are plain text with the format version on the first line, followed by one
+ * `class <name>` ... `end` block per class with a property on each line. Blank lines and lines
+ * starting with `#` are ignored. See data/unit_classes.txt for the properties.
+ */
+pub fn load(path: &str) -> Result<(), String> {
+	let contents = fs::read_to_string(path).map_err(|e| format!("Could not read unit classes '{}': {}", path, e))?;
+	let classes = parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+
+	let mut loaded = UNIT_CLASSES.lock().unwrap();
+	loaded.clear();
+	for class in classes {
+		loaded.push(Box::leak(Box::new(class)));
+	}
+	Ok(())
+}
+
+pub fn by_id(id: u8) -> Result<&'static UnitClass, String> {
+	UNIT_CLASSES.lock().unwrap().iter().find(|c| c.id == id).copied().ok_or(format!("Unknown unit class {}", id))
+}
+
+pub fn by_name(name: &str) -> Result<&'static UnitClass, String> {
+	UNIT_CLASSES.lock().unwrap().iter().find(|c| c.name == name).copied().ok_or(format!("Unknown unit class '{}'", name))
+}
+
+pub fn all() -> Vec<&'static UnitClass> {
+	UNIT_CLASSES.lock().unwrap().clone()
+}
+
+fn parse(contents: &str) -> Result<Vec<UnitClass>, String> {
+	let mut lines = contents.lines().enumerate().map(|(i, l)| (i + 1, l.trim()))
+		.filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+	match lines.next() {
+		Some((_, first)) if first == format!("castle_quest_units {}", UNIT_CLASSES_FORMAT_VERSION) => {},
+		Some((_, first)) if first.starts_with("castle_quest_units ") => return Err(format!("Unsupported format version '{}'", &first[19..])),
+		_ => return Err(String::from("Not a castle_quest_units file")),
+	}
+
+	let mut classes: Vec<UnitClass> = Vec::new();
+	while let Some((line_num, line)) = lines.next() {
+		let name = line.strip_prefix("class ").ok_or(format!("line {}: expected `class <name>`", line_num))?.trim();
+		let mut class = UnitClass {
+			id: 0,
+			name: name.to_string(),
+			hp: 0,
+			movement_range: 0,
+			attack_range: 0,
+			accuracy: 0,
+			min_damage: 0,
+			max_damage: 0,
+			vision_range: DEFAULT_VISION_RANGE,
+			ranged_attacker: false,
+			sprites: [String::new(), String::new(), String::new()],
+			barbarian_stats: (0, 0, 0),
+			abilities: Vec::new(),
+		};
+		let mut id = None;
+
+		loop {
+			let (line_num, line) = lines.next().ok_or(format!("class '{}' is missing its `end`", name))?;
+			let values: Vec<&str> = line.split_whitespace().collect();
+			let invalid = || format!("line {}: invalid property '{}'", line_num, line);
+			let nums = || values[1..].iter().map(|v| v.parse::<u32>()).collect::<Result<Vec<u32>, _>>().map_err(|_e| invalid());
+
+			match values[..] {
+				["end"] => break,
+				["id", _] => id = Some(values[1].parse::<u8>().map_err(|_e| invalid())?),
+				["hp", _] => class.hp = nums()?[0],
+				["move", _] => class.movement_range = nums()?[0],
+				["range", _] => class.attack_range = nums()?[0],
+				["accuracy", _] => class.accuracy = nums()?[0],
+				["damage", _, _] => {
+					let nums = nums()?;
+					class.min_damage = nums[0];
+					class.max_damage = nums[1].max(nums[0]);
+				},
+				["vision", _] => class.vision_range = nums()?[0],
+				["ranged"] => class.ranged_attacker = true,
+				["sprites", p1, p2, barbarian] => class.sprites = [p1.to_string(), p2.to_string(), barbarian.to_string()],
+				["barbarian", _, _, _] => {
+					let nums = nums()?;
+					class.barbarian_stats = (nums[0], nums[1], nums[2].max(nums[1]));
+				},
+				["ability", "ranged_shield"] => class.abilities.push(Ability::RangedShield),
+				["ability", "evasive", _] => class.abilities.push(Ability::Evasive(values[2].parse::<u32>().map_err(|_e| invalid())?)),
+				_ => return Err(invalid()),
+			}
+		}
+
+		class.id = id.ok_or(format!("class '{}' is missing its id", name))?;
+		if class.hp == 0 || class.sprites[0].is_empty() {
+			return Err(format!("class '{}' needs at least hp and sprites", name));
+		}
+		if class.barbarian_stats.0 == 0 {
+			class.barbarian_stats = (class.hp, class.min_damage, class.max_damage);
+		}
+		if classes.iter().any(|c| c.id == class.id || c.name == class.name) {
+			return Err(format!("class '{}' has the same name or id as another class", name));
+		}
+		classes.push(class);
+	}
+
+	if classes.is_empty() {
+		return Err(String::from("No unit classes defined"));
+	}
+	for id in REQUIRED_CLASSES.iter() {
+		if !classes.iter().any(|c| c.id == *id) {
+			return Err(format!("No class with id {}, the game needs classes with ids {:?}", id, REQUIRED_CLASSES));
+		}
+	}
+	Ok(classes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CLASS: &str = "class melee
+	id 3
+	hp 20
+	move 7
+	range 1
+	accuracy 95
+	damage 1 5
+	sprites p1.png p2.png barbarian.png
+end
+";
+
+	//The required archer & mage, followed by the given class
+	fn parse_with(class: &str) -> Result<Vec<UnitClass>, String> {
+		let required = "class archer\n id 0\n hp 15\n sprites a b c\nend\nclass mage\n id 2\n hp 10\n sprites a b c\nend\n";
+		parse(&format!("castle_quest_units 1\n# comment\n\n{}{}", required, class))
+	}
+
+	#[test]
+	fn parses_classes() {
+		let classes = parse_with(&CLASS.replace("end", "vision 3\n\tranged\n\tbarbarian 10 1 3\n\tability evasive 20\n\tability ranged_shield\nend")).unwrap();
+		let melee = &classes[2];
+		assert_eq!((melee.id, melee.name.as_str(), melee.hp, melee.movement_range, melee.attack_range), (3, "melee", 20, 7, 1));
+		assert_eq!((melee.accuracy, melee.min_damage, melee.max_damage, melee.vision_range), (95, 1, 5, 3));
+		assert_eq!(melee.sprite(Team::Enemy), "p2.png");
+		assert_eq!(melee.barbarian_stats, (10, 1, 3));
+		assert!(melee.ranged_attacker && melee.has_ranged_shield());
+		assert_eq!(melee.evasion(), 20);
+
+		//Barbarians are as strong as the players' units unless the class says otherwise
+		let classes = parse_with(CLASS).unwrap();
+		assert_eq!(classes[2].barbarian_stats, (20, 1, 5));
+		assert_eq!(classes[2].vision_range, DEFAULT_VISION_RANGE);
+	}
+
+	#[test]
+	fn default_classes_parse() {
+		let classes = parse(&fs::read_to_string(DEFAULT_UNIT_CLASSES).unwrap()).unwrap();
+		for id in REQUIRED_CLASSES.iter() {
+			assert!(classes.iter().any(|c| c.id == *id));
+		}
+	}
+
+	#[test]
+	fn rejects_bad_classes() {
+		assert!(parse("").is_err());
+		assert!(parse("castle_quest_units 2\n").is_err());
+		assert!(parse_with(&CLASS.replace("id 3", "id 0")).is_err()); //Same id as the archer
+		assert!(parse_with(&CLASS.replace("\tid 3\n", "")).is_err());
+		assert!(parse_with(&CLASS.replace("hp 20", "hp lots")).is_err());
+		assert!(parse_with(&CLASS.replace("range 1", "reach 1")).is_err());
+		assert!(parse_with(&CLASS.replace("end\n", "")).is_err());
+		assert!(parse_with(&CLASS.replace("\tsprites p1.png p2.png barbarian.png\n", "")).is_err());
+
+		//Converted barbarians can become melee, so there has to be a melee class
+		let e = parse_with(&CLASS.replace("id 3", "id 4")).err().unwrap();
+		assert!(e.contains("id 3"), "{}", e);
+	}
+}